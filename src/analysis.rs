@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::fs;
+
+use rgb::RGBA8 as RGBA;
+
+use crate::cli::{AnalyzeArgs, DEFAULT_MAX_PIXELS};
+use crate::decode::{DecodedImage, decode_rgba};
+use crate::error::{Context, PathContext, Result};
+
+/// Largest per-channel step between neighbours still considered part of a smooth gradient.
+const GRADIENT_STEP: u8 = 3;
+/// Side length of the square blocks the image is scanned in.
+const BLOCK: usize = 8;
+
+/// Fraction of the image covered by smooth gradients: blocks whose neighbouring pixels
+/// never differ by more than a few levels, yet which aren't a single flat color.
+///
+/// Such regions are where a small palette produces visible banding.
+fn gradient_fraction(pixels: &[RGBA], width: u32, height: u32) -> f64 {
+    let width = width as usize;
+    let height = height as usize;
+    if width < BLOCK || height < BLOCK || pixels.len() < width * height {
+        return 0.0;
+    }
+
+    let blocks_x = width / BLOCK;
+    let blocks_y = height / BLOCK;
+    let mut gradient_blocks = 0usize;
+    for by in 0..blocks_y {
+        for bx in 0..blocks_x {
+            if is_gradient_block(pixels, width, bx * BLOCK, by * BLOCK) {
+                gradient_blocks += 1;
+            }
+        }
+    }
+
+    gradient_blocks as f64 / (blocks_x * blocks_y) as f64
+}
+
+/// Share of the image covered by gradients above which the palette is enlarged.
+const BANDING_ELEVATED: f64 = 0.25;
+/// Share of the image covered by gradients above which quantization is skipped entirely.
+const BANDING_SEVERE: f64 = 0.6;
+
+/// How likely a palette is to band the image, with its gradient fraction.
+#[derive(Debug, Clone, Copy)]
+pub enum BandingRisk {
+    Low,
+    Elevated(f64),
+    Severe(f64),
+}
+
+pub fn assess_banding(pixels: &[RGBA], width: u32, height: u32) -> BandingRisk {
+    let score = gradient_fraction(pixels, width, height);
+    if score >= BANDING_SEVERE {
+        BandingRisk::Severe(score)
+    } else if score >= BANDING_ELEVATED {
+        BandingRisk::Elevated(score)
+    } else {
+        BandingRisk::Low
+    }
+}
+
+fn is_gradient_block(pixels: &[RGBA], width: usize, x0: usize, y0: usize) -> bool {
+    let first = pixels[y0 * width + x0];
+    let mut varies = false;
+    for y in y0..y0 + BLOCK {
+        for x in x0..x0 + BLOCK {
+            let here = pixels[y * width + x];
+            varies |= here != first;
+            if x + 1 < x0 + BLOCK && channel_step(here, pixels[y * width + x + 1]) > GRADIENT_STEP {
+                return false;
+            }
+            if y + 1 < y0 + BLOCK && channel_step(here, pixels[(y + 1) * width + x]) > GRADIENT_STEP
+            {
+                return false;
+            }
+        }
+    }
+
+    varies
+}
+
+fn channel_step(a: RGBA, b: RGBA) -> u8 {
+    a.r.abs_diff(b.r)
+        .max(a.g.abs_diff(b.g))
+        .max(a.b.abs_diff(b.b))
+        .max(a.a.abs_diff(b.a))
+}
+
+/// Per-channel step between neighbours above which the pair counts as a hard edge.
+const EDGE_STEP: u8 = 48;
+/// Minimum share of distinct colors (relative to pixel count) for photographic content.
+const PHOTO_UNIQUE_RATIO: f64 = 0.1;
+/// Maximum share of hard-edged pixels for photographic content.
+const PHOTO_EDGE_DENSITY: f64 = 0.15;
+
+/// Coarse content type, used to pick quantization and filtering settings under `--adaptive`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ContentClass {
+    /// Continuous-tone imagery: many colors, soft transitions.
+    Photo,
+    /// Flat-colored artwork such as icons, UI and diagrams.
+    Graphic,
+}
+
+impl ContentClass {
+    pub fn label(self) -> &'static str {
+        match self {
+            ContentClass::Photo => "photo",
+            ContentClass::Graphic => "graphic",
+        }
+    }
+}
+
+/// Classifies an image from its unique color ratio and edge density.
+pub fn classify_content(pixels: &[RGBA], width: u32, height: u32) -> ContentClass {
+    let width = width as usize;
+    let height = height as usize;
+    let pixel_count = width * height;
+    if pixel_count == 0 || pixels.len() < pixel_count {
+        return ContentClass::Graphic;
+    }
+
+    let unique: HashSet<RGBA> = pixels[..pixel_count].iter().copied().collect();
+    let unique_ratio = unique.len() as f64 / pixel_count as f64;
+
+    let mut edges = 0usize;
+    for y in 0..height {
+        for x in 0..width {
+            let here = pixels[y * width + x];
+            let right = x + 1 < width && channel_step(here, pixels[y * width + x + 1]) > EDGE_STEP;
+            let below =
+                y + 1 < height && channel_step(here, pixels[(y + 1) * width + x]) > EDGE_STEP;
+            if right || below {
+                edges += 1;
+            }
+        }
+    }
+    let edge_density = edges as f64 / pixel_count as f64;
+
+    if unique_ratio >= PHOTO_UNIQUE_RATIO && edge_density <= PHOTO_EDGE_DENSITY {
+        ContentClass::Photo
+    } else {
+        ContentClass::Graphic
+    }
+}
+
+/// An indexed PNG holds at most this many palette entries.
+#[cfg(feature = "compress")]
+const MAX_PALETTE_SIZE: usize = 256;
+
+/// Prints the color, alpha and gradient profile of `args.file` and which mode and
+/// settings suit it, using the same classifier and banding check as `compress` mode.
+pub fn run(args: &AnalyzeArgs) -> Result<()> {
+    let bytes = fs::read(&args.file)
+        .path_context(&args.file)
+        .context("reading input PNG")?;
+    let image = decode_rgba(&bytes, DEFAULT_MAX_PIXELS)
+        .with_context(|| format!("decoding {}", args.file.display()))?;
+    let pixel_count = image.pixels.len().max(1);
+
+    let unique_colors = image.pixels.iter().collect::<HashSet<_>>().len();
+    let transparent = image.pixels.iter().filter(|px| px.a == 0).count();
+    let translucent = image
+        .pixels
+        .iter()
+        .filter(|px| px.a != 0 && px.a != 255)
+        .count();
+    let percent = |count: usize| 100.0 * count as f64 / pixel_count as f64;
+    let alpha = if translucent > 0 {
+        format!("partial ({:.1}% translucent)", percent(translucent))
+    } else if transparent > 0 {
+        format!("binary ({:.1}% transparent)", percent(transparent))
+    } else {
+        String::from("opaque")
+    };
+    let content = classify_content(&image.pixels, image.width, image.height);
+    let banding = assess_banding(&image.pixels, image.width, image.height);
+    let (score, risk) = match banding {
+        BandingRisk::Low => (
+            gradient_fraction(&image.pixels, image.width, image.height),
+            "low",
+        ),
+        BandingRisk::Elevated(score) => (score, "elevated"),
+        BandingRisk::Severe(score) => (score, "severe"),
+    };
+
+    println!(
+        "dimensions:    {}x{} ({} pixels)",
+        image.width,
+        image.height,
+        image.pixels.len()
+    );
+    println!("unique colors: {unique_colors}");
+    println!("alpha:         {alpha}");
+    println!("content:       {}", content.label());
+    println!("banding risk:  {:.0}% gradient ({risk})", score * 100.0);
+    println!(
+        "recommended:   {}",
+        recommend(&image, unique_colors, content, banding)?
+    );
+
+    Ok(())
+}
+
+/// The mode and settings that should shrink the image most without visible damage,
+/// described from the tuning `compress` mode picks for it under those settings.
+#[cfg(feature = "compress")]
+fn recommend(
+    image: &DecodedImage,
+    unique_colors: usize,
+    content: ContentClass,
+    banding: BandingRisk,
+) -> Result<String> {
+    use crate::compressor::select_tuning;
+    use crate::options::CompressOptionsBuilder;
+
+    if unique_colors <= MAX_PALETTE_SIZE {
+        return Ok(format!(
+            "--mode optimize ({unique_colors} colors already fit a palette losslessly)"
+        ));
+    }
+    let (flags, options) = match (banding, content) {
+        (BandingRisk::Severe(_), _) => {
+            return Ok(String::from(
+                "--mode optimize (gradients would band with any palette; compress keeps it lossless)",
+            ));
+        }
+        (BandingRisk::Elevated(_), _) => ("--mode compress", CompressOptionsBuilder::new()),
+        (BandingRisk::Low, ContentClass::Photo) => (
+            "--mode compress --adaptive",
+            CompressOptionsBuilder::new().adaptive(true),
+        ),
+        (BandingRisk::Low, ContentClass::Graphic) => (
+            "--mode compress --adaptive --quality 85",
+            CompressOptionsBuilder::new().adaptive(true).quality(85),
+        ),
+    };
+    let tuning = select_tuning(image, &options.build()?, banding);
+    Ok(format!("{flags} ({})", tuning.summary()))
+}
+
+/// Without compress mode only lossless optimization is available.
+#[cfg(not(feature = "compress"))]
+fn recommend(
+    _image: &DecodedImage,
+    _unique_colors: usize,
+    _content: ContentClass,
+    _banding: BandingRisk,
+) -> Result<String> {
+    Ok(String::from(
+        "--mode optimize (built without compress mode)",
+    ))
+}
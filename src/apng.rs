@@ -0,0 +1,234 @@
+use std::fs;
+use std::io::Cursor;
+#[cfg(feature = "compress")]
+use std::path::PathBuf;
+
+#[cfg(feature = "compress")]
+use png::{AdaptiveFilterType, FilterType};
+use png::{BitDepth, BlendOp, ColorType, Compression, DisposeOp, Encoder, Transformations};
+
+use crate::cli::{ApngJoinArgs, ApngSplitArgs};
+#[cfg(feature = "compress")]
+use crate::compressor::quantize_frames;
+use crate::error::{Context, PathContext, Result, TurboPngError, bail};
+use crate::pipeline::{ensure_output_available, write_atomic};
+
+/// Writes each frame of `args.file` as `<stem>_0001.png`, `<stem>_0002.png`, ... in
+/// `args.out`. Frames are composited onto the full canvas, so each file is what a viewer
+/// shows at that point in the animation.
+pub fn split(args: &ApngSplitArgs) -> Result<()> {
+    let file = &args.file;
+    let stem = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("input file lacks a valid stem")?;
+    let bytes = fs::read(file)
+        .path_context(file)
+        .context("reading input APNG")?;
+    let frames =
+        composite_frames(&bytes).with_context(|| format!("decoding {}", file.display()))?;
+
+    fs::create_dir_all(&args.out)
+        .path_context(&args.out)
+        .context("creating output directory")?;
+    for (index, frame) in frames.iter().enumerate() {
+        let path = args.out.join(format!("{stem}_{:04}.png", index + 1));
+        ensure_output_available(&path, args.overwrite)?;
+        let encoded = encode_rgba(frame)?;
+        write_atomic(&path, &encoded, args.overwrite)
+            .with_context(|| format!("writing {}", path.display()))?;
+    }
+
+    println!(
+        "{} → {} frames in {}",
+        file.display(),
+        frames.len(),
+        args.out.display()
+    );
+    Ok(())
+}
+
+/// Quantizes `args.frames` against one shared palette and writes them as an indexed APNG
+/// playing at `args.fps`.
+#[cfg(feature = "compress")]
+pub fn join(args: &ApngJoinArgs) -> Result<()> {
+    ensure_output_available(&args.output, args.overwrite)?;
+    let frames = args
+        .frames
+        .iter()
+        .map(|path| {
+            fs::read(path)
+                .path_context(path)
+                .context("reading frame")
+                .map(|bytes| (path.clone(), bytes))
+        })
+        .collect::<Result<Vec<(PathBuf, Vec<u8>)>>>()?;
+
+    let shared = quantize_frames(&frames, args.quality)?;
+    let mut palette = Vec::with_capacity(shared.palette.len() * 3);
+    let mut alpha = Vec::with_capacity(shared.palette.len());
+    for color in &shared.palette {
+        palette.extend([color.r, color.g, color.b]);
+        alpha.push(color.a);
+    }
+    while matches!(alpha.last(), Some(&255)) {
+        alpha.pop();
+    }
+
+    let mut output = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut output, shared.width, shared.height);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_palette(palette);
+        if !alpha.is_empty() {
+            encoder.set_trns(alpha);
+        }
+        encoder.set_compression(Compression::Best);
+        encoder.set_filter(FilterType::Paeth);
+        encoder.set_adaptive_filter(AdaptiveFilterType::Adaptive);
+        encoder.set_animated(shared.frames.len() as u32, args.loops)?;
+        encoder.set_frame_delay(1, args.fps)?;
+
+        let mut writer = encoder.write_header()?;
+        for indices in &shared.frames {
+            writer.write_image_data(indices)?;
+        }
+        writer.finish()?;
+    }
+
+    write_atomic(&args.output, &output, args.overwrite)
+        .with_context(|| format!("writing {}", args.output.display()))?;
+    println!("{} frames → {}", shared.frames.len(), args.output.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "compress"))]
+pub fn join(_args: &ApngJoinArgs) -> Result<()> {
+    bail!("`apng join` is unavailable: turbo-png was built without the `compress` feature")
+}
+
+/// A full-canvas RGBA frame.
+struct Frame {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+/// Decodes every animation frame and applies its blend and dispose operations, returning
+/// the canvas as shown for each frame. A still PNG yields a single frame.
+fn composite_frames(bytes: &[u8]) -> Result<Vec<Frame>> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info().context("reading PNG info")?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    // Without an fcTL before IDAT the default image is a fallback, not an animation frame.
+    let skip_default =
+        reader.info().animation_control.is_some() && reader.info().frame_control.is_none();
+    let frame_count = reader
+        .info()
+        .animation_control
+        .map_or(1, |animation| animation.num_frames as usize)
+        + usize::from(skip_default);
+
+    let mut canvas = vec![0u8; width as usize * height as usize * 4];
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let mut frames = Vec::with_capacity(frame_count);
+    for index in 0..frame_count {
+        let info = reader
+            .next_frame(&mut buffer)
+            .with_context(|| format!("reading frame {}", index + 1))?;
+        if index == 0 && skip_default {
+            continue;
+        }
+
+        let control = reader.info().frame_control().copied().unwrap_or_default();
+        let (x0, y0) = (control.x_offset as usize, control.y_offset as usize);
+        let (frame_width, frame_height) = (info.width as usize, info.height as usize);
+        if x0 + frame_width > width as usize || y0 + frame_height > height as usize {
+            bail!("frame {} extends past the canvas", frames.len() + 1);
+        }
+
+        let restore = (control.dispose_op == DisposeOp::Previous).then(|| canvas.clone());
+        for y in 0..frame_height {
+            let row = &buffer[y * info.line_size..];
+            for x in 0..frame_width {
+                let source = rgba_at(row, x, info.color_type)?;
+                let offset = ((y0 + y) * width as usize + x0 + x) * 4;
+                let target = &mut canvas[offset..offset + 4];
+                match control.blend_op {
+                    BlendOp::Source => target.copy_from_slice(&source),
+                    BlendOp::Over => blend_over(target, source),
+                }
+            }
+        }
+        frames.push(Frame {
+            width,
+            height,
+            pixels: canvas.clone(),
+        });
+
+        // Disposing to "previous" on the first frame clears, like disposing to background.
+        match (control.dispose_op, restore) {
+            (DisposeOp::None, _) => {}
+            (DisposeOp::Previous, Some(previous)) if frames.len() > 1 => canvas = previous,
+            _ => {
+                for y in y0..y0 + frame_height {
+                    let start = (y * width as usize + x0) * 4;
+                    canvas[start..start + frame_width * 4].fill(0);
+                }
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+fn rgba_at(row: &[u8], x: usize, color_type: ColorType) -> Result<[u8; 4]> {
+    Ok(match color_type {
+        ColorType::Rgba => row[x * 4..x * 4 + 4].try_into().unwrap(),
+        ColorType::Rgb => [row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 255],
+        ColorType::GrayscaleAlpha => [row[x * 2], row[x * 2], row[x * 2], row[x * 2 + 1]],
+        ColorType::Grayscale => [row[x], row[x], row[x], 255],
+        other => bail!("unsupported color type after decoding: {:?}", other),
+    })
+}
+
+/// Composites `source` over `target` (APNG's `APNG_BLEND_OP_OVER`).
+fn blend_over(target: &mut [u8], source: [u8; 4]) {
+    let source_alpha = u32::from(source[3]);
+    if source_alpha == 255 {
+        target.copy_from_slice(&source);
+        return;
+    }
+    if source_alpha == 0 {
+        return;
+    }
+
+    let target_alpha = u32::from(target[3]) * (255 - source_alpha) / 255;
+    let alpha = source_alpha + target_alpha;
+    for channel in 0..3 {
+        let mixed =
+            u32::from(source[channel]) * source_alpha + u32::from(target[channel]) * target_alpha;
+        target[channel] = ((mixed + alpha / 2) / alpha) as u8;
+    }
+    target[3] = alpha as u8;
+}
+
+fn encode_rgba(frame: &Frame) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut output, frame.width, frame.height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_compression(Compression::Best);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&frame.pixels)?;
+        writer.finish()?;
+    }
+
+    // Frames are usually flat artwork; let oxipng pick the smallest color type for each.
+    oxipng::optimize_from_memory(&output, &oxipng::Options::from_preset(2))
+        .map_err(TurboPngError::Deflate)
+        .context("optimizing frame")
+}
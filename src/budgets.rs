@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobMatcher};
+
+use crate::error::{Context, PathContext, Result, bail, format_err};
+use crate::pipeline::ProcessedFile;
+use crate::ui::format_bytes;
+
+/// Maximum output sizes keyed by glob pattern, loaded from a TOML manifest.
+///
+/// Patterns are matched against input paths relative to the manifest's
+/// directory; the first matching entry (in file order) applies.
+#[derive(Debug)]
+pub struct Budgets {
+    root: PathBuf,
+    entries: Vec<BudgetEntry>,
+}
+
+#[derive(Debug)]
+struct BudgetEntry {
+    pattern: String,
+    matcher: GlobMatcher,
+    max_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct BudgetViolation<'a> {
+    pub file: &'a ProcessedFile,
+    pub pattern: &'a str,
+    pub max_bytes: u64,
+}
+
+impl Budgets {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .path_context(path)
+            .context("reading budgets file")?;
+        let table: toml::Table = contents
+            .parse()
+            .with_context(|| format!("parsing budgets file {}", path.display()))?;
+
+        let mut entries = Vec::with_capacity(table.len());
+        for (pattern, value) in table {
+            let max_bytes = value
+                .as_integer()
+                .and_then(|bytes| u64::try_from(bytes).ok())
+                .ok_or_else(|| {
+                    format_err!("budget for {pattern:?} must be a non-negative byte count")
+                })?;
+            let matcher = Glob::new(&pattern)
+                .with_context(|| format!("invalid budget pattern {pattern:?}"))?
+                .compile_matcher();
+            entries.push(BudgetEntry {
+                pattern,
+                matcher,
+                max_bytes,
+            });
+        }
+
+        if entries.is_empty() {
+            bail!("budgets file {} defines no budgets", path.display());
+        }
+
+        let root = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+
+        Ok(Self { root, entries })
+    }
+
+    pub fn violations<'a>(&'a self, files: &'a [ProcessedFile]) -> Vec<BudgetViolation<'a>> {
+        files
+            .iter()
+            .filter_map(|file| {
+                let entry = self.lookup(&file.input)?;
+                (file.outcome.output_size > entry.max_bytes).then_some(BudgetViolation {
+                    file,
+                    pattern: &entry.pattern,
+                    max_bytes: entry.max_bytes,
+                })
+            })
+            .collect()
+    }
+
+    fn lookup(&self, input: &Path) -> Option<&BudgetEntry> {
+        let canonical = input.canonicalize().unwrap_or_else(|_| input.to_path_buf());
+        let relative = canonical.strip_prefix(&self.root).unwrap_or(&canonical);
+        self.entries
+            .iter()
+            .find(|entry| entry.matcher.is_match(relative))
+    }
+}
+
+pub fn enforce(budgets: &Budgets, files: &[ProcessedFile]) -> Result<()> {
+    let violations = budgets.violations(files);
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!("{} file(s) exceed their size budget:\n", violations.len());
+    for violation in &violations {
+        message.push_str(&format!(
+            " • {}: {} > {} (budget {:?})\n",
+            violation.file.input.display(),
+            format_bytes(violation.file.outcome.output_size),
+            format_bytes(violation.max_bytes),
+            violation.pattern,
+        ));
+    }
+    bail!(message);
+}
@@ -0,0 +1,191 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tempfile::TempDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+use crate::chunks::civil_date;
+use crate::cli::{AppConfig, Bundle, BundleFormat, CollisionPolicy, OutputLayout};
+use crate::error::{Context, PathContext, Result};
+use crate::interrupt;
+use crate::pipeline::{self, ProcessedFile};
+
+/// Points `config`'s output directory at a fresh staging directory when `--bundle` is
+/// set, returning that directory. It is removed when dropped.
+pub fn stage(config: &mut AppConfig) -> Result<Option<TempDir>> {
+    let Some(bundle) = &config.bundle else {
+        return Ok(None);
+    };
+    pipeline::ensure_output_available(&bundle.path, config.common.overwrite)?;
+    let dir = staging_dir(bundle)?;
+    config.output_dir = Some(OutputLayout {
+        dir: dir.path().to_path_buf(),
+        flatten: false,
+        on_collision: CollisionPolicy::Error,
+        copy_unchanged: false,
+    });
+    Ok(Some(dir))
+}
+
+/// Creates the directory outputs are written to before being packed, next to the bundle so
+/// it shares its filesystem.
+fn staging_dir(bundle: &Bundle) -> Result<TempDir> {
+    let parent = bundle_parent(bundle);
+    tempfile::Builder::new()
+        .prefix(".turbo-png-bundle")
+        .tempdir_in(parent)
+        .path_context(parent)
+        .context("creating a staging directory")
+}
+
+/// The directory the bundle is written to.
+fn bundle_parent(bundle: &Bundle) -> &Path {
+    match bundle.path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// A file to pack: its path inside the archive, where it is staged, its size and
+/// modification time.
+struct Entry {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+}
+
+/// Packs the outputs staged under `staging` into `bundle`, named by their path relative
+/// to it and sorted so the same outputs always produce the same listing. The archive is
+/// streamed into a temporary file next to the bundle and renamed into place.
+pub fn write_bundle(
+    bundle: &Bundle,
+    staging: &Path,
+    files: &[ProcessedFile],
+    overwrite: bool,
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(files.len());
+    for file in files {
+        let output = &file.outcome.output_path;
+        let relative = output.strip_prefix(staging).with_context(|| {
+            format!(
+                "{} is outside the bundle staging directory",
+                output.display()
+            )
+        })?;
+        let name = relative
+            .iter()
+            .map(|segment| segment.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let metadata = fs::metadata(output)
+            .path_context(output)
+            .context("reading output")?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        entries.push(Entry {
+            name,
+            path: output.clone(),
+            size: metadata.len(),
+            modified,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let temp_file = tempfile::Builder::new()
+        .prefix(".turbo-png-bundle-")
+        .suffix(".tmp")
+        .tempfile_in(bundle_parent(bundle))
+        .with_context(|| format!("creating a temporary file for {}", bundle.path.display()))?;
+    let temp_path = temp_file.path().to_path_buf();
+    interrupt::track_temp_file(&temp_path);
+    let result = pack(bundle.format, &entries, temp_file.as_file()).and_then(|()| {
+        let persisted = if overwrite {
+            temp_file.persist(&bundle.path)
+        } else {
+            temp_file.persist_noclobber(&bundle.path)
+        };
+        persisted.map(drop).map_err(|err| err.error.into())
+    });
+    interrupt::untrack_temp_file(&temp_path);
+    result.with_context(|| format!("writing bundle {}", bundle.path.display()))
+}
+
+fn pack(format: BundleFormat, entries: &[Entry], file: &File) -> Result<()> {
+    let writer = BufWriter::new(file);
+    let mut writer = match format {
+        BundleFormat::Zip => zip_archive(entries, writer)?,
+        BundleFormat::Tar => tar_archive(entries, writer)?,
+        BundleFormat::TarGz => {
+            let encoder = GzEncoder::new(writer, Compression::default());
+            tar_archive(entries, encoder)?
+                .finish()
+                .context("compressing tar archive")?
+        }
+    };
+    writer.flush()?;
+    Ok(())
+}
+
+/// A ZIP archive with every entry stored as is; PNG and WebP data is already compressed.
+fn zip_archive<W: Write + Seek>(entries: &[Entry], writer: W) -> Result<W> {
+    let mut archive = ZipWriter::new(writer);
+    for entry in entries {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .last_modified_time(dos_timestamp(entry.modified))
+            .unix_permissions(0o644)
+            .large_file(entry.size > u64::from(u32::MAX));
+        archive.start_file(entry.name.as_str(), options)?;
+        let mut file = File::open(&entry.path)
+            .path_context(&entry.path)
+            .context("reading output")?;
+        io::copy(&mut file, &mut archive)?;
+    }
+    Ok(archive.finish()?)
+}
+
+/// The ZIP timestamp for `seconds` since the epoch, in UTC. ZIP dates run from 1980 to
+/// 2107, so other times are clamped to that range.
+fn dos_timestamp(seconds: u64) -> DateTime {
+    const DOS_EPOCH: u64 = 315_532_800;
+    let seconds = seconds.max(DOS_EPOCH);
+    let (year, month, day) = civil_date(seconds / 86_400);
+    let of_day = seconds % 86_400;
+    DateTime::from_date_and_time(
+        year.min(2107) as u16,
+        month,
+        day,
+        (of_day / 3600) as u8,
+        (of_day / 60 % 60) as u8,
+        (of_day % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// A POSIX ustar archive.
+fn tar_archive<W: Write>(entries: &[Entry], writer: W) -> Result<W> {
+    let mut archive = tar::Builder::new(writer);
+    for entry in entries {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(entry.size);
+        header.set_mode(0o644);
+        header.set_mtime(entry.modified);
+        header.set_entry_type(tar::EntryType::Regular);
+        let file = File::open(&entry.path)
+            .path_context(&entry.path)
+            .context("reading output")?;
+        archive
+            .append_data(&mut header, &entry.name, file)
+            .with_context(|| format!("adding {} to the tar archive", entry.name))?;
+    }
+    Ok(archive.into_inner()?)
+}
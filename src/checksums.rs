@@ -0,0 +1,51 @@
+use std::path::{Path, PathBuf};
+
+use crate::cli::ChecksumAlgorithm;
+use crate::error::{Context, Result};
+use crate::pipeline::{ProcessedFile, write_atomic};
+
+/// Writes `<output>.<algorithm>` next to `output`. The line names the output by file name
+/// only, so it checks from the output's own directory.
+pub fn write_file_checksum(
+    output: &Path,
+    digest: &str,
+    algorithm: ChecksumAlgorithm,
+) -> Result<()> {
+    let name = output.file_name().unwrap_or(output.as_os_str());
+    let line = checksum_line(digest, Path::new(name));
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(algorithm.extension());
+    let path = PathBuf::from(path);
+    write_atomic(&path, line.as_bytes(), true)
+        .with_context(|| format!("writing checksum {}", path.display()))
+}
+
+/// Writes the checksum of every output to `dir/SHA256SUMS` (or the algorithm's
+/// equivalent), sorted by path. Outputs below `dir` are listed relative to it.
+pub fn write_sums(dir: &Path, files: &[ProcessedFile], algorithm: ChecksumAlgorithm) -> Result<()> {
+    let mut entries: Vec<(&Path, &str)> = files
+        .iter()
+        .map(|file| {
+            let output = &file.outcome.output_path;
+            (
+                output.strip_prefix(dir).unwrap_or(output),
+                file.outcome.output_sha256.as_str(),
+            )
+        })
+        .collect();
+    entries.sort();
+
+    let sums: String = entries
+        .into_iter()
+        .map(|(output, digest)| checksum_line(digest, output))
+        .collect();
+    let path = dir.join(algorithm.sums_file_name());
+    write_atomic(&path, sums.as_bytes(), true)
+        .with_context(|| format!("writing checksums {}", path.display()))
+}
+
+/// One line in the format `sha256sum` reads back with `-c`.
+fn checksum_line(digest: &str, path: &Path) -> String {
+    format!("{digest}  {}\n", path.display())
+}
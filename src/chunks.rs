@@ -0,0 +1,1005 @@
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::error::{Context, PathContext, Result, bail, format_err};
+
+pub const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Largest chunk length the PNG specification allows (2^31 - 1).
+const MAX_CHUNK_LENGTH: usize = i32::MAX as usize;
+/// Ceiling on the combined size of ancillary chunks, so a file can't make us buffer
+/// gigabytes of metadata.
+const MAX_ANCILLARY_BYTES: usize = 64 * 1024 * 1024;
+
+pub struct RawChunk<'a> {
+    pub name: [u8; 4],
+    pub data: &'a [u8],
+    /// The whole chunk: length, name, data and CRC.
+    pub raw: &'a [u8],
+    /// Whether the stored CRC matches; only ever false for lenient iterators.
+    pub crc_valid: bool,
+}
+
+impl RawChunk<'_> {
+    pub fn is_critical(&self) -> bool {
+        self.name[0].is_ascii_uppercase()
+    }
+}
+
+/// Chunk name for error messages, escaping anything that isn't printable ASCII.
+pub struct ChunkName<'a>(pub &'a [u8; 4]);
+
+impl fmt::Display for ChunkName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.escape_ascii())
+    }
+}
+
+/// Checked iterator over the chunks of a PNG file.
+///
+/// Every chunk's length, name and CRC are validated before it is yielded; the first
+/// problem ends iteration with an error naming the chunk and its byte offset.
+pub struct Chunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+    ancillary_bytes: usize,
+    check_crc: bool,
+    done: bool,
+}
+
+impl<'a> Chunks<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(&PNG_SIGNATURE) {
+            bail!("file is not a valid PNG (bad signature)");
+        }
+
+        Ok(Self {
+            data,
+            offset: PNG_SIGNATURE.len(),
+            ancillary_bytes: 0,
+            check_crc: true,
+            done: false,
+        })
+    }
+
+    /// Yields chunks with bad CRCs instead of failing on them, flagging them in `crc_valid`.
+    pub fn lenient(mut self) -> Self {
+        self.check_crc = false;
+        self
+    }
+
+    fn read_chunk(&mut self) -> Result<RawChunk<'a>> {
+        let start = self.offset;
+        let remaining = self.data.len() - start;
+        if remaining < 8 {
+            bail!("PNG is truncated: chunk header at offset {start} is incomplete");
+        }
+
+        let length = u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as usize;
+        let name: [u8; 4] = self.data[start + 4..start + 8].try_into().unwrap();
+        if !name.iter().all(u8::is_ascii_alphabetic) {
+            bail!(
+                "invalid chunk name \"{}\" at offset {start}",
+                ChunkName(&name)
+            );
+        }
+        if length > MAX_CHUNK_LENGTH {
+            bail!(
+                "{} chunk at offset {start} declares an invalid length of {length} bytes",
+                ChunkName(&name)
+            );
+        }
+        if remaining - 8 < length + 4 {
+            bail!(
+                "PNG is truncated: {} chunk at offset {start} needs {} bytes but only {} remain",
+                ChunkName(&name),
+                length + 12,
+                remaining
+            );
+        }
+
+        if name[0].is_ascii_lowercase() {
+            self.ancillary_bytes += length;
+            if self.ancillary_bytes > MAX_ANCILLARY_BYTES {
+                bail!(
+                    "{} chunk at offset {start} pushes ancillary data past the {} MiB limit",
+                    ChunkName(&name),
+                    MAX_ANCILLARY_BYTES / (1024 * 1024)
+                );
+            }
+        }
+
+        let data = &self.data[start + 8..start + 8 + length];
+        let crc_offset = start + 8 + length;
+        let stored = u32::from_be_bytes(self.data[crc_offset..crc_offset + 4].try_into().unwrap());
+        let computed = chunk_crc(&name, data);
+        if stored != computed && self.check_crc {
+            bail!(
+                "{} chunk at offset {start} has a bad CRC (stored {stored:08x}, computed {computed:08x})",
+                ChunkName(&name)
+            );
+        }
+
+        self.offset = crc_offset + 4;
+        Ok(RawChunk {
+            name,
+            data,
+            raw: &self.data[start..self.offset],
+            crc_valid: stored == computed,
+        })
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<RawChunk<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.offset >= self.data.len() {
+            self.done = true;
+            return Some(Err(format_err!(
+                "PNG is truncated: no IEND chunk before end of file at offset {}",
+                self.offset
+            )));
+        }
+
+        let chunk = self.read_chunk();
+        match &chunk {
+            Ok(chunk) if &chunk.name == b"IEND" => self.done = true,
+            Err(_) => self.done = true,
+            Ok(_) => {}
+        }
+        Some(chunk)
+    }
+}
+
+/// Where a chunk may appear relative to the critical chunks, in file order.
+fn chunk_rank(name: &[u8; 4], after_idat: bool) -> u8 {
+    match name {
+        b"IHDR" => 0,
+        b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" | b"cICP" | b"mDCv" | b"cLLi" => 1,
+        b"PLTE" => 2,
+        b"tRNS" | b"bKGD" | b"hIST" => 3,
+        b"IDAT" => 5,
+        b"IEND" => 7,
+        _ if after_idat => 6,
+        _ => 4,
+    }
+}
+
+/// Chunks the specification allows at most once.
+const SINGLE_CHUNKS: [&[u8; 4]; 15] = [
+    b"IHDR", b"PLTE", b"IEND", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"bKGD",
+    b"hIST", b"tRNS", b"pHYs", b"tIME", b"eXIf",
+];
+
+/// Ancillary chunks registered with the PNG specification or its extensions; readers
+/// understand these, and anything else is private to whoever wrote it.
+const KNOWN_ANCILLARY: [&[u8; 4]; 26] = [
+    b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCv", b"cLLi", b"tRNS", b"bKGD",
+    b"hIST", b"pHYs", b"sPLT", b"eXIf", b"tIME", b"tEXt", b"zTXt", b"iTXt", b"acTL", b"fcTL",
+    b"fdAT", b"oFFs", b"pCAL", b"sCAL", b"sTER", b"gIFg",
+];
+
+/// Names of the ancillary chunks in `data` that aren't registered, each listed once.
+pub fn unknown_ancillary(data: &[u8]) -> Result<Vec<[u8; 4]>> {
+    let mut unknown = Vec::new();
+    for chunk in Chunks::new(data)? {
+        let name = chunk?.name;
+        if name[0].is_ascii_lowercase()
+            && !KNOWN_ANCILLARY.contains(&&name)
+            && !unknown.contains(&name)
+        {
+            unknown.push(name);
+        }
+    }
+    Ok(unknown)
+}
+
+/// Whether `data` contains a chunk called `name`.
+pub fn has_chunk(data: &[u8], name: &[u8; 4]) -> Result<bool> {
+    for chunk in Chunks::new(data)? {
+        if &chunk?.name == name {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Structural problems that make `data` a non-conforming PNG: chunk order, repeated
+/// single chunks, and PLTE, tRNS and hIST that don't fit the header. Empty when it
+/// conforms. Errors only when the chunks themselves can't be read.
+pub fn conformance_violations(data: &[u8]) -> Result<Vec<String>> {
+    let mut chunks = Vec::new();
+    let mut length = PNG_SIGNATURE.len();
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        length += chunk.raw.len();
+        chunks.push(chunk);
+    }
+
+    let mut violations = Vec::new();
+    if chunks.first().map(|chunk| &chunk.name) != Some(b"IHDR") {
+        violations.push(String::from("IHDR is not the first chunk"));
+    }
+    if length != data.len() {
+        violations.push(format!("{} bytes after IEND", data.len() - length));
+    }
+    for name in SINGLE_CHUNKS {
+        let count = chunks.iter().filter(|chunk| &chunk.name == name).count();
+        if count > 1 {
+            violations.push(format!("{count} {} chunks", ChunkName(name)));
+        }
+    }
+
+    // Ranks must never decrease; chunks free to sit anywhere are left out of the check.
+    let mut after_idat = false;
+    let mut latest: Option<(u8, &[u8; 4])> = None;
+    let mut idat_runs = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        if &chunk.name == b"IDAT" {
+            after_idat = true;
+            if index == 0 || &chunks[index - 1].name != b"IDAT" {
+                idat_runs += 1;
+            }
+        } else if after_idat && matches!(&chunk.name, b"pHYs" | b"sPLT" | b"eXIf" | b"acTL") {
+            violations.push(format!("{} after IDAT", ChunkName(&chunk.name)));
+        }
+        let rank = chunk_rank(&chunk.name, after_idat);
+        if matches!(rank, 4 | 6) {
+            continue;
+        }
+        if let Some((previous, name)) = latest
+            && rank < previous
+        {
+            violations.push(format!(
+                "{} after {}",
+                ChunkName(&chunk.name),
+                ChunkName(name)
+            ));
+        }
+        latest = Some((rank, &chunk.name));
+    }
+    match idat_runs {
+        0 => violations.push(String::from("no IDAT chunk")),
+        1 => {}
+        _ => violations.push(String::from("IDAT chunks are not consecutive")),
+    }
+
+    let Some(ihdr) = chunks.iter().find(|chunk| &chunk.name == b"IHDR") else {
+        return Ok(violations);
+    };
+    if ihdr.data.len() != 13 {
+        violations.push(format!("IHDR is {} bytes, not 13", ihdr.data.len()));
+        return Ok(violations);
+    }
+    let (depth, color_type) = (ihdr.data[8], ihdr.data[9]);
+    let depths: &[u8] = match color_type {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        2 | 4 | 6 => &[8, 16],
+        other => {
+            violations.push(format!("unknown color type {other}"));
+            return Ok(violations);
+        }
+    };
+    if !depths.contains(&depth) {
+        violations.push(format!(
+            "bit depth {depth} is invalid for color type {color_type}"
+        ));
+    }
+
+    let find = |name: &[u8; 4]| chunks.iter().find(|chunk| &chunk.name == name);
+    let entries = match find(b"PLTE") {
+        Some(plte) => {
+            let entries = plte.data.len() / 3;
+            if plte.data.len() % 3 != 0 {
+                violations.push(format!(
+                    "PLTE is {} bytes, not a multiple of 3",
+                    plte.data.len()
+                ));
+            }
+            if matches!(color_type, 0 | 4) {
+                violations.push(String::from("PLTE in a grayscale image"));
+            }
+            if entries == 0 || entries > 256 {
+                violations.push(format!("PLTE has {entries} entries"));
+            } else if color_type == 3 && entries > 1 << depth {
+                violations.push(format!(
+                    "PLTE has {entries} entries, more than {depth}-bit indices can address"
+                ));
+            }
+            Some(entries)
+        }
+        None => {
+            if color_type == 3 {
+                violations.push(String::from("indexed image without PLTE"));
+            }
+            None
+        }
+    };
+    if let Some(trns) = find(b"tRNS") {
+        let length = trns.data.len();
+        match (color_type, entries) {
+            (0, _) if length != 2 => {
+                violations.push(format!("grayscale tRNS is {length} bytes, not 2"));
+            }
+            (2, _) if length != 6 => {
+                violations.push(format!("truecolor tRNS is {length} bytes, not 6"));
+            }
+            (3, Some(entries)) if length > entries => violations.push(format!(
+                "tRNS has {length} entries for a {entries}-entry palette"
+            )),
+            (4 | 6, _) => violations.push(String::from("tRNS in an image with an alpha channel")),
+            _ => {}
+        }
+    }
+    if let Some(hist) = find(b"hIST") {
+        match entries {
+            Some(entries) if hist.data.len() != 2 * entries => violations.push(format!(
+                "hIST has {} entries for a {entries}-entry palette",
+                hist.data.len() / 2
+            )),
+            None => violations.push(String::from("hIST without PLTE")),
+            _ => {}
+        }
+    }
+    Ok(violations)
+}
+
+/// Rewrites an indexed image's `tRNS` for decoders that only handle the common layout:
+/// one alpha per palette entry, opaque ones included, after `PLTE`. With `strict` an
+/// all-opaque `tRNS` is dropped instead, as are `hIST` and `sPLT`. Other color types
+/// only lose those two chunks under `strict`.
+pub fn improve_compatibility(data: &[u8], strict: bool) -> Result<Vec<u8>> {
+    let indexed = is_indexed(data)?;
+    let entries = Chunks::new(data)?
+        .filter_map(Result::ok)
+        .find(|chunk| &chunk.name == b"PLTE")
+        .map_or(0, |plte| plte.data.len() / 3);
+
+    let mut output = Vec::with_capacity(data.len() + 256);
+    output.extend_from_slice(&PNG_SIGNATURE);
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        match &chunk.name {
+            b"hIST" | b"sPLT" if strict => {}
+            b"tRNS" if indexed => {
+                let mut alpha = chunk.data.to_vec();
+                alpha.resize(entries, 255);
+                if !(strict && alpha.iter().all(|&value| value == 255)) {
+                    write_chunk(&mut output, b"tRNS", &alpha);
+                }
+            }
+            _ => output.extend_from_slice(chunk.raw),
+        }
+    }
+    order_chunks(&output)
+}
+
+/// Stable-sorts the chunks of an encoded PNG into spec order without touching their bytes.
+pub fn order_chunks(data: &[u8]) -> Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut after_idat = false;
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if &chunk.name == b"IDAT" {
+            after_idat = true;
+        }
+        chunks.push((chunk_rank(&chunk.name, after_idat), chunk.raw));
+    }
+    chunks.sort_by_key(|(rank, _)| *rank);
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    for (_, raw) in chunks {
+        output.extend_from_slice(raw);
+    }
+    Ok(output)
+}
+
+/// Drops the APNG chunks, leaving the default image as a still PNG.
+pub fn drop_animation(data: &[u8]) -> Result<Vec<u8>> {
+    rewrite_chunks(
+        data,
+        |name| matches!(name, b"acTL" | b"fcTL" | b"fdAT"),
+        &[],
+    )
+}
+
+/// Replaces any `pHYs` chunk with one declaring `dpi` in both directions.
+pub fn set_physical_dpi(data: &[u8], dpi: u32) -> Result<Vec<u8>> {
+    // pHYs stores pixels per metre; one inch is 0.0254 m.
+    let per_metre = ((f64::from(dpi) / 0.0254).round() as u32).to_be_bytes();
+    let mut phys = Vec::with_capacity(9);
+    phys.extend_from_slice(&per_metre);
+    phys.extend_from_slice(&per_metre);
+    phys.push(1); // unit: metre
+
+    rewrite_chunks(data, |name| name == b"pHYs", &[(*b"pHYs", phys)])
+}
+
+/// Replaces any `tIME` chunk with one recording `time` in UTC.
+pub fn set_modification_time(data: &[u8], time: SystemTime) -> Result<Vec<u8>> {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .context("modification time predates the Unix epoch")?
+        .as_secs();
+    let (year, month, day) = civil_date(seconds / 86_400);
+    let of_day = seconds % 86_400;
+    let mut stamp = Vec::with_capacity(7);
+    stamp.extend_from_slice(
+        &u16::try_from(year)
+            .context("year out of range")?
+            .to_be_bytes(),
+    );
+    stamp.extend_from_slice(&[
+        month,
+        day,
+        (of_day / 3600) as u8,
+        (of_day / 60 % 60) as u8,
+        (of_day % 60) as u8,
+    ]);
+
+    rewrite_chunks(data, |name| name == b"tIME", &[(*b"tIME", stamp)])
+}
+
+/// Year, month and day of the date `days` after 1970-01-01 (proleptic Gregorian).
+pub fn civil_date(days: u64) -> (u64, u8, u8) {
+    // Count from 0000-03-01 so leap days fall at the end of each 400-year era.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u8;
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Sets the text for `keyword`: the first `tEXt`/`zTXt`/`iTXt` chunk with that keyword is
+/// replaced where it stands and any later ones are dropped. Text that fits Latin-1 is
+/// stored as `tEXt`, anything else as UTF-8 `iTXt`.
+pub fn set_text(data: &[u8], keyword: &str, text: &str) -> Result<Vec<u8>> {
+    let keyword = latin1_keyword(keyword)?;
+    let (name, payload) = match text
+        .chars()
+        .map(u8::try_from)
+        .collect::<Result<Vec<u8>, _>>()
+    {
+        Ok(latin1) => (*b"tEXt", [keyword.as_slice(), &[0], &latin1].concat()),
+        // Uncompressed, with an empty language tag and translated keyword.
+        Err(_) => (
+            *b"iTXt",
+            [keyword.as_slice(), &[0, 0, 0, 0, 0], text.as_bytes()].concat(),
+        ),
+    };
+    let has_keyword = |chunk: &RawChunk<'_>| text_keyword(chunk) == Some(keyword.as_slice());
+
+    let mut existing = false;
+    for chunk in Chunks::new(data)? {
+        existing |= has_keyword(&chunk?);
+    }
+
+    let mut output = Vec::with_capacity(data.len() + payload.len() + 12);
+    output.extend_from_slice(&PNG_SIGNATURE);
+    let mut written = false;
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if has_keyword(&chunk) {
+            if !written {
+                write_chunk(&mut output, &name, &payload);
+                written = true;
+            }
+            continue;
+        }
+        output.extend_from_slice(chunk.raw);
+        if &chunk.name == b"IHDR" && !existing {
+            write_chunk(&mut output, &name, &payload);
+        }
+    }
+    Ok(output)
+}
+
+/// Drops `tIME` and gathers the text chunks, sorted by keyword, type and contents, where
+/// the first of them stood, so equal metadata always serializes the same way.
+pub fn strip_nondeterministic(data: &[u8]) -> Result<Vec<u8>> {
+    let mut texts = Vec::new();
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if let Some(keyword) = text_keyword(&chunk) {
+            texts.push((keyword, chunk.name, chunk.raw));
+        }
+    }
+    texts.sort();
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    let mut texts = Some(texts);
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if text_keyword(&chunk).is_some() {
+            for (_, _, raw) in texts.take().into_iter().flatten() {
+                output.extend_from_slice(raw);
+            }
+        } else if &chunk.name != b"tIME" {
+            output.extend_from_slice(chunk.raw);
+        }
+    }
+    Ok(output)
+}
+
+/// Drops every `tEXt`/`zTXt`/`iTXt` chunk whose keyword isn't in `keywords`.
+pub fn retain_text(data: &[u8], keywords: &[String]) -> Result<Vec<u8>> {
+    let keywords = keywords
+        .iter()
+        .map(|keyword| latin1_keyword(keyword))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if let Some(keyword) = text_keyword(&chunk)
+            && !keywords.iter().any(|kept| kept.as_slice() == keyword)
+        {
+            continue;
+        }
+        output.extend_from_slice(chunk.raw);
+    }
+    Ok(output)
+}
+
+/// The keyword of a text chunk, or `None` for any other chunk.
+fn text_keyword<'a>(chunk: &RawChunk<'a>) -> Option<&'a [u8]> {
+    matches!(&chunk.name, b"tEXt" | b"zTXt" | b"iTXt")
+        .then(|| chunk.data.split(|&byte| byte == 0).next())
+        .flatten()
+}
+
+/// Encodes a text keyword as the Latin-1 bytes stored in the chunk.
+fn latin1_keyword(keyword: &str) -> Result<Vec<u8>> {
+    // Latin-1 maps one-to-one onto the first 256 code points.
+    keyword
+        .chars()
+        .map(|c| {
+            u8::try_from(c).map_err(|_| format_err!("text keyword {keyword:?} is not Latin-1"))
+        })
+        .collect()
+}
+
+/// An ICC profile ready to embed, held as the payload of an `iCCP` chunk.
+#[derive(Debug, Clone)]
+pub struct IccProfile {
+    chunk: Vec<u8>,
+}
+
+impl IccProfile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let profile = fs::read(path)
+            .path_context(path)
+            .context("reading ICC profile")?;
+        // Every ICC profile starts with a 128-byte header carrying the `acsp` signature.
+        if profile.len() < 128 || &profile[36..40] != b"acsp" {
+            bail!("{} is not an ICC profile", path.display());
+        }
+
+        // The profile name must be 1-79 printable Latin-1 characters.
+        let mut name: Vec<u8> = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+            .bytes()
+            .filter(|byte| (0x20..=0x7E).contains(byte))
+            .take(79)
+            .collect();
+        if name.is_empty() {
+            name = b"ICC profile".to_vec();
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&profile)?;
+        let compressed = encoder.finish()?;
+
+        let mut chunk = name;
+        chunk.extend_from_slice(&[0, 0]); // name terminator, zlib compression method
+        chunk.extend_from_slice(&compressed);
+        Ok(Self { chunk })
+    }
+}
+
+/// The color space signature (`RGB `, `GRAY`, ...) of the profile in an `iCCP` payload.
+#[cfg(feature = "compress")]
+pub fn icc_color_space(iccp: &[u8]) -> Option<[u8; 4]> {
+    // Profile name, its terminator and the compression method precede the zlib stream.
+    let name_end = iccp.iter().position(|&byte| byte == 0)?;
+    let mut header = [0u8; 20];
+    ZlibDecoder::new(iccp.get(name_end + 2..)?)
+        .read_exact(&mut header)
+        .ok()?;
+    header[16..20].try_into().ok()
+}
+
+/// Embeds `profile`, replacing any existing profile and the `sRGB`/`gAMA`/`cHRM` hints
+/// that would contradict it.
+pub fn embed_icc(data: &[u8], profile: &IccProfile) -> Result<Vec<u8>> {
+    rewrite_chunks(
+        data,
+        |name| matches!(name, b"iCCP" | b"sRGB" | b"gAMA" | b"cHRM"),
+        &[(*b"iCCP", profile.chunk.clone())],
+    )
+}
+
+/// Leaves exactly one color-space hint: the embedded ICC profile if there is one,
+/// otherwise an `sRGB` chunk with `intent`. Redundant `gAMA`/`cHRM` chunks are dropped.
+pub fn normalize_colorspace(data: &[u8], intent: u8) -> Result<Vec<u8>> {
+    let mut has_profile = false;
+    for chunk in Chunks::new(data)? {
+        has_profile |= &chunk?.name == b"iCCP";
+    }
+
+    let srgb = if has_profile {
+        Vec::new()
+    } else {
+        vec![(*b"sRGB", vec![intent])]
+    };
+    rewrite_chunks(
+        data,
+        |name| matches!(name, b"sRGB" | b"gAMA" | b"cHRM"),
+        &srgb,
+    )
+}
+
+/// Replaces any `bKGD` chunk with one declaring `color`, or just drops it when `color`
+/// is `None`. The color is encoded for the image's color type: indexed images get the
+/// nearest palette entry, grayscale images its luma.
+pub fn set_background(data: &[u8], color: Option<[u8; 3]>) -> Result<Vec<u8>> {
+    let Some(color) = color else {
+        return rewrite_chunks(data, |name| name == b"bKGD", &[]);
+    };
+
+    let mut header = None;
+    let mut palette = None;
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        match &chunk.name {
+            b"IHDR" if chunk.data.len() == 13 => header = Some((chunk.data[8], chunk.data[9])),
+            b"PLTE" => palette = Some(chunk.data),
+            _ => {}
+        }
+    }
+    let Some((bit_depth, color_type)) = header else {
+        bail!("PNG does not start with a valid IHDR chunk");
+    };
+
+    let [r, g, b] = color;
+    let sample = |value: u8| -> [u8; 2] {
+        match bit_depth {
+            16 => (u16::from(value) * 257).to_be_bytes(),
+            depth @ 1..8 => [0, value >> (8 - depth)],
+            _ => [0, value],
+        }
+    };
+    let background = match color_type {
+        0 | 4 => {
+            let luma = (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b) + 500) / 1000;
+            sample(luma as u8).to_vec()
+        }
+        2 | 6 => [sample(r), sample(g), sample(b)].concat(),
+        3 => {
+            let Some(palette) = palette.filter(|palette| !palette.is_empty()) else {
+                bail!("indexed PNG has no palette to map the background color onto");
+            };
+            let distance = |entry: &[u8]| -> u32 {
+                [r, g, b]
+                    .iter()
+                    .zip(entry)
+                    .map(|(&want, &have)| u32::from(want.abs_diff(have)).pow(2))
+                    .sum()
+            };
+            let (index, _) = palette
+                .chunks_exact(3)
+                .enumerate()
+                .min_by_key(|(_, entry)| distance(entry))
+                .expect("palette is not empty");
+            vec![index as u8]
+        }
+        other => bail!("PNG has an invalid color type {other}"),
+    };
+
+    // bKGD has to follow PLTE, so put the inserted chunk back in spec order.
+    let rewritten = rewrite_chunks(data, |name| name == b"bKGD", &[(*b"bKGD", background)])?;
+    order_chunks(&rewritten)
+}
+
+/// Re-deflates the image data with zlib at `level`, replacing the `IDAT` chunks with one.
+pub fn recompress_image_data(data: &[u8], level: u8) -> Result<Vec<u8>> {
+    redeflate_image_data(
+        data,
+        |raw| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(u32::from(level)));
+            encoder.write_all(raw)?;
+            Ok(encoder.finish()?)
+        },
+        |_| true,
+    )
+}
+
+/// Inflates the image data and compresses the same filtered bytes again with `deflate`,
+/// which must produce a zlib stream, replacing the `IDAT` chunks with one. Ancillary
+/// chunks for which `keep` returns false are dropped.
+pub fn redeflate_image_data(
+    data: &[u8],
+    deflate: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+    keep: impl Fn(&[u8; 4]) -> bool,
+) -> Result<Vec<u8>> {
+    let recompressed = deflate(&inflate_image_data(data)?)?;
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    let mut written = false;
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if &chunk.name != b"IDAT" {
+            if chunk.is_critical() || keep(&chunk.name) {
+                output.extend_from_slice(chunk.raw);
+            }
+        } else if !written {
+            write_chunk(&mut output, b"IDAT", &recompressed);
+            written = true;
+        }
+    }
+    Ok(output)
+}
+
+/// Copies a PNG, dropping the ancillary chunks for which `keep` returns false.
+pub fn strip_ancillary(data: &[u8], keep: impl Fn(&[u8; 4]) -> bool) -> Result<Vec<u8>> {
+    rewrite_chunks(
+        data,
+        |name| name[0].is_ascii_lowercase() && !keep(name),
+        &[],
+    )
+}
+
+/// The filtered scanlines: every `IDAT` payload joined and inflated.
+fn inflate_image_data(data: &[u8]) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if &chunk.name == b"IDAT" {
+            compressed.extend_from_slice(chunk.data);
+        }
+    }
+
+    let mut raw = Vec::new();
+    ZlibDecoder::new(compressed.as_slice())
+        .read_to_end(&mut raw)
+        .context("inflating image data")?;
+    Ok(raw)
+}
+
+/// The filter type byte of every scanline, in file order, walking the Adam7 passes of
+/// interlaced images.
+pub fn row_filters(data: &[u8]) -> Result<Vec<u8>> {
+    let ihdr = header(data)?;
+    let width = u64::from(u32::from_be_bytes([ihdr[0], ihdr[1], ihdr[2], ihdr[3]]));
+    let height = u64::from(u32::from_be_bytes([ihdr[4], ihdr[5], ihdr[6], ihdr[7]]));
+    let channels = match ihdr[9] {
+        0 | 3 => 1,
+        4 => 2,
+        2 => 3,
+        6 => 4,
+        other => bail!("unknown PNG color type {other}"),
+    };
+    let bits_per_pixel = channels * u64::from(ihdr[8]);
+    // (x start, y start, x step, y step) of each pass.
+    let passes: &[(u64, u64, u64, u64)] = if ihdr[12] == 1 {
+        &[
+            (0, 0, 8, 8),
+            (4, 0, 8, 8),
+            (0, 4, 4, 8),
+            (2, 0, 4, 4),
+            (0, 2, 2, 4),
+            (1, 0, 2, 2),
+            (0, 1, 1, 2),
+        ]
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    let raw = inflate_image_data(data)?;
+    let mut filters = Vec::new();
+    let mut offset = 0;
+    for &(x, y, x_step, y_step) in passes {
+        let columns = width.saturating_sub(x).div_ceil(x_step);
+        let rows = height.saturating_sub(y).div_ceil(y_step);
+        if columns == 0 {
+            continue;
+        }
+        let stride = usize::try_from((columns * bits_per_pixel).div_ceil(8) + 1)?;
+        for _ in 0..rows {
+            let Some(&filter) = raw.get(offset) else {
+                bail!("image data ends before the last scanline");
+            };
+            filters.push(filter);
+            offset += stride;
+        }
+    }
+    Ok(filters)
+}
+
+/// The color type and bit depth declared by `IHDR`.
+pub fn pixel_format(data: &[u8]) -> Result<(u8, u8)> {
+    let ihdr = header(data)?;
+    Ok((ihdr[9], ihdr[8]))
+}
+
+/// The significant bits `sBIT` declares for the red, green, blue and alpha samples once
+/// decoded to 8 bits per channel; channels the image doesn't store count as 8. `None`
+/// without a well-formed `sBIT`.
+#[cfg(feature = "compress")]
+pub fn significant_bits(data: &[u8]) -> Result<Option<[u8; 4]>> {
+    let ihdr = header(data)?;
+    let (depth, color_type) = (ihdr[8], ihdr[9]);
+    let Some(sbit) = Chunks::new(data)?
+        .filter_map(Result::ok)
+        .find(|chunk| &chunk.name == b"sBIT")
+    else {
+        return Ok(None);
+    };
+    // Indexed samples are 8-bit palette entries whatever the index depth.
+    let depth = if color_type == 3 { 8 } else { depth };
+    if sbit.data.iter().any(|&bits| bits == 0 || bits > depth) {
+        return Ok(None);
+    }
+    let bits = |bits: u8| bits.min(8);
+    Ok(match (color_type, sbit.data) {
+        (0, &[gray]) => Some([bits(gray), bits(gray), bits(gray), 8]),
+        (4, &[gray, alpha]) => Some([bits(gray), bits(gray), bits(gray), bits(alpha)]),
+        (2 | 3, &[r, g, b]) => Some([bits(r), bits(g), bits(b), 8]),
+        (6, &[r, g, b, alpha]) => Some([bits(r), bits(g), bits(b), bits(alpha)]),
+        _ => None,
+    })
+}
+
+/// The color `bKGD` declares, at 8 bits per channel. Samples are read at the `IHDR` bit
+/// depth and indexed backgrounds are looked up in `PLTE`. `None` without a well-formed
+/// `bKGD`.
+#[cfg(feature = "compress")]
+pub fn background_color(data: &[u8]) -> Result<Option<[u8; 3]>> {
+    let ihdr = header(data)?;
+    let (depth, color_type) = (ihdr[8], ihdr[9]);
+    let mut background = None;
+    let mut palette = None;
+    for chunk in Chunks::new(data)?.filter_map(Result::ok) {
+        match &chunk.name {
+            b"bKGD" => background = Some(chunk.data),
+            b"PLTE" => palette = Some(chunk.data),
+            _ => {}
+        }
+    }
+    let Some(background) = background else {
+        return Ok(None);
+    };
+
+    let max = (1u32 << depth.min(16)) - 1;
+    let level = |pair: &[u8]| -> Option<u8> {
+        let sample = u32::from(u16::from_be_bytes([pair[0], pair[1]]));
+        (sample <= max).then(|| ((sample * 255 + max / 2) / max) as u8)
+    };
+    let samples: Option<Vec<u8>> = background.chunks_exact(2).map(level).collect();
+    Ok(match (color_type, background) {
+        (3, &[index]) => palette.and_then(|palette| {
+            let entry = palette.chunks_exact(3).nth(usize::from(index))?;
+            Some([entry[0], entry[1], entry[2]])
+        }),
+        (0 | 4, &[_, _]) => samples.map(|samples| [samples[0]; 3]),
+        (2 | 6, &[_, _, _, _, _, _]) => samples.map(|samples| [samples[0], samples[1], samples[2]]),
+        _ => None,
+    })
+}
+
+/// Replaces any `sBIT` chunk with one declaring `bits` (red, green, blue, alpha) in the
+/// layout of the image's color type. Gray images take the largest color count, and
+/// every count is capped at the sample depth.
+#[cfg(feature = "compress")]
+pub fn set_significant_bits(data: &[u8], bits: [u8; 4]) -> Result<Vec<u8>> {
+    let ihdr = header(data)?;
+    let (depth, color_type) = (ihdr[8], ihdr[9]);
+    let cap = |count: u8| count.min(if color_type == 3 { 8 } else { depth });
+    let [r, g, b, alpha] = bits.map(cap);
+    let gray = r.max(g).max(b);
+    let sbit = match color_type {
+        0 => vec![gray],
+        4 => vec![gray, alpha],
+        2 | 3 => vec![r, g, b],
+        6 => vec![r, g, b, alpha],
+        other => bail!("unknown PNG color type {other}"),
+    };
+    rewrite_chunks(data, |name| name == b"sBIT", &[(*b"sBIT", sbit)])
+}
+
+/// Copies a PNG, dropping chunks matched by `drop` and inserting `insert` right after
+/// `IHDR`, where any ancillary chunk is allowed.
+fn rewrite_chunks(
+    data: &[u8],
+    drop: impl Fn(&[u8; 4]) -> bool,
+    insert: &[([u8; 4], Vec<u8>)],
+) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&PNG_SIGNATURE);
+    for chunk in Chunks::new(data)? {
+        let chunk = chunk?;
+        if drop(&chunk.name) {
+            continue;
+        }
+        output.extend_from_slice(chunk.raw);
+        if &chunk.name == b"IHDR" {
+            for (name, chunk_data) in insert {
+                write_chunk(&mut output, name, chunk_data);
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn write_chunk(output: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(name);
+    output.extend_from_slice(data);
+    output.extend_from_slice(&chunk_crc(name, data).to_be_bytes());
+}
+
+/// Width and height declared by the leading `IHDR` chunk.
+pub fn dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    let ihdr = header(data)?;
+    let width = u32::from_be_bytes(ihdr[0..4].try_into().unwrap());
+    let height = u32::from_be_bytes(ihdr[4..8].try_into().unwrap());
+    Ok((width, height))
+}
+
+/// Whether the image data is stored Adam7-interlaced.
+pub fn is_interlaced(data: &[u8]) -> Result<bool> {
+    Ok(header(data)?[12] == 1)
+}
+
+/// Whether the image is stored as palette indices.
+pub fn is_indexed(data: &[u8]) -> Result<bool> {
+    Ok(header(data)?[9] == 3)
+}
+
+/// The 13-byte IHDR payload.
+fn header(data: &[u8]) -> Result<&[u8]> {
+    let ihdr = match Chunks::new(data)?.next() {
+        Some(chunk) => chunk?,
+        None => bail!("PNG has no chunks"),
+    };
+    if &ihdr.name != b"IHDR" || ihdr.data.len() != 13 {
+        bail!("PNG does not start with a valid IHDR chunk");
+    }
+
+    Ok(ihdr.data)
+}
+
+pub fn chunk_crc(name: &[u8; 4], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(name);
+    hasher.update(data);
+    hasher.finalize()
+}
@@ -1,8 +1,25 @@
+#[cfg(feature = "compress")]
+use std::borrow::Cow;
+use std::fs;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result, bail};
-use clap::{ArgAction, Parser, ValueEnum};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::Serialize;
+
+use crate::chunks::IccProfile;
+use crate::error::{Context, PathContext, Result, bail};
+use crate::options::{CompressOptionsBuilder, OptimizeOptionsBuilder};
+
+/// Default `--max-pixels`: a 16384x16384 image.
+pub const DEFAULT_MAX_PIXELS: u64 = 16_384 * 16_384;
+/// Default `--quality`, for compress mode and `apng join`.
+pub const DEFAULT_QUALITY: u8 = 90;
+/// Placeholder in `--upload-url` replaced by each output's path below the output root.
+pub const RELPATH_PLACEHOLDER: &str = "{relpath}";
 
 /// Command-line interface definition.
 #[derive(Debug, Parser)]
@@ -10,13 +27,31 @@ use clap::{ArgAction, Parser, ValueEnum};
     author,
     version,
     about = "TurboPNG: high-performance PNG optimizer & compressor",
-    long_about = None
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// One or more PNG file paths (files or directories are expanded).
-    #[arg(required = true, value_name = "PATH", num_args = 1..)]
+    #[arg(required_unless_present = "base64", value_name = "PATH", num_args = 1..)]
     pub inputs: Vec<PathBuf>,
 
+    /// Read one base64 PNG (bare or as a `data:` URL) from stdin and write the result to
+    /// stdout the same way, without touching the filesystem.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = [
+            "inputs", "output_dir", "output", "dry_run", "interactive", "post_cmd", "baseline",
+            "budgets", "manifest_out", "checksums", "rename_map", "upload_url", "emit_sidecar",
+        ]
+    )]
+    pub base64: bool,
+
     /// Processing mode: lossless optimize or quality-balanced compression.
     #[arg(long, value_enum, default_value_t = Mode::Optimize)]
     pub mode: Mode,
@@ -25,14 +60,119 @@ pub struct Cli {
     #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
     pub keep_metadata: bool,
 
+    /// Keep the `eXIf` chunk even when other metadata is stripped.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue, conflicts_with = "strip_exif")]
+    pub keep_exif: bool,
+
+    /// Drop the `eXIf` chunk even with `--keep-metadata`.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub strip_exif: bool,
+
+    /// Text chunk keywords (`tEXt`/`zTXt`/`iTXt`) kept when other metadata is stripped.
+    #[arg(
+        long,
+        value_name = "KEYWORD,...",
+        value_delimiter = ',',
+        value_parser = parse_text_keyword
+    )]
+    pub keep_text: Vec<String>,
+
+    /// Rotate/flip pixels to match the EXIF orientation, then remove the orientation tag.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub apply_orientation: bool,
+
+    /// Write a `pHYs` chunk declaring this density (dots per inch), replacing any existing one.
+    #[arg(
+        long,
+        value_name = "DPI",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub set_dpi: Option<u32>,
+
+    /// Write a `tIME` chunk stamped with the current time, replacing any existing one.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub update_time: bool,
+
+    /// Drop `tIME`, sort text chunks and pin the deflate settings, so re-running on the same
+    /// input always produces identical bytes.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with = "update_time"
+    )]
+    pub strip_nondeterministic: bool,
+
+    /// Set the text chunk for `KEY` to `VALUE`, replacing existing entries with that keyword
+    /// (repeatable).
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_text_entry)]
+    pub set_text: Vec<(String, String)>,
+
+    /// Embed this ICC profile in outputs, replacing any existing profile.
+    #[arg(long, value_name = "FILE")]
+    pub embed_icc: Option<PathBuf>,
+
+    /// Leave exactly one color hint on outputs: an `sRGB` chunk with this rendering intent
+    /// (or the embedded ICC profile), dropping redundant `gAMA`/`cHRM`.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "INTENT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "perceptual"
+    )]
+    pub normalize_colorspace: Option<RenderingIntent>,
+
+    /// Write a `bKGD` chunk with this color (`#rrggbb`; `bKGD` has no alpha), replacing any
+    /// existing one.
+    #[arg(
+        long,
+        value_name = "COLOR",
+        value_parser = parse_opaque_color,
+        conflicts_with = "strip_background"
+    )]
+    pub set_background: Option<[u8; 3]>,
+
+    /// Drop the `bKGD` chunk from outputs.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub strip_background: bool,
+
     /// Allow overwriting existing output files in place.
     #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
     pub overwrite: bool,
 
+    /// Exit with the number of files that failed (capped at 125) instead of 1.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub exit_code_failures: bool,
+
+    /// Ask whether to overwrite, skip or rename each output that already exists.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["overwrite", "force"]
+    )]
+    pub interactive: bool,
+
     /// Limit the number of worker threads (defaults to logical CPU count).
     #[arg(long, value_parser = clap::value_parser!(NonZeroUsize))]
     pub threads: Option<NonZeroUsize>,
 
+    /// Limit the threads oxipng's filter and compression trials use on a single file, in a
+    /// pool of their own (defaults to the worker threads).
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(NonZeroUsize))]
+    pub intra_threads: Option<NonZeroUsize>,
+
+    /// Run at lower priority so long batches leave the machine responsive: raise the
+    /// niceness by N on Unix, or use the background priority class on Windows.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=19))]
+    pub nice: Option<u8>,
+
+    /// Refuse images with more pixels than this before decoding them.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_PIXELS)]
+    pub max_pixels: u64,
+
     /// Disable the fancy progress UI and emit plain log lines instead.
     #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
     pub no_progress: bool,
@@ -41,21 +181,566 @@ pub struct Cli {
     #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
     pub dry_run: bool,
 
+    /// Run the full pipeline, post command included, but keep every original and write
+    /// only a JSON ledger of each file's exact original and output sizes.
+    #[arg(
+        long,
+        value_name = "LEDGER",
+        conflicts_with_all = [
+            "dry_run", "output", "bundle", "base64", "interactive", "recreate_symlinks",
+            "link_duplicates", "emit_sidecar", "checksums", "manifest_out", "rename_map",
+            "upload_url",
+        ]
+    )]
+    pub measure_only: Option<PathBuf>,
+
     /// Compression quality (only relevant in `compress` mode).
     #[arg(
         long,
-        default_value_t = 90u8,
+        default_value_t = DEFAULT_QUALITY,
         value_name = "LEVEL",
         value_parser = clap::value_parser!(u8).range(1..=100)
     )]
     pub quality: u8,
 
-    /// Enable exhaustive Zopfli-style DEFLATE even in optimize mode.
+    /// Compress files under `PATH` at `LEVEL` instead of `--quality` (repeatable; the most
+    /// specific path wins). Inputs may also be given as `PATH:LEVEL`.
+    #[arg(long, value_name = "PATH=LEVEL", value_parser = parse_input_quality)]
+    pub input_quality: Vec<(PathBuf, u8)>,
+
+    /// Follow symlinks while walking directories.
+    #[arg(long, value_enum, value_name = "on|off", default_value_t = Toggle::On)]
+    pub follow_symlinks: Toggle,
+
+    /// Recreate symlinked inputs as symlinks to their target's output instead of copies.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with = "output_dir"
+    )]
+    pub recreate_symlinks: bool,
+
+    /// After the run, replace outputs whose bytes match another output with hard links to
+    /// it, and report the space saved.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["output", "bundle", "base64", "dry_run"]
+    )]
+    pub link_duplicates: bool,
+
+    /// File extensions treated as PNG inputs (comma-separated, case-insensitive).
+    #[arg(
+        long,
+        value_name = "EXT,...",
+        value_delimiter = ',',
+        default_value = "png,apng"
+    )]
+    pub extensions: Vec<String>,
+
+    /// Skip files and directories excluded by `.gitignore` while walking directories.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub respect_gitignore: bool,
+
+    /// Skip hidden files and directories (names starting with `.`) while walking directories.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub skip_hidden: bool,
+
+    /// Only process PNGs that git reports as changed relative to REF.
+    #[arg(long, value_name = "REF", conflicts_with = "staged")]
+    pub changed_since: Option<String>,
+
+    /// Only process PNGs that are staged in git (for pre-commit hooks).
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub staged: bool,
+
+    /// Only process inputs modified after FILE's mtime or a Unix timestamp (seconds).
+    #[arg(long, value_name = "FILE|TIMESTAMP")]
+    pub newer_than: Option<String>,
+
+    /// Skip inputs whose output already exists and is newer than the input. Older outputs
+    /// are stale and get replaced, as if `--overwrite` were given.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub only_if_newer: bool,
+
+    /// Re-process and overwrite everything, as if `--overwrite` were given. Takes
+    /// precedence over `--only-if-newer` and `--baseline`: up-to-date outputs are redone
+    /// and the new output is written even when the baseline file is smaller.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub force: bool,
+
+    /// Only process slice K of N (e.g. `2/8`) of the resolved inputs, for splitting a run
+    /// across CI workers.
+    #[arg(long, value_name = "K/N", value_parser = parse_shard)]
+    pub shard: Option<Shard>,
+
+    /// Order in which files are processed: largest first, by path, or as discovered.
+    #[arg(long, value_enum, default_value_t = FileOrder::Size)]
+    pub order: FileOrder,
+
+    /// Write outputs under this directory, mirroring each file's path below the directory
+    /// argument it was found in (files given directly land at the top).
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Stream outputs to a directory on a remote host over SSH instead of writing them
+    /// locally, mirroring paths as `--output-dir` does. `sftp://` is accepted as an alias,
+    /// and IPv6 hosts go in brackets. The host needs a POSIX shell; SFTP-only and chrooted
+    /// accounts aren't supported.
+    #[arg(
+        long,
+        value_name = "ssh://[USER@]HOST[:PORT]/PATH",
+        value_parser = parse_ssh_url,
+        conflicts_with_all = [
+            "output_dir", "recreate_symlinks", "interactive", "only_if_newer", "post_cmd",
+            "emit_sidecar", "checksums", "temp_dir", "fsync", "upload_url",
+        ]
+    )]
+    pub output: Option<SshUrl>,
+
+    /// Pack all outputs, by their path relative to the inputs, into one `.zip`, `.tar`,
+    /// `.tar.gz` or `.tgz` archive instead of writing them as separate files.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = parse_bundle,
+        conflicts_with_all = [
+            "output_dir", "output", "base64", "dry_run", "recreate_symlinks", "interactive",
+            "only_if_newer", "emit_sidecar", "checksums", "manifest_out", "rename_map",
+            "upload_url",
+        ]
+    )]
+    pub bundle: Option<Bundle>,
+
+    /// With `--output`, the SSH client and options to connect with.
+    #[arg(
+        long,
+        value_name = "CMD",
+        default_value = "ssh -o BatchMode=yes",
+        requires = "output"
+    )]
+    pub ssh_command: String,
+
+    /// With `--output-dir`, write every output directly into it instead of mirroring.
+    #[arg(long, requires = "output_dir")]
+    pub flatten: bool,
+
+    /// With `--output-dir`, how to handle inputs that map to the same output name.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CollisionPolicy::Error,
+        requires = "output_dir"
+    )]
+    pub on_collision: CollisionPolicy,
+
+    /// With `--output-dir`, also copy files that aren't processed (other file types, and
+    /// PNGs the incremental filters skip) so the output directory is a complete tree.
+    /// Nothing is copied under `--dry-run` or `--measure-only`.
+    #[arg(long, requires = "output_dir")]
+    pub copy_unchanged: bool,
+
+    /// Stage temporary output files in this directory instead of next to each output.
+    #[arg(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Sync each output and its directory to disk before moving on, so it survives a power loss.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub fsync: bool,
+
+    /// Cap how fast inputs are read and outputs written, together (e.g. `50MB/s`,
+    /// `512KiB/s`), so a batch on network storage doesn't saturate the link.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub io_limit: Option<u64>,
+
+    /// Map inputs into memory instead of reading them, so the OS pages them in on demand
+    /// and can drop them again; lowers resident memory for very large files. Inputs must
+    /// not be modified while they are processed.
+    #[arg(long, conflicts_with = "io_limit")]
+    pub mmap: bool,
+
+    /// Run this shell command on each output (`{out}` is replaced by its path) and keep
+    /// the result when it is smaller and, in optimize mode, shows the same pixels.
+    /// Failures only skip the file's post-processing; they and the command's stderr are
+    /// recorded in the file's notes.
+    #[arg(long, value_name = "CMD")]
+    pub post_cmd: Option<String>,
+
+    /// Keep the same-named file from this directory instead of a new output that isn't
+    /// smaller (or, in compress mode, looks worse). Ignored under `--force`.
+    #[arg(long, value_name = "DIR")]
+    pub baseline: Option<PathBuf>,
+
+    /// Fail when outputs exceed the byte budgets declared in a TOML manifest.
+    #[arg(long, value_name = "FILE")]
+    pub budgets: Option<PathBuf>,
+
+    /// Write a JSON manifest mapping inputs to outputs, sizes, and SHA-256 hashes.
+    #[arg(long, value_name = "FILE")]
+    pub manifest_out: Option<PathBuf>,
+
+    /// Write checksums of the outputs to `SHA256SUMS` in `--output-dir` (or the current
+    /// directory), in `sha256sum -c` format.
+    #[arg(long, value_enum, value_name = "ALGORITHM")]
+    pub checksums: Option<ChecksumAlgorithm>,
+
+    /// With `--checksums`, write a `<output>.sha256` next to each output instead.
+    #[arg(long, requires = "checksums")]
+    pub checksums_per_file: bool,
+
+    /// Write an `input,output,status` CSV row for every file the run was asked to process,
+    /// including failed and skipped ones.
+    #[arg(long, value_name = "FILE")]
+    pub rename_map: Option<PathBuf>,
+
+    /// PUT each output to this URL as soon as it is written; `{relpath}` becomes the
+    /// output's path below `--output-dir` (or the current directory).
+    #[arg(long, value_name = "URL")]
+    pub upload_url: Option<String>,
+
+    /// With `--upload-url`, how many uploads run at once.
+    #[arg(long, value_name = "N", default_value = "4", requires = "upload_url")]
+    pub upload_jobs: NonZeroUsize,
+
+    /// With `--upload-url`, how often a failed upload is retried (network errors, 429 and
+    /// 5xx responses only).
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "upload_url")]
+    pub upload_retries: u32,
+
+    /// Where finished and failed files are reported besides the progress output: nowhere
+    /// else, or the system log (Unix), with structured fields when journald is running.
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    pub log: LogTarget,
+
+    /// With `--log syslog`, send RFC 5424 messages to this Unix datagram socket instead
+    /// of journald or `/dev/log`.
+    #[arg(long, value_name = "PATH")]
+    pub syslog_socket: Option<PathBuf>,
+
+    /// Stop starting new files once the run has taken this long (e.g. `90s`, `10m`, `1h30m`).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub time_budget: Option<Duration>,
+
+    /// Encode each output in these formats and keep the smallest (e.g. `png,webp`).
+    /// 16-bit images are never written as WebP, which only holds 8 bits per sample.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT,...",
+        value_delimiter = ',',
+        default_value = "png"
+    )]
+    pub emit_smallest: Vec<OutputFormat>,
+
+    /// Write a `<output>.json` sidecar describing each result.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub emit_sidecar: bool,
+
+    /// How to treat chunks with bad CRCs: fail, repair ancillary chunks, or ignore them.
+    #[arg(long, value_enum, default_value_t = CrcPolicy::Strict)]
+    pub crc: CrcPolicy,
+
+    /// Minimum acceptable quantization quality (overrides the floor derived from --quality).
+    /// Without --quality-max, it must not exceed the target --quality derives.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub quality_min: Option<u8>,
+
+    /// Target quantization quality (overrides the ceiling derived from --quality).
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub quality_max: Option<u8>,
+
+    /// Quantize in linear light for smoother gradients at small palette sizes.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub linear_quantization: bool,
+
+    /// Colors (`#rrggbb` or `#rrggbbaa`) kept verbatim in the palette and never remapped.
+    #[arg(
+        long,
+        value_name = "COLOR,...",
+        value_delimiter = ',',
+        value_parser = parse_hex_color
+    )]
+    pub lock_colors: Vec<[u8; 4]>,
+
+    /// Grayscale mask (same size as each input) whose bright areas keep more fidelity.
+    #[arg(long, value_name = "FILE")]
+    pub roi_mask: Option<PathBuf>,
+
+    /// Snap near-transparent/near-opaque alpha and avoid dithering it, for crisp sprite edges.
     #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub crisp_alpha: bool,
+
+    /// How compress mode discards detail: a palette, fewer bits per channel, or not at all.
+    #[arg(long, value_enum, default_value_t = Lossy::Palette)]
+    pub lossy: Lossy,
+
+    /// Pick palette size, dithering and filters from each image's content instead of the quality level alone.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub adaptive: bool,
+
+    /// Allow palettes of up to 256 colors at any quality, as pngquant does, instead of the
+    /// quality-derived cap (at most 128).
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub max_colors_256: bool,
+
+    /// Skip gradient detection (which raises the palette cap or keeps banding-prone images lossless).
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub no_banding_check: bool,
+
+    /// Largest change compress mode may make to any channel of any pixel; files that
+    /// stray further are quantized again at full quality, or kept lossless.
+    #[arg(long, value_name = "N")]
+    pub max_channel_diff: Option<u8>,
+
+    /// Override the imagequant speed (1 = slowest/best palette search, 10 = fastest).
+    #[arg(
+        long,
+        value_name = "SPEED",
+        value_parser = clap::value_parser!(u8).range(1..=10)
+    )]
+    pub quant_speed: Option<u8>,
+
+    /// Enable exhaustive Zopfli-style DEFLATE even in optimize mode.
+    #[arg(
+        long,
+        default_value_t = false,
+        action = ArgAction::SetTrue,
+        conflicts_with = "deflater"
+    )]
     pub zopfli: bool,
+
+    /// Let optimize mode scale 16-bit samples down to 8 bits. Not lossless, but keeps the
+    /// source's colors otherwise, unlike `compress` mode.
+    #[arg(long = "allow-16to8")]
+    pub allow_16to8: bool,
+
+    /// DEFLATE backend for image data, overriding each mode's default.
+    #[arg(long, value_enum, value_name = "zopfli|libdeflate|zlib")]
+    pub deflater: Option<Deflater>,
+
+    /// Level for `--deflater`: Zopfli iterations (1-255), libdeflate 0-12, or zlib 0-9.
+    #[arg(long, value_name = "N", requires = "deflater")]
+    pub compression_level: Option<u8>,
+
+    /// Skip an optional pipeline stage; repeatable. `quantize` needs compress mode.
+    #[arg(long, value_enum, value_name = "transform|quantize|deflate")]
+    pub skip_stage: Vec<StageKind>,
+
+    /// Shorthand for `--skip-stage quantize`: compress mode stays lossless. Needs
+    /// `--mode compress`.
+    #[arg(long)]
+    pub skip_quantize: bool,
+
+    /// Only re-filter and re-deflate image data, keeping pixels, color type and bit depth.
+    #[arg(long, conflicts_with_all = ["skip_stage", "skip_quantize"])]
+    pub only_deflate: bool,
+
+    /// Run only this stage: `deflate` re-deflates the existing image data as is, keeping
+    /// its filters, which is much faster than `--only-deflate`'s filter search.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "deflate",
+        conflicts_with_all = ["skip_stage", "skip_quantize", "only_deflate"]
+    )]
+    pub stage_only: Option<StageOnly>,
+
+    /// Keep the palette order and pixel indices of indexed inputs exactly, failing any file
+    /// where that can't be honored.
+    #[arg(long)]
+    pub stable_palette: bool,
+
+    /// Check every PNG written against the specification's structural rules (chunk order,
+    /// one IHDR and IEND, PLTE and tRNS constraints) and fail files that break them.
+    #[arg(long)]
+    pub validate_output: bool,
+
+    /// Never write a PNG larger than its input: when optimization can't shrink a file,
+    /// write the input's bytes unchanged instead (optimize mode).
+    #[arg(long)]
+    pub no_write_larger: bool,
+
+    /// Shape indexed output for picky decoders, at the cost of a few bytes: `android` pads
+    /// `tRNS` to the full palette, `strict` also drops unused palette extras.
+    #[arg(long, value_enum, value_name = "android|strict")]
+    pub compat: Option<Compat>,
+
+    /// What to do with animated PNGs: write them untouched, recompress their frames
+    /// losslessly (compress mode can't quantize them), or flatten them to the first frame.
+    #[arg(long = "apng", value_enum, default_value_t = ApngPolicy::Optimize)]
+    pub apng_policy: ApngPolicy,
+
+    /// Fail files that raise warnings (an unknown chunk preserved, an ICC profile dropped,
+    /// 16-bit samples reduced, an EXIF orientation lost) instead of just reporting them.
+    #[arg(long)]
+    pub warnings_as_errors: bool,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+/// Standalone tools that run instead of the optimize/compress pipeline.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Dump ICC profiles, EXIF and text chunks into separate files before stripping.
+    ExtractMeta(ExtractMetaArgs),
+    /// Split an APNG into frames, or join frames into an APNG.
+    #[command(subcommand)]
+    Apng(ApngCommand),
+    /// Measure how far two images differ, failing when a threshold is exceeded.
+    Compare(CompareArgs),
+    /// Report an image's colors, alpha usage and banding risk, and which mode suits it.
+    Analyze(AnalyzeArgs),
+    /// Report how a tree of PNGs spends its bytes, by chunk type, color type, bit depth
+    /// and interlacing, without modifying anything.
+    Stats(StatsArgs),
+    /// Report CPU features, threads, temp directory, terminal and default options, for
+    /// diagnosing slow or odd runs.
+    Doctor,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ApngCommand {
+    /// Write every frame of an APNG, fully composited, as a separate PNG.
+    Split(ApngSplitArgs),
+    /// Build an APNG from frames, quantized against one shared palette.
+    Join(ApngJoinArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ApngSplitArgs {
+    /// Animated PNG to split.
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+
+    /// Directory to write the frames into (created if missing).
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    pub out: PathBuf,
+
+    /// Replace frame files that already exist.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ApngJoinArgs {
+    /// Frames in playback order; all must share the same dimensions.
+    #[arg(required = true, value_name = "FRAME", num_args = 1..)]
+    pub frames: Vec<PathBuf>,
+
+    /// Path of the animated PNG to write.
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+
+    /// Playback rate in frames per second.
+    #[arg(
+        long,
+        default_value_t = 24,
+        value_name = "FPS",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub fps: u16,
+
+    /// Number of times to play the animation (0 loops forever).
+    #[arg(long, default_value_t = 0, value_name = "COUNT")]
+    pub loops: u32,
+
+    /// Compression quality, as in `compress` mode.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_QUALITY,
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub quality: u8,
+
+    /// Replace the output if it already exists.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExtractMetaArgs {
+    /// PNG files to read metadata from.
+    #[arg(required = true, value_name = "FILE", num_args = 1..)]
+    pub files: Vec<PathBuf>,
+
+    /// Directory to write the metadata files into (created if missing).
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    pub out: PathBuf,
+
+    /// Replace metadata files that already exist.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CompareArgs {
+    /// Reference image.
+    #[arg(value_name = "A")]
+    pub reference: PathBuf,
+
+    /// Image to measure against the reference; must have the same dimensions.
+    #[arg(value_name = "B")]
+    pub candidate: PathBuf,
+
+    /// Fail when any channel of any pixel differs by more than this.
+    #[arg(long, value_name = "DELTA")]
+    pub max_delta: Option<u8>,
+
+    /// Fail when the PSNR (dB) is below this.
+    #[arg(long, value_name = "DB")]
+    pub min_psnr: Option<f64>,
+
+    /// Fail when the DSSIM is above this (0 means identical).
+    #[arg(long, value_name = "DSSIM")]
+    pub max_dssim: Option<f64>,
+}
+
+#[derive(Debug, Args)]
+pub struct AnalyzeArgs {
+    /// Image to analyze.
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    /// PNG files or directories to scan.
+    #[arg(required = true, value_name = "PATH", num_args = 1..)]
+    pub paths: Vec<PathBuf>,
+
+    /// File extensions treated as PNGs (comma-separated, case-insensitive).
+    #[arg(
+        long,
+        value_name = "EXT,...",
+        value_delimiter = ',',
+        default_value = "png,apng"
+    )]
+    pub extensions: Vec<String>,
+
+    /// Follow symlinks while walking directories.
+    #[arg(long, value_enum, value_name = "on|off", default_value_t = Toggle::On)]
+    pub follow_symlinks: Toggle,
+
+    /// Skip files and directories excluded by `.gitignore` while walking directories.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub respect_gitignore: bool,
+
+    /// Skip hidden files and directories (names starting with `.`) while walking directories.
+    #[arg(long, default_value_t = false, action = ArgAction::SetTrue)]
+    pub skip_hidden: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
     Optimize,
     Compress,
@@ -66,28 +751,444 @@ pub enum Mode {
 pub struct AppConfig {
     pub inputs: Vec<PathBuf>,
     pub mode: Mode,
+    pub extensions: Vec<String>,
+    pub follow_symlinks: bool,
+    pub recreate_symlinks: bool,
+    pub link_duplicates: bool,
+    pub respect_gitignore: bool,
+    pub skip_hidden: bool,
+    pub changes: Option<ChangeFilter>,
+    pub newer_than: Option<SystemTime>,
+    pub only_if_newer: bool,
+    pub force: bool,
+    pub interactive: bool,
+    pub exit_code_failures: bool,
+    pub base64: bool,
+    pub shard: Option<Shard>,
+    pub order: FileOrder,
+    pub output_dir: Option<OutputLayout>,
+    #[cfg(feature = "bundle")]
+    pub bundle: Option<Bundle>,
+    pub budgets: Option<PathBuf>,
+    pub manifest_out: Option<PathBuf>,
+    pub rename_map: Option<PathBuf>,
+    /// Where `--measure-only` writes its ledger.
+    pub ledger: Option<PathBuf>,
+    #[cfg(feature = "upload")]
+    pub upload: Option<UploadTarget>,
+    pub log: LogTarget,
+    pub syslog_socket: Option<PathBuf>,
+    /// `--io-limit` in bytes per second.
+    pub io_limit: Option<u64>,
     pub common: CommonOptions,
     pub optimize: OptimizeOptions,
     pub compress: CompressOptions,
 }
 
+/// Where `--output-dir` places outputs.
+#[derive(Debug, Clone)]
+pub struct OutputLayout {
+    pub dir: PathBuf,
+    pub flatten: bool,
+    pub on_collision: CollisionPolicy,
+    /// `--copy-unchanged`: copy files that aren't processed into the tree as well.
+    pub copy_unchanged: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Fail before processing anything.
+    Error,
+    /// Append `-2`, `-3`, ... to later inputs' output names.
+    Rename,
+    /// Skip later inputs whose output name is taken.
+    Skip,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum LogTarget {
+    /// Only the progress output on the terminal.
+    Stderr,
+    /// Also the system journal or syslog daemon.
+    Syslog,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the file listing every output's checksum.
+    pub fn sums_file_name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "SHA256SUMS",
+        }
+    }
+
+    /// Extension of the per-output checksum files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// What `--checksums` writes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Checksums {
+    pub algorithm: ChecksumAlgorithm,
+    pub per_file: bool,
+}
+
+/// Where `--upload-url` sends outputs.
+#[cfg(feature = "upload")]
+#[derive(Debug, Clone)]
+pub struct UploadTarget {
+    /// URL template containing [`RELPATH_PLACEHOLDER`].
+    pub url: String,
+    pub jobs: NonZeroUsize,
+    pub retries: u32,
+}
+
+/// A `--bundle` archive. Parsed in every build, so that builds without the `bundle`
+/// feature can reject it by name.
+#[cfg_attr(not(feature = "bundle"), allow(dead_code))]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bundle {
+    pub path: PathBuf,
+    pub format: BundleFormat,
+}
+
+/// Archive format of a `--bundle`, from its extension.
+#[cfg_attr(not(feature = "bundle"), allow(dead_code))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BundleFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// A parsed `--output` URL. Parsed in every build, so that builds without the `remote`
+/// feature can reject it by name.
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SshUrl {
+    /// `HOST` or `USER@HOST`, as passed to the SSH client.
+    pub host: String,
+    pub port: Option<u16>,
+    /// Absolute directory on the remote host.
+    pub path: PathBuf,
+}
+
+/// Where `--output` streams results; the remote directory is the output layout's `dir`.
+#[cfg(feature = "remote")]
+#[derive(Debug, Clone)]
+pub struct RemoteTarget {
+    pub host: String,
+    pub port: Option<u16>,
+    pub ssh_command: String,
+}
+
+/// One of `count` disjoint slices of the inputs; `index` counts from 1.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum FileOrder {
+    /// Largest inputs first, so the slowest files don't start last.
+    Size,
+    /// Sorted by path.
+    Name,
+    /// The order inputs were given and discovered in.
+    None,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Webp,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+        }
+    }
+}
+
+/// The steps a file passes through, in the order they run. Modes leave out the ones
+/// they don't need; only transform, quantize and deflate can be skipped.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum StageKind {
+    #[value(skip)]
+    Read,
+    #[cfg(feature = "compress")]
+    #[value(skip)]
+    Decode,
+    /// `--apply-orientation` and `--crisp-alpha`.
+    Transform,
+    /// Palette quantization (compress mode).
+    Quantize,
+    #[cfg(feature = "compress")]
+    #[value(skip)]
+    Encode,
+    /// The oxipng pass that re-filters and re-deflates the image data.
+    Deflate,
+    #[value(skip)]
+    Write,
+}
+
+impl StageKind {
+    /// Lowercase name shown in progress output.
+    #[cfg_attr(not(feature = "fancy-ui"), allow(dead_code))]
+    pub fn label(self) -> &'static str {
+        match self {
+            StageKind::Read => "read",
+            #[cfg(feature = "compress")]
+            StageKind::Decode => "decode",
+            StageKind::Transform => "transform",
+            StageKind::Quantize => "quantize",
+            #[cfg(feature = "compress")]
+            StageKind::Encode => "encode",
+            StageKind::Deflate => "deflate",
+            StageKind::Write => "write",
+        }
+    }
+}
+
+/// The stage `--stage-only` runs on its own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum StageOnly {
+    /// Re-deflate the existing `IDAT` stream without touching pixels or filters.
+    Deflate,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Deflater {
+    /// Slowest and smallest.
+    Zopfli,
+    /// Close to Zopfli's size at a fraction of the time.
+    Libdeflate,
+    /// Plain zlib, for output matching other zlib-based tools.
+    Zlib,
+}
+
+impl Deflater {
+    /// The accepted `--compression-level` range and the level used without one.
+    pub fn levels(self) -> (std::ops::RangeInclusive<u8>, u8) {
+        match self {
+            Self::Zopfli => (1..=255, 15),
+            Self::Libdeflate => (0..=12, 12),
+            Self::Zlib => (0..=9, 9),
+        }
+    }
+}
+
+/// Lossy reduction applied in compress mode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lossy {
+    /// Keep every pixel exactly.
+    None,
+    /// Quantize to an indexed palette of at most 256 colors.
+    Palette,
+    /// Stay truecolor but round each channel to fewer bits (5 at the default quality).
+    Bitcrush,
+}
+
+/// What happens to animated PNGs (those with an `acTL` chunk).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum ApngPolicy {
+    /// Write them exactly as they are.
+    Passthrough,
+    /// Recompress every frame losslessly, keeping the frame chunks where they were.
+    Optimize,
+    /// Drop the animation, keeping only the default image (usually the first frame).
+    Flatten,
+}
+
+/// Decoder quirks `--compat` works around.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Compat {
+    /// Older Android decoders, which mishandle a `tRNS` shorter than the palette.
+    Android,
+    /// Every decoder we know of: `android`, plus no `hIST`, `sPLT` or all-opaque `tRNS`.
+    Strict,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum CrcPolicy {
+    /// Fail on any chunk with a bad CRC.
+    Strict,
+    /// Recompute bad CRCs on ancillary chunks; bad critical chunks still fail.
+    Fix,
+    /// Process the file regardless of bad CRCs.
+    Ignore,
+}
+
+/// Rendering intent stored in an `sRGB` chunk.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum RenderingIntent {
+    Perceptual,
+    Relative,
+    Saturation,
+    Absolute,
+}
+
+impl RenderingIntent {
+    /// Byte value used by the `sRGB` chunk.
+    pub fn code(self) -> u8 {
+        match self {
+            RenderingIntent::Perceptual => 0,
+            RenderingIntent::Relative => 1,
+            RenderingIntent::Saturation => 2,
+            RenderingIntent::Absolute => 3,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+impl Mode {
+    /// Suffix appended to the input stem to form the output file name.
+    pub fn output_suffix(self) -> &'static str {
+        match self {
+            Mode::Optimize => "_optimized.png",
+            Mode::Compress => "_compressed.png",
+        }
+    }
+
+    /// How outputs of this mode are described in messages.
+    pub fn output_label(self) -> &'static str {
+        match self {
+            Mode::Optimize => "optimized",
+            Mode::Compress => "compressed",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CommonOptions {
     pub keep_metadata: bool,
+    pub keep_exif: bool,
+    pub strip_exif: bool,
+    pub keep_text: Vec<String>,
+    pub apply_orientation: bool,
+    pub set_dpi: Option<u32>,
+    pub update_time: bool,
+    pub strip_nondeterministic: bool,
+    /// Keyword and text pairs from `--set-text`, applied in order.
+    pub set_text: Vec<(String, String)>,
+    pub icc_profile: Option<IccProfile>,
+    pub normalize_colorspace: Option<RenderingIntent>,
+    pub set_background: Option<[u8; 3]>,
+    pub strip_background: bool,
     pub overwrite: bool,
     pub threads: Option<NonZeroUsize>,
+    /// The pool oxipng runs in under `--intra-threads`.
+    pub intra_pool: Option<Arc<ThreadPool>>,
+    pub max_pixels: u64,
     pub progress: ProgressKind,
     pub dry_run: bool,
+    /// Set by `--measure-only`: outputs are measured, then discarded.
+    pub measure_only: bool,
+    pub emit_sidecar: bool,
+    pub checksums: Option<Checksums>,
+    /// Host that `--output` streams results to.
+    #[cfg(feature = "remote")]
+    pub remote: Option<RemoteTarget>,
+    pub formats: Vec<OutputFormat>,
+    pub crc: CrcPolicy,
+    pub baseline: Option<PathBuf>,
+    pub temp_dir: Option<PathBuf>,
+    pub fsync: bool,
+    pub mmap: bool,
+    /// Backend and level chosen with `--deflater`/`--compression-level`.
+    pub deflater: Option<(Deflater, u8)>,
+    pub post_cmd: Option<String>,
+    /// When `--time-budget` runs out; files not started by then are skipped.
+    pub deadline: Option<Instant>,
+    /// Stages disabled with `--skip-stage`, `--skip-quantize`, `--only-deflate` or
+    /// `--stage-only`.
+    pub skip_stages: Vec<StageKind>,
+    pub only_deflate: bool,
+    pub stage_only: Option<StageOnly>,
+    pub stable_palette: bool,
+    pub validate_output: bool,
+    pub no_write_larger: bool,
+    pub compat: Option<Compat>,
+    pub apng_policy: ApngPolicy,
+    pub warnings_as_errors: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct OptimizeOptions {
     pub zopfli: bool,
+    /// `--allow-16to8`: let oxipng scale 16-bit samples to 8 bits.
+    pub scale_16: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CompressOptions {
     pub quality: u8,
+    pub quality_min: Option<u8>,
+    pub quality_max: Option<u8>,
+    pub quant_speed: Option<u8>,
+    pub linear_quantization: bool,
+    pub lock_colors: Vec<[u8; 4]>,
+    pub roi_mask: Option<PathBuf>,
+    pub crisp_alpha: bool,
+    pub lossy: Lossy,
+    pub banding_check: bool,
+    pub adaptive: bool,
+    /// `--max-colors-256`: lift the quality-derived palette cap to a full palette.
+    pub full_palette: bool,
+    pub max_channel_diff: Option<u8>,
+    /// `--input-quality`: quality levels for inputs under particular paths.
+    pub input_quality: Vec<(PathBuf, u8)>,
+}
+
+impl CompressOptions {
+    /// The options for compressing `path`: `quality` replaced by the level of the most
+    /// specific `--input-quality` path containing it, if any.
+    #[cfg(feature = "compress")]
+    pub fn for_input<'a>(&'a self, path: &'a Path) -> Cow<'a, Self> {
+        // `./assets` and `assets` name the same inputs.
+        let relative = |path: &'a Path| path.strip_prefix(".").unwrap_or(path);
+        let path = relative(path);
+        let quality = self
+            .input_quality
+            .iter()
+            .map(|(prefix, quality)| (relative(prefix), *quality))
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, quality)| quality);
+        match quality {
+            Some(quality) if quality != self.quality => Cow::Owned(Self {
+                quality,
+                ..self.clone()
+            }),
+            _ => Cow::Borrowed(self),
+        }
+    }
+}
+
+/// Restricts the resolved inputs to files git considers changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ChangeFilter {
+    Since(String),
+    Staged,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -98,16 +1199,20 @@ pub enum ProgressKind {
 
 impl Cli {
     pub fn build(self) -> Result<AppConfig> {
-        if self.inputs.is_empty() {
+        if self.inputs.is_empty() && !self.base64 {
             bail!("at least one PNG path must be provided");
         }
 
+        let mut input_quality = self.input_quality;
         let inputs = self
             .inputs
             .into_iter()
             .map(|path| {
                 if path.exists() {
                     Ok(path)
+                } else if let Some((path, quality)) = split_input_quality(&path) {
+                    input_quality.push((path.clone(), quality));
+                    Ok(path)
                 } else {
                     bail!("input path {:?} does not exist", path)
                 }
@@ -115,31 +1220,505 @@ impl Cli {
             .collect::<Result<Vec<PathBuf>>>()
             .context("validating input paths")?;
 
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect::<Vec<_>>();
+        if extensions.is_empty() {
+            bail!("--extensions must list at least one extension");
+        }
+
+        let changes = match (self.changed_since, self.staged) {
+            (Some(reference), _) => Some(ChangeFilter::Since(reference)),
+            (None, true) => Some(ChangeFilter::Staged),
+            (None, false) => None,
+        };
+
+        let newer_than = self
+            .newer_than
+            .as_deref()
+            .map(parse_reference_time)
+            .transpose()
+            .context("parsing --newer-than")?;
+
+        let icc_profile = self
+            .embed_icc
+            .as_deref()
+            .map(IccProfile::load)
+            .transpose()?;
+
+        if let Some(baseline) = &self.baseline
+            && !baseline.is_dir()
+        {
+            bail!("baseline directory {} does not exist", baseline.display());
+        }
+        if let Some(temp_dir) = &self.temp_dir
+            && !temp_dir.is_dir()
+        {
+            bail!("temp directory {} does not exist", temp_dir.display());
+        }
+
+        if self.mode == Mode::Compress && !cfg!(feature = "compress") {
+            bail!(
+                "compress mode is unavailable: turbo-png was built without the `compress` feature"
+            );
+        }
+        if !cfg!(feature = "zopfli")
+            && (self.zopfli || matches!(self.deflater, Some(Deflater::Zopfli)))
+        {
+            bail!("Zopfli is unavailable: turbo-png was built without the `zopfli` feature");
+        }
+
+        if self.stage_only.is_some() && self.mode == Mode::Compress {
+            bail!("--stage-only deflate keeps pixels as they are, so it needs --mode optimize");
+        }
+        if self.mode == Mode::Optimize
+            && (self.skip_quantize || self.skip_stage.contains(&StageKind::Quantize))
+        {
+            bail!(
+                "optimize mode has no quantize stage to skip, so skipping it needs --mode compress"
+            );
+        }
+        if self.no_write_larger && self.mode == Mode::Compress {
+            bail!("--no-write-larger keeps inputs byte for byte, so it needs --mode optimize");
+        }
+        if self.log == LogTarget::Syslog && !cfg!(unix) {
+            bail!("--log syslog is only supported on Unix");
+        }
+        if self.syslog_socket.is_some() && self.log != LogTarget::Syslog {
+            bail!("--syslog-socket requires --log syslog");
+        }
+        if self.output.is_some() && !cfg!(feature = "remote") {
+            bail!(
+                "remote outputs are unavailable: turbo-png was built without the `remote` feature"
+            );
+        }
+        if self.bundle.is_some() && !cfg!(feature = "bundle") {
+            bail!("--bundle is unavailable: turbo-png was built without the `bundle` feature");
+        }
+        if let Some(url) = &self.upload_url {
+            if !cfg!(feature = "upload") {
+                bail!("uploads are unavailable: turbo-png was built without the `upload` feature");
+            }
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                bail!("--upload-url must be an http:// or https:// URL");
+            }
+            if !url.contains(RELPATH_PLACEHOLDER) {
+                bail!("--upload-url must contain {RELPATH_PLACEHOLDER}");
+            }
+        }
+        #[cfg(feature = "upload")]
+        let upload = self.upload_url.map(|url| UploadTarget {
+            url,
+            jobs: self.upload_jobs,
+            retries: self.upload_retries,
+        });
+
+        let deflater = match self.deflater {
+            Some(deflater) => {
+                let (range, default) = deflater.levels();
+                let level = self.compression_level.unwrap_or(default);
+                if !range.contains(&level) {
+                    bail!(
+                        "--compression-level {level} is out of range for {} ({}-{})",
+                        deflater
+                            .to_possible_value()
+                            .expect("no skipped variants")
+                            .get_name(),
+                        range.start(),
+                        range.end()
+                    );
+                }
+                Some((deflater, level))
+            }
+            None => None,
+        };
+
+        let intra_pool = self
+            .intra_threads
+            .map(|threads| {
+                ThreadPoolBuilder::new()
+                    .num_threads(threads.get())
+                    .thread_name(|index| format!("turbo-png-intra-{index}"))
+                    .build()
+                    .map(Arc::new)
+                    .context("configuring the --intra-threads pool")
+            })
+            .transpose()?;
+
+        let mut skip_stages = self.skip_stage;
+        if self.skip_quantize {
+            skip_stages.push(StageKind::Quantize);
+        }
+        if self.only_deflate {
+            skip_stages.extend([StageKind::Transform, StageKind::Quantize]);
+        }
+        if self.stage_only == Some(StageOnly::Deflate) {
+            skip_stages.push(StageKind::Transform);
+        }
+
         let common = CommonOptions {
             keep_metadata: self.keep_metadata,
-            overwrite: self.overwrite,
+            keep_exif: self.keep_exif,
+            strip_exif: self.strip_exif,
+            keep_text: self.keep_text,
+            apply_orientation: self.apply_orientation,
+            set_dpi: self.set_dpi,
+            update_time: self.update_time,
+            strip_nondeterministic: self.strip_nondeterministic,
+            set_text: self.set_text,
+            icc_profile,
+            normalize_colorspace: self.normalize_colorspace,
+            set_background: self.set_background,
+            strip_background: self.strip_background,
+            // Outputs older than their input are stale, so `--only-if-newer` replaces them.
+            overwrite: self.overwrite || self.only_if_newer || self.force,
             threads: self.threads,
+            intra_pool,
+            max_pixels: self.max_pixels,
             progress: if self.no_progress {
                 ProgressKind::Quiet
             } else {
                 ProgressKind::Fancy
             },
             dry_run: self.dry_run,
+            measure_only: self.measure_only.is_some(),
+            emit_sidecar: self.emit_sidecar,
+            #[cfg(feature = "remote")]
+            remote: self.output.as_ref().map(|url| RemoteTarget {
+                host: url.host.clone(),
+                port: url.port,
+                ssh_command: self.ssh_command.clone(),
+            }),
+            checksums: self.checksums.map(|algorithm| Checksums {
+                algorithm,
+                per_file: self.checksums_per_file,
+            }),
+            formats: self.emit_smallest,
+            crc: self.crc,
+            // `--force` always writes the new output.
+            baseline: self.baseline.filter(|_| !self.force),
+            temp_dir: self.temp_dir,
+            fsync: self.fsync,
+            mmap: self.mmap,
+            deflater,
+            post_cmd: self.post_cmd,
+            deadline: self.time_budget.map(|budget| Instant::now() + budget),
+            skip_stages,
+            only_deflate: self.only_deflate,
+            stage_only: self.stage_only,
+            stable_palette: self.stable_palette,
+            validate_output: self.validate_output,
+            no_write_larger: self.no_write_larger,
+            compat: self.compat,
+            apng_policy: self.apng_policy,
+            warnings_as_errors: self.warnings_as_errors,
         };
 
-        let optimize = OptimizeOptions {
-            zopfli: self.zopfli,
-        };
-        let compress = CompressOptions {
-            quality: self.quality,
-        };
+        let optimize = OptimizeOptionsBuilder::new()
+            .zopfli(self.zopfli)
+            .scale_16(self.allow_16to8)
+            .build();
+        let compress = CompressOptionsBuilder::new()
+            .quality(self.quality)
+            .quality_window(self.quality_min, self.quality_max)
+            .quant_speed(self.quant_speed)
+            .linear_quantization(self.linear_quantization)
+            .lock_colors(self.lock_colors)
+            .roi_mask(self.roi_mask)
+            .crisp_alpha(self.crisp_alpha)
+            .lossy(self.lossy)
+            .banding_check(!self.no_banding_check)
+            .adaptive(self.adaptive)
+            .full_palette(self.max_colors_256)
+            .max_channel_diff(self.max_channel_diff)
+            .input_quality(input_quality)
+            .build()?;
 
         Ok(AppConfig {
             inputs,
             mode: self.mode,
+            extensions,
+            follow_symlinks: self.follow_symlinks == Toggle::On,
+            recreate_symlinks: self.recreate_symlinks,
+            link_duplicates: self.link_duplicates,
+            respect_gitignore: self.respect_gitignore,
+            skip_hidden: self.skip_hidden,
+            changes,
+            newer_than,
+            only_if_newer: self.only_if_newer,
+            force: self.force,
+            interactive: self.interactive,
+            exit_code_failures: self.exit_code_failures,
+            base64: self.base64,
+            shard: self.shard,
+            order: self.order,
+            output_dir: self
+                .output_dir
+                .or(self.output.map(|url| url.path))
+                .map(|dir| OutputLayout {
+                    dir,
+                    flatten: self.flatten,
+                    on_collision: self.on_collision,
+                    copy_unchanged: self.copy_unchanged,
+                }),
+            #[cfg(feature = "bundle")]
+            bundle: self.bundle,
+            budgets: self.budgets,
+            manifest_out: self.manifest_out,
+            rename_map: self.rename_map,
+            ledger: self.measure_only,
+            #[cfg(feature = "upload")]
+            upload,
+            log: self.log,
+            syslog_socket: self.syslog_socket,
+            io_limit: self.io_limit,
             common,
             optimize,
             compress,
         })
     }
 }
+
+fn parse_reference_time(value: &str) -> Result<SystemTime> {
+    let path = Path::new(value);
+    if path.exists() {
+        return fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .path_context(path)
+            .context("reading modification time");
+    }
+
+    let seconds: f64 = value
+        .parse()
+        .with_context(|| format!("{value:?} is neither an existing file nor a Unix timestamp"))?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        bail!("timestamp {value:?} must be a non-negative number of seconds");
+    }
+
+    Ok(UNIX_EPOCH + Duration::from_secs_f64(seconds))
+}
+
+/// Parses `ssh://[user@]host[:port]/path`, with `sftp://` as an alias and IPv6 hosts in
+/// brackets. Both schemes go through the remote shell, never the SFTP subsystem.
+fn parse_ssh_url(value: &str) -> std::result::Result<SshUrl, String> {
+    let invalid = || format!("{value:?} is not a URL like ssh://user@host/srv/assets");
+    let rest = value
+        .strip_prefix("ssh://")
+        .or_else(|| value.strip_prefix("sftp://"))
+        .ok_or_else(invalid)?;
+    let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+    let (user, address) = match authority.rsplit_once('@') {
+        Some((user, address)) if !user.is_empty() => (Some(user), address),
+        Some(_) => return Err(invalid()),
+        None => (None, authority),
+    };
+    let (host, port) = match address.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+            match port {
+                "" => (host, None),
+                port => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        }
+        None => match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        },
+    };
+    let port = port
+        .map(|port| {
+            port.parse::<u16>()
+                .map_err(|_| format!("{port:?} is not a valid port"))
+        })
+        .transpose()?;
+    if host.is_empty() || path.is_empty() || (host.contains(':') && !address.starts_with('[')) {
+        return Err(invalid());
+    }
+
+    Ok(SshUrl {
+        host: match user {
+            Some(user) => format!("{user}@{host}"),
+            None => host.to_owned(),
+        },
+        port,
+        path: Path::new("/").join(path),
+    })
+}
+
+fn parse_bundle(value: &str) -> std::result::Result<Bundle, String> {
+    let name = value.to_ascii_lowercase();
+    let format = if name.ends_with(".zip") {
+        BundleFormat::Zip
+    } else if name.ends_with(".tar") {
+        BundleFormat::Tar
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        BundleFormat::TarGz
+    } else {
+        return Err(format!("{value:?} must end in .zip, .tar, .tar.gz or .tgz"));
+    };
+
+    Ok(Bundle {
+        path: PathBuf::from(value),
+        format,
+    })
+}
+
+fn parse_shard(value: &str) -> std::result::Result<Shard, String> {
+    let invalid = || format!("{value:?} is not a shard like 2/8");
+    let (index, count) = value.split_once('/').ok_or_else(invalid)?;
+    let index: usize = index.trim().parse().map_err(|_| invalid())?;
+    let count: usize = count.trim().parse().map_err(|_| invalid())?;
+    if count == 0 || index == 0 || index > count {
+        return Err(format!(
+            "shard {value:?} must be between 1/{count} and {count}/{count}",
+            count = count.max(1)
+        ));
+    }
+
+    Ok(Shard { index, count })
+}
+
+/// Parses `90`, `90s`, `10m`, `1h30m` and the like into a duration.
+fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let invalid = || format!("{value:?} is not a duration like 90s, 10m or 1h30m");
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        total = amount
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || value.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Parses a transfer rate like `50MB/s` into bytes per second. `K`, `M` and `G` are
+/// decimal; `KiB`, `MiB` and `GiB` binary. The `/s` is optional.
+fn parse_rate(value: &str) -> std::result::Result<u64, String> {
+    let invalid = || format!("{value:?} is not a rate like 50MB/s or 512KiB/s");
+    let rate = value.trim();
+    let rate = rate.strip_suffix("/s").unwrap_or(rate);
+    let split = rate
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rate.len());
+    let (amount, unit) = rate.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let unit: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    match amount.checked_mul(unit) {
+        Some(0) => Err(format!("{value:?}: the rate must be above zero")),
+        Some(rate) => Ok(rate),
+        None => Err(invalid()),
+    }
+}
+
+/// Parses `PATH=LEVEL` for `--input-quality`.
+fn parse_input_quality(value: &str) -> std::result::Result<(PathBuf, u8), String> {
+    let (path, level) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("{value:?} is not a PATH=LEVEL pair"))?;
+    let quality = parse_quality_level(level)
+        .ok_or_else(|| format!("{value:?}: the level must be between 1 and 100"))?;
+    Ok((PathBuf::from(path), quality))
+}
+
+/// Splits a missing positional input written as `PATH:LEVEL` into an existing path and
+/// its quality.
+fn split_input_quality(input: &Path) -> Option<(PathBuf, u8)> {
+    let (path, level) = input.to_str()?.rsplit_once(':')?;
+    let path = PathBuf::from(path);
+    let quality = parse_quality_level(level)?;
+    path.exists().then_some((path, quality))
+}
+
+fn parse_quality_level(level: &str) -> Option<u8> {
+    level
+        .parse()
+        .ok()
+        .filter(|quality| (1..=100).contains(quality))
+}
+
+/// Parses `KEY=VALUE`, checking `KEY` is a valid PNG text keyword.
+fn parse_text_entry(value: &str) -> std::result::Result<(String, String), String> {
+    let (keyword, text) = value
+        .split_once('=')
+        .ok_or_else(|| format!("{value:?} is not a KEY=VALUE pair"))?;
+    Ok((parse_text_keyword(keyword)?, text.to_owned()))
+}
+
+/// Checks `keyword` is a valid PNG text keyword: 1-79 printable Latin-1 characters without
+/// leading, trailing or doubled spaces.
+fn parse_text_keyword(keyword: &str) -> std::result::Result<String, String> {
+    let printable = |c: char| matches!(c, ' '..='~' | '\u{A1}'..='\u{FF}');
+    if keyword.is_empty()
+        || keyword.chars().count() > 79
+        || !keyword.chars().all(printable)
+        || keyword.starts_with(' ')
+        || keyword.ends_with(' ')
+        || keyword.contains("  ")
+    {
+        return Err(format!(
+            "{keyword:?} is not a valid text keyword (1-79 printable Latin-1 characters, \
+             no leading, trailing or doubled spaces)"
+        ));
+    }
+
+    Ok(keyword.to_owned())
+}
+
+fn parse_hex_color(value: &str) -> std::result::Result<[u8; 4], String> {
+    let hex = value.trim().trim_start_matches('#');
+    if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{value:?} is not a #rrggbb or #rrggbbaa color"));
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16);
+    let alpha = if hex.len() == 8 { channel(3) } else { Ok(255) };
+    match (channel(0), channel(1), channel(2), alpha) {
+        (Ok(r), Ok(g), Ok(b), Ok(a)) => Ok([r, g, b, a]),
+        _ => Err(format!("{value:?} is not a #rrggbb or #rrggbbaa color")),
+    }
+}
+
+/// Parses a `#rrggbb` color, rejecting an alpha channel that would be ignored.
+fn parse_opaque_color(value: &str) -> std::result::Result<[u8; 3], String> {
+    let [r, g, b, _] = parse_hex_color(value)?;
+    if value.trim().trim_start_matches('#').len() == 8 {
+        return Err(format!(
+            "{value:?} has an alpha channel; use a #rrggbb color"
+        ));
+    }
+    Ok([r, g, b])
+}
@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{CompareArgs, DEFAULT_MAX_PIXELS};
+use crate::decode::{DecodedImage, decode_rgba};
+use crate::error::{Context, PathContext, Result, bail};
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: usize = 8;
+/// Offset between neighbouring SSIM windows.
+const SSIM_STRIDE: usize = 4;
+/// SSIM stabilizers for 8-bit samples: (0.01 * 255)^2 and (0.03 * 255)^2.
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+/// How far two same-sized images differ, over all four RGBA channels.
+struct Metrics {
+    max_delta: u8,
+    mean_delta: f64,
+    /// Infinite when the images are identical.
+    psnr: f64,
+    /// `1 / SSIM - 1`: 0 for identical images, growing as structure diverges.
+    dssim: f64,
+}
+
+/// Prints the metrics for `args.reference` vs `args.candidate`, and fails when any of
+/// the requested thresholds is exceeded.
+pub fn run(args: &CompareArgs) -> Result<()> {
+    let reference = load(&args.reference)?;
+    let candidate = load(&args.candidate)?;
+    if (reference.width, reference.height) != (candidate.width, candidate.height) {
+        bail!(
+            "{} is {}x{} but {} is {}x{}",
+            args.reference.display(),
+            reference.width,
+            reference.height,
+            args.candidate.display(),
+            candidate.width,
+            candidate.height
+        );
+    }
+
+    let metrics = measure(&reference, &candidate);
+    println!("max delta:  {}", metrics.max_delta);
+    println!("mean delta: {:.4}", metrics.mean_delta);
+    println!("PSNR:       {:.2} dB", metrics.psnr);
+    println!("DSSIM:      {:.6}", metrics.dssim);
+
+    let mut failures = Vec::new();
+    if let Some(limit) = args.max_delta
+        && metrics.max_delta > limit
+    {
+        failures.push(format!("max delta {} exceeds {limit}", metrics.max_delta));
+    }
+    if let Some(limit) = args.min_psnr
+        && metrics.psnr < limit
+    {
+        failures.push(format!("PSNR {:.2} dB is below {limit}", metrics.psnr));
+    }
+    if let Some(limit) = args.max_dssim
+        && metrics.dssim > limit
+    {
+        failures.push(format!("DSSIM {:.6} exceeds {limit}", metrics.dssim));
+    }
+    if !failures.is_empty() {
+        bail!("images differ too much: {}", failures.join(", "));
+    }
+
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<DecodedImage> {
+    let bytes = fs::read(path).path_context(path).context("reading image")?;
+    decode_rgba(&bytes, DEFAULT_MAX_PIXELS).with_context(|| format!("decoding {}", path.display()))
+}
+
+fn measure(reference: &DecodedImage, candidate: &DecodedImage) -> Metrics {
+    let a = channels(reference);
+    let b = channels(candidate);
+
+    let mut max_delta = 0u8;
+    let mut total_delta = 0u64;
+    let mut squared_error = 0u64;
+    for (&left, &right) in a.iter().zip(&b) {
+        let delta = left.abs_diff(right);
+        max_delta = max_delta.max(delta);
+        total_delta += u64::from(delta);
+        squared_error += u64::from(delta) * u64::from(delta);
+    }
+
+    let samples = a.len().max(1) as f64;
+    let mse = squared_error as f64 / samples;
+    let psnr = if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    };
+
+    Metrics {
+        max_delta,
+        mean_delta: total_delta as f64 / samples,
+        psnr,
+        dssim: dssim_of(&a, &b, reference.width as usize, reference.height as usize),
+    }
+}
+
+/// DSSIM between two images, or `None` when their dimensions differ.
+pub fn dssim(reference: &DecodedImage, candidate: &DecodedImage) -> Option<f64> {
+    if (reference.width, reference.height) != (candidate.width, candidate.height) {
+        return None;
+    }
+    Some(dssim_of(
+        &channels(reference),
+        &channels(candidate),
+        reference.width as usize,
+        reference.height as usize,
+    ))
+}
+
+fn dssim_of(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    let ssim = mean_ssim(a, b, width, height);
+    if ssim > 0.0 {
+        (1.0 / ssim - 1.0).max(0.0)
+    } else {
+        f64::INFINITY
+    }
+}
+
+fn channels(image: &DecodedImage) -> Vec<u8> {
+    image
+        .pixels
+        .iter()
+        .flat_map(|px| [px.r, px.g, px.b, px.a])
+        .collect()
+}
+
+/// Mean SSIM over overlapping windows of each RGBA channel.
+fn mean_ssim(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    let starts = |extent: usize, window: usize| -> Vec<usize> {
+        let mut starts: Vec<usize> = (0..=extent - window).step_by(SSIM_STRIDE).collect();
+        // Always cover the trailing edge, even when the stride skips past it.
+        if starts.last() != Some(&(extent - window)) {
+            starts.push(extent - window);
+        }
+        starts
+    };
+
+    let mut total = 0.0;
+    let mut windows = 0usize;
+    for y0 in starts(height, window_height) {
+        for x0 in starts(width, window_width) {
+            for channel in 0..4 {
+                let sample = |data: &[u8], x: usize, y: usize| {
+                    f64::from(data[((y0 + y) * width + x0 + x) * 4 + channel])
+                };
+                let count = (window_width * window_height) as f64;
+                let (mut sum_a, mut sum_b) = (0.0, 0.0);
+                let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+                for y in 0..window_height {
+                    for x in 0..window_width {
+                        let (va, vb) = (sample(a, x, y), sample(b, x, y));
+                        sum_a += va;
+                        sum_b += vb;
+                        sum_aa += va * va;
+                        sum_bb += vb * vb;
+                        sum_ab += va * vb;
+                    }
+                }
+
+                let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+                let variance_a = sum_aa / count - mean_a * mean_a;
+                let variance_b = sum_bb / count - mean_b * mean_b;
+                let covariance = sum_ab / count - mean_a * mean_b;
+                total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                    / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1)
+                        * (variance_a + variance_b + SSIM_C2));
+                windows += 1;
+            }
+        }
+    }
+
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
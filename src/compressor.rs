@@ -1,121 +1,678 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fs;
 use std::io::Cursor;
-use std::num::NonZeroU8;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
 
-use anyhow::{Context, Result, bail};
-use imagequant::{self, RGBA};
-use oxipng::{self, Deflaters, Options, RowFilter, StripChunks, indexset};
+use imagequant::{self, HistogramEntry, RGBA};
+use oxipng::{self, Interlacing, Options, RowFilter, StripChunks, indexset};
 use png::chunk::ChunkType;
 use png::{self, AdaptiveFilterType, BitDepth, ColorType, Compression, Encoder, FilterType};
+use rgb::ComponentBytes;
 
-use crate::cli::{CommonOptions, CompressOptions};
-use crate::pipeline::{build_strip_policy, derive_output_path, strip_policy_allows, write_atomic};
-use crate::ui::{FileOutcome, ProgressDispatcher};
+use crate::analysis::{BandingRisk, ContentClass, assess_banding, classify_content};
+use crate::chunks::{self, Chunks, RawChunk, order_chunks};
+use crate::cli::{CommonOptions, CompressOptions, DEFAULT_MAX_PIXELS, Lossy, Mode, StageKind};
+use crate::decode::{DecodedImage, WideSamples, decode_rgba, decode_rgba_into};
+use crate::error::{Context, PathContext, Result, TurboPngError, bail};
+use crate::options::derived_quality_window;
+use crate::pipeline::{
+    FileState, OutputPlan, ProcessedFile, ReadInput, RunSummary, Stage, WriteOutput,
+    apply_deflater, build_strip_policy, keep_pixel_format, optimize_png, orient_input,
+    pin_deflate_strategy, reduction_notes, run_stages, strip_policy_allows, strongest_deflater,
+    time_budget_exhausted,
+};
+use crate::ui::{FileOutcome, ProgressReporter};
+use crate::{interrupt, orientation};
 
 pub struct CompressJob<'a> {
     pub inputs: &'a [PathBuf],
+    pub outputs: &'a OutputPlan,
     pub options: &'a CompressOptions,
     pub common: &'a CommonOptions,
-    pub progress: &'a ProgressDispatcher,
+    pub progress: &'a dyn ProgressReporter,
 }
 
-pub fn run(job: CompressJob<'_>) -> Result<()> {
-    let mut failures = Vec::new();
+pub fn run(job: CompressJob<'_>) -> RunSummary {
+    let mut processed = Vec::new();
+    let mut failed = Vec::new();
+    // Decoded pixels dominate memory on large images, so one allocation serves every file.
+    let mut pixel_buffer = Vec::new();
 
-    for path in job.inputs {
+    let mut out_of_time = Vec::new();
+
+    for (index, path) in job.inputs.iter().enumerate() {
+        if interrupt::requested() {
+            break;
+        }
+        if time_budget_exhausted(job.common) {
+            out_of_time = job.inputs[index..].to_vec();
+            break;
+        }
         job.progress.file_started(path);
-        match process_file(path, &job) {
-            Ok(outcome) => job.progress.file_finished(path, Some(outcome)),
+        match process_file(path, &job, &mut pixel_buffer) {
+            Ok(outcome) => {
+                job.progress.file_finished(path, Some(outcome.clone()));
+                processed.push(ProcessedFile {
+                    input: path.clone(),
+                    outcome,
+                });
+            }
             Err(err) => {
                 job.progress.file_failed(path, &err);
-                failures.push(err);
+                failed.push((path.clone(), err));
             }
         }
     }
 
-    if failures.is_empty() {
+    RunSummary {
+        activity: "compression",
+        processed,
+        failed,
+        out_of_time,
+    }
+}
+
+fn process_file(
+    path: &Path,
+    job: &CompressJob<'_>,
+    pixel_buffer: &mut Vec<RGBA>,
+) -> Result<FileOutcome> {
+    let options = job.options.for_input(path);
+    let work = CompressWork::new(&options, job.common, pixel_buffer)?;
+    let mut state = FileState::new(path, Mode::Compress, job.common, job.outputs, work)?;
+    run_all_stages(&mut state, job.progress)?;
+    Ok(state.finish())
+}
+
+/// Compresses `input` without touching the filesystem, for `--base64`.
+pub fn process_in_memory(
+    label: &Path,
+    input: Vec<u8>,
+    options: &CompressOptions,
+    common: &CommonOptions,
+    progress: &dyn ProgressReporter,
+) -> Result<(FileOutcome, Vec<u8>)> {
+    let mut pixel_buffer = Vec::new();
+    let work = CompressWork::new(options, common, &mut pixel_buffer)?;
+    let mut state = FileState::in_memory(label, input, Mode::Compress, common, work);
+    run_all_stages(&mut state, progress)?;
+    Ok(state.finish_in_memory())
+}
+
+fn run_all_stages(
+    state: &mut FileState<'_, CompressWork<'_>>,
+    progress: &dyn ProgressReporter,
+) -> Result<()> {
+    let options = state.work.options;
+    let stages: [&dyn Stage<CompressWork<'_>>; 7] = [
+        &ReadInput,
+        &Decode,
+        &Transform,
+        &Quantize,
+        &Encode,
+        &Deflate,
+        &WriteOutput { options },
+    ];
+    let result = run_stages(&stages, state, progress);
+    // Failed files hand their pixels back too, or the next file would allocate afresh.
+    if let Some(decoded) = state.work.decoded.take() {
+        recycle_pixels(decoded, state.work.pixel_buffer);
+    }
+    result
+}
+
+/// Compress-mode state handed from stage to stage.
+struct CompressWork<'a> {
+    options: &'a CompressOptions,
+    /// Pixel allocation reused across files; decoding borrows it, `recycle_pixels` returns it.
+    pixel_buffer: &'a mut Vec<RGBA>,
+    strip_policy: StripChunks,
+    preserved: Option<PreservedChunks>,
+    decoded: Option<DecodedImage>,
+    quantized: Option<QuantizedImage>,
+    /// Whether the chosen tuning searches the filters suited to continuous-tone images.
+    photo: bool,
+    /// Set when the input's own palette is small enough to keep and only tidy up.
+    reuse_palette: bool,
+    /// Significant bits per RGBA channel declared by the input's `sBIT`.
+    significant: Option<[u8; 4]>,
+    /// Bits kept per RGBA channel when `--lossy bitcrush` rounded the decoded pixels.
+    crushed_bits: Option<[u8; 4]>,
+    /// The `sBIT` to write once oxipng has settled the output's color type.
+    output_significant: Option<[u8; 4]>,
+}
+
+impl<'a> CompressWork<'a> {
+    fn new(
+        options: &'a CompressOptions,
+        common: &CommonOptions,
+        pixel_buffer: &'a mut Vec<RGBA>,
+    ) -> Result<Self> {
+        Ok(Self {
+            options,
+            pixel_buffer,
+            strip_policy: build_strip_policy(common)?,
+            preserved: None,
+            decoded: None,
+            quantized: None,
+            photo: false,
+            reuse_palette: false,
+            significant: None,
+            crushed_bits: None,
+            output_significant: None,
+        })
+    }
+}
+
+/// Extracts the chunks to carry over and decodes the input to RGBA.
+struct Decode;
+
+impl Stage<CompressWork<'_>> for Decode {
+    fn kind(&self) -> StageKind {
+        StageKind::Decode
+    }
+
+    fn run(&self, state: &mut FileState<'_, CompressWork<'_>>) -> Result<()> {
+        let preserved = extract_preserved_chunks(state.input(), &state.work.strip_policy)
+            .context("extracting metadata chunks")?;
+        let pixels = std::mem::take(state.work.pixel_buffer);
+        let decoded = decode_rgba_into(
+            state.input(),
+            state.common.max_pixels,
+            pixels,
+            WideSamples::Dither,
+        )
+        .context("decoding PNG")?;
+        state.work.significant = chunks::significant_bits(state.input()).context("reading sBIT")?;
+        state.work.preserved = Some(preserved);
+        state.work.decoded = Some(decoded);
         Ok(())
-    } else {
-        let mut message = String::from("one or more files failed during compression:\n");
-        for failure in &failures {
-            message.push_str(" • ");
-            message.push_str(&failure.to_string());
-            message.push('\n');
+    }
+}
+
+/// Applies `--apply-orientation` (turning the decoded pixels to match the rotated input)
+/// and `--crisp-alpha`.
+struct Transform;
+
+impl Stage<CompressWork<'_>> for Transform {
+    fn kind(&self) -> StageKind {
+        StageKind::Transform
+    }
+
+    fn run(&self, state: &mut FileState<'_, CompressWork<'_>>) -> Result<()> {
+        if let Some(orientation) = orient_input(state)? {
+            // The rotated input's chunks changed (eXIf, pHYs, sBIT), its pixels only moved.
+            state.work.preserved = Some(
+                extract_preserved_chunks(state.input(), &state.work.strip_policy)
+                    .context("extracting metadata chunks")?,
+            );
+            state.work.significant =
+                chunks::significant_bits(state.input()).context("reading sBIT")?;
+            if let Some(decoded) = &mut state.work.decoded {
+                let mut rotated = std::mem::take(state.work.pixel_buffer);
+                let (width, height) = orientation::reorient_pixels(
+                    &decoded.pixels,
+                    decoded.width as usize,
+                    decoded.height as usize,
+                    orientation,
+                    &mut rotated,
+                );
+                (decoded.width, decoded.height) = (width as u32, height as u32);
+                *state.work.pixel_buffer = std::mem::replace(&mut decoded.pixels, rotated);
+            }
+        }
+        if state.work.options.crisp_alpha
+            && let Some(decoded) = &mut state.work.decoded
+        {
+            snap_alpha(&mut decoded.pixels);
         }
-        bail!(message);
+        Ok(())
     }
 }
 
-fn process_file(path: &Path, job: &CompressJob<'_>) -> Result<FileOutcome> {
-    let start = Instant::now();
+/// Picks a tuning for the image and reduces it to a palette, unless banding risk or an
+/// already small palette makes that pointless.
+struct Quantize;
 
-    let output_path =
-        derive_output_path(path, "_compressed.png").context("computing compressed output path")?;
+impl Stage<CompressWork<'_>> for Quantize {
+    fn kind(&self) -> StageKind {
+        StageKind::Quantize
+    }
 
-    if output_path.exists() && !job.common.overwrite {
-        bail!(
-            "output file {} already exists (use --overwrite to replace)",
-            output_path.display()
-        );
+    fn run(&self, state: &mut FileState<'_, CompressWork<'_>>) -> Result<()> {
+        let options = state.work.options;
+        if state.animated {
+            // The other frames share the header's color type, so they'd all need remapping.
+            state.notes.push(String::from("animated, kept lossless"));
+            return Ok(());
+        }
+        if state.common.stable_palette && chunks::is_indexed(state.input())? {
+            if options.lossy == Lossy::Bitcrush {
+                bail!(
+                    "--stable-palette can't keep the palette of an indexed input with --lossy bitcrush"
+                );
+            }
+            state.notes.push(String::from("palette kept"));
+            return Ok(());
+        }
+        match options.lossy {
+            Lossy::None => return Ok(()),
+            Lossy::Palette => {}
+            Lossy::Bitcrush => {
+                let bits = select_crush_bits(options.quality.clamp(1, 100));
+                // Channels the source already declares at no more bits are posterized as
+                // they are, so rounding them again would only lose detail.
+                let depths = match state.work.significant {
+                    Some(significant) => significant.map(|declared| declared.min(bits)),
+                    None => [bits; 4],
+                };
+                let keep = state
+                    .work
+                    .significant
+                    .map(|significant| significant.map(|declared| declared <= bits));
+                let decoded = state
+                    .work
+                    .decoded
+                    .as_mut()
+                    .expect("the decode stage always runs");
+                let source = options.max_channel_diff.map(|_| decoded.pixels.clone());
+                bitcrush(&mut decoded.pixels, depths, keep.unwrap_or_default());
+                if let (Some(limit), Some(source)) = (options.max_channel_diff, source) {
+                    let diff = max_channel_diff(&source, decoded.pixels.iter().copied());
+                    if diff > limit {
+                        decoded.pixels = source;
+                        state
+                            .notes
+                            .push(format!("channel diff {diff} over {limit}, kept lossless"));
+                        return Ok(());
+                    }
+                }
+                if depths == [bits; 4] && keep.is_none_or(|keep| !keep.contains(&true)) {
+                    state
+                        .notes
+                        .push(format!("bitcrushed to {bits} bits per channel"));
+                } else {
+                    let [r, g, b, a] = depths;
+                    state.notes.push(format!(
+                        "bitcrushed to {r}/{g}/{b}/{a} bits per channel after sBIT"
+                    ));
+                }
+                state.work.crushed_bits = Some(depths);
+                if strip_policy_allows(&state.work.strip_policy, b"sBIT") {
+                    state.work.output_significant = Some(depths);
+                }
+                return Ok(());
+            }
+        }
+
+        let existing_palette = indexed_palette_size(state.input())?;
+        let decoded = state
+            .work
+            .decoded
+            .as_ref()
+            .expect("the decode stage always runs");
+        let banding = if options.banding_check {
+            assess_banding(&decoded.pixels, decoded.width, decoded.height)
+        } else {
+            BandingRisk::Low
+        };
+        if let BandingRisk::Severe(score) = banding {
+            // No palette size avoids banding on this much gradient; keep it lossless instead.
+            state
+                .notes
+                .push(format!("banding risk {:.0}%, kept lossless", score * 100.0));
+            return Ok(());
+        }
+
+        let tuning = select_tuning(decoded, options, banding);
+        if let Some(content) = tuning.content {
+            state.notes.push(format!("{} content", content.label()));
+        }
+        if let BandingRisk::Elevated(score) = banding {
+            state.notes.push(format!(
+                "banding risk {:.0}%, palette raised to {}",
+                score * 100.0,
+                tuning.max_colors
+            ));
+        }
+        state.work.photo = tuning.photo;
+
+        match existing_palette {
+            Some(colors) if colors <= tuning.max_colors as usize => {
+                // Requantizing an existing palette only loses detail; tidy it up instead.
+                state.notes.insert(0, format!("{colors} colors"));
+                state
+                    .notes
+                    .push(String::from("already indexed, requantization skipped"));
+                state.work.reuse_palette = true;
+            }
+            Some(colors) => {
+                let merged = merge_palette(decoded, &tuning, options)
+                    .context("merging the existing palette")?;
+                if let Some(limit) = options.max_channel_diff {
+                    let diff = max_channel_diff(&decoded.pixels, merged.pixels());
+                    if diff > limit {
+                        state
+                            .notes
+                            .push(format!("channel diff {diff} over {limit}, kept lossless"));
+                        return Ok(());
+                    }
+                }
+                state
+                    .notes
+                    .insert(0, format!("{} colors", merged.palette.len()));
+                state
+                    .notes
+                    .push(format!("already indexed, merged from {colors} colors"));
+                if merged.relaxed_floor {
+                    state.notes.push(String::from("quality floor relaxed"));
+                }
+                state.work.quantized = Some(merged);
+            }
+            None => {
+                if let Some(exact) =
+                    exact_palette(decoded, &options.lock_colors, tuning.max_colors as usize)
+                {
+                    // Every color fits: imagequant could only lose some of them.
+                    state
+                        .notes
+                        .insert(0, format!("{} colors", exact.palette.len()));
+                    state
+                        .notes
+                        .push(String::from("exact palette, quantization skipped"));
+                    state.work.quantized = Some(exact);
+                    return Ok(());
+                }
+                let importance = options
+                    .roi_mask
+                    .as_deref()
+                    .map(|mask| load_importance_map(mask, decoded, state.common.max_pixels))
+                    .transpose()?;
+                let retry_importance = options.max_channel_diff.and(importance.clone());
+                let mut quantized = quantize_image(decoded, importance, &tuning, options)
+                    .context("quantizing image to palette")?;
+                if let Some(limit) = options.max_channel_diff {
+                    let diff = max_channel_diff(&decoded.pixels, quantized.pixels());
+                    if diff > limit {
+                        // Dithering trades a larger worst case for a smoother average.
+                        let finest = Tuning {
+                            max_colors: FULL_PALETTE_CAP,
+                            dithering: 0.0,
+                            target_floor: 100,
+                            ..tuning
+                        };
+                        quantized = quantize_image(decoded, retry_importance, &finest, options)
+                            .context("quantizing image to palette at full quality")?;
+                        let retried = max_channel_diff(&decoded.pixels, quantized.pixels());
+                        if retried > limit {
+                            state.notes.push(format!(
+                                "channel diff {retried} over {limit}, kept lossless"
+                            ));
+                            return Ok(());
+                        }
+                        state.notes.push(format!(
+                            "channel diff {diff} over {limit}, requantized at full quality"
+                        ));
+                    }
+                }
+                state
+                    .notes
+                    .insert(0, format!("{} colors", quantized.palette.len()));
+                if quantized.relaxed_floor {
+                    state.notes.push(String::from("quality floor relaxed"));
+                }
+                state.work.quantized = Some(quantized);
+            }
+        }
+        Ok(())
     }
+}
 
-    let original_bytes =
-        fs::read(path).with_context(|| format!("reading input PNG {}", path.display()))?;
-    let original_size = original_bytes.len() as u64;
-
-    let strip_policy = build_strip_policy(job.common)?;
-    let preserved = extract_preserved_chunks(&original_bytes, &strip_policy)
-        .context("extracting metadata chunks")?;
-    let decoded = decode_rgba(&original_bytes).context("decoding PNG")?;
-    let quantized =
-        quantize_image(&decoded, job.options.quality).context("quantizing image to palette")?;
-    let palette_note = format!("{} colors", quantized.palette.len());
-    let indexed_png = encode_indexed_png(&quantized, &decoded, &preserved, job.options.quality)
-        .context("encoding indexed PNG")?;
-
-    let mut options = configure_options(job.common, job.options);
-    options.strip = strip_policy.clone();
-
-    let optimized_bytes = oxipng::optimize_from_memory(&indexed_png, &options)
-        .with_context(|| format!("optimizing {}", path.display()))?;
-    let output_size = optimized_bytes.len() as u64;
-
-    if job.common.dry_run {
-        return Ok(FileOutcome {
-            original_size,
-            output_size,
-            elapsed: start.elapsed(),
-            notes: Some(format!("dry run, {palette_note}")),
-        });
-    }
-
-    write_atomic(&output_path, &optimized_bytes, job.common.overwrite)
-        .with_context(|| format!("writing compressed PNG {}", output_path.display()))?;
-
-    Ok(FileOutcome {
-        original_size,
-        output_size,
-        elapsed: start.elapsed(),
-        notes: Some(palette_note),
-    })
+/// Writes the quantized image as an indexed PNG, or the bitcrushed pixels as truecolor,
+/// with the preserved chunks.
+struct Encode;
+
+impl Stage<CompressWork<'_>> for Encode {
+    fn kind(&self) -> StageKind {
+        StageKind::Encode
+    }
+
+    fn run(&self, state: &mut FileState<'_, CompressWork<'_>>) -> Result<()> {
+        let quantized = state.work.quantized.take();
+        if quantized.is_none() && state.work.crushed_bits.is_none() {
+            return Ok(());
+        }
+        if chunks::pixel_format(state.input())?.1 == 16 {
+            state
+                .warnings
+                .push(String::from("16-bit samples reduced to 8 bits"));
+        }
+        let decoded = state
+            .work
+            .decoded
+            .as_ref()
+            .expect("the decode stage always runs");
+        let preserved = state
+            .work
+            .preserved
+            .as_ref()
+            .expect("the decode stage always runs");
+        let encoded = match quantized {
+            Some(quantized) => {
+                // The source's sBIT only still holds if every palette entry keeps to it.
+                let carried = preserved.carries(b"sBIT");
+                state.work.output_significant = state.work.significant.filter(|&bits| {
+                    carried
+                        && quantized
+                            .palette
+                            .iter()
+                            .all(|color| fits_significant_bits(*color, bits))
+                });
+                encode_indexed_png(&quantized, decoded, preserved, state.work.photo)
+                    .context("encoding indexed PNG")?
+            }
+            None => encode_truecolor_png(decoded, preserved).context("encoding truecolor PNG")?,
+        };
+        state.output = Some(encoded);
+        Ok(())
+    }
+}
+
+/// Runs oxipng over the encoded PNG, or over the input when nothing was encoded. Only
+/// indexed output keeps its color type; the rest may be reduced losslessly.
+struct Deflate;
+
+impl Stage<CompressWork<'_>> for Deflate {
+    fn kind(&self) -> StageKind {
+        StageKind::Deflate
+    }
+
+    fn run(&self, state: &mut FileState<'_, CompressWork<'_>>) -> Result<()> {
+        if let Some(decoded) = state.work.decoded.take() {
+            recycle_pixels(decoded, state.work.pixel_buffer);
+        }
+
+        let common = state.common;
+        let target = if state.work.reuse_palette {
+            DeflateTarget::ReusedPalette
+        } else if state.output.is_some() && state.work.crushed_bits.is_none() {
+            DeflateTarget::Indexed
+        } else {
+            DeflateTarget::Lossless
+        };
+        let mut options = configure_options(common, state.work.options, state.work.photo, target);
+        options.strip = state.work.strip_policy.clone();
+        if common.stable_palette && chunks::is_indexed(state.input())? {
+            keep_pixel_format(&mut options);
+        }
+        if common.strip_nondeterministic {
+            pin_deflate_strategy(&mut options);
+        }
+        // Our encoder only writes non-interlaced images, so restore Adam7 for sources that
+        // were interlaced rather than silently dropping it. Interlacing usually costs a few
+        // bytes, so oxipng has to be told to keep its result even when it is larger.
+        if chunks::is_interlaced(state.input())? {
+            options.interlace = Some(Interlacing::Adam7);
+            options.force = true;
+        }
+
+        let source = state.output.as_deref().unwrap_or(state.input());
+        let mut optimized = optimize_png(source, &options, common)
+            .with_context(|| format!("optimizing {}", state.path.display()))?;
+        let reductions = reduction_notes(source, &optimized, &options, common)?;
+        state.notes.extend(reductions);
+        if let Some(bits) = state.work.output_significant {
+            optimized = chunks::set_significant_bits(&optimized, bits).context("writing sBIT")?;
+        }
+        state.output = Some(optimized);
+        Ok(())
+    }
+}
+
+/// Palette cap used when an image is at elevated banding risk.
+const BANDING_PALETTE_CAP: u32 = 256;
+/// Minimum quantization target used when an image is at elevated banding risk, so the
+/// larger palette actually gets used.
+const BANDING_QUALITY_TARGET: u8 = 90;
+
+/// Palette cap under `--max-colors-256`.
+const FULL_PALETTE_CAP: u32 = 256;
+
+/// Palette cap used for photographic content under `--adaptive`.
+const ADAPTIVE_PHOTO_PALETTE_CAP: u32 = 256;
+/// Minimum quantization target for photographic content under `--adaptive`.
+const ADAPTIVE_PHOTO_QUALITY_TARGET: u8 = 90;
+
+/// Palette, dithering and filter choices for one image.
+pub struct Tuning {
+    /// Detected content type, when `--adaptive` classified the image.
+    content: Option<ContentClass>,
+    /// Whether to search the full filter set suited to continuous-tone images.
+    photo: bool,
+    max_colors: u32,
+    dithering: f32,
+    /// Lower bound applied to the derived quantization target.
+    target_floor: u8,
+}
+
+impl Tuning {
+    /// The palette size and dithering, as `analyze` reports them.
+    pub fn summary(&self) -> String {
+        let dithering = if self.dithering == 0.0 {
+            String::from("no dithering")
+        } else if self.dithering == 1.0 {
+            String::from("dithered")
+        } else {
+            format!("dithering {:.1}", self.dithering)
+        };
+        format!("{} colors, {dithering}", self.max_colors)
+    }
+}
+
+/// Derives settings from the quality level, or from the image content under `--adaptive`,
+/// then enlarges the palette when `banding` is elevated.
+pub fn select_tuning(
+    image: &DecodedImage,
+    options: &CompressOptions,
+    banding: BandingRisk,
+) -> Tuning {
+    let mut tuning = tune_for_content(image, options);
+    if let BandingRisk::Elevated(_) = banding {
+        tuning.max_colors = BANDING_PALETTE_CAP;
+        tuning.target_floor = tuning.target_floor.max(BANDING_QUALITY_TARGET);
+    }
+    tuning
 }
 
-struct DecodedImage {
-    width: u32,
-    height: u32,
-    pixels: Vec<RGBA>,
+fn tune_for_content(image: &DecodedImage, options: &CompressOptions) -> Tuning {
+    let quality = options.quality.clamp(1, 100);
+    if !options.adaptive {
+        return Tuning {
+            content: None,
+            photo: is_photo_quality(quality),
+            max_colors: palette_cap(options, quality),
+            dithering: select_dithering(quality),
+            target_floor: 0,
+        };
+    }
+
+    let content = classify_content(&image.pixels, image.width, image.height);
+    match content {
+        ContentClass::Photo => Tuning {
+            content: Some(content),
+            photo: true,
+            max_colors: ADAPTIVE_PHOTO_PALETTE_CAP,
+            dithering: 1.0,
+            target_floor: ADAPTIVE_PHOTO_QUALITY_TARGET,
+        },
+        // Flat artwork has few colors to begin with, and dithering only adds noise to it.
+        ContentClass::Graphic => Tuning {
+            content: Some(content),
+            photo: false,
+            max_colors: palette_cap(options, quality),
+            dithering: 0.0,
+            target_floor: 0,
+        },
+    }
 }
 
+/// Gamma imagequant assumes when a PNG declares none (sRGB's ~1/2.2).
+const SRGB_GAMMA: f64 = 0.45455;
+/// Gamma of imagequant's internal color comparisons (`imagequant::pal::INTERNAL_GAMMA`).
+const IMAGEQUANT_INTERNAL_GAMMA: f64 = 0.57;
+
 struct QuantizedImage {
     palette: Vec<RGBA>,
     indices: Vec<u8>,
+    /// Set when the requested quality floor could not be met and was dropped.
+    relaxed_floor: bool,
+}
+
+impl QuantizedImage {
+    /// The colors each pixel is mapped to.
+    fn pixels(&self) -> impl Iterator<Item = RGBA> + '_ {
+        self.indices
+            .iter()
+            .map(|&index| self.palette[usize::from(index)])
+    }
+}
+
+/// The largest difference in any channel between `source` and the pixels it became,
+/// for `--max-channel-diff`. Pixels transparent in both count as equal, whatever their
+/// color.
+fn max_channel_diff(source: &[RGBA], output: impl Iterator<Item = RGBA>) -> u8 {
+    source
+        .iter()
+        .zip(output)
+        .filter(|(before, after)| before.a != 0 || after.a != 0)
+        .map(|(before, after)| {
+            before
+                .r
+                .abs_diff(after.r)
+                .max(before.g.abs_diff(after.g))
+                .max(before.b.abs_diff(after.b))
+                .max(before.a.abs_diff(after.a))
+        })
+        .max()
+        .unwrap_or(0)
 }
 
 struct PreservedChunks {
     before_idat: Vec<PngChunk>,
     after_idat: Vec<PngChunk>,
+    /// The source's `bKGD` color, resolved against its own bit depth and palette.
+    background: Option<RGBA>,
+}
+
+impl PreservedChunks {
+    /// Whether a chunk called `name` is being carried over.
+    fn carries(&self, name: &[u8; 4]) -> bool {
+        self.before_idat
+            .iter()
+            .chain(&self.after_idat)
+            .any(|chunk| &chunk.name == name)
+    }
 }
 
 struct PngChunk {
@@ -123,76 +680,450 @@ struct PngChunk {
     data: Vec<u8>,
 }
 
-fn decode_rgba(bytes: &[u8]) -> Result<DecodedImage> {
-    let cursor = Cursor::new(bytes);
-    let mut decoder = png::Decoder::new(cursor);
-    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
-    let mut reader = decoder.read_info().context("reading PNG info")?;
-    let mut buffer = vec![0; reader.output_buffer_size()];
-    let info = reader
-        .next_frame(&mut buffer)
-        .context("reading PNG image data")?;
+/// Hands a decoded image's pixel allocation back for the next file once its pixels
+/// are no longer needed.
+fn recycle_pixels(decoded: DecodedImage, pixel_buffer: &mut Vec<RGBA>) {
+    *pixel_buffer = decoded.pixels;
+}
 
-    if info.bit_depth != BitDepth::Eight {
-        bail!("expected 8-bit output after decoding");
+/// Palette size of an input that is already stored as indexed color.
+fn indexed_palette_size(bytes: &[u8]) -> Result<Option<usize>> {
+    let reader = png::Decoder::new(Cursor::new(bytes))
+        .read_info()
+        .context("reading PNG info")?;
+    let info = reader.info();
+    if info.color_type != ColorType::Indexed {
+        return Ok(None);
     }
 
-    let pixel_count = info.width as usize * info.height as usize;
-    let mut pixels = Vec::with_capacity(pixel_count);
+    Ok(info.palette.as_ref().map(|palette| palette.len() / 3))
+}
 
-    match info.color_type {
-        ColorType::Rgba => {
-            let expected_len = pixel_count * 4;
-            let data = &buffer[..expected_len];
-            for chunk in data.chunks_exact(4) {
-                pixels.push(RGBA::new(chunk[0], chunk[1], chunk[2], chunk[3]));
-            }
+/// Loads a region-of-interest mask whose brightness weights quantization importance.
+fn load_importance_map(mask: &Path, image: &DecodedImage, max_pixels: u64) -> Result<Vec<u8>> {
+    let bytes = fs::read(mask)
+        .path_context(mask)
+        .context("reading ROI mask")?;
+    let decoded = decode_rgba(&bytes, max_pixels)
+        .with_context(|| format!("decoding ROI mask {}", mask.display()))?;
+    if decoded.width != image.width || decoded.height != image.height {
+        bail!(
+            "ROI mask {} is {}x{} but the image is {}x{}",
+            mask.display(),
+            decoded.width,
+            decoded.height,
+            image.width,
+            image.height
+        );
+    }
+
+    Ok(decoded
+        .pixels
+        .iter()
+        .map(|px| {
+            ((u32::from(px.r) * 77 + u32::from(px.g) * 150 + u32::from(px.b) * 29) >> 8) as u8
+        })
+        .collect())
+}
+
+fn quantize_image(
+    image: &DecodedImage,
+    importance: Option<Vec<u8>>,
+    tuning: &Tuning,
+    options: &CompressOptions,
+) -> Result<QuantizedImage> {
+    let (mut attr, quality_target) = quantizer_attributes(tuning, options)?;
+    let input_gamma = quantizer_gamma(image, options);
+    let mut liq_image = attr.new_image_borrowed(
+        &image.pixels,
+        image.width as usize,
+        image.height as usize,
+        input_gamma,
+    )?;
+    for &[r, g, b, a] in &options.lock_colors {
+        liq_image.add_fixed_color(RGBA::new(r, g, b, a))?;
+    }
+    if let Some(importance) = importance {
+        liq_image.set_importance_map(importance)?;
+    }
+
+    let mut relaxed_floor = false;
+    let mut result = match attr.quantize(&mut liq_image) {
+        Ok(result) => result,
+        Err(imagequant::Error::QualityTooLow) => {
+            // One stubborn image shouldn't sink the batch: drop the floor and keep the target.
+            attr.set_quality(0, quality_target)?;
+            relaxed_floor = true;
+            attr.quantize(&mut liq_image)
+                .context("quantization failed even with a relaxed quality floor")?
         }
-        ColorType::Rgb => {
-            let expected_len = pixel_count * 3;
-            let data = &buffer[..expected_len];
-            for chunk in data.chunks_exact(3) {
-                pixels.push(RGBA::new(chunk[0], chunk[1], chunk[2], 255));
+        Err(err) => return Err(err.into()),
+    };
+    result.set_dithering_level(tuning.dithering)?;
+    let (mut palette, mut indices) = result.remapped(&mut liq_image)?;
+    if options.crisp_alpha {
+        crisp_alpha_edges(image, &mut palette, &mut indices);
+    }
+    apply_locked_colors(image, &options.lock_colors, &mut palette, &mut indices);
+
+    Ok(QuantizedImage {
+        palette,
+        indices,
+        relaxed_floor,
+    })
+}
+
+/// Maps `image` onto a palette of exactly its own colors when it has no more than `cap`
+/// of them, counting any locked colors it lacks. Translucent entries go first so the tRNS
+/// chunk stays short.
+fn exact_palette(image: &DecodedImage, locked: &[[u8; 4]], cap: usize) -> Option<QuantizedImage> {
+    let mut slots: HashMap<RGBA, u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let locked = locked.iter().map(|&[r, g, b, a]| RGBA::new(r, g, b, a));
+    for pixel in locked.chain(image.pixels.iter().copied()) {
+        if let Entry::Vacant(slot) = slots.entry(pixel) {
+            if palette.len() >= cap.min(FULL_PALETTE_CAP as usize) {
+                return None;
             }
-        }
-        other => {
-            bail!("unsupported color type after decoding: {:?}", other);
+            slot.insert(0);
+            palette.push(pixel);
         }
     }
+    palette.sort_by_key(|color| color.a == 255);
+    for (slot, color) in palette.iter().enumerate() {
+        slots.insert(*color, slot as u8);
+    }
+    let indices = image.pixels.iter().map(|pixel| slots[pixel]).collect();
 
-    Ok(DecodedImage {
-        width: info.width,
-        height: info.height,
-        pixels,
+    Some(QuantizedImage {
+        palette,
+        indices,
+        relaxed_floor: false,
     })
 }
 
-fn quantize_image(image: &DecodedImage, quality: u8) -> Result<QuantizedImage> {
+/// Reduces an indexed input's own colors to the tuned palette size without dithering.
+///
+/// Every pixel of a source palette entry lands on the same new entry, so flat areas stay
+/// flat and partially transparent entries keep their alpha level, where requantizing the
+/// expanded RGBA with dithering would scatter them across neighbouring colors.
+fn merge_palette(
+    image: &DecodedImage,
+    tuning: &Tuning,
+    options: &CompressOptions,
+) -> Result<QuantizedImage> {
+    let mut counts: HashMap<RGBA, u32> = HashMap::new();
+    for &pixel in &image.pixels {
+        *counts.entry(pixel).or_default() += 1;
+    }
+    let entries: Vec<HistogramEntry> = counts
+        .into_iter()
+        .map(|(color, count)| HistogramEntry { color, count })
+        .collect();
+
+    let (mut attr, quality_target) = quantizer_attributes(tuning, options)?;
+    let gamma = quantizer_gamma(image, options);
+    let mut histogram = imagequant::Histogram::new(&attr);
+    histogram.add_colors(&entries, gamma)?;
+    for &[r, g, b, a] in &options.lock_colors {
+        histogram.add_fixed_color(RGBA::new(r, g, b, a), gamma)?;
+    }
+
+    let mut relaxed_floor = false;
+    let mut result = match histogram.quantize(&attr) {
+        Ok(result) => result,
+        Err(imagequant::Error::QualityTooLow) => {
+            attr.set_quality(0, quality_target)?;
+            relaxed_floor = true;
+            histogram
+                .quantize(&attr)
+                .context("quantization failed even with a relaxed quality floor")?
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut palette = result.palette_vec();
+
+    let nearest: HashMap<RGBA, u8> = entries
+        .iter()
+        .map(|entry| {
+            let slot = palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, candidate)| premultiplied_distance(**candidate, entry.color))
+                .map_or(0, |(slot, _)| slot as u8);
+            (entry.color, slot)
+        })
+        .collect();
+    let mut indices: Vec<u8> = image.pixels.iter().map(|pixel| nearest[pixel]).collect();
+    apply_locked_colors(image, &options.lock_colors, &mut palette, &mut indices);
+
+    Ok(QuantizedImage {
+        palette,
+        indices,
+        relaxed_floor,
+    })
+}
+
+/// imagequant settings for `tuning`, plus the quality target to keep if the floor has to
+/// be relaxed.
+fn quantizer_attributes(
+    tuning: &Tuning,
+    options: &CompressOptions,
+) -> Result<(imagequant::Attributes, u8)> {
     let mut attr = imagequant::new();
+    let quality = options.quality.clamp(1, 100);
+    let (derived_min, derived_target) = derived_quality_window(quality);
+    let quality_target = options
+        .quality_max
+        .unwrap_or(derived_target.max(tuning.target_floor));
+    // `CompressOptionsBuilder` rejects a `--quality-min` above the derived target.
+    let quality_min = options.quality_min.unwrap_or(derived_min);
+    attr.set_quality(quality_min, quality_target)?;
+    attr.set_max_colors(tuning.max_colors)?;
+    let speed = options.quant_speed.unwrap_or_else(|| select_speed(quality));
+    attr.set_speed(i32::from(speed))?;
+    Ok((attr, quality_target))
+}
+
+/// Gamma to declare to imagequant for `image`.
+///
+/// imagequant compares colors after raising them to INTERNAL_GAMMA / input_gamma, so
+/// scaling the declared gamma by its internal gamma makes that comparison happen in
+/// linear light. The output gamma defaults to the input gamma, so the palette is
+/// converted back into the source encoding.
+fn quantizer_gamma(image: &DecodedImage, options: &CompressOptions) -> f64 {
+    let encoding_gamma = if image.gamma > 0.0 {
+        image.gamma
+    } else {
+        SRGB_GAMMA
+    };
+    if options.linear_quantization {
+        IMAGEQUANT_INTERNAL_GAMMA * encoding_gamma
+    } else {
+        image.gamma
+    }
+}
+
+/// Frames of an animation quantized against one palette.
+pub struct SharedPalette {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<RGBA>,
+    /// One index per pixel for each frame, in input order.
+    pub frames: Vec<Vec<u8>>,
+}
+
+/// Quantizes every frame against a single palette built from all of them, so colors
+/// don't shift between frames. Palette size, speed and dithering follow `quality` as
+/// they do in compress mode.
+pub fn quantize_frames(frames: &[(PathBuf, Vec<u8>)], quality: u8) -> Result<SharedPalette> {
+    let decoded = frames
+        .iter()
+        .map(|(path, bytes)| {
+            decode_rgba(bytes, DEFAULT_MAX_PIXELS)
+                .with_context(|| format!("decoding frame {}", path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let Some(first) = decoded.first() else {
+        bail!("no frames to quantize");
+    };
+    let (width, height) = (first.width, first.height);
+    for ((path, _), frame) in frames.iter().zip(&decoded) {
+        if (frame.width, frame.height) != (width, height) {
+            bail!(
+                "frame {} is {}x{} but the first frame is {width}x{height}",
+                path.display(),
+                frame.width,
+                frame.height
+            );
+        }
+    }
+
     let quality = quality.clamp(1, 100);
-    let (quality_min, quality_target) = select_quality_window(quality);
+    let (quality_min, quality_target) = derived_quality_window(quality);
+    let mut attr = imagequant::new();
     attr.set_quality(quality_min, quality_target)?;
     attr.set_max_colors(select_palette_cap(quality))?;
     attr.set_speed(i32::from(select_speed(quality)))?;
 
-    let mut liq_image = attr.new_image_borrowed(
-        &image.pixels,
-        image.width as usize,
-        image.height as usize,
-        0.0,
-    )?;
-    let mut result = attr.quantize(&mut liq_image)?;
+    let mut histogram = imagequant::Histogram::new(&attr);
+    for frame in &decoded {
+        let mut image =
+            attr.new_image_borrowed(&frame.pixels, width as usize, height as usize, frame.gamma)?;
+        histogram.add_image(&attr, &mut image)?;
+    }
+    let mut result = match histogram.quantize(&attr) {
+        Ok(result) => result,
+        Err(imagequant::Error::QualityTooLow) => {
+            attr.set_quality(0, quality_target)?;
+            histogram
+                .quantize(&attr)
+                .context("quantization failed even with a relaxed quality floor")?
+        }
+        Err(err) => return Err(err.into()),
+    };
     result.set_dithering_level(select_dithering(quality))?;
-    let (palette, indices) = result.remapped(&mut liq_image)?;
 
-    Ok(QuantizedImage { palette, indices })
+    // A palette from `Histogram::quantize` is frozen, so remapping can't refine it towards
+    // whichever frame it maps. Read it before any frame is remapped and hold every frame
+    // to it, so all indices share one palette.
+    let palette = result.palette_vec();
+    let mut indexed = Vec::with_capacity(decoded.len());
+    for ((path, _), frame) in frames.iter().zip(&decoded) {
+        let mut image =
+            attr.new_image_borrowed(&frame.pixels, width as usize, height as usize, frame.gamma)?;
+        let (remapped, indices) = result.remapped(&mut image)?;
+        if remapped != palette {
+            bail!(
+                "remapping frame {} changed the shared palette",
+                path.display()
+            );
+        }
+        indexed.push(indices);
+    }
+
+    Ok(SharedPalette {
+        width,
+        height,
+        palette,
+        frames: indexed,
+    })
+}
+
+/// Alpha values within this distance of fully transparent/opaque are snapped to it.
+const ALPHA_SNAP_THRESHOLD: u8 = 8;
+
+/// Rounds each RGBA channel to the nearest of `2^bits` evenly spaced levels, keeping 0
+/// and 255 exact. Channels marked in `keep` are left as they are.
+fn bitcrush(pixels: &mut [RGBA], bits: [u8; 4], keep: [bool; 4]) {
+    let tables: [[u8; 256]; 4] = std::array::from_fn(|channel| {
+        let levels = (1u32 << bits[channel]) - 1;
+        std::array::from_fn(|value| {
+            if keep[channel] {
+                return value as u8;
+            }
+            let level = (value as u32 * levels + 127) / 255;
+            ((level * 255 + levels / 2) / levels) as u8
+        })
+    });
+    for pixel in pixels {
+        *pixel = RGBA::new(
+            tables[0][usize::from(pixel.r)],
+            tables[1][usize::from(pixel.g)],
+            tables[2][usize::from(pixel.b)],
+            tables[3][usize::from(pixel.a)],
+        );
+    }
+}
+
+/// Whether every channel of `color` is a value an encoder could have widened from the
+/// declared significant bits, by shifting, bit replication or even scaling.
+fn fits_significant_bits(color: RGBA, bits: [u8; 4]) -> bool {
+    [color.r, color.g, color.b, color.a]
+        .into_iter()
+        .zip(bits)
+        .all(|(value, bits)| {
+            if bits >= 8 {
+                return true;
+            }
+            let level = u32::from(value >> (8 - bits));
+            let levels = (1u32 << bits) - 1;
+            let shifted = level << (8 - bits);
+            let replicated = (0..8).step_by(usize::from(bits)).fold(0, |widened, shift| {
+                widened | ((level << (8 - bits)) >> shift)
+            });
+            let scaled = (level * 255 + levels / 2) / levels;
+            [shifted, replicated, scaled].contains(&u32::from(value))
+        })
+}
+
+fn snap_alpha(pixels: &mut [RGBA]) {
+    for pixel in pixels {
+        if pixel.a <= ALPHA_SNAP_THRESHOLD {
+            pixel.a = 0;
+        } else if pixel.a >= 255 - ALPHA_SNAP_THRESHOLD {
+            pixel.a = 255;
+        }
+    }
+}
+
+/// Undoes alpha dithering: fully transparent and fully opaque pixels are remapped to the
+/// nearest palette entry with the same alpha, so sprite edges don't grow halos.
+fn crisp_alpha_edges(image: &DecodedImage, palette: &mut [RGBA], indices: &mut [u8]) {
+    snap_alpha(palette);
+
+    for (pixel, index) in image.pixels.iter().zip(indices.iter_mut()) {
+        if !matches!(pixel.a, 0 | 255) || palette[usize::from(*index)].a == pixel.a {
+            continue;
+        }
+        let nearest = palette
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.a == pixel.a)
+            .min_by_key(|(_, entry)| color_distance(**entry, *pixel))
+            .map(|(slot, _)| slot);
+        if let Some(slot) = nearest {
+            *index = slot as u8;
+        }
+    }
+}
+
+/// Guarantees locked colors survive verbatim: the palette entry imagequant reserved for
+/// each is snapped to the exact value, and matching pixels are pinned to it so
+/// dithering can't move them.
+fn apply_locked_colors(
+    image: &DecodedImage,
+    locked: &[[u8; 4]],
+    palette: &mut [RGBA],
+    indices: &mut [u8],
+) {
+    for &[r, g, b, a] in locked {
+        let color = RGBA::new(r, g, b, a);
+        let Some(slot) = palette
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| color_distance(**entry, color))
+            .map(|(slot, _)| slot)
+        else {
+            continue;
+        };
+
+        palette[slot] = color;
+        for (pixel, index) in image.pixels.iter().zip(indices.iter_mut()) {
+            if *pixel == color {
+                *index = slot as u8;
+            }
+        }
+    }
+}
+
+fn color_distance(a: RGBA, b: RGBA) -> u32 {
+    [(a.r, b.r), (a.g, b.g), (a.b, b.b), (a.a, b.a)]
+        .iter()
+        .map(|&(x, y)| u32::from(x.abs_diff(y)).pow(2))
+        .sum()
+}
+
+/// Like `color_distance`, but weighs color by opacity so that the RGB of barely visible
+/// pixels doesn't outweigh their alpha.
+fn premultiplied_distance(a: RGBA, b: RGBA) -> u32 {
+    let premultiply = |color: RGBA| {
+        let alpha = u32::from(color.a);
+        [color.r, color.g, color.b].map(|channel| u32::from(channel) * alpha / 255)
+    };
+    let (left, right) = (premultiply(a), premultiply(b));
+    left.iter()
+        .zip(&right)
+        .map(|(x, y)| x.abs_diff(*y).pow(2))
+        .sum::<u32>()
+        + u32::from(a.a.abs_diff(b.a)).pow(2)
 }
 
 fn encode_indexed_png(
     quantized: &QuantizedImage,
     decoded: &DecodedImage,
     preserved: &PreservedChunks,
-    quality: u8,
+    photo: bool,
 ) -> Result<Vec<u8>> {
     if quantized.palette.is_empty() {
         bail!("quantizer returned an empty palette");
@@ -227,7 +1158,7 @@ fn encode_indexed_png(
             encoder.set_trns(alpha_bytes);
         }
         encoder.set_compression(Compression::Best);
-        if is_photo_quality(quality) {
+        if photo {
             encoder.set_filter(FilterType::Paeth);
             encoder.set_adaptive_filter(AdaptiveFilterType::Adaptive);
         } else {
@@ -235,13 +1166,77 @@ fn encode_indexed_png(
             encoder.set_adaptive_filter(AdaptiveFilterType::NonAdaptive);
         }
 
+        let mut writer = encoder.write_header().map_err(TurboPngError::Encode)?;
+        for chunk in &preserved.before_idat {
+            if let Some(data) = adapt_to_palette(chunk, &quantized.palette, preserved.background) {
+                writer
+                    .write_chunk(ChunkType(chunk.name), &data)
+                    .map_err(TurboPngError::Encode)?;
+            }
+        }
+        writer
+            .write_image_data(&quantized.indices)
+            .map_err(TurboPngError::Encode)?;
+        for chunk in &preserved.after_idat {
+            if let Some(data) = adapt_to_palette(chunk, &quantized.palette, preserved.background) {
+                writer
+                    .write_chunk(ChunkType(chunk.name), &data)
+                    .map_err(TurboPngError::Encode)?;
+            }
+        }
+        writer.finish().map_err(TurboPngError::Encode)?;
+    }
+
+    // The encoder can only append chunks after PLTE, so move them where the spec wants them.
+    order_chunks(&output)
+}
+
+/// Rewrites a preserved chunk for the new indexed color type, or drops it when it can't
+/// be expressed there.
+fn adapt_to_palette(
+    chunk: &PngChunk,
+    palette: &[RGBA],
+    background: Option<RGBA>,
+) -> Option<Vec<u8>> {
+    match &chunk.name {
+        // Histogram counts belong to the source palette, which no longer exists.
+        b"hIST" => None,
+        b"iCCP" => color_profile(chunk),
+        // The background becomes the nearest entry of the new palette.
+        b"bKGD" => {
+            let color = background?;
+            palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| color_distance(**entry, color))
+                .map(|(slot, _)| vec![slot as u8])
+        }
+        // Quantized colors rarely keep the source's significant bits; the deflate stage
+        // writes a fresh sBIT when they do.
+        b"sBIT" => None,
+        _ => Some(chunk.data.clone()),
+    }
+}
+
+fn encode_truecolor_png(decoded: &DecodedImage, preserved: &PreservedChunks) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut output, decoded.width, decoded.height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_compression(Compression::Best);
+
         let mut writer = encoder.write_header()?;
         for chunk in &preserved.before_idat {
-            writer.write_chunk(ChunkType(chunk.name), &chunk.data)?;
+            if let Some(data) = adapt_to_truecolor(chunk, preserved.background) {
+                writer.write_chunk(ChunkType(chunk.name), &data)?;
+            }
         }
-        writer.write_image_data(&quantized.indices)?;
+        writer.write_image_data(decoded.pixels.as_bytes())?;
         for chunk in &preserved.after_idat {
-            writer.write_chunk(ChunkType(chunk.name), &chunk.data)?;
+            if let Some(data) = adapt_to_truecolor(chunk, preserved.background) {
+                writer.write_chunk(ChunkType(chunk.name), &data)?;
+            }
         }
         writer.finish()?;
     }
@@ -249,33 +1244,39 @@ fn encode_indexed_png(
     Ok(output)
 }
 
-fn extract_preserved_chunks(data: &[u8], policy: &StripChunks) -> Result<PreservedChunks> {
-    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
-    if data.len() < SIGNATURE.len() || data[..8] != SIGNATURE {
-        bail!("file is not a valid PNG");
+/// Rewrites a preserved chunk for 8-bit RGBA, or drops it when it can't be expressed there.
+fn adapt_to_truecolor(chunk: &PngChunk, background: Option<RGBA>) -> Option<Vec<u8>> {
+    match &chunk.name {
+        b"hIST" => None,
+        b"iCCP" => color_profile(chunk),
+        b"bKGD" => {
+            let color = background?;
+            Some(
+                [color.r, color.g, color.b]
+                    .iter()
+                    .flat_map(|&sample| [0, sample])
+                    .collect(),
+            )
+        }
+        // The rounded channels get a fresh sBIT once the output's color type is settled.
+        b"sBIT" => None,
+        _ => Some(chunk.data.clone()),
     }
+}
+
+/// Passes an `iCCP` payload through byte for byte, unless it holds a grayscale profile,
+/// which the spec only allows on grayscale images and our output is now color.
+fn color_profile(chunk: &PngChunk) -> Option<Vec<u8>> {
+    (chunks::icc_color_space(&chunk.data) != Some(*b"GRAY")).then(|| chunk.data.clone())
+}
 
-    let mut index = SIGNATURE.len();
+fn extract_preserved_chunks(data: &[u8], policy: &StripChunks) -> Result<PreservedChunks> {
     let mut before_idat = Vec::new();
     let mut after_idat = Vec::new();
     let mut seen_idat = false;
 
-    while index + 12 <= data.len() {
-        let length = u32::from_be_bytes(data[index..index + 4].try_into().unwrap()) as usize;
-        index += 4;
-        if index + 4 > data.len() {
-            bail!("truncated PNG chunk header");
-        }
-        let mut name = [0u8; 4];
-        name.copy_from_slice(&data[index..index + 4]);
-        index += 4;
-
-        if index + length + 4 > data.len() {
-            bail!("truncated PNG chunk data");
-        }
-        let chunk_data = data[index..index + length].to_vec();
-        index += length + 4; // skip data and CRC
-
+    for chunk in Chunks::new(data)? {
+        let RawChunk { name, data, .. } = chunk?;
         match &name {
             b"IDAT" => {
                 seen_idat = true;
@@ -288,7 +1289,7 @@ fn extract_preserved_chunks(data: &[u8], policy: &StripChunks) -> Result<Preserv
         if strip_policy_allows(policy, &name) {
             let chunk = PngChunk {
                 name,
-                data: chunk_data,
+                data: data.to_vec(),
             };
             if seen_idat {
                 after_idat.push(chunk);
@@ -298,46 +1299,74 @@ fn extract_preserved_chunks(data: &[u8], policy: &StripChunks) -> Result<Preserv
         }
     }
 
+    let background = chunks::background_color(data)?.map(|[r, g, b]| RGBA::new(r, g, b, 255));
     Ok(PreservedChunks {
         before_idat,
         after_idat,
+        background,
     })
 }
 
-fn configure_options(_common: &CommonOptions, opts: &CompressOptions) -> Options {
+/// What the deflate stage recompresses, which decides how much oxipng may change it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum DeflateTarget {
+    /// Our freshly quantized palette, kept exactly as encoded.
+    Indexed,
+    /// The input's own palette, which may still be tidied up losslessly.
+    ReusedPalette,
+    /// The input itself, or its truecolor re-encoding, reduced losslessly.
+    Lossless,
+}
+
+fn configure_options(
+    common: &CommonOptions,
+    opts: &CompressOptions,
+    photo: bool,
+    target: DeflateTarget,
+) -> Options {
     let mut options = Options::max_compression();
     options.fast_evaluation = false;
-    if is_photo_quality(opts.quality) {
-        options.filter = indexset! {
-            RowFilter::None,
-            RowFilter::Sub,
-            RowFilter::Up,
-            RowFilter::Average,
-            RowFilter::Paeth
-        };
-    } else {
-        options.filter = indexset! { RowFilter::None };
-    }
-    options.bit_depth_reduction = false;
-    options.color_type_reduction = false;
-    options.palette_reduction = false;
-    options.grayscale_reduction = false;
-    let iterations = select_zopfli_iterations(opts.quality);
-    options.deflate = Deflaters::Zopfli {
-        iterations: NonZeroU8::new(iterations).expect("iterations > 0"),
-    };
+    if target != DeflateTarget::Lossless {
+        if photo {
+            options.filter = indexset! {
+                RowFilter::None,
+                RowFilter::Sub,
+                RowFilter::Up,
+                RowFilter::Average,
+                RowFilter::Paeth
+            };
+        } else {
+            options.filter = indexset! { RowFilter::None };
+        }
+        let reused = target == DeflateTarget::ReusedPalette;
+        options.bit_depth_reduction = reused;
+        options.color_type_reduction = false;
+        options.palette_reduction = reused;
+        options.grayscale_reduction = false;
+    }
+    options.deflate = strongest_deflater(select_zopfli_iterations(opts.quality));
+    apply_deflater(&mut options, common);
+    if common.only_deflate {
+        keep_pixel_format(&mut options);
+    }
     options
 }
 
-fn select_quality_window(quality: u8) -> (u8, u8) {
+fn select_crush_bits(quality: u8) -> u8 {
     match quality {
-        98..=100 => (85, 99),
-        95..=97 => (80, 96),
-        85..=94 => (70, 92),
-        70..=84 => (60, 88),
-        55..=69 => (45, 82),
-        40..=54 => (35, 76),
-        _ => (25, 68),
+        95..=100 => 6,
+        80..=94 => 5,
+        50..=79 => 4,
+        _ => 3,
+    }
+}
+
+/// The palette cap for `quality`, or a full palette under `--max-colors-256`.
+fn palette_cap(options: &CompressOptions, quality: u8) -> u32 {
+    if options.full_palette {
+        FULL_PALETTE_CAP
+    } else {
+        select_palette_cap(quality)
     }
 }
 
@@ -0,0 +1,96 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use crate::cli::{AppConfig, Mode, OutputFormat, ProgressKind};
+use crate::error::{Context, Result, bail};
+use crate::optimizer;
+use crate::ui::{FileOutcome, ProgressDispatcher};
+
+/// Names the payload in errors.
+const STDIN_LABEL: &str = "<stdin>.png";
+
+/// `--base64`: processes one base64 PNG read from stdin and prints the result, as a
+/// `data:` URL when the input was one and as bare base64 otherwise.
+pub fn run(config: &AppConfig) -> Result<()> {
+    let mut payload = String::new();
+    io::stdin()
+        .read_to_string(&mut payload)
+        .context("reading base64 from stdin")?;
+    let (data_url, input) = decode_payload(&payload)?;
+
+    // Stdout carries the result, so there is no per-file progress output.
+    let progress = ProgressDispatcher::new(ProgressKind::Quiet, 1);
+    let label = Path::new(STDIN_LABEL);
+    let (outcome, output) = match config.mode {
+        Mode::Optimize => {
+            optimizer::process_in_memory(label, input, &config.optimize, &config.common, &progress)
+        }
+        Mode::Compress => compress(config, label, input, &progress),
+    }
+    .context("processing the PNG from stdin")?;
+
+    let encoded = STANDARD.encode(&output);
+    let mut stdout = io::stdout().lock();
+    if data_url {
+        write!(stdout, "data:{};base64,", media_type(&outcome))?;
+    }
+    writeln!(stdout, "{encoded}").context("writing base64 to stdout")?;
+    Ok(())
+}
+
+/// Decodes a bare base64 payload or a base64 `data:` URL, ignoring whitespace, and
+/// reports whether it was a URL.
+fn decode_payload(payload: &str) -> Result<(bool, Vec<u8>)> {
+    let payload = payload.trim();
+    let (data_url, base64) = match payload.strip_prefix("data:") {
+        Some(url) => {
+            let Some((media_type, data)) = url.split_once(',') else {
+                bail!("data URL on stdin has no `,` before its data");
+            };
+            if !media_type.ends_with(";base64") {
+                bail!("data URL on stdin is not base64-encoded");
+            }
+            (true, data)
+        }
+        None => (false, payload),
+    };
+    let compact: String = base64.split_whitespace().collect();
+    if compact.is_empty() {
+        bail!("no base64 data on stdin");
+    }
+    let bytes = STANDARD
+        .decode(compact)
+        .context("decoding base64 from stdin")?;
+    Ok((data_url, bytes))
+}
+
+/// The media type of the chosen output format.
+fn media_type(outcome: &FileOutcome) -> &'static str {
+    match outcome.output_path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext == OutputFormat::Webp.extension() => "image/webp",
+        _ => "image/png",
+    }
+}
+
+#[cfg(feature = "compress")]
+fn compress(
+    config: &AppConfig,
+    label: &Path,
+    input: Vec<u8>,
+    progress: &ProgressDispatcher,
+) -> Result<(FileOutcome, Vec<u8>)> {
+    crate::compressor::process_in_memory(label, input, &config.compress, &config.common, progress)
+}
+
+#[cfg(not(feature = "compress"))]
+fn compress(
+    _config: &AppConfig,
+    _label: &Path,
+    _input: Vec<u8>,
+    _progress: &ProgressDispatcher,
+) -> Result<(FileOutcome, Vec<u8>)> {
+    unreachable!("Cli::build rejects compress mode without the `compress` feature")
+}
@@ -0,0 +1,344 @@
+use std::io::Cursor;
+
+use png::{BitDepth, ColorType};
+use rgb::{ComponentBytes, RGBA8 as RGBA};
+
+use crate::error::{Context, Result, TurboPngError, bail};
+
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<RGBA>,
+    /// Encoding gamma declared by `gAMA`/`sRGB`, or `0.0` for imagequant's sRGB default.
+    #[cfg(feature = "compress")]
+    pub gamma: f64,
+}
+
+/// How 16-bit samples are brought down to 8 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WideSamples {
+    /// Keep the high byte, as `png`'s `STRIP_16` does.
+    Truncate,
+    /// Round with Floyd–Steinberg error diffusion, so smooth 16-bit gradients don't band.
+    #[cfg(feature = "compress")]
+    Dither,
+}
+
+/// Decoded buffers hold at most 8 bytes per pixel (16-bit RGBA).
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+pub fn decode_rgba(bytes: &[u8], max_pixels: u64) -> Result<DecodedImage> {
+    decode_rgba_into(bytes, max_pixels, Vec::new(), WideSamples::Truncate)
+}
+
+/// Decodes straight into `pixels`' allocation, reusing its capacity, and widens RGB and
+/// grayscale samples to RGBA in place rather than through a second buffer.
+pub fn decode_rgba_into(
+    bytes: &[u8],
+    max_pixels: u64,
+    mut pixels: Vec<RGBA>,
+    wide: WideSamples,
+) -> Result<DecodedImage> {
+    let cursor = Cursor::new(bytes);
+    let limits = png::Limits {
+        bytes: usize::try_from(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL))
+            .unwrap_or(usize::MAX),
+    };
+    let mut decoder = png::Decoder::new_with_limits(cursor, limits);
+    decoder.set_transformations(match wide {
+        WideSamples::Truncate => png::Transformations::EXPAND | png::Transformations::STRIP_16,
+        #[cfg(feature = "compress")]
+        WideSamples::Dither => png::Transformations::EXPAND,
+    });
+    let mut reader = decoder
+        .read_info()
+        .map_err(TurboPngError::Decode)
+        .context("reading PNG info")?;
+    #[cfg(feature = "compress")]
+    let gamma = source_gamma(reader.info());
+
+    let pixel_count = reader.info().width as usize * reader.info().height as usize;
+    pixels.clear();
+    pixels.resize(pixel_count, RGBA::default());
+    if reader.output_color_type().1 == BitDepth::Sixteen {
+        let mut samples = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut samples)
+            .map_err(TurboPngError::Decode)
+            .context("reading PNG image data")?;
+        dither_wide_samples(&samples, info.line_size, info.color_type, &mut pixels)?;
+        return Ok(DecodedImage {
+            width: info.width,
+            height: info.height,
+            pixels,
+            #[cfg(feature = "compress")]
+            gamma,
+        });
+    }
+
+    if reader.output_buffer_size() > pixel_count * 4 {
+        bail!("expected 8-bit output after decoding");
+    }
+    let buffer = pixels.as_bytes_mut();
+    let info = reader
+        .next_frame(buffer)
+        .map_err(TurboPngError::Decode)
+        .context("reading PNG image data")?;
+
+    if info.bit_depth != BitDepth::Eight {
+        bail!("expected 8-bit output after decoding");
+    }
+
+    // Narrower pixels were decoded into the front of the buffer; widen them in place.
+    match info.color_type {
+        ColorType::Rgba => {}
+        ColorType::Rgb => widen_in_place(buffer, pixel_count, |&[r, g, b]| [r, g, b, 255]),
+        ColorType::GrayscaleAlpha => widen_in_place(buffer, pixel_count, |&[gray, alpha]| {
+            [gray, gray, gray, alpha]
+        }),
+        ColorType::Grayscale => {
+            widen_in_place(buffer, pixel_count, |&[gray]| [gray, gray, gray, 255])
+        }
+        other => {
+            bail!("unsupported color type after decoding: {:?}", other);
+        }
+    }
+
+    Ok(DecodedImage {
+        width: info.width,
+        height: info.height,
+        pixels,
+        #[cfg(feature = "compress")]
+        gamma,
+    })
+}
+
+/// An indexed image as stored: its palette and one index per pixel.
+pub struct IndexedImage {
+    /// Palette entries with their `tRNS` alpha, which defaults to opaque.
+    pub palette: Vec<RGBA>,
+    pub indices: Vec<u8>,
+}
+
+/// Decodes the palette and per-pixel indices of an indexed PNG, unpacking 1, 2 and 4-bit
+/// indices to a byte each. Returns `None` for any other color type.
+pub fn decode_indexed(bytes: &[u8], max_pixels: u64) -> Result<Option<IndexedImage>> {
+    let limits = png::Limits {
+        bytes: usize::try_from(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL))
+            .unwrap_or(usize::MAX),
+    };
+    let mut decoder = png::Decoder::new_with_limits(Cursor::new(bytes), limits);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder
+        .read_info()
+        .map_err(TurboPngError::Decode)
+        .context("reading PNG info")?;
+    let info = reader.info();
+    if info.color_type != ColorType::Indexed {
+        return Ok(None);
+    }
+    let Some(rgb) = info.palette.as_deref() else {
+        bail!("indexed PNG has no palette");
+    };
+    let alpha = info.trns.as_deref().unwrap_or_default();
+    let palette = rgb
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(slot, entry)| {
+            RGBA::new(
+                entry[0],
+                entry[1],
+                entry[2],
+                alpha.get(slot).copied().unwrap_or(255),
+            )
+        })
+        .collect();
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut buffer)
+        .map_err(TurboPngError::Decode)
+        .context("reading PNG image data")?;
+    let bits = frame.bit_depth as usize;
+    let width = frame.width as usize;
+    let mask = ((1u16 << bits) - 1) as u8;
+    let mut indices = Vec::with_capacity(width * frame.height as usize);
+    for row in buffer.chunks_exact(frame.line_size) {
+        indices.extend((0..width).map(|x| {
+            let bit = x * bits;
+            row[bit / 8] >> (8 - bits - bit % 8) & mask
+        }));
+    }
+
+    Ok(Some(IndexedImage { palette, indices }))
+}
+
+/// Reduces rows of big-endian 16-bit samples to 8-bit RGBA `pixels`, diffusing each
+/// color channel's rounding error Floyd–Steinberg style. Alpha is rounded, not dithered,
+/// so edges don't pick up speckles.
+fn dither_wide_samples(
+    samples: &[u8],
+    line_size: usize,
+    color_type: ColorType,
+    pixels: &mut [RGBA],
+) -> Result<()> {
+    let (channels, color_channels) = match color_type {
+        ColorType::Rgba => (4, 3),
+        ColorType::Rgb => (3, 3),
+        ColorType::GrayscaleAlpha => (2, 1),
+        ColorType::Grayscale => (1, 1),
+        other => bail!("unsupported color type after decoding: {:?}", other),
+    };
+    let width = line_size / (channels * 2);
+    let round = |value: i32| ((value + 128) / 257).clamp(0, 255);
+
+    // Error carried into the current and next rows, with a spare pixel on either side.
+    let mut current = vec![0i32; (width + 2) * color_channels];
+    let mut next = current.clone();
+    for (row, out) in samples
+        .chunks_exact(line_size)
+        .zip(pixels.chunks_mut(width))
+    {
+        for (x, pixel) in out.iter_mut().enumerate() {
+            let mut levels = [0u8; 4];
+            for (channel, level) in levels.iter_mut().enumerate().take(channels) {
+                let offset = (x * channels + channel) * 2;
+                let value = i32::from(u16::from_be_bytes([row[offset], row[offset + 1]]));
+                if channel >= color_channels {
+                    *level = round(value) as u8;
+                    continue;
+                }
+
+                let slot = (x + 1) * color_channels + channel;
+                let wanted = (value + current[slot]).clamp(0, 65535);
+                let rounded = round(wanted);
+                let error = wanted - rounded * 257;
+                current[slot + color_channels] += error * 7 / 16;
+                next[slot - color_channels] += error * 3 / 16;
+                next[slot] += error * 5 / 16;
+                next[slot + color_channels] += error / 16;
+                *level = rounded as u8;
+            }
+
+            *pixel = match color_type {
+                ColorType::Rgba => RGBA::new(levels[0], levels[1], levels[2], levels[3]),
+                ColorType::Rgb => RGBA::new(levels[0], levels[1], levels[2], 255),
+                ColorType::GrayscaleAlpha => RGBA::new(levels[0], levels[0], levels[0], levels[1]),
+                _ => RGBA::new(levels[0], levels[0], levels[0], 255),
+            };
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill(0);
+    }
+
+    Ok(())
+}
+
+/// Pixels widened per block by `widen_in_place`.
+const WIDEN_BLOCK: usize = 256;
+
+/// Expands `pixel_count` packed `N`-byte pixels at the front of `buffer` to RGBA in place.
+///
+/// Blocks are processed back to front, and each block's samples are copied out before its
+/// RGBA output overwrites them, so the per-pixel loop runs over non-overlapping fixed-size
+/// chunks the compiler can vectorize.
+fn widen_in_place<const N: usize>(
+    buffer: &mut [u8],
+    pixel_count: usize,
+    widen: impl Fn(&[u8; N]) -> [u8; 4],
+) {
+    let mut scratch = [0u8; WIDEN_BLOCK * 4];
+    let mut end = pixel_count;
+    while end > 0 {
+        let start = end.saturating_sub(WIDEN_BLOCK);
+        let samples = &mut scratch[..(end - start) * N];
+        samples.copy_from_slice(&buffer[start * N..end * N]);
+        for (sample, pixel) in samples
+            .chunks_exact(N)
+            .zip(buffer[start * 4..end * 4].chunks_exact_mut(4))
+        {
+            pixel.copy_from_slice(&widen(sample.try_into().expect("chunk of N bytes")));
+        }
+        end = start;
+    }
+}
+
+#[cfg(feature = "compress")]
+fn source_gamma(info: &png::Info<'_>) -> f64 {
+    // `source_gamma` is populated from `gAMA`, or from `sRGB` when that chunk is present.
+    match info.source_gamma.map(|gamma| f64::from(gamma.into_value())) {
+        Some(gamma) if gamma > 0.0 && gamma < 1.0 => gamma,
+        _ => 0.0,
+    }
+}
+
+/// Whether two PNGs show the same pixels in every frame, compared at 16 bits per channel
+/// so that neither bit depth nor color type changes count as differences.
+pub fn same_pixels(a: &[u8], b: &[u8], max_pixels: u64) -> Result<bool> {
+    Ok(decode_frames_wide(a, max_pixels)? == decode_frames_wide(b, max_pixels)?)
+}
+
+/// A stored frame widened to 16-bit RGBA.
+#[derive(PartialEq)]
+struct WideFrame {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u16; 4]>,
+}
+
+/// Every stored frame, the default image included.
+fn decode_frames_wide(bytes: &[u8], max_pixels: u64) -> Result<Vec<WideFrame>> {
+    let limits = png::Limits {
+        bytes: usize::try_from(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL))
+            .unwrap_or(usize::MAX),
+    };
+    let mut decoder = png::Decoder::new_with_limits(Cursor::new(bytes), limits);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .map_err(TurboPngError::Decode)
+        .context("reading PNG info")?;
+    let info = reader.info();
+    // Without an fcTL before IDAT the default image is stored on top of the frames.
+    let frame_count = info
+        .animation_control
+        .map_or(1, |animation| animation.num_frames as usize)
+        + usize::from(info.animation_control.is_some() && info.frame_control.is_none());
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let mut frames = Vec::with_capacity(frame_count);
+    for _ in 0..frame_count {
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(TurboPngError::Decode)
+            .context("reading PNG image data")?;
+        let wide = info.bit_depth == BitDepth::Sixteen;
+        let channels = info.color_type.samples();
+        let sample = |row: &[u8], index: usize| {
+            if wide {
+                u16::from_be_bytes([row[index * 2], row[index * 2 + 1]])
+            } else {
+                u16::from(row[index]) * 257
+            }
+        };
+        let mut pixels = Vec::with_capacity(info.width as usize * info.height as usize);
+        for row in buffer.chunks(info.line_size).take(info.height as usize) {
+            for x in 0..info.width as usize {
+                let at = |channel: usize| sample(row, x * channels + channel);
+                pixels.push(match info.color_type {
+                    ColorType::Grayscale => [at(0), at(0), at(0), u16::MAX],
+                    ColorType::GrayscaleAlpha => [at(0), at(0), at(0), at(1)],
+                    ColorType::Rgb => [at(0), at(1), at(2), u16::MAX],
+                    ColorType::Rgba => [at(0), at(1), at(2), at(3)],
+                    other => bail!("unsupported color type after decoding: {:?}", other),
+                });
+            }
+        }
+        frames.push(WideFrame {
+            width: info.width,
+            height: info.height,
+            pixels,
+        });
+    }
+    Ok(frames)
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use crate::error::{Context, PathContext, Result, TurboPngError};
+use crate::pipeline::ProcessedFile;
+
+/// What `--link-duplicates` collapsed.
+#[derive(Debug, Default)]
+pub struct Collapsed {
+    /// Outputs replaced by a hard link to an identical one.
+    pub links: usize,
+    /// Disk space those outputs no longer take up.
+    pub bytes: u64,
+}
+
+/// Replaces every output whose bytes match an earlier one (by path) with a hard link to
+/// it. Outputs on another filesystem than their twin are left alone.
+pub fn link_duplicates(files: &[ProcessedFile]) -> Result<Collapsed> {
+    let mut groups: BTreeMap<(&str, u64), Vec<&Path>> = BTreeMap::new();
+    for file in files {
+        let outcome = &file.outcome;
+        groups
+            .entry((outcome.output_sha256.as_str(), outcome.output_size))
+            .or_default()
+            .push(&outcome.output_path);
+    }
+
+    let mut collapsed = Collapsed::default();
+    let mut failures = Vec::new();
+    for ((_, size), mut paths) in groups {
+        if paths.len() < 2 {
+            continue;
+        }
+        paths.sort();
+        let (original, duplicates) = paths.split_first().expect("groups are never empty");
+        for duplicate in duplicates {
+            match link_duplicate(original, duplicate) {
+                Ok(true) => {
+                    collapsed.links += 1;
+                    collapsed.bytes += size;
+                }
+                Ok(false) => {}
+                Err(err) => failures.push(format!("{}: {err:#}", duplicate.display())),
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(collapsed)
+    } else {
+        Err(TurboPngError::Batch {
+            activity: "linking duplicates",
+            failures,
+        })
+    }
+}
+
+/// Links `duplicate` to `original` once their contents are confirmed equal, returning
+/// whether it was replaced. The link is made under a temporary name and renamed over
+/// `duplicate`, so it is never missing.
+fn link_duplicate(original: &Path, duplicate: &Path) -> Result<bool> {
+    let contents = fs::read(original)
+        .path_context(original)
+        .context("reading original")?;
+    if fs::read(duplicate).context("reading output")? != contents {
+        return Ok(false);
+    }
+
+    let name = duplicate.file_name().context("output has no file name")?;
+    let temp = duplicate.with_file_name(format!(
+        ".{}.{}.turbo-png-link",
+        name.to_string_lossy(),
+        process::id()
+    ));
+    match fs::hard_link(original, &temp) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| format!("linking to {}", original.display()));
+        }
+    }
+    if let Err(err) = fs::rename(&temp, duplicate) {
+        let _ = fs::remove_file(&temp);
+        return Err(err).context("replacing output with the link");
+    }
+    // Renaming onto another link to the same file does nothing, so it was linked already.
+    if fs::symlink_metadata(&temp).is_ok() {
+        let _ = fs::remove_file(&temp);
+        return Ok(false);
+    }
+    Ok(true)
+}
@@ -0,0 +1,223 @@
+use std::env;
+use std::io::IsTerminal;
+use std::thread;
+
+use clap::{Parser, ValueEnum};
+
+use crate::cli::{AppConfig, Cli, ProgressKind};
+use crate::error::Result;
+
+/// Prints what affects speed and behavior on this machine: the build, CPU features,
+/// threads, the temp directory, the terminal and the options a bare run uses.
+pub fn run() -> Result<()> {
+    print_build();
+    print_cpu();
+    print_threads();
+    print_temp_dir();
+    print_terminal();
+    print_defaults()?;
+    Ok(())
+}
+
+fn print_build() {
+    let features: Vec<&str> = [
+        ("compress", cfg!(feature = "compress")),
+        ("zopfli", cfg!(feature = "zopfli")),
+        ("fancy-ui", cfg!(feature = "fancy-ui")),
+        ("upload", cfg!(feature = "upload")),
+        ("remote", cfg!(feature = "remote")),
+        ("bundle", cfg!(feature = "bundle")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    println!("turbo-png {}", env!("CARGO_PKG_VERSION"));
+    println!("features:      {}", list_or_none(&features));
+    println!(
+        "build:         {}",
+        if cfg!(debug_assertions) {
+            "debug (much slower than release)"
+        } else {
+            "release"
+        }
+    );
+}
+
+fn print_cpu() {
+    println!();
+    println!("arch:          {}", env::consts::ARCH);
+    let features = cpu_features();
+    if features.is_empty() {
+        println!("cpu features:  not probed on this architecture");
+        return;
+    }
+    let detected: Vec<&str> = features
+        .iter()
+        .filter(|feature| feature.detected)
+        .map(|feature| feature.name)
+        .collect();
+    let missing: Vec<&str> = features
+        .iter()
+        .filter(|feature| !feature.detected)
+        .map(|feature| feature.name)
+        .collect();
+    let compiled: Vec<&str> = features
+        .iter()
+        .filter(|feature| feature.compiled)
+        .map(|feature| feature.name)
+        .collect();
+    println!("cpu features:  {}", list_or_none(&detected));
+    println!("missing:       {}", list_or_none(&missing));
+    // libdeflate picks its SIMD paths at runtime; code compiled by rustc, including
+    // imagequant's, only uses what the build targeted.
+    println!("compiled in:   {}", list_or_none(&compiled));
+}
+
+/// A SIMD extension the deflate and quantization code can use.
+struct CpuFeature {
+    name: &'static str,
+    /// Whether this CPU supports it.
+    detected: bool,
+    /// Whether the build targets it, so rustc may use it without runtime checks.
+    compiled: bool,
+}
+
+macro_rules! probe_features {
+    ($detect:ident: $($name:tt),*) => {
+        vec![$(CpuFeature {
+            name: $name,
+            detected: $detect!($name),
+            compiled: cfg!(target_feature = $name),
+        }),*]
+    };
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_features() -> Vec<CpuFeature> {
+    use std::arch::is_x86_feature_detected;
+    probe_features!(is_x86_feature_detected:
+        "sse2", "ssse3", "sse4.1", "pclmulqdq", "avx", "avx2", "bmi2", "avx512f")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn cpu_features() -> Vec<CpuFeature> {
+    use std::arch::is_aarch64_feature_detected;
+    probe_features!(is_aarch64_feature_detected: "neon", "crc", "aes", "sha2")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn cpu_features() -> Vec<CpuFeature> {
+    Vec::new()
+}
+
+fn print_threads() {
+    println!();
+    match thread::available_parallelism() {
+        Ok(cores) => println!("cpus:          {cores}"),
+        Err(err) => println!("cpus:          unknown ({err})"),
+    }
+    let source = match env::var("RAYON_NUM_THREADS") {
+        Ok(value) => format!("RAYON_NUM_THREADS={value}"),
+        Err(_) => "one per cpu; --threads overrides".to_owned(),
+    };
+    println!(
+        "thread pool:   {} threads ({source})",
+        rayon::current_num_threads()
+    );
+}
+
+fn print_temp_dir() {
+    println!();
+    let dir = env::temp_dir();
+    let status = match tempfile::tempfile_in(&dir) {
+        Ok(_) => "writable".to_owned(),
+        Err(err) => format!("NOT writable: {err}"),
+    };
+    println!("temp dir:      {} ({status})", dir.display());
+    println!("staging:       next to each output unless --temp-dir is given");
+}
+
+fn print_terminal() {
+    println!();
+    let describe = |terminal: bool| {
+        if terminal {
+            "terminal"
+        } else {
+            "not a terminal"
+        }
+    };
+    println!(
+        "stdout:        {}",
+        describe(std::io::stdout().is_terminal())
+    );
+    println!(
+        "stderr:        {}",
+        describe(std::io::stderr().is_terminal())
+    );
+    for name in ["TERM", "COLUMNS", "NO_COLOR"] {
+        let value = env::var(name).unwrap_or_else(|_| "(unset)".to_owned());
+        println!("{:<15}{value}", format!("{name}:"));
+    }
+}
+
+fn print_defaults() -> Result<()> {
+    // Exactly what a bare `turbo-png <path>` resolves to.
+    let config: AppConfig = Cli::try_parse_from(["turbo-png", "."])?.build()?;
+    let common = &config.common;
+    let compress = &config.compress;
+
+    println!();
+    println!("mode:          {}", value_name(config.mode));
+    println!(
+        "optimize:      oxipng max compression, {}",
+        if config.optimize.zopfli {
+            "zopfli"
+        } else {
+            "libdeflate"
+        }
+    );
+    println!(
+        "compress:      quality {}, lossy {}, banding check {}",
+        compress.quality,
+        value_name(compress.lossy),
+        on_off(compress.banding_check)
+    );
+    println!(
+        "metadata:      {}",
+        if common.keep_metadata {
+            "kept"
+        } else {
+            "stripped"
+        }
+    );
+    println!("max pixels:    {}", common.max_pixels);
+    println!(
+        "progress:      {}",
+        match common.progress {
+            ProgressKind::Fancy if cfg!(feature = "fancy-ui") => "progress bars",
+            ProgressKind::Fancy => "one line per file",
+            ProgressKind::Quiet => "none",
+        }
+    );
+    Ok(())
+}
+
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .expect("no skipped variants")
+        .get_name()
+        .to_owned()
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+fn list_or_none(items: &[&str]) -> String {
+    if items.is_empty() {
+        "none".to_owned()
+    } else {
+        items.join(" ")
+    }
+}
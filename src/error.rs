@@ -0,0 +1,397 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// `Result` with [`TurboPngError`] as the default error, used throughout the crate; only
+/// `main` converts to `anyhow` for reporting.
+pub type Result<T, E = TurboPngError> = std::result::Result<T, E>;
+
+/// Failures raised by the processing layer, one variant per class of problem.
+///
+/// Code attaches per-file context with [`Context`], which wraps the error in
+/// [`TurboPngError::Context`]; [`TurboPngError::root`] recovers the underlying variant.
+#[derive(Debug)]
+pub enum TurboPngError {
+    /// Reading or writing `path` failed; the path is `None` when `?` converted a bare
+    /// [`io::Error`].
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The input isn't a PNG the decoder can read.
+    Decode(png::DecodingError),
+    /// imagequant couldn't build a palette for the image.
+    #[cfg(feature = "compress")]
+    Quantize(imagequant::Error),
+    /// The PNG encoder rejected the image or one of its chunks.
+    Encode(png::EncodingError),
+    /// oxipng failed to recompress the image.
+    Deflate(oxipng::PngError),
+    /// The output file exists and overwriting it wasn't allowed.
+    OutputConflict { path: PathBuf },
+    /// The image has more pixels than the configured limit.
+    Limits {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+    /// Some files of a batch failed; `failures` holds one formatted error per file.
+    Batch {
+        activity: &'static str,
+        failures: Vec<String>,
+    },
+    /// A problem described only by its message, raised with [`bail!`] or [`format_err!`].
+    Message(String),
+    /// An error from a dependency without a variant of its own.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+    /// `source`, with what was being done when it happened.
+    Context {
+        context: String,
+        source: Box<TurboPngError>,
+    },
+}
+
+impl TurboPngError {
+    /// Wraps the error with what was being done when it happened.
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        Self::Context {
+            context: context.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Takes `err` as is when it already is a `TurboPngError`, as [`TurboPngError::Io`]
+    /// when it is an [`io::Error`], or as [`TurboPngError::Other`].
+    fn wrap(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        let err: Box<dyn std::error::Error + Send + Sync> = Box::new(err);
+        match err.downcast::<Self>() {
+            Ok(err) => *err,
+            Err(other) => match other.downcast::<io::Error>() {
+                Ok(err) => Self::from(*err),
+                Err(other) => Self::Other(other),
+            },
+        }
+    }
+
+    /// The error underneath any [`TurboPngError::Context`] wrapping.
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::Context { source, .. } => source.root(),
+            other => other,
+        }
+    }
+
+    fn fmt_message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {source}", path.display()),
+            Self::Io { path: None, source } => write!(f, "{source}"),
+            Self::Decode(err) => write!(f, "{err}"),
+            #[cfg(feature = "compress")]
+            Self::Quantize(err) => write!(f, "{err}"),
+            Self::Encode(err) => write!(f, "{err}"),
+            Self::Deflate(err) => write!(f, "{err}"),
+            Self::OutputConflict { path } => write!(
+                f,
+                "output file {} already exists (use --overwrite to replace)",
+                path.display()
+            ),
+            Self::Limits {
+                width,
+                height,
+                max_pixels,
+            } => write!(
+                f,
+                "image is {width}x{height} ({} pixels), which exceeds --max-pixels {max_pixels}",
+                u64::from(*width) * u64::from(*height)
+            ),
+            Self::Batch { activity, failures } => {
+                writeln!(f, "one or more files failed during {activity}:")?;
+                for failure in failures {
+                    writeln!(f, " • {failure}")?;
+                }
+                Ok(())
+            }
+            Self::Message(message) => f.write_str(message),
+            Self::Other(err) => write!(f, "{err}"),
+            Self::Context { context, .. } => f.write_str(context),
+        }
+    }
+}
+
+/// `{err:#}` appends the causes, like `anyhow`: `reading input PNG: file.png: not found`.
+impl fmt::Display for TurboPngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_message(f)?;
+        if f.alternate() {
+            let mut cause = std::error::Error::source(self);
+            while let Some(err) = cause {
+                write!(f, ": {err}")?;
+                cause = err.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+// Wrapped errors are displayed verbatim, so report their sources rather than the errors
+// themselves to keep anyhow's cause chain from printing the same message twice.
+impl std::error::Error for TurboPngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => source.source(),
+            Self::Decode(err) => err.source(),
+            #[cfg(feature = "compress")]
+            Self::Quantize(err) => err.source(),
+            Self::Encode(err) => err.source(),
+            Self::Deflate(err) => err.source(),
+            Self::Other(err) => err.source(),
+            Self::Context { source, .. } => Some(source.as_ref()),
+            Self::OutputConflict { .. }
+            | Self::Limits { .. }
+            | Self::Batch { .. }
+            | Self::Message(_) => None,
+        }
+    }
+}
+
+impl From<png::DecodingError> for TurboPngError {
+    fn from(err: png::DecodingError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+#[cfg(feature = "compress")]
+impl From<imagequant::Error> for TurboPngError {
+    fn from(err: imagequant::Error) -> Self {
+        Self::Quantize(err)
+    }
+}
+
+impl From<png::EncodingError> for TurboPngError {
+    fn from(err: png::EncodingError) -> Self {
+        Self::Encode(err)
+    }
+}
+
+impl From<oxipng::PngError> for TurboPngError {
+    fn from(err: oxipng::PngError) -> Self {
+        Self::Deflate(err)
+    }
+}
+
+impl From<io::Error> for TurboPngError {
+    fn from(source: io::Error) -> Self {
+        Self::Io { path: None, source }
+    }
+}
+
+impl From<String> for TurboPngError {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+/// Dependency errors that `?` turns into [`TurboPngError::Other`].
+macro_rules! other_errors {
+    ($($error:ty),* $(,)?) => {
+        $(impl From<$error> for TurboPngError {
+            fn from(err: $error) -> Self {
+                Self::Other(Box::new(err))
+            }
+        })*
+    };
+}
+
+other_errors!(
+    std::num::TryFromIntError,
+    clap::Error,
+    image_webp::EncodingError,
+    libdeflater::CompressionError,
+);
+#[cfg(feature = "bundle")]
+other_errors!(zip::result::ZipError);
+
+/// Attaches context to failures, as `anyhow::Context` does: on `Result`s of any error
+/// type, and on `Option`s, where `None` becomes the message.
+pub trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T, E> Context<T> for std::result::Result<T, E>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|err| TurboPngError::wrap(err).context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|err| TurboPngError::wrap(err).context(context()))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.ok_or_else(|| TurboPngError::Message(context.to_string()))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.ok_or_else(|| TurboPngError::Message(context().to_string()))
+    }
+}
+
+/// Records which file an I/O operation failed on, as a [`TurboPngError::Io`] that
+/// [`Context`] can then say more about.
+pub trait PathContext<T> {
+    fn path_context(self, path: &Path) -> Result<T>;
+}
+
+impl<T> PathContext<T> for io::Result<T> {
+    fn path_context(self, path: &Path) -> Result<T> {
+        self.map_err(|source| TurboPngError::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        })
+    }
+}
+
+/// Builds a [`TurboPngError::Message`] from a format string, or converts an error or
+/// `String` into a [`TurboPngError`].
+macro_rules! format_err {
+    ($message:literal $(,)?) => {
+        $crate::error::TurboPngError::Message(format!($message))
+    };
+    ($format:literal, $($arg:tt)*) => {
+        $crate::error::TurboPngError::Message(format!($format, $($arg)*))
+    };
+    ($err:expr $(,)?) => {
+        $crate::error::TurboPngError::from($err)
+    };
+}
+pub(crate) use format_err;
+
+/// Returns early with [`format_err!`]'s error.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::format_err!($($arg)*))
+    };
+}
+pub(crate) use bail;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_rgba;
+    use crate::pipeline::{RunSummary, ensure_output_available, ensure_within_pixel_limit};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&vec![0; (width * height) as usize])
+            .unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    #[test]
+    fn undecodable_inputs_are_decode_errors_under_their_context() {
+        let Err(err) = decode_rgba(b"\x89PNG\r\n\x1a\nnot really", 100) else {
+            panic!("decoded a broken PNG");
+        };
+        assert!(matches!(err, TurboPngError::Context { .. }), "{err:?}");
+        assert!(matches!(err.root(), TurboPngError::Decode(_)), "{err:?}");
+        assert!(
+            format!("{err:#}").starts_with("reading PNG info: "),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn oversized_images_are_limit_errors() {
+        let err = ensure_within_pixel_limit(&png(8, 4), 16).unwrap_err();
+        assert!(matches!(
+            err,
+            TurboPngError::Limits {
+                width: 8,
+                height: 4,
+                max_pixels: 16
+            }
+        ));
+        ensure_within_pixel_limit(&png(4, 4), 16).unwrap();
+    }
+
+    #[test]
+    fn existing_outputs_are_conflicts_unless_overwriting() {
+        let existing = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let err = ensure_output_available(&existing, false).unwrap_err();
+        assert!(matches!(&err, TurboPngError::OutputConflict { path } if *path == existing));
+        ensure_output_available(&existing, true).unwrap();
+    }
+
+    #[test]
+    fn io_errors_keep_the_path_they_happened_at() {
+        let path = Path::new("missing.png");
+        let err = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .path_context(path)
+            .context("reading input")
+            .unwrap_err();
+        assert!(
+            matches!(err.root(), TurboPngError::Io { path: Some(at), .. } if at == path),
+            "{err:?}"
+        );
+        assert_eq!(
+            format!("{err:#}"),
+            "reading input: missing.png: entity not found"
+        );
+
+        let err = TurboPngError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(
+            matches!(err, TurboPngError::Io { path: None, .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn failed_runs_are_batch_errors_listing_each_failure() {
+        let summary = RunSummary {
+            activity: "optimization",
+            processed: Vec::new(),
+            failed: vec![(
+                "a.png".into(),
+                Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+                    .context("reading a.png")
+                    .unwrap_err(),
+            )],
+            out_of_time: Vec::new(),
+        };
+        let err = summary.into_result().unwrap_err();
+        let TurboPngError::Batch { activity, failures } = &err else {
+            panic!("expected a batch error, got {err:?}");
+        };
+        assert_eq!(*activity, "optimization");
+        assert_eq!(failures, &["reading a.png: entity not found"]);
+    }
+
+    #[test]
+    fn context_keeps_the_underlying_variant() {
+        let err = Err::<(), _>(TurboPngError::OutputConflict {
+            path: PathBuf::from("out.png"),
+        })
+        .context("writing out.png")
+        .context("processing in.png")
+        .unwrap_err();
+        assert_eq!(err.to_string(), "processing in.png");
+        assert!(matches!(err.root(), TurboPngError::OutputConflict { .. }));
+
+        let err = None::<()>.context("missing").unwrap_err();
+        assert!(matches!(err, TurboPngError::Message(ref message) if message == "missing"));
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image_webp::{ColorType as WebpColorType, WebPEncoder};
+use png::{ColorType, Transformations};
+
+use crate::chunks;
+use crate::cli::OutputFormat;
+use crate::error::{Context, Result, bail};
+
+/// Encoded result for the format that produced the fewest bytes.
+pub struct ChosenOutput {
+    pub format: OutputFormat,
+    pub bytes: Vec<u8>,
+    /// Why a requested format wasn't encoded at all.
+    pub passed_over: Option<String>,
+}
+
+/// Re-encodes the processed PNG into every requested format and keeps the smallest.
+///
+/// Ties go to PNG, which keeps any preserved metadata chunks. WebP only holds 8 bits per
+/// sample, so 16-bit images stay PNG rather than being reduced.
+pub fn choose_smallest(png_bytes: Vec<u8>, formats: &[OutputFormat]) -> Result<ChosenOutput> {
+    let wide = chunks::pixel_format(&png_bytes)?.1 == 16;
+    let mut passed_over = None;
+    let webp = if !formats.contains(&OutputFormat::Webp) {
+        None
+    } else if wide {
+        if !formats.contains(&OutputFormat::Png) {
+            bail!("lossless WebP can't hold 16-bit samples; add png to --emit-smallest");
+        }
+        passed_over = Some(String::from("webp skipped, 16-bit samples"));
+        None
+    } else {
+        Some(encode_lossless_webp(&png_bytes).context("encoding lossless WebP")?)
+    };
+    let png = formats.contains(&OutputFormat::Png).then_some(png_bytes);
+
+    let (format, bytes) = match (png, webp) {
+        (Some(png), Some(webp)) if webp.len() < png.len() => (OutputFormat::Webp, webp),
+        (Some(png), _) => (OutputFormat::Png, png),
+        (None, Some(webp)) => (OutputFormat::Webp, webp),
+        (None, None) => bail!("no output formats requested"),
+    };
+
+    Ok(ChosenOutput {
+        format,
+        bytes,
+        passed_over,
+    })
+}
+
+/// Swaps the extension of a derived PNG output path to match `format`; `.apng` outputs
+/// keep theirs when they stay PNG.
+pub fn output_path_for(png_output: &Path, format: OutputFormat) -> PathBuf {
+    if format == OutputFormat::Png && has_apng_extension(png_output) {
+        return png_output.to_path_buf();
+    }
+    png_output.with_extension(format.extension())
+}
+
+/// Whether `path` ends in `.apng`, in any case.
+pub fn has_apng_extension(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("apng"))
+}
+
+fn encode_lossless_webp(png_bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = png::Decoder::new(Cursor::new(png_bytes));
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info().context("reading PNG info")?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buffer)
+        .context("reading PNG image data")?;
+    buffer.truncate(info.buffer_size());
+
+    let color = match info.color_type {
+        ColorType::Grayscale => WebpColorType::L8,
+        ColorType::GrayscaleAlpha => WebpColorType::La8,
+        ColorType::Rgb => WebpColorType::Rgb8,
+        ColorType::Rgba => WebpColorType::Rgba8,
+        other => bail!("unsupported color type after decoding: {:?}", other),
+    };
+
+    let mut output = Vec::new();
+    WebPEncoder::new(&mut output).encode(&buffer, info.width, info.height, color)?;
+    Ok(output)
+}
@@ -0,0 +1,42 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::cli::ChangeFilter;
+use crate::error::{Context, Result, bail};
+use crate::inputs::canonical;
+
+/// Collects the canonical paths of files git reports as changed.
+pub fn changed_files(filter: &ChangeFilter) -> Result<HashSet<PathBuf>> {
+    let root = PathBuf::from(run_git(&["rev-parse", "--show-toplevel"])?.trim());
+
+    let mut args = vec!["diff", "--name-only", "--diff-filter=d", "-z"];
+    match filter {
+        ChangeFilter::Since(reference) => args.push(reference),
+        ChangeFilter::Staged => args.push("--cached"),
+    }
+    let output = run_git(&args)?;
+
+    Ok(output
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| canonical(&root.join(name)))
+        .collect())
+}
+
+fn run_git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("running git (is it installed?)")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).context("git produced non UTF-8 output")
+}
@@ -0,0 +1,540 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use ignore::WalkBuilder;
+
+use crate::cli::{AppConfig, CollisionPolicy, FileOrder};
+use crate::error::{Context, PathContext, Result, bail};
+use crate::git;
+use crate::pipeline::{self, OutputPlan, ProcessedFile};
+use crate::ui::SkipReason;
+
+#[derive(Debug, Default)]
+pub struct ResolvedInputs {
+    pub files: Vec<PathBuf>,
+    /// Symlinked inputs whose target is already part of `files`.
+    pub links: Vec<LinkedInput>,
+}
+
+#[derive(Debug)]
+pub struct LinkedInput {
+    pub link: PathBuf,
+    pub target: PathBuf,
+}
+
+pub fn resolve_inputs(config: &AppConfig) -> Result<ResolvedInputs> {
+    let mut files = Vec::new();
+    for input in &config.inputs {
+        if input.is_dir() {
+            collect_from_directory(input, config, &mut files)?;
+        } else if is_png(input, &config.extensions) {
+            files.push(input.canonicalize().unwrap_or_else(|_| input.clone()));
+        }
+    }
+
+    // Prefer paths without symlinks in them so links resolve to a processed target.
+    files.sort_by_key(|path| is_linked(path));
+
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut seen_links: HashSet<PathBuf> = HashSet::new();
+    let mut resolved = ResolvedInputs::default();
+    for path in files {
+        let target = canonical(&path);
+        if seen.insert(target.clone()) {
+            resolved.files.push(path);
+        } else if config.recreate_symlinks
+            && is_symlink(&path)
+            && seen_links.insert(link_location(&path))
+        {
+            resolved.links.push(LinkedInput { link: path, target });
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Plans `--output-dir` locations for `files`, applying `--on-collision` to inputs whose
+/// outputs would share a path. Skipped inputs are moved from `files` to `skipped`.
+pub fn plan_outputs(
+    config: &AppConfig,
+    files: &mut Vec<PathBuf>,
+    skipped: &mut Vec<(PathBuf, SkipReason)>,
+) -> Result<OutputPlan> {
+    let mut plan = OutputPlan::default();
+    let Some(layout) = &config.output_dir else {
+        return Ok(plan);
+    };
+    let roots: Vec<&Path> = config
+        .inputs
+        .iter()
+        .filter(|input| input.is_dir())
+        .map(PathBuf::as_path)
+        .collect();
+    let suffix = config.mode.output_suffix();
+
+    // Plan in path order so renames don't depend on directory walk order.
+    let mut sorted = files.clone();
+    sorted.sort();
+    let mut taken: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut colliding = Vec::new();
+    for path in sorted {
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            // Left unplanned; processing reports the missing stem.
+            continue;
+        };
+        let dir = if layout.flatten {
+            layout.dir.clone()
+        } else {
+            layout.dir.join(relative_parent(&path, &roots))
+        };
+        let output_suffix = pipeline::output_suffix(&path, suffix);
+
+        let mut name = stem.to_string();
+        if let Some(first) = taken.get(&dir.join(format!("{name}{output_suffix}"))) {
+            match layout.on_collision {
+                CollisionPolicy::Error => bail!(
+                    "{} and {} would both be written as {} in {}",
+                    first.display(),
+                    path.display(),
+                    stem,
+                    dir.display()
+                ),
+                CollisionPolicy::Skip => {
+                    // Still placed, so reports show the output it lost out on.
+                    colliding.push(path.clone());
+                    plan.place(path, dir, name);
+                    continue;
+                }
+                CollisionPolicy::Rename => {
+                    let mut counter = 2;
+                    while taken.contains_key(&dir.join(format!("{stem}-{counter}{output_suffix}")))
+                    {
+                        counter += 1;
+                    }
+                    name = format!("{stem}-{counter}");
+                }
+            }
+        }
+
+        taken.insert(dir.join(format!("{name}{output_suffix}")), path.clone());
+        plan.place(path, dir, name);
+    }
+
+    files.retain(|path| !colliding.contains(path));
+    skipped.extend(
+        colliding
+            .into_iter()
+            .map(|path| (path, SkipReason::Collision)),
+    );
+    Ok(plan)
+}
+
+/// Applies `--interactive`: asks what to do about each target whose output already
+/// exists, and returns the targets still to process; the rest are added to `skipped`.
+/// Reading stops at end of input, skipping whatever is left undecided.
+pub fn confirm_overwrites(
+    config: &AppConfig,
+    targets: Vec<PathBuf>,
+    outputs: &mut OutputPlan,
+    skipped: &mut Vec<(PathBuf, SkipReason)>,
+) -> Result<Vec<PathBuf>> {
+    let suffix = config.mode.output_suffix();
+    let mut stdin = io::stdin().lock();
+    let mut overwrite_all = false;
+    let mut kept = Vec::with_capacity(targets.len());
+    let mut targets = targets.into_iter();
+    for target in targets.by_ref() {
+        let output = outputs.output_path(&target, suffix)?;
+        if !output.exists() {
+            kept.push(target);
+            continue;
+        }
+        if overwrite_all {
+            outputs.allow_replacing(target.clone());
+            kept.push(target);
+            continue;
+        }
+
+        let answer = loop {
+            eprint!(
+                "{} exists. [o]verwrite, [s]kip, [r]ename, overwrite [a]ll? ",
+                output.display()
+            );
+            io::stderr().flush()?;
+            let mut line = String::new();
+            if stdin.read_line(&mut line).context("reading answer")? == 0 {
+                eprintln!();
+                break None;
+            }
+            match line.trim().to_ascii_lowercase().as_str() {
+                "o" | "overwrite" => break Some(OverwriteAnswer::Overwrite),
+                "s" | "skip" => break Some(OverwriteAnswer::Skip),
+                "r" | "rename" => break Some(OverwriteAnswer::Rename),
+                "a" | "all" => break Some(OverwriteAnswer::All),
+                _ => {}
+            }
+        };
+        match answer {
+            Some(OverwriteAnswer::Overwrite) => {
+                outputs.allow_replacing(target.clone());
+                kept.push(target);
+            }
+            Some(OverwriteAnswer::Skip) => skipped.push((target, SkipReason::Exists)),
+            Some(OverwriteAnswer::Rename) => {
+                let renamed = outputs.rename(&target, suffix)?;
+                eprintln!("writing {} instead", renamed.display());
+                kept.push(target);
+            }
+            Some(OverwriteAnswer::All) => {
+                overwrite_all = true;
+                outputs.allow_replacing(target.clone());
+                kept.push(target);
+            }
+            None => {
+                skipped.push((target, SkipReason::Exists));
+                break;
+            }
+        }
+    }
+    skipped.extend(targets.map(|target| (target, SkipReason::Exists)));
+
+    Ok(kept)
+}
+
+enum OverwriteAnswer {
+    Overwrite,
+    Skip,
+    Rename,
+    All,
+}
+
+/// The directory of `path` relative to the deepest input directory containing it.
+fn relative_parent<'p>(path: &'p Path, roots: &[&Path]) -> &'p Path {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())
+        .and_then(Path::parent)
+        .unwrap_or(Path::new(""))
+}
+
+/// Applies `--shard` and the incremental filters to `files`. Inputs a filter leaves out
+/// are added to `skipped`; those belonging to other shards aren't.
+pub fn select_targets(
+    config: &AppConfig,
+    mut files: Vec<PathBuf>,
+    outputs: &OutputPlan,
+    skipped: &mut Vec<(PathBuf, SkipReason)>,
+) -> Result<Vec<PathBuf>> {
+    if let Some(shard) = config.shard {
+        // Every worker resolves the same files, so dealing out the sorted list
+        // round-robin gives disjoint, evenly sized slices without coordination.
+        files.sort();
+        files = files
+            .into_iter()
+            .skip(shard.index - 1)
+            .step_by(shard.count)
+            .collect();
+    }
+
+    if let Some(filter) = &config.changes {
+        let changed = git::changed_files(filter).context("querying git for changed files")?;
+        files = retain_or_skip(files, skipped, SkipReason::Excluded, |path| {
+            changed.contains(&canonical(path))
+        });
+    }
+
+    if let Some(reference) = config.newer_than {
+        files = retain_or_skip(files, skipped, SkipReason::NotNewer, |path| {
+            modified(path).is_some_and(|time| time > reference)
+        });
+    }
+
+    if config.only_if_newer && !config.force {
+        let suffix = config.mode.output_suffix();
+        files = retain_or_skip(files, skipped, SkipReason::NotNewer, |path| {
+            let output = match outputs.output_path(path, suffix) {
+                Ok(output) => output,
+                Err(_) => return true,
+            };
+            match (modified(path), modified(&output)) {
+                (Some(input_time), Some(output_time)) => input_time > output_time,
+                _ => true,
+            }
+        });
+    }
+
+    match config.order {
+        FileOrder::Size => files.sort_by_cached_key(|path| {
+            Reverse(fs::metadata(path).map_or(0, |metadata| metadata.len()))
+        }),
+        FileOrder::Name => files.sort(),
+        FileOrder::None => {}
+    }
+
+    Ok(files)
+}
+
+/// Keeps the `files` that satisfy `keep`, recording the rest in `skipped` as `reason`.
+fn retain_or_skip(
+    files: Vec<PathBuf>,
+    skipped: &mut Vec<(PathBuf, SkipReason)>,
+    reason: SkipReason,
+    keep: impl Fn(&Path) -> bool,
+) -> Vec<PathBuf> {
+    let (kept, rejected): (Vec<_>, Vec<_>) = files.into_iter().partition(|path| keep(path));
+    skipped.extend(rejected.into_iter().map(|path| (path, reason)));
+    kept
+}
+
+/// Points each linked input's output at the output produced for its target,
+/// instead of materializing a second copy.
+pub fn recreate_links(
+    links: &[LinkedInput],
+    processed: &[ProcessedFile],
+    suffix: &str,
+    overwrite: bool,
+) -> Result<()> {
+    let outputs: HashMap<PathBuf, &Path> = processed
+        .iter()
+        .map(|file| (canonical(&file.input), file.outcome.output_path.as_path()))
+        .collect();
+
+    let mut failures = Vec::new();
+    for link in links {
+        let Some(target_output) = outputs.get(&link.target) else {
+            continue;
+        };
+        if let Err(err) = recreate_link(&link.link, target_output, suffix, overwrite) {
+            failures.push(err);
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        let mut message = String::from("one or more symlinks could not be recreated:\n");
+        for failure in &failures {
+            message.push_str(" • ");
+            message.push_str(&failure.to_string());
+            message.push('\n');
+        }
+        bail!(message);
+    }
+}
+
+fn recreate_link(link: &Path, target_output: &Path, suffix: &str, overwrite: bool) -> Result<()> {
+    let mut link_output = pipeline::derive_output_path(link, suffix)?;
+    if let Some(extension) = target_output.extension() {
+        link_output.set_extension(extension);
+    }
+
+    if fs::symlink_metadata(&link_output).is_ok() {
+        if !overwrite {
+            bail!(
+                "output file {} already exists (use --overwrite to replace)",
+                link_output.display()
+            );
+        }
+        fs::remove_file(&link_output)
+            .path_context(&link_output)
+            .context("removing existing file")?;
+    }
+
+    // Keep links relative when both outputs live side by side.
+    let destination = match (link_output.parent(), target_output.parent()) {
+        (Some(a), Some(b)) if canonical(a) == canonical(b) => {
+            PathBuf::from(target_output.file_name().unwrap_or_default())
+        }
+        _ => canonical(target_output),
+    };
+
+    symlink(&destination, &link_output)
+        .path_context(&link_output)
+        .context("creating symlink")
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+pub fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn is_linked(path: &Path) -> bool {
+    std::path::absolute(path).is_ok_and(|absolute| absolute != canonical(path))
+}
+
+/// Resolves the directories leading to `path` but not the final link itself.
+fn link_location(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => canonical(parent).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink())
+}
+
+fn collect_from_directory(dir: &Path, config: &AppConfig, files: &mut Vec<PathBuf>) -> Result<()> {
+    walk_files(dir, config.into(), |path| {
+        if is_png(path, &config.extensions) {
+            files.push(path.to_path_buf());
+        }
+    });
+    Ok(())
+}
+
+/// Applies `--copy-unchanged`: copies every input that isn't processed into the
+/// `--output-dir` tree. Files outside `--extensions` keep their name at their mirrored
+/// location; skipped PNGs go to the output path they would have been written to, unless
+/// an output is already there or another input's output is planned for it.
+pub fn copy_unchanged(
+    config: &AppConfig,
+    skipped: &[(PathBuf, SkipReason)],
+    outputs: &OutputPlan,
+) -> Result<()> {
+    let Some(layout) = config
+        .output_dir
+        .as_ref()
+        .filter(|layout| layout.copy_unchanged)
+    else {
+        return Ok(());
+    };
+    let roots: Vec<&Path> = config
+        .inputs
+        .iter()
+        .filter(|input| input.is_dir())
+        .map(PathBuf::as_path)
+        .collect();
+
+    let mut others = Vec::new();
+    for input in &config.inputs {
+        if input.is_dir() {
+            walk_files(input, config.into(), |path| {
+                if !is_png(path, &config.extensions) && !path.starts_with(&layout.dir) {
+                    others.push(path.to_path_buf());
+                }
+            });
+        } else if !is_png(input, &config.extensions) {
+            others.push(input.clone());
+        }
+    }
+
+    let mut copies = Vec::new();
+    for path in others {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        let dir = if layout.flatten {
+            layout.dir.clone()
+        } else {
+            layout.dir.join(relative_parent(&path, &roots))
+        };
+        let destination = dir.join(name);
+        if config.common.overwrite || !destination.exists() {
+            copies.push((path, destination));
+        }
+    }
+    for (path, reason) in skipped {
+        if *reason == SkipReason::Collision {
+            continue;
+        }
+        let destination = outputs.output_path(path, config.mode.output_suffix())?;
+        if !destination.exists() {
+            copies.push((path.clone(), destination));
+        }
+    }
+
+    for (source, destination) in copies {
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .path_context(parent)
+                .context("creating output directory")?;
+        }
+        fs::copy(&source, &destination).with_context(|| {
+            format!(
+                "copying unchanged {} to {}",
+                source.display(),
+                destination.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// How directories are walked: `--follow-symlinks`, `--respect-gitignore` and
+/// `--skip-hidden`.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions {
+    pub follow_symlinks: bool,
+    pub respect_gitignore: bool,
+    pub skip_hidden: bool,
+}
+
+impl From<&AppConfig> for WalkOptions {
+    fn from(config: &AppConfig) -> Self {
+        Self {
+            follow_symlinks: config.follow_symlinks,
+            respect_gitignore: config.respect_gitignore,
+            skip_hidden: config.skip_hidden,
+        }
+    }
+}
+
+/// Calls `visit` with every file under `dir`, honoring the walk options.
+pub fn walk_files(dir: &Path, options: WalkOptions, mut visit: impl FnMut(&Path)) {
+    let walker = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .follow_links(options.follow_symlinks)
+        .hidden(options.skip_hidden)
+        .git_ignore(options.respect_gitignore)
+        .git_exclude(options.respect_gitignore)
+        .git_global(options.respect_gitignore)
+        .parents(options.respect_gitignore)
+        .build();
+
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Symlink cycles are reported as errors, so skipping them breaks the loop.
+            Err(_) => continue,
+        };
+        if !entry.file_type().is_some_and(|kind| kind.is_file()) {
+            continue;
+        }
+
+        visit(entry.path());
+    }
+}
+
+pub fn is_png(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            extensions
+                .iter()
+                .any(|candidate| matches_ignore_ascii_case(ext, candidate))
+        })
+        .unwrap_or(false)
+}
+
+fn matches_ignore_ascii_case(value: &str, needle: &str) -> bool {
+    value.eq_ignore_ascii_case(needle)
+}
@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::error::{Context, Result};
+
+/// Exit status for a run cut short by SIGINT/SIGTERM (128 + SIGINT).
+pub const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Temporary outputs that haven't been persisted yet, removed if we abort mid-write.
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// Time the process has spent stopped by SIGTSTP, in nanoseconds.
+static PAUSED_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Reference point the pause handler measures from, set when it is installed.
+static CLOCK: OnceLock<Instant> = OnceLock::new();
+
+/// Installs the SIGINT/SIGTERM handler. The first signal stops new files from being
+/// picked up and lets the in-flight one finish; a second aborts immediately after
+/// removing any temporary files.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            remove_temp_files();
+            std::process::exit(i32::from(INTERRUPTED_EXIT_CODE));
+        }
+        eprintln!("interrupted: finishing the current file (interrupt again to abort)");
+    })
+    .context("installing interrupt handler")?;
+    #[cfg(unix)]
+    install_pause_handler()?;
+    Ok(())
+}
+
+/// Installs the SIGTSTP handler (`Ctrl-Z`, `kill -TSTP`). The process stops as usual and
+/// carries on where it left off on SIGCONT (`fg`, `kill -CONT`); the stopped time is
+/// recorded so it doesn't count against `--time-budget`.
+#[cfg(unix)]
+fn install_pause_handler() -> Result<()> {
+    use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+
+    CLOCK.get_or_init(Instant::now);
+    let action = SigAction::new(
+        SigHandler::Handler(pause),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only reads the monotonic clock, updates an atomic and raises
+    // a signal, all of which are async-signal-safe.
+    unsafe { sigaction(Signal::SIGTSTP, &action) }.context("installing pause handler")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn pause(_signal: std::ffi::c_int) {
+    use nix::sys::signal::{Signal, raise};
+
+    let Some(clock) = CLOCK.get() else {
+        return;
+    };
+    let stopped = clock.elapsed();
+    // SIGSTOP can't be caught, so this returns once SIGCONT resumes the process.
+    let _ = raise(Signal::SIGSTOP);
+    let paused = clock.elapsed().saturating_sub(stopped);
+    PAUSED_NANOS.fetch_add(paused.as_nanos() as u64, Ordering::SeqCst);
+}
+
+/// How long the run has been paused by SIGTSTP.
+pub fn paused_time() -> Duration {
+    Duration::from_nanos(PAUSED_NANOS.load(Ordering::SeqCst))
+}
+
+/// Whether an interrupt has been received.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn track_temp_file(path: &Path) {
+    if let Ok(mut files) = TEMP_FILES.lock() {
+        files.push(path.to_path_buf());
+    }
+}
+
+pub fn untrack_temp_file(path: &Path) {
+    if let Ok(mut files) = TEMP_FILES.lock() {
+        files.retain(|file| file != path);
+    }
+}
+
+fn remove_temp_files() {
+    if let Ok(files) = TEMP_FILES.lock() {
+        for file in files.iter() {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
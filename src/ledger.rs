@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::{Context, Result};
+use crate::pipeline::{ProcessedFile, write_atomic};
+
+/// Exact sizes measured for one input, or for the whole run.
+#[derive(Debug, Serialize)]
+struct Sizes {
+    original_size: u64,
+    output_size: u64,
+    saved_bytes: i64,
+}
+
+impl Sizes {
+    fn new(original_size: u64, output_size: u64) -> Self {
+        Self {
+            original_size,
+            output_size,
+            saved_bytes: original_size as i64 - output_size as i64,
+        }
+    }
+}
+
+/// The `--measure-only` ledger: per-input sizes and their totals.
+#[derive(Debug, Serialize)]
+struct Ledger {
+    files: BTreeMap<PathBuf, Sizes>,
+    total: Sizes,
+}
+
+/// Writes the sizes `--measure-only` measured, for planning an in-place rollout.
+pub fn write_ledger(path: &Path, files: &[ProcessedFile]) -> Result<()> {
+    let files: BTreeMap<PathBuf, Sizes> = files
+        .iter()
+        .map(|file| {
+            let outcome = &file.outcome;
+            (
+                file.input.clone(),
+                Sizes::new(outcome.original_size, outcome.output_size),
+            )
+        })
+        .collect();
+    let total = Sizes::new(
+        files.values().map(|sizes| sizes.original_size).sum(),
+        files.values().map(|sizes| sizes.output_size).sum(),
+    );
+
+    let mut json =
+        serde_json::to_vec_pretty(&Ledger { files, total }).context("serializing ledger")?;
+    json.push(b'\n');
+    write_atomic(path, &json, true).with_context(|| format!("writing ledger {}", path.display()))
+}
@@ -1,21 +1,80 @@
+mod analysis;
+mod apng;
+mod budgets;
+#[cfg(feature = "bundle")]
+mod bundle;
+mod checksums;
+mod chunks;
 mod cli;
+mod compare;
+#[cfg(feature = "compress")]
 mod compressor;
+mod data_url;
+mod decode;
+mod dedupe;
+mod doctor;
+mod error;
+mod formats;
+mod git;
+mod inputs;
+mod interrupt;
+mod ledger;
+mod manifest;
+mod metadata;
 mod optimizer;
+mod options;
+mod orientation;
 mod pipeline;
+mod priority;
+#[cfg(feature = "remote")]
+mod remote;
+mod rename_map;
+mod sidecar;
+mod stats;
+#[cfg(unix)]
+mod syslog;
+mod throttle;
 mod ui;
+#[cfg(feature = "upload")]
+mod upload;
+mod warnings;
 
-use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use walkdir::WalkDir;
 
-use crate::cli::{AppConfig, Mode};
+use crate::cli::{ApngCommand, AppConfig, Command, LogTarget, Mode};
+use crate::error::TurboPngError;
+use crate::pipeline::{OutputPlan, RunSummary};
+use crate::ui::{ProgressDispatcher, ProgressReporter};
 
-fn main() -> Result<()> {
-    let parsed = cli::Cli::parse();
-    let config = parsed.build()?;
+/// Highest exit status `--exit-code-failures` reports; 126 and up mean other things to
+/// shells.
+const MAX_FAILURE_EXIT_CODE: u8 = 125;
+
+fn main() -> Result<ExitCode> {
+    let mut parsed = cli::Cli::parse();
+    // Before anything starts threads, so they all inherit the lower priority.
+    if let Some(increment) = parsed.nice {
+        priority::lower(increment)?;
+    }
+    if let Some(command) = parsed.command.take() {
+        let result = match command {
+            Command::ExtractMeta(args) => metadata::extract(&args),
+            Command::Apng(ApngCommand::Split(args)) => apng::split(&args),
+            Command::Apng(ApngCommand::Join(args)) => apng::join(&args),
+            Command::Compare(args) => compare::run(&args),
+            Command::Analyze(args) => analysis::run(&args),
+            Command::Stats(args) => stats::run(&args),
+            Command::Doctor => doctor::run(),
+        };
+        result?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    #[cfg_attr(not(feature = "bundle"), allow(unused_mut))]
+    let mut config = parsed.build()?;
 
     if let Some(threads) = config.common.threads {
         rayon::ThreadPoolBuilder::new()
@@ -23,69 +82,221 @@ fn main() -> Result<()> {
             .build_global()
             .context("configuring rayon thread pool")?;
     }
+    if let Some(rate) = config.io_limit {
+        throttle::install(rate);
+    }
 
-    let targets = resolve_inputs(&config)?;
-    if targets.is_empty() {
+    if config.base64 {
+        data_url::run(&config)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let resolved = inputs::resolve_inputs(&config)?;
+    if resolved.files.is_empty() {
         anyhow::bail!("no PNG files found in the provided inputs");
     }
 
-    let progress = ui::ProgressDispatcher::new(config.common.progress, targets.len());
+    // Bundled outputs are staged as with `--output-dir`, then packed once the run ends.
+    #[cfg(feature = "bundle")]
+    let mut staging = bundle::stage(&mut config)?;
+
+    let mut files = resolved.files;
+    let mut skipped = Vec::new();
+    let mut outputs = inputs::plan_outputs(&config, &mut files, &mut skipped)?;
+    let mut targets = inputs::select_targets(&config, files, &outputs, &mut skipped)?;
+    if config.interactive {
+        targets = inputs::confirm_overwrites(&config, targets, &mut outputs, &mut skipped)?;
+    }
+
+    let budgets = config
+        .budgets
+        .as_deref()
+        .map(budgets::Budgets::load)
+        .transpose()?;
 
-    match config.mode {
+    let progress =
+        ui::ProgressDispatcher::new(config.common.progress, targets.len() + skipped.len());
+    #[cfg(unix)]
+    let progress = match config.log {
+        LogTarget::Syslog => {
+            progress.with_system_log(syslog::SystemLog::connect(config.syslog_socket.as_deref())?)
+        }
+        LogTarget::Stderr => progress,
+    };
+    for (path, reason) in &skipped {
+        progress.file_skipped(path, *reason);
+    }
+    // Before processing, so this run's own outputs aren't taken for unchanged files.
+    if !config.common.dry_run && !config.common.measure_only {
+        inputs::copy_unchanged(&config, &skipped, &outputs)?;
+    }
+    if targets.is_empty() {
+        // Incremental filters legitimately select nothing when nothing changed.
+        progress.print_tally();
+        if let Some(map) = &config.rename_map
+            && !config.common.dry_run
+        {
+            rename_map::write_rename_map(
+                map,
+                &skipped,
+                &targets,
+                &outputs,
+                config.mode.output_suffix(),
+                None,
+            )?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    #[cfg(feature = "upload")]
+    let progress = match &config.upload {
+        Some(target) if !config.common.dry_run => progress.with_uploader(upload::Uploader::start(
+            target.clone(),
+            output_root(&config)?,
+        )),
+        _ => progress,
+    };
+
+    interrupt::install()?;
+    let summary = match config.mode {
         Mode::Optimize => optimizer::run(optimizer::OptimizeJob {
             inputs: &targets,
+            outputs: &outputs,
             options: &config.optimize,
             common: &config.common,
             progress: &progress,
         }),
-        Mode::Compress => compressor::run(compressor::CompressJob {
-            inputs: &targets,
-            options: &config.compress,
-            common: &config.common,
-            progress: &progress,
-        }),
+        Mode::Compress => compress(&config, &targets, &outputs, &progress)?,
+    };
+    progress.print_tally();
+    // Outputs are uploaded as they are written; wait for them before failures end the run.
+    #[cfg(feature = "upload")]
+    let uploaded = progress.finish_uploads();
+    #[cfg(feature = "upload")]
+    if let Err(err) = &uploaded
+        && !summary.failed.is_empty()
+    {
+        eprintln!("Error: {err:?}");
     }
-}
 
-fn resolve_inputs(config: &AppConfig) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
-    for input in &config.inputs {
-        if input.is_dir() {
-            collect_from_directory(input, &mut files)?;
-        } else if is_png(input) {
-            files.push(input.canonicalize().unwrap_or_else(|_| input.clone()));
+    // Written before failures end the run, since it records them too.
+    if let Some(map) = &config.rename_map
+        && !config.common.dry_run
+    {
+        rename_map::write_rename_map(
+            map,
+            &skipped,
+            &targets,
+            &outputs,
+            config.mode.output_suffix(),
+            Some(&summary),
+        )?;
+    }
+
+    // Like the rename map, the ledger records whatever was measured before a failure.
+    if let Some(ledger) = &config.ledger {
+        ledger::write_ledger(ledger, &summary.processed)?;
+    }
+
+    // Packed before failures end the run, like the outputs a plain run leaves in place.
+    #[cfg(feature = "bundle")]
+    if let (Some(bundle), Some(staging)) = (&config.bundle, staging.take()) {
+        bundle::write_bundle(
+            bundle,
+            staging.path(),
+            &summary.processed,
+            config.common.overwrite,
+        )?;
+    }
+
+    // Reported before failures end the run, so the files left for next time are known.
+    ui::print_skipped_for_time_budget(&summary, targets.len());
+
+    let processed = match summary.into_result() {
+        Err(err) if config.exit_code_failures => {
+            if let TurboPngError::Batch { failures, .. } = err.root() {
+                let code = u8::try_from(failures.len()).map_or(MAX_FAILURE_EXIT_CODE, |count| {
+                    count.min(MAX_FAILURE_EXIT_CODE)
+                });
+                eprintln!("Error: {:?}", anyhow::Error::from(err));
+                return Ok(ExitCode::from(code));
+            }
+            return Err(err.into());
         }
+        processed => processed?,
+    };
+
+    if !resolved.links.is_empty() && !config.common.dry_run {
+        inputs::recreate_links(
+            &resolved.links,
+            &processed,
+            config.mode.output_suffix(),
+            config.common.overwrite,
+        )?;
     }
 
-    let mut seen: HashSet<PathBuf> = HashSet::new();
-    files.retain(|path| seen.insert(path.clone()));
+    if config.link_duplicates {
+        ui::print_collapsed_duplicates(&dedupe::link_duplicates(&processed)?);
+    }
 
-    Ok(files)
-}
+    if let Some(manifest) = &config.manifest_out
+        && !config.common.dry_run
+    {
+        manifest::write_manifest(manifest, &processed)?;
+    }
 
-fn collect_from_directory(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
-    for entry in WalkDir::new(dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
+    if let Some(checksums) = config.common.checksums
+        && !checksums.per_file
+        && !config.common.dry_run
     {
-        let path = entry.path();
-        if is_png(path) {
-            files.push(path.to_path_buf());
-        }
+        checksums::write_sums(&output_root(&config)?, &processed, checksums.algorithm)?;
+    }
+
+    #[cfg(feature = "upload")]
+    uploaded?;
+
+    // The run's outputs are all recorded above; budgets only judge a complete run.
+    if interrupt::requested() {
+        ui::print_interrupted_summary(&processed, targets.len());
+        return Ok(ExitCode::from(interrupt::INTERRUPTED_EXIT_CODE));
     }
 
-    Ok(())
+    if let Some(budgets) = &budgets {
+        budgets::enforce(budgets, &processed)?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The directory outputs are listed relative to: `--output-dir`, or the current directory.
+fn output_root(config: &AppConfig) -> Result<PathBuf> {
+    match &config.output_dir {
+        Some(layout) => Ok(layout.dir.clone()),
+        None => std::env::current_dir().context("resolving the current directory"),
+    }
 }
 
-fn is_png(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| matches_ignore_ascii_case(ext, "png"))
-        .unwrap_or(false)
+#[cfg(feature = "compress")]
+fn compress(
+    config: &AppConfig,
+    targets: &[PathBuf],
+    outputs: &OutputPlan,
+    progress: &ProgressDispatcher,
+) -> Result<RunSummary> {
+    Ok(compressor::run(compressor::CompressJob {
+        inputs: targets,
+        outputs,
+        options: &config.compress,
+        common: &config.common,
+        progress,
+    }))
 }
 
-fn matches_ignore_ascii_case(value: &str, needle: &str) -> bool {
-    value.eq_ignore_ascii_case(needle)
+#[cfg(not(feature = "compress"))]
+fn compress(
+    _config: &AppConfig,
+    _targets: &[PathBuf],
+    _outputs: &OutputPlan,
+    _progress: &ProgressDispatcher,
+) -> Result<RunSummary> {
+    unreachable!("Cli::build rejects compress mode without the `compress` feature")
 }
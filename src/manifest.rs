@@ -0,0 +1,36 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::{Context, Result};
+use crate::pipeline::{ProcessedFile, write_atomic};
+
+/// Build manifest entry describing the output produced for one input.
+#[derive(Debug, Serialize)]
+struct ManifestEntry<'a> {
+    output: &'a Path,
+    size: u64,
+    sha256: &'a str,
+}
+
+/// Writes an input → output manifest for bundlers and cache-busting steps.
+pub fn write_manifest(path: &Path, files: &[ProcessedFile]) -> Result<()> {
+    let entries: BTreeMap<PathBuf, ManifestEntry<'_>> = files
+        .iter()
+        .map(|file| {
+            (
+                file.input.clone(),
+                ManifestEntry {
+                    output: &file.outcome.output_path,
+                    size: file.outcome.output_size,
+                    sha256: &file.outcome.output_sha256,
+                },
+            )
+        })
+        .collect();
+
+    let mut json = serde_json::to_vec_pretty(&entries).context("serializing manifest")?;
+    json.push(b'\n');
+    write_atomic(path, &json, true).with_context(|| format!("writing manifest {}", path.display()))
+}
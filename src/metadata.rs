@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::ZlibDecoder;
+
+use crate::chunks::Chunks;
+use crate::cli::ExtractMetaArgs;
+use crate::error::{Context, PathContext, Result, bail};
+use crate::pipeline::{ensure_output_available, write_atomic};
+
+/// Upper bound on a single decompressed profile or text chunk.
+const MAX_INFLATED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Writes each input's ICC profile, EXIF block and text chunks to separate files in
+/// `args.out`, named after the input: `<stem>.icc`, `<stem>.exif`, `<stem>.<keyword>.txt`.
+/// Inputs sharing a stem with an earlier one get `<stem>-2`, `<stem>-3`, ….
+pub fn extract(args: &ExtractMetaArgs) -> Result<()> {
+    fs::create_dir_all(&args.out)
+        .path_context(&args.out)
+        .context("creating output directory")?;
+
+    let mut stems = HashSet::new();
+    for file in &args.files {
+        let written = unique_stem(file, &mut stems)
+            .and_then(|stem| extract_file(file, &stem, &args.out, args.overwrite))
+            .with_context(|| format!("extracting metadata from {}", file.display()))?;
+        if written.is_empty() {
+            println!("{}: no metadata found", file.display());
+        }
+        for path in written {
+            println!("{} → {}", file.display(), path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// The input's stem, numbered when an earlier input already used it.
+fn unique_stem(file: &Path, taken: &mut HashSet<String>) -> Result<String> {
+    let stem = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("input file lacks a valid stem")?;
+    if taken.insert(stem.to_string()) {
+        return Ok(stem.to_string());
+    }
+    Ok((2..)
+        .map(|index| format!("{stem}-{index}"))
+        .find(|candidate| taken.insert(candidate.clone()))
+        .expect("an unused stem exists"))
+}
+
+fn extract_file(file: &Path, stem: &str, out: &Path, overwrite: bool) -> Result<Vec<PathBuf>> {
+    let bytes = fs::read(file).context("reading PNG")?;
+
+    let mut entries: Vec<(String, Vec<u8>)> = Vec::new();
+    for chunk in Chunks::new(&bytes)? {
+        let chunk = chunk?;
+        let entry = match &chunk.name {
+            b"iCCP" => {
+                let (_, rest) = split_keyword(chunk.data)?;
+                (format!("{stem}.icc"), inflate(compressed_payload(rest)?)?)
+            }
+            b"eXIf" => (format!("{stem}.exif"), chunk.data.to_vec()),
+            b"tEXt" => {
+                let (keyword, text) = split_keyword(chunk.data)?;
+                (text_name(stem, &keyword), latin1_to_utf8(text))
+            }
+            b"zTXt" => {
+                let (keyword, rest) = split_keyword(chunk.data)?;
+                let text = inflate(compressed_payload(rest)?)?;
+                (text_name(stem, &keyword), latin1_to_utf8(&text))
+            }
+            b"iTXt" => {
+                let (keyword, rest) = split_keyword(chunk.data)?;
+                (text_name(stem, &keyword), international_text(rest)?)
+            }
+            _ => continue,
+        };
+        entries.push(entry);
+    }
+
+    let mut written = Vec::new();
+    for (name, data) in entries {
+        let path = unique_path(out, &name, &written);
+        ensure_output_available(&path, overwrite)?;
+        write_atomic(&path, &data, overwrite)
+            .with_context(|| format!("writing {}", path.display()))?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Splits the null-terminated Latin-1 keyword that starts iCCP and the text chunks.
+fn split_keyword(data: &[u8]) -> Result<(String, &[u8])> {
+    let Some(end) = data.iter().position(|&byte| byte == 0) else {
+        bail!("chunk keyword is not null-terminated");
+    };
+    let keyword = data[..end].iter().map(|&byte| char::from(byte)).collect();
+    Ok((keyword, &data[end + 1..]))
+}
+
+/// Strips the compression method byte, of which zlib (0) is the only one defined.
+fn compressed_payload(data: &[u8]) -> Result<&[u8]> {
+    match data.split_first() {
+        Some((0, payload)) => Ok(payload),
+        Some((method, _)) => bail!("unknown compression method {method}"),
+        None => bail!("chunk is missing its compression method"),
+    }
+}
+
+/// Decodes the part of an iTXt chunk after its keyword.
+fn international_text(data: &[u8]) -> Result<Vec<u8>> {
+    let [compressed, method, rest @ ..] = data else {
+        bail!("iTXt chunk is truncated");
+    };
+    // Skip the language tag and translated keyword.
+    let mut fields = rest.splitn(3, |&byte| byte == 0);
+    let (Some(_), Some(_), Some(text)) = (fields.next(), fields.next(), fields.next()) else {
+        bail!("iTXt chunk is truncated");
+    };
+
+    if *compressed == 0 {
+        return Ok(text.to_vec());
+    }
+    if *method != 0 {
+        bail!("unknown compression method {method}");
+    }
+    inflate(text)
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut inflated)
+        .context("decompressing chunk")?;
+    if inflated.len() as u64 > MAX_INFLATED_BYTES {
+        bail!(
+            "chunk inflates past {} MiB",
+            MAX_INFLATED_BYTES / (1024 * 1024)
+        );
+    }
+    Ok(inflated)
+}
+
+fn latin1_to_utf8(text: &[u8]) -> Vec<u8> {
+    text.iter()
+        .map(|&byte| char::from(byte))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// File name for a text chunk, keeping only characters that are safe in paths.
+fn text_name(stem: &str, keyword: &str) -> String {
+    let keyword: String = keyword
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{stem}.{keyword}.txt")
+}
+
+/// Numbers repeated keywords (`.2.txt`, `.3.txt`, …) so no chunk overwrites another.
+fn unique_path(out: &Path, name: &str, taken: &[PathBuf]) -> PathBuf {
+    let candidate = out.join(name);
+    if !taken.contains(&candidate) {
+        return candidate;
+    }
+
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    (2..)
+        .map(|index| out.join(format!("{base}.{index}.{extension}")))
+        .find(|path| !taken.contains(path))
+        .expect("an unused name exists")
+}
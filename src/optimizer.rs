@@ -1,90 +1,206 @@
-use std::fs;
-use std::num::NonZeroU8;
+use std::io::Write;
+#[cfg(feature = "zopfli")]
+use std::num::{NonZeroU8, NonZeroU64};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
 
-use anyhow::{Context, Result, bail};
-use oxipng::{self, Deflaters, Options};
-
-use crate::cli::{CommonOptions, OptimizeOptions};
-use crate::pipeline::{build_strip_policy, derive_output_path, write_atomic};
-use crate::ui::{FileOutcome, ProgressDispatcher};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use libdeflater::{CompressionLvl, Compressor};
+use oxipng::{self, Options};
+
+use crate::chunks;
+use crate::cli::{CommonOptions, Deflater, Mode, OptimizeOptions, StageKind, StageOnly};
+#[cfg(not(feature = "zopfli"))]
+use crate::error::bail;
+use crate::error::{Context, Result, format_err};
+use crate::interrupt;
+use crate::pipeline::{
+    ApplyOrientation, FileState, OutputPlan, ProcessedFile, ReadInput, RunSummary, Stage,
+    WriteOutput, apply_deflater, build_strip_policy, keep_pixel_format, optimize_png,
+    pin_deflate_strategy, reduction_notes, run_stages, strip_policy_allows, strongest_deflater,
+    time_budget_exhausted,
+};
+use crate::ui::{FileOutcome, ProgressReporter};
 
 pub struct OptimizeJob<'a> {
     pub inputs: &'a [PathBuf],
+    pub outputs: &'a OutputPlan,
     pub options: &'a OptimizeOptions,
     pub common: &'a CommonOptions,
-    pub progress: &'a ProgressDispatcher,
+    pub progress: &'a dyn ProgressReporter,
 }
 
-pub fn run(job: OptimizeJob<'_>) -> Result<()> {
-    let mut failures = Vec::new();
+pub fn run(job: OptimizeJob<'_>) -> RunSummary {
+    let mut processed = Vec::new();
+    let mut failed = Vec::new();
+
+    let mut out_of_time = Vec::new();
 
-    for path in job.inputs {
+    for (index, path) in job.inputs.iter().enumerate() {
+        if interrupt::requested() {
+            break;
+        }
+        if time_budget_exhausted(job.common) {
+            out_of_time = job.inputs[index..].to_vec();
+            break;
+        }
         job.progress.file_started(path);
         match process_file(path, &job) {
-            Ok(outcome) => job.progress.file_finished(path, Some(outcome)),
+            Ok(outcome) => {
+                job.progress.file_finished(path, Some(outcome.clone()));
+                processed.push(ProcessedFile {
+                    input: path.clone(),
+                    outcome,
+                });
+            }
             Err(err) => {
                 job.progress.file_failed(path, &err);
-                failures.push(err);
+                failed.push((path.clone(), err));
             }
         }
     }
 
-    if failures.is_empty() {
-        Ok(())
-    } else {
-        let mut message = String::from("one or more files failed during optimization:\n");
-        for failure in &failures {
-            message.push_str(" • ");
-            message.push_str(&failure.to_string());
-            message.push('\n');
-        }
-        bail!(message);
+    RunSummary {
+        activity: "optimization",
+        processed,
+        failed,
+        out_of_time,
     }
 }
 
 fn process_file(path: &Path, job: &OptimizeJob<'_>) -> Result<FileOutcome> {
-    let start = Instant::now();
+    let mut state = FileState::new(path, Mode::Optimize, job.common, job.outputs, job.options)?;
+    run_all_stages(&mut state, job.progress)?;
+    Ok(state.finish())
+}
 
-    let output_path =
-        derive_output_path(path, "_optimized.png").context("computing optimized output path")?;
+/// Optimizes `input` without touching the filesystem, for `--base64`.
+pub fn process_in_memory(
+    label: &Path,
+    input: Vec<u8>,
+    options: &OptimizeOptions,
+    common: &CommonOptions,
+    progress: &dyn ProgressReporter,
+) -> Result<(FileOutcome, Vec<u8>)> {
+    let mut state = FileState::in_memory(label, input, Mode::Optimize, common, options);
+    run_all_stages(&mut state, progress)?;
+    Ok(state.finish_in_memory())
+}
 
-    if output_path.exists() && !job.common.overwrite {
-        bail!(
-            "output file {} already exists (use --overwrite to replace)",
-            output_path.display()
-        );
-    }
+fn run_all_stages(
+    state: &mut FileState<'_, &OptimizeOptions>,
+    progress: &dyn ProgressReporter,
+) -> Result<()> {
+    let options = state.work;
+    let stages: [&dyn Stage<&OptimizeOptions>; 4] = [
+        &ReadInput,
+        &ApplyOrientation,
+        &Deflate,
+        &WriteOutput { options },
+    ];
+    run_stages(&stages, state, progress)
+}
 
-    let original_bytes =
-        fs::read(path).with_context(|| format!("reading input PNG {}", path.display()))?;
-    let original_size = original_bytes.len() as u64;
+/// Runs oxipng over the input, or only re-deflates its image data with `--stage-only`.
+struct Deflate;
 
-    let options = configure_options(job.common, job.options)?;
+impl Stage<&OptimizeOptions> for Deflate {
+    fn kind(&self) -> StageKind {
+        StageKind::Deflate
+    }
 
-    let optimized_bytes = oxipng::optimize_from_memory(&original_bytes, &options)
-        .with_context(|| format!("optimizing {}", path.display()))?;
-    let output_size = optimized_bytes.len() as u64;
+    fn run(&self, state: &mut FileState<'_, &OptimizeOptions>) -> Result<()> {
+        if state.common.stage_only == Some(StageOnly::Deflate) {
+            return redeflate(state);
+        }
+        let mut options = configure_options(state.common, state.work)?;
+        if state.common.stable_palette && chunks::is_indexed(state.input())? {
+            keep_pixel_format(&mut options);
+        }
+        let optimized = optimize_png(state.input(), &options, state.common)
+            .with_context(|| format!("optimizing {}", state.path.display()))?;
+        if options.scale_16
+            && chunks::pixel_format(state.input())?.1 == 16
+            && chunks::pixel_format(&optimized)?.1 < 16
+        {
+            state
+                .warnings
+                .push(String::from("16-bit samples reduced to 8 bits"));
+        }
+        let reductions = reduction_notes(state.input(), &optimized, &options, state.common)?;
+        state.notes.extend(reductions);
+        state.output = Some(optimized);
+        Ok(())
+    }
+}
+
+/// Compresses the input's filtered image data again, keeping the result only when it is
+/// smaller than the input's own image data; either way the strip policy applies.
+/// Without `--deflater`, uses Zopfli under `--zopfli` and libdeflate otherwise.
+fn redeflate(state: &mut FileState<'_, &OptimizeOptions>) -> Result<()> {
+    let (deflater, level) = match state.common.deflater {
+        Some(choice) => choice,
+        None if state.work.zopfli => (Deflater::Zopfli, Deflater::Zopfli.levels().1),
+        None => (Deflater::Libdeflate, Deflater::Libdeflate.levels().1),
+    };
+    let strip = build_strip_policy(state.common)?;
+    let redeflated = chunks::redeflate_image_data(
+        state.input(),
+        |raw| zlib_stream(raw, deflater, level),
+        |name| strip_policy_allows(&strip, name),
+    )
+    .with_context(|| format!("re-deflating {}", state.path.display()))?;
+    // The input's own image data still loses the chunks the strip policy drops.
+    let kept = chunks::strip_ancillary(state.input(), |name| strip_policy_allows(&strip, name))
+        .with_context(|| format!("stripping {}", state.path.display()))?;
+    if redeflated.len() < kept.len() {
+        state.output = Some(redeflated);
+    } else {
+        state.notes.push(String::from("image data kept"));
+        state.output = Some(kept);
+    }
+    Ok(())
+}
 
-    if job.common.dry_run {
-        return Ok(FileOutcome {
-            original_size,
-            output_size,
-            elapsed: start.elapsed(),
-            notes: Some(String::from("dry run")),
-        });
+/// Compresses `raw` into a zlib stream with `deflater` at `level`.
+fn zlib_stream(raw: &[u8], deflater: Deflater, level: u8) -> Result<Vec<u8>> {
+    match deflater {
+        Deflater::Libdeflate => {
+            let level = CompressionLvl::new(i32::from(level))
+                .map_err(|_| format_err!("invalid libdeflate level {level}"))?;
+            let mut compressor = Compressor::new(level);
+            let mut stream = vec![0; compressor.zlib_compress_bound(raw.len())];
+            let written = compressor.zlib_compress(raw, &mut stream)?;
+            stream.truncate(written);
+            Ok(stream)
+        }
+        Deflater::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(u32::from(level)));
+            encoder.write_all(raw)?;
+            Ok(encoder.finish()?)
+        }
+        Deflater::Zopfli => zopfli_stream(raw, level),
     }
+}
 
-    write_atomic(&output_path, &optimized_bytes, job.common.overwrite)
-        .with_context(|| format!("writing optimized PNG {}", output_path.display()))?;
+#[cfg(feature = "zopfli")]
+fn zopfli_stream(raw: &[u8], iterations: u8) -> Result<Vec<u8>> {
+    let options = zopfli::Options {
+        iteration_count: NonZeroU64::from(
+            NonZeroU8::new(iterations).context("Zopfli needs at least one iteration")?,
+        ),
+        ..zopfli::Options::default()
+    };
+    let mut stream = Vec::new();
+    zopfli::compress(options, zopfli::Format::Zlib, raw, &mut stream)?;
+    Ok(stream)
+}
 
-    Ok(FileOutcome {
-        original_size,
-        output_size,
-        elapsed: start.elapsed(),
-        notes: None,
-    })
+/// Only reachable with `--zopfli` or `--deflater zopfli`, which builds without the
+/// feature reject up front.
+#[cfg(not(feature = "zopfli"))]
+fn zopfli_stream(_raw: &[u8], _iterations: u8) -> Result<Vec<u8>> {
+    bail!("Zopfli is unavailable: turbo-png was built without the `zopfli` feature")
 }
 
 fn configure_options(common: &CommonOptions, options: &OptimizeOptions) -> Result<Options> {
@@ -92,9 +208,15 @@ fn configure_options(common: &CommonOptions, options: &OptimizeOptions) -> Resul
     opts.fast_evaluation = false;
     opts.strip = build_strip_policy(common)?;
     if options.zopfli {
-        opts.deflate = Deflaters::Zopfli {
-            iterations: NonZeroU8::new(15).expect("15 is non-zero"),
-        };
+        opts.deflate = strongest_deflater(15);
+    }
+    opts.scale_16 = options.scale_16;
+    apply_deflater(&mut opts, common);
+    if common.only_deflate {
+        keep_pixel_format(&mut opts);
+    }
+    if common.strip_nondeterministic {
+        pin_deflate_strategy(&mut opts);
     }
 
     Ok(opts)
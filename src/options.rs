@@ -0,0 +1,220 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use crate::cli::{CompressOptions, DEFAULT_QUALITY, Lossy, OptimizeOptions};
+use crate::error::{Result, bail};
+
+/// Quality levels accepted for `quality`.
+const QUALITY_RANGE: RangeInclusive<u8> = 1..=100;
+/// Bounds accepted for the explicit quality window.
+const QUALITY_WINDOW_RANGE: RangeInclusive<u8> = 0..=100;
+/// imagequant speeds, from thorough to fastest.
+const QUANT_SPEED_RANGE: RangeInclusive<u8> = 1..=10;
+/// An indexed PNG holds at most this many palette entries.
+const MAX_PALETTE_SIZE: usize = 256;
+
+/// Builds [`OptimizeOptions`] for callers that don't go through the CLI; every
+/// combination is valid.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeOptionsBuilder {
+    zopfli: bool,
+    scale_16: bool,
+}
+
+impl OptimizeOptionsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn zopfli(mut self, zopfli: bool) -> Self {
+        self.zopfli = zopfli;
+        self
+    }
+
+    /// Allow the lossy 16-to-8-bit reduction.
+    pub fn scale_16(mut self, enabled: bool) -> Self {
+        self.scale_16 = enabled;
+        self
+    }
+
+    pub fn build(self) -> OptimizeOptions {
+        OptimizeOptions {
+            zopfli: self.zopfli,
+            scale_16: self.scale_16,
+        }
+    }
+}
+
+/// Builds validated [`CompressOptions`], starting from the CLI's defaults. Errors name
+/// the options by their CLI flags.
+#[derive(Debug, Clone)]
+pub struct CompressOptionsBuilder {
+    options: CompressOptions,
+}
+
+impl Default for CompressOptionsBuilder {
+    fn default() -> Self {
+        Self {
+            options: CompressOptions {
+                quality: DEFAULT_QUALITY,
+                quality_min: None,
+                quality_max: None,
+                quant_speed: None,
+                linear_quantization: false,
+                lock_colors: Vec::new(),
+                roi_mask: None,
+                crisp_alpha: false,
+                lossy: Lossy::Palette,
+                banding_check: true,
+                adaptive: false,
+                full_palette: false,
+                max_channel_diff: None,
+                input_quality: Vec::new(),
+            },
+        }
+    }
+}
+
+impl CompressOptionsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.options.quality = quality;
+        self
+    }
+
+    /// Explicit imagequant quality window, overriding the one derived from `quality`.
+    pub fn quality_window(mut self, min: Option<u8>, max: Option<u8>) -> Self {
+        self.options.quality_min = min;
+        self.options.quality_max = max;
+        self
+    }
+
+    pub fn quant_speed(mut self, speed: Option<u8>) -> Self {
+        self.options.quant_speed = speed;
+        self
+    }
+
+    pub fn linear_quantization(mut self, enabled: bool) -> Self {
+        self.options.linear_quantization = enabled;
+        self
+    }
+
+    /// RGBA colors kept verbatim in every palette.
+    pub fn lock_colors(mut self, colors: Vec<[u8; 4]>) -> Self {
+        self.options.lock_colors = colors;
+        self
+    }
+
+    pub fn roi_mask(mut self, mask: Option<PathBuf>) -> Self {
+        self.options.roi_mask = mask;
+        self
+    }
+
+    pub fn crisp_alpha(mut self, enabled: bool) -> Self {
+        self.options.crisp_alpha = enabled;
+        self
+    }
+
+    pub fn lossy(mut self, lossy: Lossy) -> Self {
+        self.options.lossy = lossy;
+        self
+    }
+
+    pub fn banding_check(mut self, enabled: bool) -> Self {
+        self.options.banding_check = enabled;
+        self
+    }
+
+    pub fn adaptive(mut self, enabled: bool) -> Self {
+        self.options.adaptive = enabled;
+        self
+    }
+
+    /// Allow up to 256 palette colors regardless of `quality`.
+    pub fn full_palette(mut self, enabled: bool) -> Self {
+        self.options.full_palette = enabled;
+        self
+    }
+
+    /// Cap how far quantization may move any channel of any pixel.
+    pub fn max_channel_diff(mut self, limit: Option<u8>) -> Self {
+        self.options.max_channel_diff = limit;
+        self
+    }
+
+    /// Quality levels for inputs under particular paths, overriding `quality`.
+    pub fn input_quality(mut self, levels: Vec<(PathBuf, u8)>) -> Self {
+        self.options.input_quality = levels;
+        self
+    }
+
+    pub fn build(self) -> Result<CompressOptions> {
+        let options = self.options;
+        if !QUALITY_RANGE.contains(&options.quality) {
+            bail!("--quality {} must be between 1 and 100", options.quality);
+        }
+        for bound in [options.quality_min, options.quality_max]
+            .into_iter()
+            .flatten()
+        {
+            if !QUALITY_WINDOW_RANGE.contains(&bound) {
+                bail!("--quality-min/--quality-max {bound} must be between 0 and 100");
+            }
+        }
+        for (path, quality) in &options.input_quality {
+            if !QUALITY_RANGE.contains(quality) {
+                bail!(
+                    "--input-quality {quality} for {} must be between 1 and 100",
+                    path.display()
+                );
+            }
+        }
+        if let (Some(min), Some(max)) = (options.quality_min, options.quality_max)
+            && min > max
+        {
+            bail!("--quality-min ({min}) must not exceed --quality-max ({max})");
+        }
+        // Without `--quality-max`, the target comes from each quality level in use.
+        if let (Some(min), None) = (options.quality_min, options.quality_max) {
+            let levels = options.input_quality.iter().map(|(_, quality)| *quality);
+            for quality in std::iter::once(options.quality).chain(levels) {
+                let (_, target) = derived_quality_window(quality);
+                if min > target {
+                    bail!(
+                        "--quality-min ({min}) must not exceed the target {target} that \
+                         quality {quality} derives; pass --quality-max as well"
+                    );
+                }
+            }
+        }
+        if let Some(speed) = options.quant_speed
+            && !QUANT_SPEED_RANGE.contains(&speed)
+        {
+            bail!("--quant-speed {speed} must be between 1 and 10");
+        }
+        if options.lock_colors.len() > MAX_PALETTE_SIZE {
+            bail!(
+                "--lock-colors: {} locked colors exceed the {MAX_PALETTE_SIZE}-entry palette",
+                options.lock_colors.len()
+            );
+        }
+
+        Ok(options)
+    }
+}
+
+/// The imagequant quality window (floor, target) derived from a quality level.
+pub fn derived_quality_window(quality: u8) -> (u8, u8) {
+    match quality {
+        98..=100 => (85, 99),
+        95..=97 => (80, 96),
+        85..=94 => (70, 92),
+        70..=84 => (60, 88),
+        55..=69 => (45, 82),
+        40..=54 => (35, 76),
+        _ => (25, 68),
+    }
+}
@@ -0,0 +1,270 @@
+use std::io::Cursor;
+
+use png::chunk::ChunkType;
+use png::{BitDepth, ColorType, Encoder, Transformations};
+
+use crate::chunks::{Chunks, order_chunks};
+use crate::error::{Context, Result};
+
+/// EXIF tag holding the orientation of the stored pixels.
+const ORIENTATION_TAG: u16 = 0x0112;
+/// EXIF `SHORT` field type.
+const SHORT_TYPE: u16 = 3;
+
+/// Rotates/flips the pixels of a PNG according to the orientation in its `eXIf` chunk,
+/// and removes the orientation tag so viewers don't apply it twice.
+///
+/// Returns the orientation applied with the new PNG, or `None` when the image has no
+/// orientation to apply.
+pub fn apply_orientation(bytes: &[u8]) -> Result<Option<(u16, Vec<u8>)>> {
+    let Some(exif) = exif_chunk(bytes)? else {
+        return Ok(None);
+    };
+    let Some(tag) = find_orientation(&exif) else {
+        return Ok(None);
+    };
+    let orientation = tag.value;
+    if !(2..=8).contains(&orientation) {
+        return Ok(None);
+    }
+
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info().context("reading PNG info")?;
+    let source_color = reader.info().color_type;
+    let was_indexed = source_color == ColorType::Indexed;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut buffer)
+        .context("reading PNG image data")?;
+    let sample_bytes = if frame.bit_depth == BitDepth::Sixteen {
+        2
+    } else {
+        1
+    };
+    let pixel_bytes = frame.color_type.samples() * sample_bytes;
+    let mut pixels = Vec::new();
+    let (width, height) = reorient(
+        &buffer[..frame.buffer_size()],
+        (frame.width as usize, frame.height as usize),
+        frame.line_size,
+        pixel_bytes,
+        orientation,
+        &mut pixels,
+    );
+
+    let mut exif = exif;
+    remove_entry(&mut exif, &tag);
+
+    let mut output = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut output, width as u32, height as u32);
+        encoder.set_color(frame.color_type);
+        encoder.set_depth(frame.bit_depth);
+        let mut writer = encoder.write_header()?;
+        for chunk in Chunks::new(bytes)? {
+            let chunk = chunk?;
+            if chunk.is_critical() {
+                continue;
+            }
+            match &chunk.name {
+                b"eXIf" => writer.write_chunk(ChunkType(chunk.name), &exif)?,
+                // Expansion folded the palette and transparency into the pixels.
+                b"tRNS" => {}
+                b"bKGD" | b"hIST" if was_indexed => {}
+                // Expansion can add channels, and sBIT carries one byte per channel.
+                b"sBIT" if source_color != frame.color_type => {
+                    if let Some(sbit) =
+                        expand_sbit(chunk.data, source_color, frame.color_type, frame.bit_depth)
+                    {
+                        writer.write_chunk(ChunkType(chunk.name), &sbit)?;
+                    }
+                }
+                // Quarter turns swap the horizontal and vertical densities.
+                b"pHYs" if orientation >= 5 && chunk.data.len() == 9 => {
+                    let mut swapped = chunk.data.to_vec();
+                    swapped[..8].rotate_left(4);
+                    writer.write_chunk(ChunkType(chunk.name), &swapped)?;
+                }
+                _ => writer.write_chunk(ChunkType(chunk.name), chunk.data)?,
+            }
+        }
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+    }
+
+    Ok(Some((orientation, order_chunks(&output)?)))
+}
+
+/// The orientation in the image's `eXIf` chunk, when it asks for a rotation or flip.
+pub fn exif_orientation(bytes: &[u8]) -> Result<Option<u16>> {
+    let Some(exif) = exif_chunk(bytes)? else {
+        return Ok(None);
+    };
+    Ok(find_orientation(&exif)
+        .map(|tag| tag.value)
+        .filter(|orientation| (2..=8).contains(orientation)))
+}
+
+fn exif_chunk(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    for chunk in Chunks::new(bytes)? {
+        let chunk = chunk?;
+        if &chunk.name == b"eXIf" {
+            return Ok(Some(chunk.data.to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+/// The orientation entry in IFD0 of a TIFF-structured EXIF block.
+struct OrientationTag {
+    value: u16,
+    /// Byte offset of IFD0.
+    ifd: usize,
+    /// Byte offset of the orientation's 12-byte directory entry.
+    entry: usize,
+    big_endian: bool,
+}
+
+/// Finds the orientation in IFD0 of a TIFF-structured EXIF block.
+fn find_orientation(exif: &[u8]) -> Option<OrientationTag> {
+    let big_endian = match exif.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes: [u8; 2] = exif.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes: [u8; 4] = exif.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    // The next-IFD offset follows the entries; removal shifts it up.
+    exif.get(ifd + 2 + entries * 12..ifd + 6 + entries * 12)?;
+    for index in 0..entries {
+        let entry = ifd + 2 + index * 12;
+        if read_u16(entry)? == ORIENTATION_TAG && read_u16(entry + 2)? == SHORT_TYPE {
+            return Some(OrientationTag {
+                value: read_u16(entry + 8)?,
+                ifd,
+                entry,
+                big_endian,
+            });
+        }
+    }
+    None
+}
+
+/// Drops the orientation entry from IFD0, shifting the later entries and the next-IFD
+/// offset up. The block keeps its length, so offsets pointing elsewhere stay valid.
+fn remove_entry(exif: &mut [u8], tag: &OrientationTag) {
+    let count_bytes = [exif[tag.ifd], exif[tag.ifd + 1]];
+    let count = if tag.big_endian {
+        u16::from_be_bytes(count_bytes)
+    } else {
+        u16::from_le_bytes(count_bytes)
+    };
+    let end = tag.ifd + 2 + usize::from(count) * 12 + 4;
+    exif.copy_within(tag.entry + 12..end, tag.entry);
+    exif[end - 12..end].fill(0);
+    let count = if tag.big_endian {
+        (count - 1).to_be_bytes()
+    } else {
+        (count - 1).to_le_bytes()
+    };
+    exif[tag.ifd..tag.ifd + 2].copy_from_slice(&count);
+}
+
+/// Rewrites an `sBIT` chunk for the colour type expansion produced, or `None` when the
+/// chunk doesn't match the source colour type. Added alpha is fully significant.
+fn expand_sbit(
+    data: &[u8],
+    source: ColorType,
+    output: ColorType,
+    depth: BitDepth,
+) -> Option<Vec<u8>> {
+    let expected = match source {
+        ColorType::Indexed => 3,
+        other => other.samples(),
+    };
+    if data.len() != expected {
+        return None;
+    }
+    let full = depth as u8;
+    let (gray, color, alpha) = match source {
+        ColorType::Grayscale => (data[0], [data[0]; 3], full),
+        ColorType::GrayscaleAlpha => (data[0], [data[0]; 3], data[1]),
+        ColorType::Rgb | ColorType::Indexed => (data[0], [data[0], data[1], data[2]], full),
+        ColorType::Rgba => (data[0], [data[0], data[1], data[2]], data[3]),
+    };
+    Some(match output {
+        ColorType::Grayscale => vec![gray],
+        ColorType::GrayscaleAlpha => vec![gray, alpha],
+        ColorType::Rgb | ColorType::Indexed => color.to_vec(),
+        ColorType::Rgba => vec![color[0], color[1], color[2], alpha],
+    })
+}
+
+/// Rotates/flips tightly packed pixels (one element per pixel) for EXIF orientations
+/// 2–8, replacing the contents of `out` and returning the new dimensions.
+#[cfg(feature = "compress")]
+pub fn reorient_pixels<T: Copy>(
+    pixels: &[T],
+    width: usize,
+    height: usize,
+    orientation: u16,
+    out: &mut Vec<T>,
+) -> (usize, usize) {
+    reorient(pixels, (width, height), width, 1, orientation, out)
+}
+
+/// Maps each output pixel back to its source for EXIF orientations 2–8. `line_size` and
+/// `pixel_size` count elements of `source`.
+fn reorient<T: Copy>(
+    source: &[T],
+    (width, height): (usize, usize),
+    line_size: usize,
+    pixel_size: usize,
+    orientation: u16,
+    out: &mut Vec<T>,
+) -> (usize, usize) {
+    let (out_width, out_height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    out.clear();
+    out.reserve(out_width * out_height * pixel_size);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (sx, sy) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            let offset = sy * line_size + sx * pixel_size;
+            out.extend_from_slice(&source[offset..offset + pixel_size]);
+        }
+    }
+
+    (out_width, out_height)
+}
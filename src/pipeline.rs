@@ -1,12 +1,71 @@
-use std::fs;
-use std::io::Write;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+#[cfg(feature = "zopfli")]
+use std::num::NonZeroU8;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::time::{Instant, SystemTime};
 
-use anyhow::{Context, Result, anyhow};
-use oxipng::StripChunks;
-use tempfile::Builder as TempFileBuilder;
+use memmap2::Mmap;
+use oxipng::{Deflaters, IndexSet, Options, StripChunks};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tempfile::{Builder as TempFileBuilder, NamedTempFile};
 
-use crate::cli::CommonOptions;
+use crate::checksums::write_file_checksum;
+use crate::chunks::{self, ChunkName, Chunks, PNG_SIGNATURE, chunk_crc};
+use crate::cli::{
+    ApngPolicy, CommonOptions, Compat, CrcPolicy, Deflater, Mode, OutputFormat, StageKind,
+};
+use crate::compare::dssim;
+use crate::decode::{decode_indexed, decode_rgba, same_pixels};
+use crate::error::{Context, PathContext, Result, TurboPngError, bail, format_err};
+use crate::formats::{choose_smallest, has_apng_extension, output_path_for};
+#[cfg(feature = "remote")]
+use crate::remote::write_remote;
+use crate::sidecar::{Sidecar, write_sidecar};
+use crate::ui::{
+    FileOutcome, ProgressReporter, SkipReason, dry_run_notes, format_bytes, join_notes,
+};
+use crate::{interrupt, orientation, throttle, warnings};
+
+/// A successfully processed input together with its outcome.
+#[derive(Debug, Clone)]
+pub struct ProcessedFile {
+    pub input: PathBuf,
+    pub outcome: FileOutcome,
+}
+
+/// What a mode's run did with its inputs; inputs after an interrupt appear in none of
+/// the lists.
+pub struct RunSummary {
+    /// How the mode's work is described in the batch failure message.
+    pub activity: &'static str,
+    pub processed: Vec<ProcessedFile>,
+    pub failed: Vec<(PathBuf, TurboPngError)>,
+    /// Inputs never started because `--time-budget` ran out.
+    pub out_of_time: Vec<PathBuf>,
+}
+
+impl RunSummary {
+    /// The processed files, or a [`TurboPngError::Batch`] when any input failed.
+    pub fn into_result(self) -> Result<Vec<ProcessedFile>> {
+        if self.failed.is_empty() {
+            return Ok(self.processed);
+        }
+        Err(TurboPngError::Batch {
+            activity: self.activity,
+            failures: self
+                .failed
+                .iter()
+                .map(|(_, err)| format!("{err:#}"))
+                .collect(),
+        })
+    }
+}
 
 /// Chunks preserved when using oxipng’s `StripChunks::Safe` policy.
 /// Keep in sync with `oxipng::display_chunks::DISPLAY_CHUNKS`.
@@ -15,10 +74,26 @@ const DISPLAY_CHUNKS: [[u8; 4]; 7] = [
 ];
 
 pub fn build_strip_policy(common: &CommonOptions) -> Result<StripChunks> {
+    let exif = *b"eXIf";
     if common.keep_metadata {
+        if common.strip_exif {
+            return Ok(StripChunks::Strip(IndexSet::from([exif])));
+        }
         return Ok(StripChunks::None);
     }
 
+    if common.keep_exif || !common.keep_text.is_empty() {
+        let mut keep: IndexSet<[u8; 4]> = DISPLAY_CHUNKS.into_iter().collect();
+        if common.keep_exif {
+            keep.insert(exif);
+        }
+        // Text chunks are kept wholesale here; `finish_png` drops the unlisted keywords.
+        if !common.keep_text.is_empty() {
+            keep.extend([*b"tEXt", *b"zTXt", *b"iTXt"]);
+        }
+        return Ok(StripChunks::Keep(keep));
+    }
+
     Ok(StripChunks::Safe)
 }
 
@@ -32,48 +107,1150 @@ pub fn strip_policy_allows(strip: &StripChunks, name: &[u8; 4]) -> bool {
     }
 }
 
+/// Where each input's output goes: next to the input unless `--output-dir` placed it.
+#[derive(Debug, Default)]
+pub struct OutputPlan {
+    /// Output directory and file stem planned for each input.
+    placements: HashMap<PathBuf, (PathBuf, String)>,
+    /// Inputs whose existing output may be replaced even without `--overwrite`.
+    replaceable: HashSet<PathBuf>,
+}
+
+impl OutputPlan {
+    pub fn place(&mut self, input: PathBuf, dir: PathBuf, stem: String) {
+        self.placements.insert(input, (dir, stem));
+    }
+
+    pub fn allow_replacing(&mut self, input: PathBuf) {
+        self.replaceable.insert(input);
+    }
+
+    pub fn may_replace(&self, input: &Path) -> bool {
+        self.replaceable.contains(input)
+    }
+
+    /// Moves `input`'s output to the first free `<stem>-N` name in the same directory.
+    pub fn rename(&mut self, input: &Path, suffix: &str) -> Result<PathBuf> {
+        let current = self.output_path(input, suffix)?;
+        let suffix = output_suffix(input, suffix);
+        let (dir, stem) = match self.placements.get(input) {
+            Some(placement) => placement.clone(),
+            None => (
+                current.parent().map(Path::to_path_buf).unwrap_or_default(),
+                input
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .ok_or_else(|| format_err!("input file {:?} lacks a valid stem", input))?
+                    .to_string(),
+            ),
+        };
+
+        let mut counter = 2;
+        while dir.join(format!("{stem}-{counter}{suffix}")).exists() {
+            counter += 1;
+        }
+        let renamed = format!("{stem}-{counter}");
+        let path = dir.join(format!("{renamed}{suffix}"));
+        self.place(input.to_path_buf(), dir, renamed);
+        Ok(path)
+    }
+
+    pub fn output_path(&self, input: &Path, suffix: &str) -> Result<PathBuf> {
+        match self.placements.get(input) {
+            Some((dir, stem)) => Ok(dir.join(format!("{stem}{}", output_suffix(input, suffix)))),
+            None => derive_output_path(input, suffix),
+        }
+    }
+}
+
+/// One step of a file's pipeline. Each mode lists its stages in [`StageKind`] order and
+/// threads a [`FileState`] through them with [`run_stages`]; `W` is the mode's own state.
+pub trait Stage<W> {
+    fn kind(&self) -> StageKind;
+    fn run(&self, state: &mut FileState<'_, W>) -> Result<()>;
+}
+
+/// Runs `stages` in order, skipping those disabled with `--skip-stage` and friends, and
+/// reports each one to `progress` as it starts.
+pub fn run_stages<W>(
+    stages: &[&dyn Stage<W>],
+    state: &mut FileState<'_, W>,
+    progress: &dyn ProgressReporter,
+) -> Result<()> {
+    for stage in stages {
+        let kind = stage.kind();
+        // Animations passed through untouched only need reading and writing.
+        let untouched =
+            state.passes_through() && !matches!(kind, StageKind::Read | StageKind::Write);
+        if !state.common.skip_stages.contains(&kind) && !untouched {
+            progress.stage(state.path, stage.kind());
+            stage.run(state)?;
+        }
+    }
+    Ok(())
+}
+
+/// An input's bytes: read into memory, or mapped under `--mmap` so the OS pages them in
+/// as they are touched.
+pub enum InputBytes {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl InputBytes {
+    fn read(path: &Path, mmap: bool) -> io::Result<Self> {
+        if !mmap {
+            return throttle::read(path).map(Self::Owned);
+        }
+        let file = File::open(path)?;
+        // SAFETY: `--mmap` documents that inputs must not change while they are processed;
+        // the map is dropped with the file's state.
+        unsafe { Mmap::map(&file) }.map(Self::Mapped)
+    }
+}
+
+impl Deref for InputBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(bytes) => bytes,
+            Self::Mapped(map) => map,
+        }
+    }
+}
+
+/// Everything a file's stages share, plus the mode-specific `work`.
+pub struct FileState<'a, W> {
+    pub path: &'a Path,
+    pub mode: Mode,
+    pub common: &'a CommonOptions,
+    started: Instant,
+    pub output_path: PathBuf,
+    /// Whether an existing output may be replaced: `--overwrite`, or a per-file answer.
+    pub overwrite: bool,
+    pub original_bytes: InputBytes,
+    /// The input after CRC repairs and transforms, when those changed it.
+    pub revised_input: Option<Vec<u8>>,
+    /// The encoded output so far; `None` passes the input through unchanged.
+    pub output: Option<Vec<u8>>,
+    pub notes: Vec<String>,
+    /// Problems worth reporting that don't stop the file; `--warnings-as-errors` fails it.
+    pub warnings: Vec<String>,
+    /// Whether the input is an APNG, as the read stage found it.
+    pub animated: bool,
+    /// Set for `--base64`: the input was handed over in memory and the output stays in
+    /// `output` instead of being written.
+    in_memory: bool,
+    outcome: Option<FileOutcome>,
+    pub work: W,
+}
+
+impl<'a, W> FileState<'a, W> {
+    pub fn new(
+        path: &'a Path,
+        mode: Mode,
+        common: &'a CommonOptions,
+        outputs: &OutputPlan,
+        work: W,
+    ) -> Result<Self> {
+        let output_path = outputs
+            .output_path(path, mode.output_suffix())
+            .with_context(|| format!("computing {} output path", mode.output_label()))?;
+        Ok(Self {
+            path,
+            mode,
+            common,
+            started: Instant::now(),
+            output_path,
+            overwrite: common.overwrite || outputs.may_replace(path),
+            original_bytes: InputBytes::Owned(Vec::new()),
+            revised_input: None,
+            output: None,
+            notes: Vec::new(),
+            warnings: Vec::new(),
+            animated: false,
+            in_memory: false,
+            outcome: None,
+            work,
+        })
+    }
+
+    /// State for `input` read from somewhere other than a file; `label` names it in
+    /// progress output and its output.
+    pub fn in_memory(
+        label: &'a Path,
+        input: Vec<u8>,
+        mode: Mode,
+        common: &'a CommonOptions,
+        work: W,
+    ) -> Self {
+        Self {
+            path: label,
+            mode,
+            common,
+            started: Instant::now(),
+            output_path: label.to_path_buf(),
+            overwrite: true,
+            original_bytes: InputBytes::Owned(input),
+            revised_input: None,
+            output: None,
+            notes: Vec::new(),
+            warnings: Vec::new(),
+            animated: false,
+            in_memory: true,
+            outcome: None,
+            work,
+        }
+    }
+
+    /// The input as later stages should see it.
+    pub fn input(&self) -> &[u8] {
+        self.revised_input
+            .as_deref()
+            .unwrap_or(&self.original_bytes[..])
+    }
+
+    /// Whether `--apng passthrough` leaves this file exactly as it was read.
+    pub fn passes_through(&self) -> bool {
+        self.animated && self.common.apng_policy == ApngPolicy::Passthrough
+    }
+
+    /// The outcome recorded by the write stage.
+    pub fn finish(self) -> FileOutcome {
+        self.outcome.expect("the write stage always runs")
+    }
+
+    /// The outcome and the encoded output of an in-memory file.
+    pub fn finish_in_memory(self) -> (FileOutcome, Vec<u8>) {
+        let output = self
+            .output
+            .expect("the write stage keeps in-memory outputs");
+        (self.outcome.expect("the write stage always runs"), output)
+    }
+}
+
+/// Reads the input (unless it was handed over in memory) and runs the `--crc` and
+/// `--max-pixels` checks shared by both modes.
+pub struct ReadInput;
+
+impl<W> Stage<W> for ReadInput {
+    fn kind(&self) -> StageKind {
+        StageKind::Read
+    }
+
+    fn run(&self, state: &mut FileState<'_, W>) -> Result<()> {
+        let path = state.path;
+        if !state.in_memory {
+            state.original_bytes = InputBytes::read(path, state.common.mmap)
+                .path_context(path)
+                .context("reading input PNG")?;
+        }
+        let (bytes, fixed_crcs) = apply_crc_policy(&state.original_bytes, state.common.crc)
+            .with_context(|| format!("reading chunks of {}", path.display()))?;
+        ensure_within_pixel_limit(&bytes, state.common.max_pixels)
+            .with_context(|| format!("checking dimensions of {}", path.display()))?;
+
+        if fixed_crcs > 0 {
+            state
+                .notes
+                .push(format!("{fixed_crcs} bad CRC(s) rewritten"));
+        }
+        state.animated = chunks::has_chunk(&bytes, b"acTL")?;
+        if state.animated && state.common.apng_policy == ApngPolicy::Flatten {
+            state.revised_input = Some(chunks::drop_animation(&bytes)?);
+            state.animated = false;
+            state
+                .notes
+                .push(String::from("animation flattened to its default image"));
+        } else if let Cow::Owned(repaired) = bytes {
+            state.revised_input = Some(repaired);
+        }
+        Ok(())
+    }
+}
+
+/// The transform stage of modes that work on encoded PNGs: `--apply-orientation`.
+pub struct ApplyOrientation;
+
+impl<W> Stage<W> for ApplyOrientation {
+    fn kind(&self) -> StageKind {
+        StageKind::Transform
+    }
+
+    fn run(&self, state: &mut FileState<'_, W>) -> Result<()> {
+        orient_input(state).map(drop)
+    }
+}
+
+/// Applies `--apply-orientation` to the input, returning the orientation its pixels were
+/// moved by.
+pub fn orient_input<W>(state: &mut FileState<'_, W>) -> Result<Option<u16>> {
+    if !state.common.apply_orientation {
+        return Ok(None);
+    }
+    let Some((orientation, rotated)) = orientation::apply_orientation(state.input())
+        .with_context(|| format!("applying EXIF orientation to {}", state.path.display()))?
+    else {
+        return Ok(None);
+    };
+
+    state.revised_input = Some(rotated);
+    state.notes.push(String::from("EXIF orientation applied"));
+    Ok(Some(orientation))
+}
+
+/// Applies the output-side edits, picks the output format, runs `--post-cmd` and
+/// `--baseline`, then writes the result and its sidecar (unless `--dry-run` or
+/// `--measure-only`), or keeps it in memory for `--base64`.
+pub struct WriteOutput<'o, O> {
+    /// Mode options recorded in the sidecar.
+    pub options: &'o O,
+}
+
+impl<O: Serialize, W> Stage<W> for WriteOutput<'_, O> {
+    fn kind(&self) -> StageKind {
+        StageKind::Write
+    }
+
+    fn run(&self, state: &mut FileState<'_, W>) -> Result<()> {
+        let common = state.common;
+        let encoded = match state.output.take() {
+            Some(output) => output,
+            None => state.input().to_vec(),
+        };
+        let encoded = if state.passes_through() {
+            encoded
+        } else {
+            finish_png(encoded, common)?
+        };
+        if common.stable_palette {
+            ensure_palette_kept(state.input(), &encoded, common.max_pixels)?;
+        }
+        // Other formats would drop the animation.
+        let formats = if state.animated {
+            &[OutputFormat::Png][..]
+        } else {
+            &common.formats
+        };
+        let chosen = choose_smallest(encoded, formats)?;
+        let output_path = output_path_for(&state.output_path, chosen.format);
+        // Remote outputs are checked on the host as they are written; measured ones never are.
+        if !writes_remotely(common) && !state.in_memory && !common.measure_only {
+            ensure_output_available(&output_path, state.overwrite)?;
+        }
+        if common.formats.len() > 1 {
+            state
+                .notes
+                .push(format!("kept {}", chosen.format.extension()));
+        }
+        if let Some(note) = chosen.passed_over {
+            state.notes.push(note);
+        }
+
+        let mut bytes = chosen.bytes;
+        if !common.dry_run {
+            run_post_command(
+                &output_path,
+                &mut bytes,
+                state.mode,
+                common,
+                &mut state.notes,
+            );
+        }
+        let mut skipped = None;
+        // A file on disk already holding `bytes`, which the output can share blocks with.
+        let mut kept_file = None;
+        if let Some((note, baseline)) =
+            apply_baseline(&output_path, &mut bytes, state.input(), state.mode, common)?
+        {
+            state.notes.push(note);
+            skipped = Some(SkipReason::NoSavings);
+            kept_file = Some(baseline);
+        }
+        if common.no_write_larger
+            && chosen.format == OutputFormat::Png
+            && bytes.len() > state.original_bytes.len()
+        {
+            // The file as read, not the copy earlier stages repaired or rotated.
+            bytes = state.original_bytes.to_vec();
+            state
+                .notes
+                .push(String::from("larger than input, original kept"));
+            skipped = Some(SkipReason::NoSavings);
+            kept_file = (!state.in_memory).then(|| state.path.to_path_buf());
+        }
+        if common.validate_output && chosen.format == OutputFormat::Png {
+            let violations = chunks::conformance_violations(&bytes)?;
+            if !violations.is_empty() {
+                bail!("output fails PNG validation: {}", violations.join("; "));
+            }
+        }
+        if chosen.format == OutputFormat::Png {
+            let detected =
+                warnings::detect(state.input(), &bytes, common.normalize_colorspace.is_some())?;
+            state.warnings.extend(detected);
+        }
+        let warnings = std::mem::take(&mut state.warnings);
+        if common.warnings_as_errors && !warnings.is_empty() {
+            bail!("warnings treated as errors: {}", warnings.join("; "));
+        }
+        let output_size = bytes.len() as u64;
+        let output_sha256 = sha256_hex(&bytes);
+        let notes = std::mem::take(&mut state.notes);
+
+        let notes = if common.dry_run {
+            Some(dry_run_notes(notes))
+        } else if state.in_memory {
+            state.output = Some(bytes);
+            join_notes(notes)
+        } else if common.measure_only {
+            join_notes(notes)
+        } else if let Some(written) =
+            write_remote_output(common, state.mode, &output_path, &bytes, state.overwrite)
+        {
+            written?;
+            join_notes(notes)
+        } else {
+            write_output(
+                &output_path,
+                &bytes,
+                common,
+                state.overwrite,
+                kept_file.as_deref(),
+            )
+            .with_context(|| {
+                format!(
+                    "writing {} PNG {}",
+                    state.mode.output_label(),
+                    output_path.display()
+                )
+            })?;
+            if common.emit_sidecar {
+                let sidecar = Sidecar {
+                    warnings: &warnings,
+                    ..Sidecar::new(
+                        state.mode,
+                        state.path,
+                        &state.original_bytes,
+                        output_size,
+                        common.keep_metadata,
+                        self.options,
+                        &notes,
+                    )
+                };
+                write_sidecar(&output_path, &sidecar)?;
+            }
+            if let Some(checksums) = common.checksums
+                && checksums.per_file
+            {
+                write_file_checksum(&output_path, &output_sha256, checksums.algorithm)?;
+            }
+            join_notes(notes)
+        };
+
+        state.outcome = Some(FileOutcome {
+            output_path,
+            output_sha256,
+            original_size: state.original_bytes.len() as u64,
+            output_size,
+            elapsed: state.started.elapsed(),
+            notes,
+            warnings,
+            skipped,
+        });
+        Ok(())
+    }
+}
+
+/// Whether `--output` sends outputs to a remote host instead of the local filesystem.
+#[cfg(feature = "remote")]
+fn writes_remotely(common: &CommonOptions) -> bool {
+    common.remote.is_some()
+}
+
+#[cfg(not(feature = "remote"))]
+fn writes_remotely(_common: &CommonOptions) -> bool {
+    false
+}
+
+/// Writes `bytes` to `path` on the `--output` host, or returns `None` when outputs are
+/// written locally.
+#[cfg(feature = "remote")]
+fn write_remote_output(
+    common: &CommonOptions,
+    mode: Mode,
+    path: &Path,
+    bytes: &[u8],
+    overwrite: bool,
+) -> Option<Result<()>> {
+    let remote = common.remote.as_ref()?;
+    Some(
+        write_remote(remote, path, bytes, overwrite).with_context(|| {
+            format!(
+                "writing {} PNG {}:{}",
+                mode.output_label(),
+                remote.host,
+                path.display()
+            )
+        }),
+    )
+}
+
+#[cfg(not(feature = "remote"))]
+fn write_remote_output(
+    _common: &CommonOptions,
+    _mode: Mode,
+    _path: &Path,
+    _bytes: &[u8],
+    _overwrite: bool,
+) -> Option<Result<()>> {
+    None
+}
+
+/// Whether `--time-budget` has run out, so no further files should be started. Time spent
+/// paused doesn't count.
+pub fn time_budget_exhausted(common: &CommonOptions) -> bool {
+    common
+        .deadline
+        .is_some_and(|deadline| Instant::now() >= deadline + interrupt::paused_time())
+}
+
+/// DSSIM slack within which a compressed output counts as no worse than its baseline.
+const BASELINE_DSSIM_TOLERANCE: f64 = 0.001;
+
+/// Applies `--baseline`: swaps `bytes` for the same-named baseline file when the new
+/// output isn't smaller or, in compress mode, strays further from `source` than the
+/// baseline does. Returns a note when the baseline was kept.
+pub fn apply_baseline(
+    output_path: &Path,
+    bytes: &mut Vec<u8>,
+    source: &[u8],
+    mode: Mode,
+    common: &CommonOptions,
+) -> Result<Option<(String, PathBuf)>> {
+    let Some(dir) = &common.baseline else {
+        return Ok(None);
+    };
+    let Some(name) = output_path.file_name() else {
+        return Ok(None);
+    };
+    let path = dir.join(name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let baseline = fs::read(&path)
+        .path_context(&path)
+        .context("reading baseline")?;
+
+    let reason = if bytes.len() >= baseline.len() {
+        "no smaller than"
+    } else if mode == Mode::Compress && looks_worse(source, bytes, &baseline, common.max_pixels) {
+        "looks worse than"
+    } else {
+        return Ok(None);
+    };
+
+    *bytes = baseline;
+    Ok(Some((format!("{reason} baseline, kept baseline"), path)))
+}
+
+/// Whether `candidate` is perceptibly further from `source` than `baseline` is. Outputs
+/// that can't be decoded as PNG, or a baseline of different dimensions, never count as worse.
+fn looks_worse(source: &[u8], candidate: &[u8], baseline: &[u8], max_pixels: u64) -> bool {
+    let decode = |bytes: &[u8]| decode_rgba(bytes, max_pixels).ok();
+    let (Some(source), Some(candidate), Some(baseline)) =
+        (decode(source), decode(candidate), decode(baseline))
+    else {
+        return false;
+    };
+    match (dssim(&source, &candidate), dssim(&source, &baseline)) {
+        (Some(new), Some(old)) => new > old + BASELINE_DSSIM_TOLERANCE,
+        _ => false,
+    }
+}
+
+/// Applies `--post-cmd`: runs the command on a temporary copy of the output and adopts
+/// the processed file when it is smaller (and still a valid PNG for PNG outputs, showing
+/// the same pixels in optimize mode). Problems and anything the command printed to stderr
+/// are added to `notes` rather than failing the file.
+pub fn run_post_command(
+    output_path: &Path,
+    bytes: &mut Vec<u8>,
+    mode: Mode,
+    common: &CommonOptions,
+    notes: &mut Vec<String>,
+) {
+    let Some(command) = common.post_cmd.as_deref() else {
+        return;
+    };
+    let lossless = mode == Mode::Optimize;
+    match post_process(output_path, bytes, command, lossless, common) {
+        Ok((processed, stderr)) => {
+            if let Some(stderr) = stderr {
+                notes.push(format!("post-cmd stderr: {stderr}"));
+            }
+            match processed {
+                Some(processed) => {
+                    let saved = bytes.len() - processed.len();
+                    *bytes = processed;
+                    notes.push(format!("post-cmd saved {}", format_bytes(saved as u64)));
+                }
+                None => notes.push(String::from("post-cmd output not smaller, kept ours")),
+            }
+        }
+        Err(err) => notes.push(format!("post-cmd failed ({err:#}), kept ours")),
+    }
+}
+
+/// Runs `command` over a temporary copy of `bytes`, returning the processed file when it
+/// is smaller, with the command's stderr when it printed any.
+fn post_process(
+    output_path: &Path,
+    bytes: &[u8],
+    command: &str,
+    lossless: bool,
+    common: &CommonOptions,
+) -> Result<(Option<Vec<u8>>, Option<String>)> {
+    let staging = match common.temp_dir.as_deref() {
+        Some(dir) => dir,
+        None => output_path.parent().unwrap_or_else(|| Path::new(".")),
+    };
+    // Keep the extension: external tools often infer the format from it.
+    let suffix = output_path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let temp_file = TempFileBuilder::new()
+        .prefix(".png-post-")
+        .suffix(&suffix)
+        .tempfile_in(staging)
+        .with_context(|| format!("creating temporary file in {}", staging.display()))?;
+    let temp_path = temp_file.path().to_path_buf();
+    interrupt::track_temp_file(&temp_path);
+    let result = run_on_temp_file(temp_file, bytes, command);
+    interrupt::untrack_temp_file(&temp_path);
+    let (processed, stderr) = result?;
+
+    if bytes.starts_with(&PNG_SIGNATURE) {
+        for chunk in Chunks::new(&processed).context("command output is not a PNG")? {
+            chunk.context("command output is not a valid PNG")?;
+        }
+        if lossless
+            && !same_pixels(bytes, &processed, common.max_pixels)
+                .context("decoding command output")?
+        {
+            bail!("command changed the pixels");
+        }
+    }
+
+    Ok(((processed.len() < bytes.len()).then_some(processed), stderr))
+}
+
+/// Writes `bytes` to `temp_file`, runs `command` on it and reads the result back before
+/// the file is removed.
+fn run_on_temp_file(
+    mut temp_file: NamedTempFile,
+    bytes: &[u8],
+    command: &str,
+) -> Result<(Vec<u8>, Option<String>)> {
+    temp_file.write_all(bytes)?;
+    temp_file.flush()?;
+
+    let output = shell_command(&command.replace("{out}", &shell_quote(temp_file.path())))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("starting command")?;
+    let stderr = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ");
+    let stderr = (!stderr.is_empty()).then_some(stderr);
+    if !output.status.success() {
+        match stderr {
+            Some(stderr) => bail!("{}: {stderr}", output.status),
+            None => bail!("{}", output.status),
+        }
+    }
+
+    let processed = fs::read(temp_file.path()).context("reading processed output")?;
+    if processed.is_empty() {
+        bail!("command left an empty file");
+    }
+    Ok((processed, stderr))
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> process::Command {
+    let mut shell = process::Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> process::Command {
+    let mut shell = process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(unix)]
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+#[cfg(not(unix))]
+fn shell_quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
+
+/// Overrides a mode's deflate settings with `--deflater`/`--compression-level`.
+///
+/// oxipng has no zlib backend, so for zlib it evaluates with libdeflate and `finish_png`
+/// recompresses the chosen image data with zlib afterwards.
+pub fn apply_deflater(options: &mut Options, common: &CommonOptions) {
+    options.deflate = match common.deflater {
+        None => return,
+        Some((Deflater::Zopfli, iterations)) => strongest_deflater(iterations),
+        Some((Deflater::Libdeflate, level)) => Deflaters::Libdeflater { compression: level },
+        Some((Deflater::Zlib, _)) => Deflaters::Libdeflater { compression: 12 },
+    };
+}
+
+/// Runs oxipng over `data`, inside the `--intra-threads` pool when there is one so its
+/// trials on this file use no more threads than that.
+pub fn optimize_png(data: &[u8], options: &Options, common: &CommonOptions) -> Result<Vec<u8>> {
+    let optimize = || oxipng::optimize_from_memory(data, options);
+    match &common.intra_pool {
+        Some(pool) => pool.install(optimize),
+        None => optimize(),
+    }
+    .map_err(TurboPngError::Deflate)
+}
+
+/// What oxipng did to turn `before` into `after`, for the per-file notes: color type, bit
+/// depth and interlacing changes, the row filters it settled on and the deflater used.
+pub fn reduction_notes(
+    before: &[u8],
+    after: &[u8],
+    options: &Options,
+    common: &CommonOptions,
+) -> Result<Vec<String>> {
+    if before == after {
+        return Ok(vec![String::from("oxipng found nothing smaller")]);
+    }
+    let mut notes = Vec::new();
+    let (from_color, from_depth) = chunks::pixel_format(before)?;
+    let (to_color, to_depth) = chunks::pixel_format(after)?;
+    if from_color != to_color {
+        notes.push(format!(
+            "{}→{}",
+            color_type_label(from_color),
+            color_type_label(to_color)
+        ));
+    }
+    if from_depth != to_depth {
+        notes.push(format!("{from_depth}→{to_depth} bit"));
+    }
+    match (
+        chunks::is_interlaced(before)?,
+        chunks::is_interlaced(after)?,
+    ) {
+        (true, false) => notes.push(String::from("interlacing removed")),
+        (false, true) => notes.push(String::from("interlaced")),
+        _ => {}
+    }
+
+    let mut filters = chunks::row_filters(after)?;
+    filters.sort_unstable();
+    filters.dedup();
+    let filters: Vec<_> = filters.into_iter().map(filter_label).collect();
+    notes.push(format!("filters={}", filters.join("+")));
+    // `--deflater zlib` recompresses oxipng's output afterwards.
+    let deflater = match (common.deflater, options.deflate) {
+        (Some((Deflater::Zlib, _)), _) => "zlib",
+        (_, Deflaters::Libdeflater { .. }) => "libdeflate",
+        #[cfg(feature = "zopfli")]
+        (_, Deflaters::Zopfli { .. }) => "zopfli",
+    };
+    notes.push(String::from(deflater));
+    Ok(notes)
+}
+
+fn color_type_label(color_type: u8) -> &'static str {
+    match color_type {
+        0 => "Grayscale",
+        2 => "RGB",
+        3 => "Indexed",
+        4 => "GrayscaleAlpha",
+        6 => "RGBA",
+        _ => "unknown",
+    }
+}
+
+fn filter_label(filter: u8) -> &'static str {
+    match filter {
+        0 => "none",
+        1 => "sub",
+        2 => "up",
+        3 => "average",
+        4 => "paeth",
+        _ => "invalid",
+    }
+}
+
+/// Zopfli at `iterations`, or libdeflate's highest level in builds without the `zopfli`
+/// feature, where explicit Zopfli requests are rejected up front.
+#[cfg_attr(not(feature = "zopfli"), allow(unused_variables))]
+pub fn strongest_deflater(iterations: u8) -> Deflaters {
+    #[cfg(feature = "zopfli")]
+    {
+        Deflaters::Zopfli {
+            iterations: NonZeroU8::new(iterations).expect("iterations are at least 1"),
+        }
+    }
+    #[cfg(not(feature = "zopfli"))]
+    {
+        Deflaters::Libdeflater { compression: 12 }
+    }
+}
+
+/// Applies `--strip-nondeterministic`: no time limit, and every filter and deflater trial
+/// runs to completion instead of being cut short by a faster estimate.
+pub fn pin_deflate_strategy(options: &mut Options) {
+    options.timeout = None;
+    options.fast_evaluation = false;
+}
+
+/// Applies `--only-deflate`: keeps the color type, bit depth, palette and interlacing so
+/// only the filters and DEFLATE stream change.
+pub fn keep_pixel_format(options: &mut Options) {
+    options.bit_depth_reduction = false;
+    options.color_type_reduction = false;
+    options.palette_reduction = false;
+    options.grayscale_reduction = false;
+    options.scale_16 = false;
+    options.optimize_alpha = false;
+    options.interlace = None;
+}
+
+/// Applies output-side edits (`--deflater zlib`, `--set-dpi`, `--update-time`, `--keep-text`,
+/// `--set-text`, `--embed-icc`, `--normalize-colorspace`,
+/// `--set-background`/`--strip-background`, `--strip-nondeterministic`) to an encoded PNG.
+pub fn finish_png(mut bytes: Vec<u8>, common: &CommonOptions) -> Result<Vec<u8>> {
+    if let Some((Deflater::Zlib, level)) = common.deflater {
+        bytes = chunks::recompress_image_data(&bytes, level)?;
+    }
+    if let Some(dpi) = common.set_dpi {
+        bytes = chunks::set_physical_dpi(&bytes, dpi)?;
+    }
+    if common.update_time {
+        bytes = chunks::set_modification_time(&bytes, SystemTime::now())?;
+    }
+    if !common.keep_metadata && !common.keep_text.is_empty() {
+        bytes = chunks::retain_text(&bytes, &common.keep_text)?;
+    }
+    for (keyword, text) in &common.set_text {
+        bytes = chunks::set_text(&bytes, keyword, text)?;
+    }
+    if let Some(profile) = &common.icc_profile {
+        bytes = chunks::embed_icc(&bytes, profile)?;
+    }
+    if let Some(intent) = common.normalize_colorspace {
+        bytes = chunks::normalize_colorspace(&bytes, intent.code())?;
+    }
+    if common.strip_background {
+        bytes = chunks::set_background(&bytes, None)?;
+    } else if let Some(color) = common.set_background {
+        bytes = chunks::set_background(&bytes, Some(color))?;
+    }
+    if let Some(compat) = common.compat {
+        bytes = chunks::improve_compatibility(&bytes, compat == Compat::Strict)?;
+    }
+    if common.strip_nondeterministic {
+        bytes = chunks::strip_nondeterministic(&bytes)?;
+    }
+
+    Ok(bytes)
+}
+
+/// Applies `--stable-palette`: fails unless an indexed `source` comes out with the same
+/// palette, in the same order, and the same index for every pixel.
+pub fn ensure_palette_kept(source: &[u8], output: &[u8], max_pixels: u64) -> Result<()> {
+    let Some(before) = decode_indexed(source, max_pixels)? else {
+        return Ok(());
+    };
+    let Some(after) = decode_indexed(output, max_pixels)? else {
+        bail!("--stable-palette: the output is no longer indexed");
+    };
+    if after.palette != before.palette {
+        bail!(
+            "--stable-palette: the {}-entry palette would become a different {}-entry one",
+            before.palette.len(),
+            after.palette.len()
+        );
+    }
+    if after.indices != before.indices {
+        bail!("--stable-palette: pixel indices would change");
+    }
+
+    Ok(())
+}
+
+/// Applies `--crc` to the raw input: validates it, or rewrites bad CRCs so the decoders
+/// downstream accept the file. Returns the bytes to process and how many CRCs were rewritten.
+pub fn apply_crc_policy(bytes: &[u8], policy: CrcPolicy) -> Result<(Cow<'_, [u8]>, usize)> {
+    if policy == CrcPolicy::Strict {
+        for chunk in Chunks::new(bytes)? {
+            chunk?;
+        }
+        return Ok((Cow::Borrowed(bytes), 0));
+    }
+
+    let mut repaired = PNG_SIGNATURE.to_vec();
+    let mut fixed = 0;
+    for chunk in Chunks::new(bytes)?.lenient() {
+        let chunk = chunk?;
+        if chunk.crc_valid {
+            repaired.extend_from_slice(chunk.raw);
+            continue;
+        }
+        if policy == CrcPolicy::Fix && chunk.is_critical() {
+            bail!(
+                "critical {} chunk has a bad CRC and can't be repaired (use --crc ignore to process anyway)",
+                ChunkName(&chunk.name)
+            );
+        }
+
+        let (header, _) = chunk.raw.split_at(chunk.raw.len() - 4);
+        repaired.extend_from_slice(header);
+        repaired.extend_from_slice(&chunk_crc(&chunk.name, chunk.data).to_be_bytes());
+        fixed += 1;
+    }
+
+    Ok((Cow::Owned(repaired), fixed))
+}
+
+/// Fails before any pixel buffer is allocated when `IHDR` declares more than `max_pixels`.
+pub fn ensure_within_pixel_limit(bytes: &[u8], max_pixels: u64) -> Result<()> {
+    let (width, height) = chunks::dimensions(bytes)?;
+    if u64::from(width) * u64::from(height) > max_pixels {
+        return Err(TurboPngError::Limits {
+            width,
+            height,
+            max_pixels,
+        });
+    }
+
+    Ok(())
+}
+
 pub fn derive_output_path(input: &Path, suffix: &str) -> Result<PathBuf> {
     let parent = input.parent().unwrap_or_else(|| Path::new("."));
     let stem = input
         .file_stem()
         .and_then(|s| s.to_str())
-        .ok_or_else(|| anyhow!("input file {:?} lacks a valid stem", input))?;
-    Ok(parent.join(format!("{stem}{suffix}")))
+        .ok_or_else(|| format_err!("input file {:?} lacks a valid stem", input))?;
+    Ok(parent.join(format!("{stem}{}", output_suffix(input, suffix))))
+}
+
+/// The mode's output `suffix` for `input`: `.apng` inputs keep that extension.
+pub fn output_suffix<'s>(input: &Path, suffix: &'s str) -> Cow<'s, str> {
+    match suffix.strip_suffix(".png") {
+        Some(base) if has_apng_extension(input) => Cow::Owned(format!("{base}.apng")),
+        _ => Cow::Borrowed(suffix),
+    }
+}
+
+pub fn ensure_output_available(path: &Path, overwrite: bool) -> Result<()> {
+    if path.exists() && !overwrite {
+        return Err(TurboPngError::OutputConflict {
+            path: path.to_path_buf(),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
 }
 
 pub fn write_atomic(path: &Path, data: &[u8], overwrite: bool) -> Result<()> {
+    write_atomic_with(
+        path,
+        data,
+        WriteOptions {
+            overwrite,
+            ..WriteOptions::default()
+        },
+    )
+}
+
+/// Writes an optimize/compress output, honouring `--temp-dir` and `--fsync`. `kept_file`
+/// is a file already holding `data`, such as a kept baseline.
+pub fn write_output(
+    path: &Path,
+    data: &[u8],
+    common: &CommonOptions,
+    overwrite: bool,
+    kept_file: Option<&Path>,
+) -> Result<()> {
+    write_atomic_with(
+        path,
+        data,
+        WriteOptions {
+            overwrite,
+            temp_dir: common.temp_dir.as_deref(),
+            fsync: common.fsync,
+            clone_from: kept_file,
+        },
+    )
+}
+
+/// How `write_atomic_with` stages and persists a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions<'a> {
+    pub overwrite: bool,
+    /// Directory for the temporary file; defaults to the output's parent.
+    pub temp_dir: Option<&'a Path>,
+    /// Flush the file's data, and then its directory entry, to disk.
+    pub fsync: bool,
+    /// A file holding exactly `data`, to reflink instead of writing `data` out where the
+    /// filesystem supports it.
+    pub clone_from: Option<&'a Path>,
+}
+
+/// Writes `data` to a temporary file and renames it over `path`.
+pub fn write_atomic_with(path: &Path, data: &[u8], options: WriteOptions<'_>) -> Result<()> {
     let parent = path
         .parent()
-        .ok_or_else(|| anyhow!("cannot determine parent directory for {}", path.display()))?;
+        .ok_or_else(|| format_err!("cannot determine parent directory for {}", path.display()))?;
 
     if !parent.exists() {
         fs::create_dir_all(parent)
-            .with_context(|| format!("creating output directory {}", parent.display()))?;
+            .path_context(parent)
+            .context("creating output directory")?;
     }
 
-    let mut temp_file = TempFileBuilder::new()
+    let staging = options.temp_dir.unwrap_or(parent);
+    let temp_file = TempFileBuilder::new()
         .prefix(".png-opt-")
         .suffix(".tmp")
-        .tempfile_in(parent)
-        .with_context(|| format!("creating temporary file in {}", parent.display()))?;
+        .tempfile_in(staging)
+        .with_context(|| format!("creating temporary file in {}", staging.display()))?;
+    let temp_path = temp_file.path().to_path_buf();
+    interrupt::track_temp_file(&temp_path);
+    let result = write_temp_file(temp_file, path, data, options);
+    interrupt::untrack_temp_file(&temp_path);
+    result?;
 
-    temp_file
-        .write_all(data)
-        .with_context(|| format!("writing temporary output for {}", path.display()))?;
+    if options.fsync {
+        sync_directory(parent)?;
+    }
+
+    Ok(())
+}
+
+fn write_temp_file(
+    mut temp_file: NamedTempFile,
+    path: &Path,
+    data: &[u8],
+    options: WriteOptions<'_>,
+) -> Result<()> {
+    let cloned = options
+        .clone_from
+        .is_some_and(|source| clone_file(source, temp_file.as_file()).is_ok());
+    if !cloned {
+        throttle::write_all(&mut temp_file, data)
+            .with_context(|| format!("writing temporary output for {}", path.display()))?;
+    }
 
     temp_file
         .flush()
         .with_context(|| format!("flushing temporary file for {}", path.display()))?;
 
-    if overwrite && path.exists() {
-        fs::remove_file(path)
-            .with_context(|| format!("removing existing file {}", path.display()))?;
+    if options.fsync {
+        temp_file
+            .as_file()
+            .sync_all()
+            .with_context(|| format!("syncing temporary file for {}", path.display()))?;
     }
 
-    temp_file
-        .persist(path)
-        .map_err(|err| err.error)
-        .with_context(|| format!("persisting optimized file {}", path.display()))?;
+    // The rename replaces an existing output, which stays intact until it succeeds.
+    match temp_file.persist(path) {
+        Ok(_) => {}
+        // A `--temp-dir` on another filesystem can't be renamed into place, and the output's
+        // own directory may not hold temporary files, so copy the staged data over instead.
+        Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+            copy_staged_file(err.file.path(), path, options)
+                .with_context(|| format!("copying optimized file to {}", path.display()))?;
+        }
+        Err(err) => {
+            return Err(err.error)
+                .with_context(|| format!("persisting optimized file {}", path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a staged file over `path`, refusing to replace an existing file unless
+/// `options.overwrite` allows it.
+fn copy_staged_file(staged: &Path, path: &Path, options: WriteOptions<'_>) -> io::Result<()> {
+    let mut destination = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!options.overwrite)
+        .open(path)?;
+    io::copy(&mut File::open(staged)?, &mut destination)?;
+    if options.fsync {
+        destination.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Makes the empty `destination` share `source`'s data blocks, a reflink, on filesystems
+/// that support it such as btrfs and XFS. Elsewhere it fails and leaves `destination`
+/// untouched.
+#[cfg(target_os = "linux")]
+fn clone_file(source: &Path, destination: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    use nix::libc;
+
+    let source = File::open(source)?;
+    // SAFETY: FICLONE only reads the source descriptor, and both stay open for the call.
+    if unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_source: &Path, _destination: &File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Makes a rename into `dir` durable by syncing the directory itself.
+#[cfg(unix)]
+fn sync_directory(dir: &Path) -> Result<()> {
+    fs::File::open(dir)
+        .and_then(|handle| handle.sync_all())
+        .path_context(dir)
+        .context("syncing directory")
+}
 
+/// Directories can't be opened for syncing here; the file data itself is already synced.
+#[cfg(not(unix))]
+fn sync_directory(_dir: &Path) -> Result<()> {
     Ok(())
 }
@@ -0,0 +1,38 @@
+use crate::error::{Result, bail};
+
+/// Applies `--nice`: raises the process's niceness by `increment` on Unix, or moves it
+/// into the background priority class on Windows. Threads started afterwards, the worker
+/// pools included, inherit the lower priority.
+#[cfg(unix)]
+pub fn lower(increment: u8) -> Result<()> {
+    use nix::errno::Errno;
+    use nix::libc;
+
+    // `nice` returns the new niceness, which may legitimately be -1, so errors are only
+    // told apart through errno.
+    Errno::clear();
+    // SAFETY: `nice` only adjusts the scheduling priority of the calling thread.
+    let niceness = unsafe { libc::nice(i32::from(increment)) };
+    if niceness == -1 && Errno::last_raw() != 0 {
+        bail!("--nice {increment}: {}", Errno::last());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lower(_increment: u8) -> Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, PROCESS_MODE_BACKGROUND_BEGIN, SetPriorityClass,
+    };
+
+    // SAFETY: the pseudo handle from `GetCurrentProcess` is always valid for this process.
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        bail!("--nice: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower(_increment: u8) -> Result<()> {
+    bail!("--nice is only supported on Unix and Windows")
+}
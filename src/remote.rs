@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::cli::RemoteTarget;
+use crate::error::{Context, Result, TurboPngError, bail};
+
+/// Exit status the remote script uses when the output exists and may not be replaced.
+const EXISTS_STATUS: i32 = 17;
+
+/// Streams `data` to `path` on `target`'s host through `--ssh-command`, staging it
+/// under a temporary name first so a dropped connection never leaves a partial output.
+/// The staging and rename run as a script in the remote user's POSIX shell.
+pub fn write_remote(
+    target: &RemoteTarget,
+    path: &Path,
+    data: &[u8],
+    overwrite: bool,
+) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("remote output {} has no file name", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let temp = dir.join(format!(".{}.turbo-png.tmp", name.to_string_lossy()));
+    let guard = if overwrite {
+        String::new()
+    } else {
+        format!(
+            "{{ [ ! -e {path} ] || exit {EXISTS_STATUS}; }} && ",
+            path = quote(path)
+        )
+    };
+    let script = format!(
+        "mkdir -p {dir} && {guard}{{ cat > {temp} && mv -f {temp} {path} || {{ rm -f {temp}; exit 1; }}; }}",
+        dir = quote(dir),
+        temp = quote(&temp),
+        path = quote(path),
+    );
+
+    let words = shell_words::split(&target.ssh_command).context("parsing --ssh-command")?;
+    let (program, options) = words.split_first().context("--ssh-command is empty")?;
+    let mut command = Command::new(program);
+    command.args(options);
+    if let Some(port) = target.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    let mut child = command
+        .arg(&target.host)
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {program}"))?;
+    let written = child.stdin.take().expect("stdin is piped").write_all(data);
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("waiting for {program}"))?;
+
+    match output.status.code() {
+        Some(0) => {}
+        Some(EXISTS_STATUS) => {
+            return Err(TurboPngError::OutputConflict {
+                path: path.to_path_buf(),
+            });
+        }
+        _ => bail!(
+            "{program} to {} exited with {}: {}",
+            target.host,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+    written.context("streaming output to the remote host")
+}
+
+/// Single-quotes `path` for the remote POSIX shell.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context, Result};
+use crate::pipeline::{OutputPlan, RunSummary, write_atomic};
+use crate::ui::SkipReason;
+
+/// Writes one `input,output,status` row per skipped file, then per target in processing
+/// order. Status is `written`, `failed`, or `skipped` for files left out before the run
+/// (`--only-if-newer`, `--changed-since`, interactive skips and the like) or that an
+/// interrupt or `--time-budget` never let start; those list the output they would have been
+/// written to. `summary` is `None` when nothing was left to process.
+pub fn write_rename_map(
+    path: &Path,
+    skipped: &[(PathBuf, SkipReason)],
+    targets: &[PathBuf],
+    outputs: &OutputPlan,
+    suffix: &str,
+    summary: Option<&RunSummary>,
+) -> Result<()> {
+    let written: HashMap<&Path, &Path> = summary
+        .into_iter()
+        .flat_map(|summary| &summary.processed)
+        .map(|file| (file.input.as_path(), file.outcome.output_path.as_path()))
+        .collect();
+    let failed: HashSet<&Path> = summary
+        .into_iter()
+        .flat_map(|summary| &summary.failed)
+        .map(|(input, _)| input.as_path())
+        .collect();
+
+    let mut csv = String::from("input,output,status\n");
+    for (input, _) in skipped {
+        let output = outputs.output_path(input, suffix)?;
+        csv.push_str(&format!(
+            "{},{},skipped\n",
+            csv_field(input),
+            csv_field(&output)
+        ));
+    }
+    for input in targets {
+        let (output, status) = match written.get(input.as_path()) {
+            Some(output) => (output.to_path_buf(), "written"),
+            None => {
+                let status = if failed.contains(input.as_path()) {
+                    "failed"
+                } else {
+                    "skipped"
+                };
+                (outputs.output_path(input, suffix)?, status)
+            }
+        };
+        csv.push_str(&format!(
+            "{},{},{status}\n",
+            csv_field(input),
+            csv_field(&output)
+        ));
+    }
+
+    write_atomic(path, csv.as_bytes(), true)
+        .with_context(|| format!("writing rename map {}", path.display()))
+}
+
+/// Quotes a path for CSV when it contains a comma, quote or line break.
+fn csv_field(path: &Path) -> String {
+    let text = path.display().to_string();
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::cli::Mode;
+use crate::error::{Context, Result};
+use crate::pipeline::{sha256_hex, write_atomic};
+
+/// Per-output record written next to each result as `<output>.json`.
+#[derive(Debug, Serialize)]
+pub struct Sidecar<'a, O: Serialize> {
+    pub tool_version: &'static str,
+    pub mode: Mode,
+    pub input: &'a Path,
+    pub original_sha256: String,
+    pub original_size: u64,
+    pub output_size: u64,
+    pub saved_bytes: i64,
+    pub saved_percent: f64,
+    pub keep_metadata: bool,
+    pub options: &'a O,
+    /// What happened to the file, as shown after its result.
+    pub notes: &'a [String],
+    /// Problems reported alongside the result.
+    pub warnings: &'a [String],
+}
+
+impl<'a, O: Serialize> Sidecar<'a, O> {
+    pub fn new(
+        mode: Mode,
+        input: &'a Path,
+        original: &[u8],
+        output_size: u64,
+        keep_metadata: bool,
+        options: &'a O,
+        notes: &'a [String],
+    ) -> Self {
+        let original_size = original.len() as u64;
+        let saved_bytes = original_size as i64 - output_size as i64;
+        let saved_percent = if original_size == 0 {
+            0.0
+        } else {
+            saved_bytes as f64 / original_size as f64 * 100.0
+        };
+
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            mode,
+            input,
+            original_sha256: sha256_hex(original),
+            original_size,
+            output_size,
+            saved_bytes,
+            saved_percent,
+            keep_metadata,
+            options,
+            notes,
+            warnings: &[],
+        }
+    }
+}
+
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+pub fn write_sidecar<O: Serialize>(output: &Path, sidecar: &Sidecar<'_, O>) -> Result<()> {
+    let path = sidecar_path(output);
+    let mut json = serde_json::to_vec_pretty(sidecar).context("serializing sidecar")?;
+    json.push(b'\n');
+    write_atomic(&path, &json, true).with_context(|| format!("writing sidecar {}", path.display()))
+}
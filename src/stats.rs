@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::chunks::{ChunkName, Chunks, PNG_SIGNATURE};
+use crate::cli::{StatsArgs, Toggle};
+use crate::error::{Context, Result, bail};
+use crate::inputs::{WalkOptions, is_png, walk_files};
+use crate::ui::format_bytes;
+
+/// Totals for one chunk type across the corpus.
+#[derive(Default)]
+struct ChunkTotals {
+    files: usize,
+    count: usize,
+    /// Whole chunks: length, name, data and CRC.
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct Corpus {
+    files: usize,
+    bytes: u64,
+    unreadable: usize,
+    chunks: BTreeMap<[u8; 4], ChunkTotals>,
+    color_types: BTreeMap<u8, usize>,
+    bit_depths: BTreeMap<u8, usize>,
+    interlaced: usize,
+}
+
+/// Prints where the bytes of every PNG under `args.paths` go, by chunk type, color type,
+/// bit depth and interlacing. Nothing is written.
+pub fn run(args: &StatsArgs) -> Result<()> {
+    let files = collect(&args.paths, &args.extensions, walk_options(args))?;
+    if files.is_empty() {
+        bail!("no PNG files found in the provided paths");
+    }
+
+    let mut corpus = Corpus::default();
+    for file in &files {
+        if let Err(err) = scan(file, &mut corpus) {
+            corpus.unreadable += 1;
+            eprintln!("skipping {}: {err:#}", file.display());
+        }
+    }
+    print_report(&corpus);
+
+    Ok(())
+}
+
+fn walk_options(args: &StatsArgs) -> WalkOptions {
+    WalkOptions {
+        follow_symlinks: args.follow_symlinks == Toggle::On,
+        respect_gitignore: args.respect_gitignore,
+        skip_hidden: args.skip_hidden,
+    }
+}
+
+fn collect(paths: &[PathBuf], extensions: &[String], walk: WalkOptions) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.exists() {
+            bail!("input path {:?} does not exist", path);
+        }
+        if !path.is_dir() {
+            if is_png(path, extensions) {
+                files.push(path.clone());
+            }
+            continue;
+        }
+
+        walk_files(path, walk, |file| {
+            if is_png(file, extensions) {
+                files.push(file.to_path_buf());
+            }
+        });
+    }
+
+    // Overlapping arguments shouldn't count a file twice.
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.canonicalize().unwrap_or_else(|_| file.clone())));
+    Ok(files)
+}
+
+fn scan(path: &Path, corpus: &mut Corpus) -> Result<()> {
+    let bytes = fs::read(path).context("reading PNG")?;
+    // Tally a file only once all of its chunks parse, so a truncated one doesn't skew
+    // the totals. CRCs don't matter for sizes.
+    let mut chunks = Vec::new();
+    for chunk in Chunks::new(&bytes)?.lenient() {
+        chunks.push(chunk?);
+    }
+    let header = chunks
+        .first()
+        .filter(|chunk| &chunk.name == b"IHDR" && chunk.data.len() == 13)
+        .context("PNG does not start with a valid IHDR chunk")?
+        .data;
+
+    corpus.files += 1;
+    corpus.bytes += bytes.len() as u64;
+    *corpus.bit_depths.entry(header[8]).or_default() += 1;
+    *corpus.color_types.entry(header[9]).or_default() += 1;
+    if header[12] == 1 {
+        corpus.interlaced += 1;
+    }
+
+    let mut seen = HashSet::new();
+    for chunk in &chunks {
+        let totals = corpus.chunks.entry(chunk.name).or_default();
+        if seen.insert(chunk.name) {
+            totals.files += 1;
+        }
+        totals.count += 1;
+        totals.bytes += chunk.raw.len() as u64;
+    }
+
+    Ok(())
+}
+
+fn print_report(corpus: &Corpus) {
+    let mut summary = format!(
+        "{} PNG{}, {}",
+        corpus.files,
+        if corpus.files == 1 { "" } else { "s" },
+        format_bytes(corpus.bytes)
+    );
+    if corpus.unreadable > 0 {
+        summary.push_str(&format!(" ({} unreadable)", corpus.unreadable));
+    }
+    println!("{summary}");
+    if corpus.files == 0 {
+        return;
+    }
+
+    let share = |bytes: u64| 100.0 * bytes as f64 / corpus.bytes as f64;
+    println!();
+    println!(
+        "{:<6} {:>6} {:>7} {:>12} {:>7}",
+        "chunk", "files", "count", "bytes", "share"
+    );
+    let mut chunks: Vec<_> = corpus.chunks.iter().collect();
+    chunks.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+    for (name, totals) in chunks {
+        println!(
+            "{:<6} {:>6} {:>7} {:>12} {:>6.1}%",
+            ChunkName(name).to_string(),
+            totals.files,
+            totals.count,
+            format_bytes(totals.bytes),
+            share(totals.bytes)
+        );
+    }
+    // Each file's 8-byte signature sits outside any chunk.
+    let signatures = (corpus.files * PNG_SIGNATURE.len()) as u64;
+    println!(
+        "{:<6} {:>6} {:>7} {:>12} {:>6.1}%",
+        "(sig)",
+        corpus.files,
+        corpus.files,
+        format_bytes(signatures),
+        share(signatures)
+    );
+
+    println!();
+    println!("{:<16} {:>6}", "color type", "files");
+    for (&color_type, files) in &corpus.color_types {
+        println!("{:<16} {files:>6}", color_type_name(color_type));
+    }
+
+    println!();
+    println!("{:<16} {:>6}", "bit depth", "files");
+    for (depth, files) in &corpus.bit_depths {
+        println!("{depth:<16} {files:>6}");
+    }
+
+    println!();
+    println!(
+        "interlaced (Adam7): {} of {} files",
+        corpus.interlaced, corpus.files
+    );
+}
+
+fn color_type_name(color_type: u8) -> String {
+    match color_type {
+        0 => "grayscale".to_owned(),
+        2 => "RGB".to_owned(),
+        3 => "indexed".to_owned(),
+        4 => "grayscale+alpha".to_owned(),
+        6 => "RGBA".to_owned(),
+        other => format!("invalid ({other})"),
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Reads and writes are paced in slices of this size, so a large file doesn't go out in
+/// one burst and then stall everything behind it.
+const SLICE: usize = 64 * 1024;
+
+/// The `--io-limit` budget, shared by every read and write in the process.
+static LIMIT: OnceLock<TokenBucket> = OnceLock::new();
+
+/// Bytes may flow while the bucket holds tokens; it refills at `rate` bytes a second and
+/// holds at most a second's worth, so idle time doesn't buy an unbounded burst.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+/// Applies `--io-limit`: from now on input reads and output writes together move at most
+/// `bytes_per_second`.
+pub fn install(bytes_per_second: u64) {
+    let rate = bytes_per_second as f64;
+    let _ = LIMIT.set(TokenBucket {
+        rate,
+        state: Mutex::new((rate, Instant::now())),
+    });
+}
+
+/// Blocks until `bytes` more may be transferred. Callers that overdraw the bucket wait
+/// for it to refill, and so do the ones queued behind them.
+fn pace(bytes: usize) {
+    let Some(bucket) = LIMIT.get() else {
+        return;
+    };
+    let wait = {
+        let mut state = bucket.state.lock().unwrap_or_else(|err| err.into_inner());
+        let (tokens, refilled) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*refilled).as_secs_f64() * bucket.rate).min(bucket.rate);
+        *refilled = now;
+        *tokens -= bytes as f64;
+        (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / bucket.rate))
+    };
+    if let Some(wait) = wait {
+        thread::sleep(wait);
+    }
+}
+
+/// `fs::read`, paced by `--io-limit` when it is set.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    if LIMIT.get().is_none() {
+        return std::fs::read(path);
+    }
+    let mut file = File::open(path)?;
+    let mut data = Vec::new();
+    let mut slice = vec![0; SLICE];
+    loop {
+        let read = file.read(&mut slice)?;
+        if read == 0 {
+            return Ok(data);
+        }
+        pace(read);
+        data.extend_from_slice(&slice[..read]);
+    }
+}
+
+/// `write_all`, paced by `--io-limit` when it is set.
+pub fn write_all(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for slice in data.chunks(SLICE) {
+        pace(slice.len());
+        writer.write_all(slice)?;
+    }
+    Ok(())
+}
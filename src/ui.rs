@@ -1,123 +1,416 @@
-use std::cell::RefCell;
-use std::path::Path;
-use std::sync::Arc;
+#[cfg(feature = "fancy-ui")]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(feature = "fancy-ui")]
+use std::sync::MutexGuard;
 use std::time::Duration;
 
-use anyhow::Error;
+#[cfg(feature = "fancy-ui")]
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 
-use crate::cli::ProgressKind;
+use crate::cli::{ProgressKind, StageKind};
+use crate::dedupe::Collapsed;
+use crate::error::TurboPngError;
+use crate::pipeline::{ProcessedFile, RunSummary};
+#[cfg(unix)]
+use crate::syslog::SystemLog;
+#[cfg(feature = "upload")]
+use crate::upload::Uploader;
 
+/// Receives progress events from a run. `ProgressDispatcher` renders them in the
+/// terminal; embedders can implement this to surface progress in their own UI.
+///
+/// Several files may be in flight at once, so every event names the file it is about
+/// and may arrive from any thread.
+pub trait ProgressReporter: Sync {
+    fn file_started(&self, path: &Path);
+    /// Called as each pipeline stage begins on `path`.
+    fn stage(&self, path: &Path, stage: StageKind);
+    fn file_finished(&self, path: &Path, outcome: Option<FileOutcome>);
+    fn file_failed(&self, path: &Path, error: &TurboPngError);
+    /// Called for inputs that were never started, such as those `--only-if-newer`
+    /// filtered out.
+    fn file_skipped(&self, path: &Path, reason: SkipReason);
+}
+
+/// Why a file was left alone rather than processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Its output already exists and wasn't to be replaced.
+    Exists,
+    /// `--only-if-newer` or `--newer-than` found nothing new to process.
+    NotNewer,
+    /// The result was no improvement on what `--baseline` already had, so that was kept.
+    NoSavings,
+    /// A filter such as `--changes` left it out.
+    Excluded,
+    /// `--on-collision skip` found its output path taken by another input.
+    Collision,
+}
+
+impl SkipReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Exists => "output exists",
+            Self::NotNewer => "not newer",
+            Self::NoSavings => "no savings",
+            Self::Excluded => "excluded",
+            Self::Collision => "output name taken",
+        }
+    }
+}
+
+/// How many files ended in each terminal state.
+#[derive(Debug, Default, Clone, Copy)]
+struct Tally {
+    succeeded: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl Tally {
+    fn summary(self) -> String {
+        format!(
+            "{} succeeded, {} skipped, {} failed",
+            self.succeeded, self.skipped, self.failed
+        )
+    }
+}
+
+/// Renders progress for `ProgressKind::Fancy` as live bars, or as one line per file in
+/// builds without the `fancy-ui` feature; `ProgressKind::Quiet` prints only warnings.
+/// Succeeded, skipped and failed files are counted separately, and also go to the system
+/// log with `--log syslog`. With `--upload-url`, each output is uploaded once written.
 pub struct ProgressDispatcher {
     kind: ProgressKind,
-    total: usize,
-    processed: RefCell<usize>,
-    multi: Option<Arc<MultiProgress>>,
-    overall: Option<ProgressBar>,
-    current: RefCell<Option<ProgressBar>>,
+    tally: Mutex<Tally>,
+    #[cfg(feature = "fancy-ui")]
+    bars: Option<Bars>,
+    #[cfg(unix)]
+    system_log: Option<SystemLog>,
+    #[cfg(feature = "upload")]
+    uploader: Option<Uploader>,
 }
 
 impl ProgressDispatcher {
+    #[cfg_attr(not(feature = "fancy-ui"), allow(unused_variables))]
     pub fn new(kind: ProgressKind, total: usize) -> Self {
-        match kind {
-            ProgressKind::Quiet => Self {
-                kind,
-                total,
-                processed: RefCell::new(0),
-                multi: None,
-                overall: None,
-                current: RefCell::new(None),
-            },
-            ProgressKind::Fancy => {
-                let multi = Arc::new(MultiProgress::with_draw_target(
-                    ProgressDrawTarget::stderr_with_hz(20),
-                ));
-                let overall = multi.add(ProgressBar::new(total as u64));
-                overall.set_style(overall_style());
-                overall.set_position(0);
-                overall.set_message(format!("0/{} files", total));
-
-                Self {
-                    kind,
-                    total,
-                    processed: RefCell::new(0),
-                    multi: Some(multi),
-                    overall: Some(overall),
-                    current: RefCell::new(None),
-                }
-            }
+        Self {
+            #[cfg(feature = "fancy-ui")]
+            bars: matches!(kind, ProgressKind::Fancy).then(|| Bars::new(total)),
+            kind,
+            tally: Mutex::new(Tally::default()),
+            #[cfg(unix)]
+            system_log: None,
+            #[cfg(feature = "upload")]
+            uploader: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn with_system_log(mut self, log: SystemLog) -> Self {
+        self.system_log = Some(log);
+        self
+    }
+
+    #[cfg(feature = "upload")]
+    pub fn with_uploader(mut self, uploader: Uploader) -> Self {
+        self.uploader = Some(uploader);
+        self
+    }
+
+    /// Waits for the uploads of every output written so far.
+    #[cfg(feature = "upload")]
+    pub fn finish_uploads(&self) -> Result<(), TurboPngError> {
+        match &self.uploader {
+            Some(uploader) => uploader.finish(),
+            None => Ok(()),
         }
     }
 
-    pub fn file_started(&self, path: &Path) {
-        match self.kind {
-            ProgressKind::Quiet => {}
-            ProgressKind::Fancy => {
-                let spinner = self
-                    .multi
-                    .as_ref()
-                    .expect("multi exists in fancy mode")
-                    .add(ProgressBar::new_spinner());
-                spinner.set_style(spinner_style());
-                spinner.set_message(format!("processing {}", path.display()));
-                spinner.enable_steady_tick(Duration::from_millis(80));
-                *self.current.borrow_mut() = Some(spinner);
+    /// Prints how many files succeeded, were skipped and failed, in builds that print
+    /// one line per file; the overall bar already shows the counts.
+    pub fn print_tally(&self) {
+        if matches!(self.kind, ProgressKind::Quiet) {
+            return;
+        }
+        #[cfg(feature = "fancy-ui")]
+        if self.bars.is_some() {
+            return;
+        }
+        println!("{}", self.record(|_| {}).summary());
+    }
+
+    /// Prints a warning about `path`, even with `--no-progress`: warnings report problems
+    /// in outputs that were written anyway, so they shouldn't pass silently.
+    fn warn(&self, path: &Path, warning: &str) {
+        let message = format!("{WARNING} {}: {warning}", path.display());
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.multi.suspend(|| eprintln!("{message}"));
+            return;
+        }
+        eprintln!("{message}");
+    }
+
+    /// Applies `count` to the tally and returns the updated counts.
+    fn record(&self, count: impl FnOnce(&mut Tally)) -> Tally {
+        let mut tally = self
+            .tally
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        count(&mut tally);
+        *tally
+    }
+}
+
+#[cfg_attr(not(feature = "fancy-ui"), allow(unused_variables))]
+impl ProgressReporter for ProgressDispatcher {
+    fn file_started(&self, path: &Path) {
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.start(path);
+        }
+    }
+
+    fn stage(&self, path: &Path, stage: StageKind) {
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.stage(path, stage);
+        }
+    }
+
+    fn file_finished(&self, path: &Path, outcome: Option<FileOutcome>) {
+        #[cfg(unix)]
+        if let (Some(log), Some(outcome)) = (&self.system_log, &outcome) {
+            log.file_finished(path, outcome);
+        }
+        if let Some(outcome) = &outcome {
+            for warning in &outcome.warnings {
+                self.warn(path, warning);
             }
         }
+        #[cfg(feature = "upload")]
+        if let (Some(uploader), Some(outcome)) = (&self.uploader, &outcome) {
+            uploader.upload(&outcome.output_path);
+        }
+        let skipped = outcome.as_ref().and_then(|outcome| outcome.skipped);
+        let tally = self.record(|tally| match skipped {
+            Some(_) => tally.skipped += 1,
+            None => tally.succeeded += 1,
+        });
+        if matches!(self.kind, ProgressKind::Quiet) {
+            return;
+        }
+        let message = match outcome {
+            Some(ref outcome) => format_success(path, outcome),
+            None => format!("✓ {}", path.display()),
+        };
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.finish(path, message, tally);
+            return;
+        }
+        println!("{message}");
+    }
+
+    fn file_failed(&self, path: &Path, error: &TurboPngError) {
+        #[cfg(unix)]
+        if let Some(log) = &self.system_log {
+            log.file_failed(path, error);
+        }
+        let tally = self.record(|tally| tally.failed += 1);
+        if matches!(self.kind, ProgressKind::Quiet) {
+            return;
+        }
+        let message = format!("✗ {} ({})", path.display(), error);
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.fail(path, message, tally);
+            return;
+        }
+        eprintln!("{message}");
+    }
+
+    fn file_skipped(&self, path: &Path, reason: SkipReason) {
+        #[cfg(unix)]
+        if let Some(log) = &self.system_log {
+            log.file_skipped(path, reason);
+        }
+        let tally = self.record(|tally| tally.skipped += 1);
+        if matches!(self.kind, ProgressKind::Quiet) {
+            return;
+        }
+        let message = format!("{SKIPPED} {} ({})", path.display(), reason.label());
+        #[cfg(feature = "fancy-ui")]
+        if let Some(bars) = &self.bars {
+            bars.finish(path, message, tally);
+            return;
+        }
+        println!("{message}");
+    }
+}
+
+/// Marks skipped files, between `✓` for succeeded and `✗` for failed ones.
+const SKIPPED: char = '↷';
+/// Marks warnings about files that were still written.
+const WARNING: char = '⚠';
+
+/// The overall bar plus one spinner row per file in flight.
+#[cfg(feature = "fancy-ui")]
+struct Bars {
+    total: usize,
+    processed: Mutex<usize>,
+    multi: MultiProgress,
+    overall: ProgressBar,
+    active: Mutex<HashMap<PathBuf, ProgressBar>>,
+}
+
+#[cfg(feature = "fancy-ui")]
+impl Bars {
+    fn new(total: usize) -> Self {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr_with_hz(20));
+        let overall = multi.add(ProgressBar::new(total as u64));
+        overall.set_style(overall_style());
+        overall.set_position(0);
+
+        Self {
+            total,
+            processed: Mutex::new(0),
+            multi,
+            overall,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start(&self, path: &Path) {
+        let spinner = self.multi.add(ProgressBar::new_spinner());
+        spinner.set_style(spinner_style());
+        spinner.set_message(format!("processing {}", path.display()));
+        spinner.enable_steady_tick(Duration::from_millis(80));
+        // A file started twice keeps one row; the stale spinner is cleared.
+        if let Some(stale) = self.active().insert(path.to_path_buf(), spinner) {
+            stale.finish_and_clear();
+        }
+    }
+
+    fn stage(&self, path: &Path, stage: StageKind) {
+        if let Some(spinner) = self.active().get(path) {
+            spinner.set_message(format!("processing {} ({})", path.display(), stage.label()));
+        }
     }
 
-    pub fn file_finished(&self, path: &Path, outcome: Option<FileOutcome>) {
-        match self.kind {
-            ProgressKind::Quiet => {}
-            ProgressKind::Fancy => {
-                if let Some(spinner) = self.current.borrow_mut().take() {
-                    let message = match outcome {
-                        Some(ref outcome) => format_success(path, outcome),
-                        None => format!("✓ {}", path.display()),
-                    };
-                    spinner.finish_with_message(message);
-                }
-                self.tick_overall();
+    fn finish(&self, path: &Path, message: String, tally: Tally) {
+        match self.active().remove(path) {
+            Some(spinner) => spinner.finish_with_message(message),
+            None => {
+                let _ = self.multi.println(message);
             }
         }
+        self.tick_overall(tally);
     }
 
-    pub fn file_failed(&self, path: &Path, error: &Error) {
-        match self.kind {
-            ProgressKind::Quiet => {}
-            ProgressKind::Fancy => {
-                if let Some(spinner) = self.current.borrow_mut().take() {
-                    spinner.abandon_with_message(format!("✗ {} ({})", path.display(), error));
-                } else if let Some(multi) = &self.multi {
-                    let _ = multi.println(format!("✗ {} ({})", path.display(), error));
-                } else {
-                    eprintln!("✗ {} ({})", path.display(), error);
-                }
-                self.tick_overall();
+    fn fail(&self, path: &Path, message: String, tally: Tally) {
+        match self.active().remove(path) {
+            Some(spinner) => spinner.abandon_with_message(message),
+            None => {
+                let _ = self.multi.println(message);
             }
         }
+        self.tick_overall(tally);
     }
 
-    fn tick_overall(&self) {
-        if let Some(overall) = &self.overall {
-            let mut processed = self.processed.borrow_mut();
-            *processed += 1;
-            overall.set_position(*processed as u64);
-            overall.set_message(format!("{}/{} files", *processed, self.total));
+    /// The spinners by file. A panic on another file's thread leaves the map usable.
+    fn active(&self) -> MutexGuard<'_, HashMap<PathBuf, ProgressBar>> {
+        self.active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 
-            if *processed == self.total {
-                overall.finish_with_message("All files processed");
-            }
+    fn tick_overall(&self, tally: Tally) {
+        let mut processed = self
+            .processed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *processed += 1;
+        self.overall.set_position(*processed as u64);
+        self.overall.set_message(format!("({})", tally.summary()));
+
+        if *processed == self.total {
+            self.overall.finish();
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct FileOutcome {
+    pub output_path: PathBuf,
+    pub output_sha256: String,
     pub original_size: u64,
     pub output_size: u64,
     pub elapsed: Duration,
     pub notes: Option<String>,
+    /// Problems reported alongside the result.
+    pub warnings: Vec<String>,
+    /// Set when the file was left as it was rather than improved, e.g. a baseline kept.
+    pub skipped: Option<SkipReason>,
+}
+
+/// Reports which files completed before an interrupt stopped the run.
+pub fn print_interrupted_summary(processed: &[ProcessedFile], total: usize) {
+    eprintln!(
+        "interrupted after {} of {} files; the rest were not processed",
+        processed.len(),
+        total
+    );
+    for file in processed {
+        eprintln!(
+            "  ✓ {} → {}",
+            file.input.display(),
+            file.outcome.output_path.display()
+        );
+    }
+}
+
+/// Lists the files `--time-budget` left unstarted.
+pub fn print_skipped_for_time_budget(summary: &RunSummary, total: usize) {
+    if summary.out_of_time.is_empty() {
+        return;
+    }
+
+    eprintln!(
+        "time budget exhausted after {} of {} files; skipped:",
+        total - summary.out_of_time.len(),
+        total
+    );
+    for path in &summary.out_of_time {
+        eprintln!("  - {}", path.display());
+    }
+}
+
+/// Reports what `--link-duplicates` collapsed.
+pub fn print_collapsed_duplicates(collapsed: &Collapsed) {
+    if collapsed.links == 0 {
+        eprintln!("no identical outputs to link");
+    } else {
+        eprintln!(
+            "linked {} duplicate output{}, collapsing {} of duplication",
+            collapsed.links,
+            if collapsed.links == 1 { "" } else { "s" },
+            format_bytes(collapsed.bytes)
+        );
+    }
+}
+
+/// Collapses per-file notes into the single annotation shown after a result.
+pub fn join_notes(notes: Vec<String>) -> Option<String> {
+    (!notes.is_empty()).then(|| notes.join(", "))
+}
+
+pub fn dry_run_notes(mut notes: Vec<String>) -> String {
+    notes.insert(0, String::from("dry run"));
+    notes.join(", ")
 }
 
 fn format_success(path: &Path, outcome: &FileOutcome) -> String {
@@ -133,10 +426,16 @@ fn format_success(path: &Path, outcome: &FileOutcome) -> String {
         parts.push(notes.clone());
     }
 
-    format!("✓ {} ({})", path.display(), parts.join(", "))
+    match outcome.skipped {
+        Some(reason) => {
+            parts.insert(0, reason.label().to_string());
+            format!("{SKIPPED} {} ({})", path.display(), parts.join(", "))
+        }
+        None => format!("✓ {} ({})", path.display(), parts.join(", ")),
+    }
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub fn format_bytes(bytes: u64) -> String {
     const KB: f64 = 1024.0;
     const MB: f64 = KB * 1024.0;
     const GB: f64 = MB * 1024.0;
@@ -172,12 +471,14 @@ fn format_duration(duration: Duration) -> String {
     }
 }
 
+#[cfg(feature = "fancy-ui")]
 fn overall_style() -> ProgressStyle {
-    ProgressStyle::with_template("{bar:36.green/black} {pos:>2}/{len} files")
+    ProgressStyle::with_template("{bar:36.green/black} {pos:>2}/{len} files {msg}")
         .unwrap_or_else(|_| ProgressStyle::default_bar())
         .progress_chars("█▉▊▋▌▍▎▏ ")
 }
 
+#[cfg(feature = "fancy-ui")]
 fn spinner_style() -> ProgressStyle {
     ProgressStyle::with_template("{spinner:.green} {msg}")
         .unwrap_or_else(|_| ProgressStyle::default_spinner())
@@ -0,0 +1,206 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ureq::Agent;
+
+use crate::cli::{RELPATH_PLACEHOLDER, UploadTarget};
+use crate::error::{Context, Result, TurboPngError, format_err};
+use crate::interrupt;
+
+/// Wait before the first retry; doubled for each one after, up to `MAX_RETRY_BACKOFF`.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+/// Longest any single request may take, including reading the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// PUTs outputs to a `--upload-url` target as they are written, at most `target.jobs` at
+/// a time, retrying transport errors, 429s and 5xx responses. Outputs below `root` are
+/// addressed by their path relative to it, any others by file name. Queued uploads are
+/// dropped once interrupted.
+pub struct Uploader {
+    queue: Mutex<Option<Sender<PathBuf>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    failures: Arc<Mutex<Vec<String>>>,
+}
+
+impl Uploader {
+    pub fn start(target: UploadTarget, root: PathBuf) -> Self {
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .build()
+            .into();
+        let (queue, outputs) = mpsc::channel();
+        let outputs = Arc::new(Mutex::new(outputs));
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let jobs = target.jobs.get();
+        let destination = Arc::new(Destination {
+            agent,
+            target,
+            root,
+        });
+        let workers = (0..jobs)
+            .map(|_| {
+                let destination = Arc::clone(&destination);
+                let outputs = Arc::clone(&outputs);
+                let failures = Arc::clone(&failures);
+                thread::spawn(move || {
+                    while let Some(output) = next_output(&outputs) {
+                        if interrupt::requested() {
+                            continue;
+                        }
+                        if let Err(err) = destination.upload(&output) {
+                            failures
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .push(format!("{}: {err:#}", output.display()));
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            queue: Mutex::new(Some(queue)),
+            workers: Mutex::new(workers),
+            failures,
+        }
+    }
+
+    /// Queues `output`, which has just been written, for upload.
+    pub fn upload(&self, output: &Path) {
+        if let Some(queue) = &*self
+            .queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+        {
+            // The workers only stop once the queue is closed, so this can't fail.
+            let _ = queue.send(output.to_path_buf());
+        }
+    }
+
+    /// Waits for every queued upload, and reports those that failed.
+    pub fn finish(&self) -> Result<()> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        let workers = std::mem::take(
+            &mut *self
+                .workers
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        for worker in workers {
+            if worker.join().is_err() {
+                self.failures
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .push(String::from("an upload worker panicked"));
+            }
+        }
+
+        let failures = std::mem::take(
+            &mut *self
+                .failures
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(TurboPngError::Batch {
+                activity: "upload",
+                failures,
+            })
+        }
+    }
+}
+
+/// Where the workers send outputs.
+struct Destination {
+    agent: Agent,
+    target: UploadTarget,
+    root: PathBuf,
+}
+
+impl Destination {
+    fn upload(&self, output: &Path) -> Result<()> {
+        upload_file(&self.agent, &self.target, &self.root, output)
+    }
+}
+
+/// The next output to upload, or `None` once the queue is closed and drained.
+fn next_output(outputs: &Mutex<Receiver<PathBuf>>) -> Option<PathBuf> {
+    outputs
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .recv()
+        .ok()
+}
+
+fn upload_file(agent: &Agent, target: &UploadTarget, root: &Path, output: &Path) -> Result<()> {
+    let url = upload_url(&target.url, root, output);
+    let bytes = fs::read(output).context("reading output")?;
+    let content_type = match output.extension().and_then(|ext| ext.to_str()) {
+        Some("webp") => "image/webp",
+        _ => "image/png",
+    };
+
+    let mut attempt = 0;
+    loop {
+        let error = match agent
+            .put(&url)
+            .header("Content-Type", content_type)
+            .send(&bytes[..])
+        {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let error = format_err!("PUT {url} returned {status}");
+                if !(status.is_server_error() || status.as_u16() == 429) {
+                    return Err(error);
+                }
+                error
+            }
+            Err(err) => TurboPngError::Other(Box::new(err)).context(format!("PUT {url}")),
+        };
+        if attempt >= target.retries || interrupt::requested() {
+            return Err(error);
+        }
+        thread::sleep(
+            (RETRY_BACKOFF * 2u32.saturating_pow(attempt.min(16))).min(MAX_RETRY_BACKOFF),
+        );
+        attempt += 1;
+    }
+}
+
+/// `template` with the placeholder replaced by `output`'s percent-encoded path.
+fn upload_url(template: &str, root: &Path, output: &Path) -> String {
+    let relative = match output.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => output.file_name().map(Path::new).unwrap_or(output),
+    };
+    let relpath = relative
+        .iter()
+        .map(|segment| percent_encode(&segment.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join("/");
+    template.replace(RELPATH_PLACEHOLDER, &relpath)
+}
+
+/// Escapes everything but RFC 3986 unreserved characters.
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,30 @@
+use crate::chunks::{self, ChunkName};
+use crate::error::Result;
+use crate::orientation;
+
+/// Problems with `output`, written from `source`, that don't stop it being written but
+/// that whoever relies on it should hear about: private chunks carried along, a color
+/// profile lost, or an EXIF orientation viewers will no longer apply.
+///
+/// With `normalized_colorspace` (`--normalize-colorspace`), a lost profile was replaced
+/// by the `sRGB` hint that was asked for, so it isn't reported.
+pub fn detect(source: &[u8], output: &[u8], normalized_colorspace: bool) -> Result<Vec<String>> {
+    let mut warnings: Vec<String> = chunks::unknown_ancillary(output)?
+        .iter()
+        .map(|name| format!("unknown ancillary chunk {} preserved", ChunkName(name)))
+        .collect();
+    if !normalized_colorspace
+        && chunks::has_chunk(source, b"iCCP")?
+        && !chunks::has_chunk(output, b"iCCP")?
+    {
+        warnings.push(String::from("ICC profile dropped"));
+    }
+    // `--apply-orientation` resets the source's orientation, and a kept eXIf still tells
+    // viewers to rotate; only a lost one leaves the image sideways.
+    if let Some(orientation) = orientation::exif_orientation(source)?
+        && orientation::exif_orientation(output)?.is_none()
+    {
+        warnings.push(format!("EXIF orientation {orientation} ignored"));
+    }
+    Ok(warnings)
+}
@@ -1,8 +1,10 @@
 use std::error::Error;
+use std::fs;
 
 use assert_cmd::Command;
 use assert_fs::TempDir;
 use png::chunk;
+use predicates::prelude::*;
 
 mod fixtures;
 
@@ -46,3 +48,752 @@ fn compress_with_keep_metadata_retains_all_chunks() -> Result<(), Box<dyn Error>
 
     Ok(())
 }
+
+#[test]
+fn compress_keep_metadata_places_chunks_in_spec_order() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "ordered.png",
+        &[
+            (*b"bKGD", &[0, 200, 0, 40, 0, 40]),
+            (*b"sBIT", &[8, 8, 8, 8]),
+        ],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let chunks = fixtures::chunk_names(&output);
+    let position = |name: &[u8; 4]| chunks.iter().position(|chunk| chunk == name);
+    let plte = position(&chunk::PLTE.0).expect("output should be indexed");
+    assert!(
+        position(b"sBIT").expect("sBIT kept") < plte,
+        "sBIT precedes PLTE"
+    );
+    assert!(
+        position(b"bKGD").expect("bKGD kept") > plte,
+        "bKGD follows PLTE"
+    );
+
+    assert_eq!(
+        fixtures::chunk_data(&output, b"sBIT").map(|data| data.len()),
+        Some(3),
+        "indexed sBIT covers the three palette channels"
+    );
+    let background = fixtures::chunk_data(&output, b"bKGD").expect("bKGD kept");
+    assert_eq!(background.len(), 1, "indexed bKGD is a palette index");
+
+    Ok(())
+}
+
+#[test]
+fn compress_resolves_indexed_backgrounds_through_the_source_palette() -> Result<(), Box<dyn Error>>
+{
+    let temp = TempDir::new()?;
+    // More entries than the palette cap at this quality, so the palette is requantized.
+    let palette: Vec<[u8; 3]> = (0..200u8)
+        .map(|i| [i, 255 - i, i.wrapping_mul(7)])
+        .collect();
+    let input = fixtures::write_indexed_with_chunks(
+        &temp,
+        "indexed.png",
+        &palette,
+        &[],
+        &[(*b"bKGD", &[150])],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .args(["--quality", "20"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let background = fixtures::chunk_data(&output, b"bKGD").expect("indexed bKGD kept");
+    let entries = fixtures::chunk_data(&output, b"PLTE").expect("output should be indexed");
+    let distance = |entry: &[u8]| -> u32 {
+        entry
+            .iter()
+            .zip(palette[150])
+            .map(|(&have, want)| u32::from(have.abs_diff(want)).pow(2))
+            .sum()
+    };
+    let nearest = entries
+        .chunks_exact(3)
+        .enumerate()
+        .min_by_key(|(_, entry)| distance(entry))
+        .map(|(slot, _)| slot);
+    assert_eq!(nearest, Some(usize::from(background[0])));
+
+    Ok(())
+}
+
+#[test]
+fn compress_reads_background_samples_at_the_source_bit_depth() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gray16_ramp(&temp, "gray16.png", 64, 4, 0, 65535);
+    // Rewrite the fixture with a 16-bit gray bKGD of 200 / 65535, which is black at 8 bits.
+    let data = fs::read(&input)?;
+    let mut with_background = data[..33].to_vec();
+    with_background.extend_from_slice(&2u32.to_be_bytes());
+    let chunk = [b"bKGD".as_slice(), &200u16.to_be_bytes()].concat();
+    with_background.extend_from_slice(&chunk);
+    let mut crc = flate2::Crc::new();
+    crc.update(&chunk);
+    with_background.extend_from_slice(&crc.sum().to_be_bytes());
+    with_background.extend_from_slice(&data[33..]);
+    fs::write(&input, with_background)?;
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let background = fixtures::chunk_data(&output, b"bKGD").expect("bKGD kept");
+    let palette = fixtures::chunk_data(&output, b"PLTE").expect("output should be indexed");
+    let slot = usize::from(background[0]) * 3;
+    assert!(
+        palette[slot..slot + 3].iter().all(|&sample| sample < 8),
+        "background stays near black, got {:?}",
+        &palette[slot..slot + 3]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn bitcrush_keeps_channels_already_within_their_sbit() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    // Every tile channel is a 5-bit level shifted left: 200 = 25 << 3, 40 = 5 << 3.
+    let input =
+        fixtures::write_tiles_with_chunks(&temp, "five-bit.png", &[(*b"sBIT", &[5, 5, 5, 8])]);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .args(["--lossy", "bitcrush"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fixtures::max_abs_channel_difference(
+            &fixtures::decode_rgba(&input),
+            &fixtures::decode_rgba(&output)
+        ),
+        0,
+        "channels declared at five bits are left as they are"
+    );
+    let significant = fixtures::chunk_data(&output, b"sBIT").expect("sBIT written");
+    assert!(
+        significant[..3].iter().all(|&bits| bits == 5),
+        "color channels keep five significant bits, got {significant:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn palette_output_drops_an_sbit_it_no_longer_meets() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    // 200 is not a widened 3-bit level, so the declaration is already off; it must not be
+    // carried over onto the palette.
+    let input =
+        fixtures::write_tiles_with_chunks(&temp, "three-bit.png", &[(*b"sBIT", &[3, 3, 3, 8])]);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fixtures::encoded_color_type(&output).0,
+        png::ColorType::Indexed
+    );
+    assert_eq!(fixtures::chunk_data(&output, b"sBIT"), None);
+
+    Ok(())
+}
+
+#[test]
+fn validate_output_accepts_what_both_modes_write() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "ordered.png",
+        &[
+            (*b"bKGD", &[0, 200, 0, 40, 0, 40]),
+            (*b"sBIT", &[8, 8, 8, 8]),
+        ],
+    );
+
+    for mode in ["optimize", "compress"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--keep-metadata"])
+            .arg("--validate-output")
+            .arg(&input)
+            .assert()
+            .success();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compat_pads_trns_to_the_whole_palette() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let palette = [[250, 10, 10], [10, 250, 10], [10, 10, 250], [240, 240, 240]];
+    // Only the first entry is transparent, so the source's tRNS is a single byte.
+    let input = fixtures::write_indexed_with_trns(&temp, "sprite.png", &palette, &[0]);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    for compat in ["android", "strict"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .args(["--compat", compat, "--validate-output", "--stable-palette"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let entries = fixtures::chunk_data(&output, b"PLTE")
+            .expect("output stays indexed")
+            .len()
+            / 3;
+        let alpha = fixtures::chunk_data(&output, b"tRNS").expect("transparency kept");
+        assert_eq!(
+            alpha.len(),
+            entries,
+            "{compat}: one alpha per palette entry"
+        );
+        assert_eq!(alpha.iter().filter(|&&value| value == 0).count(), 1);
+    }
+    assert_eq!(
+        fixtures::max_abs_channel_difference(
+            &fixtures::decode_rgba(&input),
+            &fixtures::decode_rgba(&output)
+        ),
+        0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn exif_policy_flags_control_the_exif_chunk() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_with_exif(&temp, "photo.png", 1);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    let cases: [(&[&str], bool); 3] = [
+        (&[], false),
+        (&["--keep-exif"], true),
+        (&["--keep-metadata", "--strip-exif"], false),
+    ];
+    for (flags, kept) in cases {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .args(flags)
+            .arg(&input)
+            .assert()
+            .success();
+        assert_eq!(
+            fixtures::chunk_names(&output).contains(&chunk::eXIf.0),
+            kept,
+            "eXIf presence with {flags:?}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn apply_orientation_rotates_pixels_and_removes_the_tag() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    // Orientation 6: the stored pixels must be turned 90° clockwise for display.
+    let input = fixtures::write_with_exif(&temp, "sideways.png", 6);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .args(["--apply-orientation", "--keep-exif"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let rotated = fixtures::decode_rgba(&output);
+    assert_eq!((rotated.width, rotated.height), (2, 3));
+    for y in 0..3u8 {
+        for x in 0..2u8 {
+            // Output (x, y) shows source (y, 1 - x).
+            let offset = (usize::from(y) * 2 + usize::from(x)) * 4;
+            assert_eq!(
+                &rotated.data[offset..offset + 4],
+                &[y * 100, (1 - x) * 100, 0, 255],
+                "pixel ({x}, {y})"
+            );
+        }
+    }
+
+    let exif = fixtures::chunk_data(&output, b"eXIf").expect("eXIf kept");
+    assert_eq!(exif.len(), 26, "EXIF block keeps its length");
+    assert_eq!(&exif[8..10], &[0, 0], "orientation entry removed from IFD0");
+    assert_eq!(&exif[10..14], &[0, 0, 0, 0], "next-IFD offset shifted up");
+
+    Ok(())
+}
+
+#[test]
+fn apply_orientation_rewrites_sbit_for_the_expanded_color_type() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let exif = [
+        b"MM\0*\0\0\0\x08\0\x01".as_slice(),
+        &[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0],
+        &[0, 0, 0, 0],
+    ]
+    .concat();
+    let palette = [[248, 0, 0], [0, 252, 0], [0, 0, 248], [248, 252, 248]];
+    let input = fixtures::write_indexed_with_chunks(
+        &temp,
+        "sideways.png",
+        &palette,
+        &[0, 255, 255, 255],
+        &[(*b"sBIT", &[5, 6, 5]), (*b"eXIf", &exif)],
+    );
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--keep-metadata"])
+        .arg("--apply-orientation")
+        .arg(&input)
+        .assert()
+        .success();
+
+    let sbit = fixtures::chunk_data(&output, b"sBIT").expect("sBIT kept");
+    let expected = match fixtures::encoded_color_type(&output).0 {
+        png::ColorType::Indexed => 3,
+        color_type => color_type.samples(),
+    };
+    assert_eq!(sbit.len(), expected, "sBIT matches the output color type");
+    assert_eq!(&sbit[..3], &[5, 6, 5]);
+
+    Ok(())
+}
+
+#[test]
+fn set_dpi_writes_or_replaces_phys_in_both_modes() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let with_phys = fixtures::write_fixture(&temp, "with_phys.png");
+    let without_phys = fixtures::write_palette_source(&temp, "without_phys.png");
+    // 144 dpi is 5669 pixels per metre.
+    let expected = [0, 0, 0x16, 0x25, 0, 0, 0x16, 0x25, 1];
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        for input in [&with_phys, &without_phys] {
+            Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+                .args(["--mode", mode, "--no-progress", "--set-dpi", "144"])
+                .arg(input)
+                .assert()
+                .success();
+
+            let output = fixtures::derived_output_path(input, suffix);
+            assert_eq!(
+                fixtures::chunk_data(&output, b"pHYs").as_deref(),
+                Some(&expected[..]),
+                "{mode} output of {}",
+                input.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn update_time_replaces_time_with_the_current_utc_time() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "stamped.png",
+        &[(*b"tIME", &[0x07, 0xD0, 1, 1, 0, 0, 0])],
+    );
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--keep-metadata"])
+        .arg("--update-time")
+        .arg(&input)
+        .assert()
+        .success();
+
+    let names = fixtures::chunk_names(&output);
+    assert_eq!(names.iter().filter(|name| *name == b"tIME").count(), 1);
+    let stamp = fixtures::chunk_data(&output, b"tIME").expect("tIME written");
+    let year = u16::from_be_bytes([stamp[0], stamp[1]]);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    // Within a year of the average Gregorian year count since the epoch.
+    let approx_year = 1970 + now.as_secs() / 31_556_952;
+    assert!(u64::from(year).abs_diff(approx_year) <= 1, "year {year}");
+    assert!((1..=12).contains(&stamp[2]) && (1..=31).contains(&stamp[3]));
+    assert!(stamp[4] < 24 && stamp[5] < 60 && stamp[6] < 61);
+
+    Ok(())
+}
+
+#[test]
+fn set_text_replaces_same_keyword_entries() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "texts.png",
+        &[
+            (*b"tEXt", b"Comment\0old"),
+            (*b"tEXt", b"Author\0Someone"),
+            (*b"iTXt", b"Comment\0\0\0\0\0duplicate"),
+        ],
+    );
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--keep-metadata"])
+        .args(["--set-text", "Comment=build 42"])
+        .args(["--set-text", "Title=Fähre 渡し船"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let reader = png::Decoder::new(std::fs::File::open(&output)?).read_info()?;
+    let info = reader.info();
+    let latin1: Vec<(&str, &str)> = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+        .collect();
+    assert_eq!(latin1, [("Comment", "build 42"), ("Author", "Someone")]);
+    let utf8: Vec<(String, String)> = info
+        .utf8_text
+        .iter()
+        .map(|chunk| Ok((chunk.keyword.clone(), chunk.get_text()?)))
+        .collect::<Result<_, png::DecodingError>>()?;
+    assert_eq!(utf8, [("Title".to_owned(), "Fähre 渡し船".to_owned())]);
+
+    Ok(())
+}
+
+#[test]
+fn set_text_rejects_invalid_keywords() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "source.png");
+
+    for entry in ["no-separator", "=empty key", " Padded=value", "日本=value"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--set-text", entry])
+            .arg(&input)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("--set-text"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn keep_text_retains_listed_keywords_only() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "texts.png",
+        &[
+            (*b"tEXt", b"Copyright\0Example Ltd"),
+            (*b"tEXt", b"Software\0Some Editor 1.0"),
+            (*b"iTXt", b"Author\0\0\0\0\0Someone"),
+            (*b"iTXt", b"Comment\0\0\0\0\0exported"),
+        ],
+    );
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args([
+                "--mode",
+                mode,
+                "--no-progress",
+                "--keep-text",
+                "Copyright,Author",
+            ])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let output = fixtures::derived_output_path(&input, suffix);
+        let reader = png::Decoder::new(std::fs::File::open(&output)?).read_info()?;
+        let info = reader.info();
+        let keywords: Vec<&str> = info
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| chunk.keyword.as_str())
+            .chain(info.utf8_text.iter().map(|chunk| chunk.keyword.as_str()))
+            .collect();
+        assert_eq!(keywords, ["Copyright", "Author"], "{mode} output");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn normalize_colorspace_leaves_a_single_srgb_hint() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gamma_gradient(&temp, "hinted.png", 45_455);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    for (flag, intent) in [
+        ("--normalize-colorspace", 0u8),
+        ("--normalize-colorspace=relative", 1),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--keep-metadata"])
+            .args([flag, "--overwrite"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let chunks = fixtures::chunk_names(&output);
+        assert!(!chunks.contains(&chunk::gAMA.0), "gAMA dropped with {flag}");
+        assert!(!chunks.contains(&chunk::cHRM.0), "cHRM dropped with {flag}");
+        assert_eq!(
+            fixtures::chunk_data(&output, b"sRGB"),
+            Some(vec![intent]),
+            "sRGB intent with {flag}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn embed_icc_replaces_color_hints_with_the_profile() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gamma_gradient(&temp, "print.png", 45_455);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+    let mut profile = vec![0u8; 256];
+    profile[36..40].copy_from_slice(b"acsp");
+    profile[128..]
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = i as u8);
+    let profile_path = temp.path().join("press.icc");
+    std::fs::write(&profile_path, &profile)?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg("--embed-icc")
+        .arg(&profile_path)
+        .arg(&input)
+        .assert()
+        .success();
+
+    let chunks = fixtures::chunk_names(&output);
+    assert!(
+        !chunks.contains(&chunk::gAMA.0),
+        "gAMA contradicts the profile"
+    );
+    let decoder = png::Decoder::new(std::fs::File::open(&output)?);
+    let reader = decoder.read_info()?;
+    assert_eq!(reader.info().icc_profile.as_deref(), Some(&profile[..]));
+
+    Ok(())
+}
+
+#[test]
+fn compress_carries_source_icc_profile_ahead_of_plte() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let profile = fixtures::icc_profile(b"RGB ");
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "profiled.png",
+        &[(*b"iCCP", &fixtures::iccp_payload(&profile))],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let chunks = fixtures::chunk_names(&output);
+    let position = |name: &[u8; 4]| chunks.iter().position(|chunk| chunk == name);
+    assert!(
+        position(b"iCCP").expect("iCCP kept") < position(b"PLTE").expect("indexed output"),
+        "iCCP precedes PLTE"
+    );
+    let reader = png::Decoder::new(std::fs::File::open(&output)?).read_info()?;
+    assert_eq!(reader.info().icc_profile.as_deref(), Some(&profile[..]));
+
+    Ok(())
+}
+
+#[test]
+fn compress_drops_grayscale_icc_profile_from_color_output() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let profile = fixtures::icc_profile(b"GRAY");
+    let input = fixtures::write_tiles_with_chunks(
+        &temp,
+        "gray_profile.png",
+        &[(*b"iCCP", &fixtures::iccp_payload(&profile))],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .arg(&input)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("ICC profile dropped"));
+
+    let chunks = fixtures::chunk_names(&output);
+    assert!(chunks.contains(&chunk::PLTE.0), "output should be indexed");
+    assert!(
+        !chunks.contains(&chunk::iCCP.0),
+        "a grayscale profile is invalid on an indexed image"
+    );
+
+    // Normalizing asks for a lone sRGB hint, so the lost profile is expected.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .args(["--normalize-colorspace", "--overwrite"])
+        .arg(&input)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("ICC profile dropped").not());
+    assert!(fixtures::chunk_names(&output).contains(&chunk::sRGB.0));
+
+    Ok(())
+}
+
+#[test]
+fn embed_icc_rejects_files_that_are_not_profiles() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+    let bogus = temp.path().join("bogus.icc");
+    std::fs::write(&bogus, b"not a profile")?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--embed-icc"])
+        .arg(&bogus)
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("is not an ICC profile"));
+
+    Ok(())
+}
+
+#[test]
+fn set_background_maps_to_the_nearest_palette_entry() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input =
+        fixtures::write_tiles_with_chunks(&temp, "tiles.png", &[(*b"bKGD", &[0, 0, 0, 0, 0, 0])]);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--keep-metadata"])
+        .args(["--set-background", "#3030e0"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let chunks = fixtures::chunk_names(&output);
+    let position = |name: &[u8; 4]| chunks.iter().position(|c| c == name);
+    assert!(position(b"PLTE") < position(b"bKGD"), "bKGD follows PLTE");
+    let background = fixtures::chunk_data(&output, b"bKGD").expect("bKGD written");
+    assert_eq!(background.len(), 1, "indexed bKGD is a palette index");
+    let palette = fixtures::chunk_data(&output, b"PLTE").expect("indexed output");
+    let index = background[0] as usize * 3;
+    assert_eq!(&palette[index..index + 3], &[40, 40, 200]);
+
+    Ok(())
+}
+
+#[test]
+fn strip_background_drops_bkgd_even_with_keep_metadata() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input =
+        fixtures::write_tiles_with_chunks(&temp, "tiles.png", &[(*b"bKGD", &[0, 0, 0, 0, 0, 0])]);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--keep-metadata"])
+        .arg("--strip-background")
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(fixtures::chunk_data(&output, b"bKGD"), None);
+
+    Ok(())
+}
+
+#[test]
+fn strip_nondeterministic_drops_time_and_sorts_text() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let first = fixtures::write_tiles_with_chunks(
+        &temp,
+        "first.png",
+        &[
+            (*b"tIME", &[0x07, 0xD0, 1, 1, 0, 0, 0]),
+            (*b"tEXt", b"Software\0Some Editor 1.0"),
+            (*b"iTXt", b"Author\0\0\0\0\0Someone"),
+        ],
+    );
+    let second = fixtures::write_tiles_with_chunks(
+        &temp,
+        "second.png",
+        &[
+            (*b"iTXt", b"Author\0\0\0\0\0Someone"),
+            (*b"tEXt", b"Software\0Some Editor 1.0"),
+            (*b"tIME", &[0x07, 0xE8, 6, 30, 12, 0, 0]),
+        ],
+    );
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--keep-metadata"])
+            .arg("--strip-nondeterministic")
+            .arg(&first)
+            .arg(&second)
+            .assert()
+            .success();
+
+        let output = fixtures::derived_output_path(&first, suffix);
+        let texts: Vec<_> = fixtures::chunk_names(&output)
+            .into_iter()
+            .filter(|name| [b"tEXt", b"iTXt", b"tIME"].contains(&name))
+            .collect();
+        assert_eq!(texts, [*b"iTXt", *b"tEXt"], "{mode}");
+        assert_eq!(
+            fs::read(&output)?,
+            fs::read(fixtures::derived_output_path(&second, suffix))?,
+            "{mode}"
+        );
+    }
+
+    Ok(())
+}
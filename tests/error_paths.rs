@@ -83,3 +83,441 @@ fn overwrite_flag_behavior() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn quality_window_rejects_inverted_bounds() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "window.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .args(["--quality-min", "80", "--quality-max", "40"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--quality-min (80) must not exceed --quality-max (40)",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn quality_min_rejects_floors_above_the_derived_target() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "floor.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--quality", "50"])
+        .args(["--quality-min", "80"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--quality-min (80) must not exceed the target 76 that quality 50 derives",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn compress_reports_corrupt_chunk_name_and_offset() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "corrupt.png");
+    let offset = fixtures::corrupt_chunk(&input, b"tEXt");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(format!(
+            "tEXt chunk at offset {offset} has a bad CRC"
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn compress_reports_truncated_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "truncated.png");
+    let bytes = fs::read(&input)?;
+    fs::write(&input, &bytes[..bytes.len() - 20])?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("PNG is truncated"));
+
+    Ok(())
+}
+
+#[test]
+fn crc_fix_refuses_to_repair_critical_chunks() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "critical.png");
+    fixtures::corrupt_chunk(&input, b"IDAT");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--crc", "fix"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "critical IDAT chunk has a bad CRC",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn max_pixels_rejects_oversized_images_in_both_modes() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "large.png", 32, 32);
+
+    for mode in ["optimize", "compress"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--max-pixels", "1000"])
+            .arg(&input)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "image is 32x32 (1024 pixels), which exceeds --max-pixels 1000",
+            ));
+    }
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "compress",
+            "--no-progress",
+            "--max-pixels",
+            "1024",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[test]
+fn compare_fails_when_thresholds_are_exceeded() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let reference =
+        fixtures::write_tiles(&temp, "a.png", &[[200, 40, 40, 255], [40, 40, 200, 255]]);
+    let candidate =
+        fixtures::write_tiles(&temp, "b.png", &[[204, 40, 40, 255], [40, 40, 200, 255]]);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("compare")
+        .arg(&reference)
+        .arg(&candidate)
+        .args(["--max-delta", "4"])
+        .assert()
+        .success();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("compare")
+        .arg(&reference)
+        .arg(&candidate)
+        .args(["--max-delta", "3", "--min-psnr", "100"])
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("max delta:  4"))
+        .stderr(predicates::str::contains("max delta 4 exceeds 3"))
+        .stderr(predicates::str::contains("is below 100"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_rejects_skipping_a_required_stage() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--skip-stage", "write"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("invalid value 'write'"));
+
+    Ok(())
+}
+
+#[test]
+fn cli_rejects_skipping_quantize_in_optimize_mode() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+
+    for args in [&["--skip-stage", "quantize"][..], &["--skip-quantize"]] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize"])
+            .args(args)
+            .arg(&input)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "optimize mode has no quantize stage to skip",
+            ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn files_are_processed_largest_first_unless_ordered_by_name() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fs::write(temp.child("a.png").path(), [0u8; 10])?;
+    fs::write(temp.child("b.png").path(), [0u8; 500])?;
+
+    // Failures are listed in processing order.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("--no-progress")
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::is_match(r"(?s)b\.png.*a\.png")?);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--order", "name"])
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::is_match(r"(?s)a\.png.*b\.png")?);
+
+    Ok(())
+}
+
+#[test]
+fn cli_rejects_malformed_time_budgets() {
+    for budget in ["10x", "m", "1h30"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--time-budget", budget, "."])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("is not a duration"));
+    }
+}
+
+#[test]
+fn cli_rejects_malformed_ssh_outputs() {
+    for url in [
+        "ftp://host/srv",
+        "ssh://host",
+        "ssh:///srv",
+        "ssh://host:port/srv",
+        "ssh://::1/srv",
+        "ssh://[::1/srv",
+        "ssh://[::1]2222/srv",
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--output", url, "."])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("--output"));
+    }
+}
+
+#[test]
+fn cli_rejects_out_of_range_shards() {
+    for shard in ["0/4", "5/4", "1/0", "3"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--shard", shard, "."])
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("shard"));
+    }
+}
+
+#[test]
+fn compress_rejects_more_locked_colors_than_a_palette_holds() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "locked.png");
+    let colors: Vec<String> = (0..257u32)
+        .map(|value| format!("#{:02x}{:02x}00", value / 256, value % 256))
+        .collect();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--lock-colors"])
+        .arg(colors.join(","))
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "257 locked colors exceed the 256-entry palette",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn stable_palette_fails_when_the_palette_cannot_be_kept() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_indexed(&temp, "lut.png", &[[250, 250, 250], [10, 10, 10]]);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--stable-palette"])
+        .args(["--lossy", "bitcrush"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--stable-palette"));
+    assert!(!fixtures::derived_output_path(&input, "_compressed.png").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn validate_output_fails_files_that_break_the_png_structure() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let tiny = fixtures::write_fixture(&temp, "tiny.png");
+    let mut trailing = fs::read(&tiny)?;
+    trailing.extend_from_slice(b"junk");
+    fs::write(&tiny, trailing)?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--validate-output"])
+        .arg("--post-cmd")
+        .arg(format!("cp '{}' {{out}}", tiny.display()))
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "output fails PNG validation: 4 bytes after IEND",
+        ));
+    assert!(!fixtures::derived_output_path(&input, "_compressed.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn io_limit_rejects_malformed_rates() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+
+    for rate in ["fast", "50XB/s", "0MB/s"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--io-limit", rate])
+            .arg(&input)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("--io-limit"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn exit_code_failures_reports_the_number_of_failed_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let good = fixtures::write_fixture(&temp, "good.png");
+    let mut broken = Vec::new();
+    for name in ["a.png", "b.png"] {
+        let input = fixtures::write_fixture(&temp, name);
+        let bytes = fs::read(&input)?;
+        fs::write(&input, &bytes[..bytes.len() - 20])?;
+        broken.push(input);
+    }
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+        .arg(&good)
+        .args(&broken)
+        .assert()
+        .code(1);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+        .arg("--exit-code-failures")
+        .arg(&good)
+        .args(&broken)
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains(
+            "one or more files failed during optimization",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn base64_rejects_payloads_that_are_not_base64_pngs() {
+    let run = |stdin: &str| {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .arg("--base64")
+            .write_stdin(stdin.to_owned())
+            .assert()
+            .failure()
+    };
+    run("not base64!").stderr(predicates::str::contains("decoding base64 from stdin"));
+    run("data:image/png,rawbytes").stderr(predicates::str::contains("not base64-encoded"));
+    run("aGVsbG8=").stderr(predicates::str::contains("processing the PNG from stdin"));
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--base64", "some.png"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("cannot be used with"));
+}
+
+#[cfg(feature = "bundle")]
+#[test]
+fn bundle_rejects_unknown_archive_types_and_existing_bundles() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "a.png", 8, 8);
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--bundle", "out.rar"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "must end in .zip, .tar, .tar.gz or .tgz",
+        ));
+
+    fs::write(temp.path().join("out.zip"), b"keep me")?;
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--bundle", "out.zip"])
+        .arg(&input)
+        .current_dir(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("already exists"));
+    assert_eq!(fs::read(temp.path().join("out.zip"))?, b"keep me");
+
+    Ok(())
+}
+
+#[test]
+fn stage_only_deflate_rejects_compress_mode() {
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--stage-only", "deflate", "."])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("needs --mode optimize"));
+}
+
+#[test]
+fn no_write_larger_rejects_compress_mode() {
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-write-larger", "."])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("needs --mode optimize"));
+}
+
+#[test]
+fn set_background_rejects_colors_with_alpha() {
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--set-background", "#3030e080", "."])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "\"#3030e080\" has an alpha channel; use a #rrggbb color",
+        ));
+}
@@ -3,11 +3,12 @@
 use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs::{self, File};
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
 use assert_fs::TempDir;
 use assert_fs::fixture::PathChild;
+use flate2::write::ZlibEncoder;
 use png::chunk;
 use png::{BitDepth, ColorType, Compression, Decoder, Encoder, FilterType, Transformations};
 
@@ -52,15 +53,47 @@ pub fn write_unoptimized_rgba(dir: &TempDir, name: &str, width: u32, height: u32
     write_rgba_png(dir, name, width, height, &pixels, Compression::Fast)
 }
 
+/// Noisy RGBA image with a solid `color` patch in its top-left 4x4 corner.
+pub fn write_noisy_with_patch(dir: &TempDir, name: &str, size: u32, color: [u8; 4]) -> PathBuf {
+    let mut pixels = noisy_pixels(size, size);
+    for y in 0..4.min(size) {
+        for x in 0..4.min(size) {
+            let offset = ((y * size + x) * 4) as usize;
+            pixels[offset..offset + 4].copy_from_slice(&color);
+        }
+    }
+    write_rgba_png(dir, name, size, size, &pixels, Compression::Fast)
+}
+
+/// Colorful disc on a transparent background with a soft, partially transparent rim.
+pub fn write_sprite(dir: &TempDir, name: &str, size: u32) -> PathBuf {
+    let center = size as f32 / 2.0;
+    let mut pixels = noisy_pixels(size, size);
+    for y in 0..size {
+        for x in 0..size {
+            let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+            let coverage = (center - 2.0 - distance).clamp(0.0, 3.0) / 3.0;
+            let offset = ((y * size + x) * 4) as usize;
+            pixels[offset + 3] = (coverage * 255.0).round() as u8;
+        }
+    }
+    write_rgba_png(dir, name, size, size, &pixels, Compression::Fast)
+}
+
 pub fn write_palette_source(dir: &TempDir, name: &str) -> PathBuf {
-    let width = 16;
-    let height = 16;
     let palette = [
         [255, 0, 0, 255],
         [0, 255, 0, 255],
         [0, 0, 255, 255],
         [255, 255, 0, 255],
     ];
+    write_tiles(dir, name, &palette)
+}
+
+/// Writes a 16x16 RGBA image made of 4x4 tiles cycling through `palette`.
+pub fn write_tiles(dir: &TempDir, name: &str, palette: &[[u8; 4]]) -> PathBuf {
+    let width = 16;
+    let height = 16;
     let mut pixels = Vec::with_capacity((width * height) as usize * 4);
     for y in 0..height {
         for x in 0..width {
@@ -71,6 +104,250 @@ pub fn write_palette_source(dir: &TempDir, name: &str) -> PathBuf {
     write_rgba_png(dir, name, width, height, &pixels, Compression::Fast)
 }
 
+/// Writes a 16x16 RGBA tile image carrying the given ancillary chunks before IDAT.
+pub fn write_tiles_with_chunks(dir: &TempDir, name: &str, chunks: &[([u8; 4], &[u8])]) -> PathBuf {
+    let palette = [[200, 40, 40, 255], [40, 200, 40, 255], [40, 40, 200, 255]];
+    let pixels: Vec<u8> = (0..16u32 * 16)
+        .flat_map(|i| palette[((i % 16 / 4 + i / 64) % 3) as usize])
+        .collect();
+
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, 16, 16);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    for (chunk_name, data) in chunks {
+        png_writer
+            .write_chunk(chunk::ChunkType(*chunk_name), data)
+            .expect("failed to write chunk");
+    }
+    png_writer
+        .write_image_data(&pixels)
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Writes a 3x2 RGBA image whose pixel at (x, y) is `[x * 100, y * 100, 0, 255]`, with an
+/// `eXIf` chunk declaring `orientation`.
+pub fn write_with_exif(dir: &TempDir, name: &str, orientation: u16) -> PathBuf {
+    let pixels: Vec<u8> = (0..2u8)
+        .flat_map(|y| (0..3u8).flat_map(move |x| [x * 100, y * 100, 0, 255]))
+        .collect();
+    let mut exif = b"MM\0*\0\0\0\x08\0\x01".to_vec();
+    exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, 3, 2);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    png_writer
+        .write_chunk(chunk::eXIf, &exif)
+        .expect("failed to write eXIf chunk");
+    png_writer
+        .write_image_data(&pixels)
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Writes a 16x16 indexed PNG cycling through `palette` (RGB entries) pixel by pixel.
+pub fn write_indexed(dir: &TempDir, name: &str, palette: &[[u8; 3]]) -> PathBuf {
+    write_indexed_with_trns(dir, name, palette, &[])
+}
+
+/// Indexed image whose first `alpha.len()` palette entries carry the given tRNS alpha.
+pub fn write_indexed_with_trns(
+    dir: &TempDir,
+    name: &str,
+    palette: &[[u8; 3]],
+    alpha: &[u8],
+) -> PathBuf {
+    write_indexed_png(dir, name, palette, alpha, &[])
+}
+
+fn write_indexed_png(
+    dir: &TempDir,
+    name: &str,
+    palette: &[[u8; 3]],
+    alpha: &[u8],
+    chunks: &[([u8; 4], &[u8])],
+) -> PathBuf {
+    let size = 16;
+    let indices: Vec<u8> = (0..size * size)
+        .map(|i| ((i % size + (i / size) * 3) % palette.len() as u32) as u8)
+        .collect();
+
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, size, size);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_depth(BitDepth::Eight);
+    encoder.set_palette(palette.concat());
+    if !alpha.is_empty() {
+        encoder.set_trns(alpha.to_vec());
+    }
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    for (chunk_name, data) in chunks {
+        png_writer
+            .write_chunk(chunk::ChunkType(*chunk_name), data)
+            .expect("failed to write chunk");
+    }
+    png_writer
+        .write_image_data(&indices)
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Like `write_indexed_with_trns`, carrying the given ancillary chunks after `PLTE`.
+pub fn write_indexed_with_chunks(
+    dir: &TempDir,
+    name: &str,
+    palette: &[[u8; 3]],
+    alpha: &[u8],
+    chunks: &[([u8; 4], &[u8])],
+) -> PathBuf {
+    write_indexed_png(dir, name, palette, alpha, chunks)
+}
+
+/// Writes an 8x8 two-frame APNG at 10 fps: a solid red frame, then a 4x4 blue square at
+/// (2, 2) blended over it.
+pub fn write_apng(dir: &TempDir, name: &str) -> PathBuf {
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, 8, 8);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    encoder
+        .set_animated(2, 0)
+        .expect("failed to enable animation");
+    encoder.set_frame_delay(1, 10).expect("failed to set delay");
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    png_writer
+        .write_image_data(&[255, 0, 0, 255].repeat(64))
+        .expect("failed to write first frame");
+    png_writer
+        .set_frame_dimension(4, 4)
+        .expect("failed to size second frame");
+    png_writer
+        .set_frame_position(2, 2)
+        .expect("failed to place second frame");
+    png_writer
+        .set_blend_op(png::BlendOp::Over)
+        .expect("failed to set blend op");
+    png_writer
+        .write_image_data(&[0, 0, 255, 255].repeat(16))
+        .expect("failed to write second frame");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Writes a 16x16 image of four flat 8x8 quadrants stored as `color_type` (8-bit).
+pub fn write_quadrants(dir: &TempDir, name: &str, color_type: ColorType) -> PathBuf {
+    let levels = [
+        [30u8, 90, 150, 255],
+        [200, 60, 20, 128],
+        [10, 220, 120, 255],
+        [250, 250, 5, 64],
+    ];
+    let mut samples = Vec::new();
+    for y in 0..16usize {
+        for x in 0..16usize {
+            let [r, g, b, a] = levels[(y / 8) * 2 + x / 8];
+            match color_type {
+                ColorType::Rgba => samples.extend_from_slice(&[r, g, b, a]),
+                ColorType::Rgb => samples.extend_from_slice(&[r, g, b]),
+                ColorType::GrayscaleAlpha => samples.extend_from_slice(&[r, a]),
+                ColorType::Grayscale => samples.push(r),
+                other => panic!("unsupported fixture color type {other:?}"),
+            }
+        }
+    }
+
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, 16, 16);
+    encoder.set_color(color_type);
+    encoder.set_depth(BitDepth::Eight);
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    png_writer
+        .write_image_data(&samples)
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Photo-like image: soft color ramps with fine grain and no hard edges.
+pub fn write_photo(dir: &TempDir, name: &str, size: u32) -> PathBuf {
+    let mut pixels = Vec::with_capacity((size * size) as usize * 4);
+    for y in 0..size {
+        for x in 0..size {
+            let grain = ((x * 7 + y * 13) % 9) as u8;
+            let r = (x * 255 / size) as u8;
+            let g = (y * 255 / size) as u8;
+            let b = ((x + y) * 127 / size) as u8;
+            pixels.extend_from_slice(&[
+                r.saturating_add(grain),
+                g.saturating_add(grain),
+                b.saturating_add(grain),
+                255,
+            ]);
+        }
+    }
+    write_rgba_png(dir, name, size, size, &pixels, Compression::Fast)
+}
+
+pub fn write_gamma_gradient(dir: &TempDir, name: &str, gamma: u32) -> PathBuf {
+    let width = 64;
+    let height = 8;
+    let mut pixels = Vec::with_capacity((width * height) as usize * 4);
+    for _ in 0..height {
+        for x in 0..width {
+            let level = (x / 2) as u8;
+            pixels.extend_from_slice(&[level, level, level, 255]);
+        }
+    }
+
+    let child = dir.child(name);
+    let path = child.path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(&mut writer, width, height);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    png_writer
+        .write_chunk(chunk::gAMA, &gamma.to_be_bytes())
+        .expect("failed to write gAMA chunk");
+    png_writer
+        .write_image_data(&pixels)
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
 pub fn derived_output_path(input: &Path, suffix: &str) -> PathBuf {
     let parent = input.parent().unwrap();
     let stem = input.file_stem().unwrap().to_string_lossy();
@@ -99,6 +376,55 @@ pub fn chunk_names(path: &Path) -> Vec<[u8; 4]> {
     names
 }
 
+/// A minimal ICC profile: the `acsp` signature, `color_space` and a recognisable body.
+pub fn icc_profile(color_space: &[u8; 4]) -> Vec<u8> {
+    let mut profile: Vec<u8> = (0..=255).collect();
+    profile[..128].fill(0);
+    profile[16..20].copy_from_slice(color_space);
+    profile[36..40].copy_from_slice(b"acsp");
+    profile
+}
+
+/// Wraps `profile` as an `iCCP` chunk payload.
+pub fn iccp_payload(profile: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder
+        .write_all(profile)
+        .expect("failed to compress profile");
+    let mut payload = b"test\0\0".to_vec();
+    payload.extend(encoder.finish().expect("failed to compress profile"));
+    payload
+}
+
+pub fn chunk_data(path: &Path, name: &[u8; 4]) -> Option<Vec<u8>> {
+    let data = std::fs::read(path).expect("failed to read PNG");
+    let mut index = 8;
+    while index + 8 <= data.len() {
+        let length = u32::from_be_bytes(data[index..index + 4].try_into().unwrap()) as usize;
+        if &data[index + 4..index + 8] == name {
+            return Some(data[index + 8..index + 8 + length].to_vec());
+        }
+        index += 12 + length;
+    }
+
+    None
+}
+
+/// Flips a bit in the stored CRC of the first `name` chunk, leaving its data intact;
+/// returns the chunk's byte offset.
+pub fn corrupt_chunk(path: &Path, name: &[u8; 4]) -> usize {
+    let mut bytes = std::fs::read(path).expect("failed to read PNG");
+    let offset = bytes
+        .windows(4)
+        .position(|window| window == name)
+        .expect("chunk not found")
+        - 4;
+    let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+    bytes[offset + 8 + length] ^= 0x01;
+    std::fs::write(path, &bytes).expect("failed to write PNG");
+    offset
+}
+
 pub fn file_size(path: &Path) -> u64 {
     fs::metadata(path)
         .unwrap_or_else(|err| panic!("failed to read metadata for {}: {err}", path.display()))
@@ -151,6 +477,60 @@ pub fn encoded_color_type(path: &Path) -> (ColorType, Option<usize>) {
     (info.color_type, palette_len)
 }
 
+/// 16-bit grayscale ramp from `from` on the left edge to `to` on the right, identical on
+/// every row.
+pub fn write_gray16_ramp(
+    dir: &TempDir,
+    name: &str,
+    width: u32,
+    height: u32,
+    from: u16,
+    to: u16,
+) -> PathBuf {
+    let row: Vec<u8> = (0..width)
+        .flat_map(|x| {
+            let value = u32::from(from) + (u32::from(to) - u32::from(from)) * x / (width - 1);
+            (value as u16).to_be_bytes()
+        })
+        .collect();
+
+    let path = dir.child(name).path().to_path_buf();
+    let file = File::create(&path).expect("failed to create PNG");
+    let mut encoder = Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(ColorType::Grayscale);
+    encoder.set_depth(BitDepth::Sixteen);
+    let mut png_writer = encoder.write_header().expect("failed to write PNG header");
+    png_writer
+        .write_image_data(&row.repeat(height as usize))
+        .expect("failed to write PNG pixels");
+    png_writer.finish().expect("failed to finalize PNG");
+
+    path
+}
+
+/// Noisy RGBA image stored Adam7-interlaced.
+pub fn write_interlaced(dir: &TempDir, name: &str, size: u32) -> PathBuf {
+    let path = write_unoptimized_rgba(dir, name, size, size);
+    let sequential = fs::read(&path).expect("failed to read fixture");
+    let options = oxipng::Options {
+        interlace: Some(oxipng::Interlacing::Adam7),
+        ..oxipng::Options::from_preset(0)
+    };
+    let interlaced =
+        oxipng::optimize_from_memory(&sequential, &options).expect("failed to interlace fixture");
+    fs::write(&path, interlaced).expect("failed to write interlaced fixture");
+    path
+}
+
+pub fn is_interlaced(path: &Path) -> bool {
+    let file =
+        File::open(path).unwrap_or_else(|err| panic!("failed to open {}: {err}", path.display()));
+    let reader = Decoder::new(file)
+        .read_info()
+        .expect("failed to read PNG info");
+    reader.info().interlaced
+}
+
 pub fn unique_color_count(image: &DecodedImage) -> usize {
     image
         .data
@@ -200,6 +580,25 @@ fn write_rgba_png(
     path
 }
 
+/// Every file under `dir` with its contents, in path order.
+pub fn snapshot_dir(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current).expect("failed to list directory") {
+            let path = entry.expect("failed to read directory entry").path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                let contents = fs::read(&path).expect("failed to read file");
+                files.push((path, contents));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
 fn noisy_pixels(width: u32, height: u32) -> Vec<u8> {
     let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
     for y in 0..height {
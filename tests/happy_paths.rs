@@ -3,6 +3,7 @@ use std::path::PathBuf;
 
 use assert_cmd::Command;
 use assert_fs::TempDir;
+use predicates::prelude::*;
 
 mod fixtures;
 
@@ -104,6 +105,163 @@ fn compress_low_quality_clamp_behaves() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[test]
+fn max_channel_diff_requantizes_files_that_stray_too_far() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+    let original = fixtures::decode_rgba(&input);
+
+    let mut diffs = Vec::new();
+    for limit in [None, Some("24")] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "compress", "--quality", "20", "--overwrite"])
+            .args(
+                limit
+                    .map(|limit| ["--max-channel-diff", limit])
+                    .into_iter()
+                    .flatten(),
+            )
+            .arg("--no-progress")
+            .arg(&input)
+            .assert()
+            .success();
+        diffs.push(fixtures::max_abs_channel_difference(
+            &original,
+            &fixtures::decode_rgba(&output),
+        ));
+    }
+    assert!(diffs[0] > 24, "unbounded diff {}", diffs[0]);
+    assert!(diffs[1] <= 24, "bounded diff {}", diffs[1]);
+
+    Ok(())
+}
+
+#[test]
+fn max_channel_diff_keeps_files_lossless_when_nothing_fits() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    for lossy in ["palette", "bitcrush"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "compress", "--lossy", lossy, "--overwrite"])
+            .args(["--max-channel-diff", "0", "--no-progress"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let original = fixtures::decode_rgba(&input);
+        let compressed = fixtures::decode_rgba(&output);
+        assert_eq!(original.data, compressed.data, "--lossy {lossy}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compress_keeps_images_within_the_palette_cap_exact() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles(
+        &temp,
+        "shades.png",
+        &[
+            [120, 120, 120, 255],
+            [122, 120, 120, 255],
+            [124, 120, 120, 255],
+            [126, 120, 120, 128],
+            [128, 120, 120, 255],
+            [130, 120, 120, 255],
+            [132, 120, 120, 255],
+        ],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let original = fixtures::decode_rgba(&input);
+    let compressed = fixtures::decode_rgba(&output);
+    assert_eq!(
+        fixtures::max_abs_channel_difference(&original, &compressed),
+        0
+    );
+    assert_eq!(
+        fixtures::encoded_color_type(&output),
+        (png::ColorType::Indexed, Some(7))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_write_larger_keeps_the_input_when_optimization_grows_it() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let source = fixtures::write_fixture(&temp, "source.png");
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&source)
+        .assert()
+        .success();
+    let input = fixtures::derived_output_path(&source, "_optimized.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    let optimize = |extra: &[&str]| {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .args([
+                "--set-text",
+                "Comment=a comment longer than anything left to save",
+            ])
+            .args(extra)
+            .arg(&input)
+            .assert()
+            .success();
+    };
+    optimize(&[]);
+    assert!(fixtures::file_size(&output) > fixtures::file_size(&input));
+
+    optimize(&["--no-write-larger"]);
+    assert_eq!(std::fs::read(&output)?, std::fs::read(&input)?);
+
+    Ok(())
+}
+
+#[test]
+fn allow_16to8_lets_optimize_scale_16_bit_samples() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gray16_ramp(&temp, "ramp16.png", 64, 8, 0, 65535);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    let bit_depth = || fixtures::chunk_data(&output, b"IHDR").map(|ihdr| ihdr[8]);
+
+    let optimize = |extra: &[&str]| {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .args(extra)
+            .arg(&input)
+            .assert()
+            .success();
+    };
+    optimize(&[]);
+    assert_eq!(bit_depth(), Some(16), "optimize stays lossless by default");
+
+    optimize(&["--allow-16to8"]);
+    assert_eq!(bit_depth(), Some(8));
+    let diff = fixtures::max_abs_channel_difference(
+        &fixtures::decode_rgba(&input),
+        &fixtures::decode_rgba(&output),
+    );
+    assert!(
+        diff <= 1,
+        "scaling keeps the ramp within rounding, observed {diff}"
+    );
+
+    Ok(())
+}
+
 #[test]
 fn threads_option_effect() -> Result<(), Box<dyn Error>> {
     let temp = TempDir::new()?;
@@ -124,3 +282,2003 @@ fn threads_option_effect() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn intra_threads_caps_oxipng_without_changing_its_output() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    let unrestricted = std::fs::read(&output)?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+        .args(["--intra-threads", "1"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&output)?, unrestricted);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn nice_lowers_the_priority_of_the_run() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "nice.png", 8, 8);
+    let reported = temp.path().join("niceness.txt");
+    let niceness = |output: &[u8]| -> Result<i32, Box<dyn Error>> {
+        Ok(String::from_utf8_lossy(output).trim().parse()?)
+    };
+    let before = niceness(&std::process::Command::new("nice").output()?.stdout)?;
+
+    // The post command inherits the priority of the thread processing the file.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "optimize",
+            "--no-progress",
+            "--nice",
+            "5",
+            "--post-cmd",
+        ])
+        .arg(format!("nice > '{}'", reported.display()))
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(niceness(&std::fs::read(&reported)?)?, (before + 5).min(19));
+
+    Ok(())
+}
+
+#[test]
+fn io_limit_paces_reads_and_writes() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "noise.png", 128, 128);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    let started = std::time::Instant::now();
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "optimize",
+            "--no-progress",
+            "--io-limit",
+            "32KiB/s",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+
+    // The first second's worth goes through at once; the rest waits for the limit.
+    let transferred = fixtures::file_size(&input) + fixtures::file_size(&output);
+    let paced = (transferred - 32 * 1024) as f64 / (32.0 * 1024.0);
+    assert!(
+        started.elapsed().as_secs_f64() >= paced * 0.9,
+        "moved {transferred} bytes in {:?}",
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mmap_produces_the_same_outputs_as_reading() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 32);
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        let output = fixtures::derived_output_path(&input, suffix);
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--overwrite"])
+            .arg(&input)
+            .assert()
+            .success();
+        let read = std::fs::read(&output)?;
+
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--overwrite", "--mmap"])
+            .arg(&input)
+            .assert()
+            .success();
+        assert_eq!(std::fs::read(&output)?, read, "{mode}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn emit_smallest_keeps_single_smallest_format() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "smallest.png", 32, 32);
+    let png_output = fixtures::derived_output_path(&input, "_optimized.png");
+    let webp_output = fixtures::derived_output_path(&input, "_optimized.webp");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--emit-smallest", "png,webp", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert!(
+        png_output.exists() != webp_output.exists(),
+        "exactly one output format should be kept"
+    );
+
+    let webp_only = fixtures::write_unoptimized_rgba(&temp, "webp_only.png", 8, 8);
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--emit-smallest", "webp", "--no-progress"])
+        .arg(&webp_only)
+        .assert()
+        .success();
+
+    let webp = std::fs::read(fixtures::derived_output_path(&webp_only, "_optimized.webp"))?;
+    assert!(webp.starts_with(b"RIFF") && &webp[8..12] == b"WEBP");
+
+    Ok(())
+}
+
+#[test]
+fn compress_quant_speed_override() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "fast.png");
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quant-speed", "10", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(output.exists());
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quant-speed", "11", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn compress_explicit_quality_window() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "window.png", 32, 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .args(["--quality-min", "0", "--quality-max", "100"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(output.exists());
+
+    Ok(())
+}
+
+#[test]
+fn compress_relaxes_unreachable_quality_floor() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "stubborn.png", 32, 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .args(["--quality-min", "100", "--quality-max", "100"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(output.exists());
+
+    Ok(())
+}
+
+#[test]
+fn compress_honours_source_gamma() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gamma_gradient(&temp, "dark_gradient.png", 55_556);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let max_diff = fixtures::max_abs_channel_difference(
+        &fixtures::decode_rgba(&input),
+        &fixtures::decode_rgba(&output),
+    );
+    assert!(
+        max_diff <= 8,
+        "expected a faithful dark gradient, observed diff {max_diff}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_linear_quantization_returns_source_colors() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles(
+        &temp,
+        "linear.png",
+        &[[96, 128, 160, 255], [40, 60, 80, 255], [200, 180, 30, 255]],
+    );
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "compress",
+            "--linear-quantization",
+            "--no-progress",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let max_diff = fixtures::max_abs_channel_difference(
+        &fixtures::decode_rgba(&input),
+        &fixtures::decode_rgba(&output),
+    );
+    assert!(
+        max_diff <= 5,
+        "expected palette in the source encoding, observed diff {max_diff}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_lock_colors_survive_verbatim() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let brand = [255, 102, 0, 255];
+    let input = fixtures::write_noisy_with_patch(&temp, "brand.png", 32, brand);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .args(["--lock-colors", "#FF6600"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let original = fixtures::decode_rgba(&input);
+    let compressed = fixtures::decode_rgba(&output);
+    for (before, after) in original
+        .data
+        .chunks_exact(4)
+        .zip(compressed.data.chunks_exact(4))
+    {
+        if before == brand {
+            assert_eq!(after, brand, "locked color must not be remapped");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compress_lock_colors_join_an_exact_palette() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles(&temp, "tiles.png", &[[0, 0, 0, 255], [255, 255, 255, 255]]);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .args(["--lock-colors", "#FF6600"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let palette = fixtures::chunk_data(&output, b"PLTE").expect("output should be indexed");
+    assert!(
+        palette.chunks_exact(3).any(|entry| entry == [255, 102, 0]),
+        "locked color missing from the palette"
+    );
+    assert_eq!(
+        fixtures::decode_rgba(&input).data,
+        fixtures::decode_rgba(&output).data
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_roi_mask_weights_quantization() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "roi.png", 32, 32);
+    let mask = fixtures::write_gamma_gradient(&temp, "mask.png", 45_455);
+    let matching_mask = fixtures::write_unoptimized_rgba(&temp, "matching_mask.png", 32, 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--roi-mask"])
+        .arg(&mask)
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("ROI mask"));
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--roi-mask"])
+        .arg(&matching_mask)
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(output.exists());
+
+    Ok(())
+}
+
+#[test]
+fn compress_crisp_alpha_keeps_edges_binary() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_sprite(&temp, "sprite.png", 32);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--crisp-alpha"])
+        .arg("--no-progress")
+        .arg(&input)
+        .assert()
+        .success();
+
+    let original = fixtures::decode_rgba(&input);
+    let compressed = fixtures::decode_rgba(&output);
+    for (before, after) in original
+        .data
+        .chunks_exact(4)
+        .zip(compressed.data.chunks_exact(4))
+    {
+        match before[3] {
+            0 => assert_eq!(after[3], 0, "transparent pixels must stay transparent"),
+            255 => assert_eq!(after[3], 255, "opaque pixels must stay opaque"),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compress_keeps_gradient_heavy_images_lossless() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gamma_gradient(&temp, "gradient.png", 45_455);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+    let original = fixtures::decode_rgba(&input);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(
+        fixtures::max_abs_channel_difference(&original, &fixtures::decode_rgba(&output)),
+        0,
+        "banding-prone gradients should fall back to lossless"
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .args(["--no-banding-check", "--overwrite"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(
+        fixtures::max_abs_channel_difference(&original, &fixtures::decode_rgba(&output)) > 0,
+        "without the check a 16-color palette must band the gradient"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_adaptive_gives_photos_a_larger_palette() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    let fixed = fixtures::unique_color_count(&fixtures::decode_rgba(&output));
+    assert!(fixed <= 16, "quality 20 caps the palette at 16 colors");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .args(["--adaptive", "--overwrite"])
+        .arg(&input)
+        .assert()
+        .success();
+    let adaptive = fixtures::unique_color_count(&fixtures::decode_rgba(&output));
+    assert!(
+        adaptive > fixed,
+        "photo content should get a larger palette ({adaptive} vs {fixed})"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn input_quality_overrides_the_quality_per_path() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    std::fs::create_dir(temp.path().join("thumbs"))?;
+    let thumb = fixtures::write_photo(&temp, "thumbs/thumb.png", 64);
+    let hero = fixtures::write_photo(&temp, "hero.png", 64);
+    let banner = fixtures::write_photo(&temp, "banner.png", 64);
+
+    let mut input_quality = temp.path().join("thumbs").into_os_string();
+    input_quality.push("=20");
+    let mut hero_arg = hero.clone().into_os_string();
+    hero_arg.push(":20");
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "90", "--no-progress"])
+        .arg("--input-quality")
+        .arg(input_quality)
+        .arg(temp.path().join("thumbs"))
+        .arg(hero_arg)
+        .arg(&banner)
+        .assert()
+        .success();
+
+    let colors = |input: &PathBuf| {
+        let output = fixtures::derived_output_path(input, "_compressed.png");
+        fixtures::unique_color_count(&fixtures::decode_rgba(&output))
+    };
+    assert!(colors(&thumb) <= 16, "--input-quality caps the thumbnail");
+    assert!(colors(&hero) <= 16, "PATH:LEVEL caps the hero image");
+    assert!(colors(&banner) > 16, "other inputs keep --quality 90");
+
+    Ok(())
+}
+
+#[test]
+fn max_colors_256_lifts_the_quality_palette_cap() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .args(["--max-colors-256", "--no-banding-check"])
+        .arg(&input)
+        .assert()
+        .success();
+    let colors = fixtures::unique_color_count(&fixtures::decode_rgba(&output));
+    assert!(
+        colors > 16,
+        "quality 20 alone caps the palette at 16 colors, got {colors}"
+    );
+    assert!(colors <= 256);
+
+    Ok(())
+}
+
+#[test]
+fn compress_keeps_already_indexed_inputs_exact() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let palette: Vec<[u8; 3]> = (0..16u8)
+        .map(|i| [i * 3, 50 + i * 3, 200 - i * 2])
+        .collect();
+    let input = fixtures::write_indexed(&temp, "indexed.png", &palette);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let original = fixtures::decode_rgba(&input);
+    assert_eq!(
+        fixtures::max_abs_channel_difference(&original, &fixtures::decode_rgba(&output)),
+        0,
+        "a palette that already fits must not be requantized"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_merges_oversized_indexed_palettes_without_dithering() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let palette: Vec<[u8; 3]> = (0..64u8)
+        .map(|i| [i * 4, 255 - i * 3, i.wrapping_mul(7)])
+        .collect();
+    let alpha: Vec<u8> = (0..64u8)
+        .map(|i| [0, 96, 160, 255][usize::from(i % 4)])
+        .collect();
+    let input = fixtures::write_indexed_with_trns(&temp, "retro.png", &palette, &alpha);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--quality", "20", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fixtures::encoded_color_type(&output).0,
+        png::ColorType::Indexed
+    );
+    let original = fixtures::decode_rgba(&input);
+    let compressed = fixtures::decode_rgba(&output);
+    let mut mapping = std::collections::HashMap::new();
+    for (source, merged) in original
+        .data
+        .chunks_exact(4)
+        .zip(compressed.data.chunks_exact(4))
+    {
+        let previous = mapping.insert(source.to_vec(), merged.to_vec());
+        assert!(
+            previous.is_none_or(|previous| previous == merged),
+            "pixels of source color {source:?} were mapped to different colors"
+        );
+        if source[3] == 0 {
+            assert_eq!(merged[3], 0, "transparent entries must stay transparent");
+        }
+    }
+    assert!(
+        mapping
+            .values()
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+            < 64,
+        "the palette should have been reduced"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn compress_dithers_16_bit_sources_down_to_8_bits() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let (width, from, to) = (64u32, 0x1000u16, 0x1400u16);
+    let input = fixtures::write_gray16_ramp(&temp, "ramp16.png", width, 32, from, to);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-banding-check", "--no-progress"])
+        // A maximal quality window keeps every 8-bit level the ramp passes through.
+        .args(["--quality-min", "100", "--quality-max", "100"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    // Truncation turns the ramp into a few hard steps, each off by up to a whole level;
+    // dithering keeps every column's average on the 16-bit value.
+    let compressed = fixtures::decode_rgba(&output);
+    for x in 0..width {
+        let expected =
+            (f64::from(from) + f64::from(to - from) * f64::from(x) / f64::from(width - 1)) / 257.0;
+        let column: Vec<f64> = (0..compressed.height)
+            .map(|y| f64::from(compressed.data[((y * width + x) * 4) as usize]))
+            .collect();
+        let mean = column.iter().sum::<f64>() / column.len() as f64;
+        assert!(
+            (mean - expected).abs() < 0.35,
+            "column {x} averages {mean:.2}, expected {expected:.2}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn crc_fix_and_ignore_process_files_with_bad_crcs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "scanned.png");
+    fixtures::corrupt_chunk(&input, b"tEXt");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .failure();
+    assert!(!output.exists(), "strict mode rejects bad CRCs");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--crc", "fix"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(output.exists());
+
+    fixtures::corrupt_chunk(&input, b"IDAT");
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--crc", "ignore"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[test]
+fn apng_split_and_join_round_trip_frames() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_apng(&temp, "anim.png");
+    let frames_dir = temp.path().join("frames");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["apng", "split"])
+        .arg(&input)
+        .arg("--out")
+        .arg(&frames_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("2 frames"));
+
+    let first = fixtures::decode_rgba(&frames_dir.join("anim_0001.png"));
+    let second = fixtures::decode_rgba(&frames_dir.join("anim_0002.png"));
+    assert_eq!(first.data, [255, 0, 0, 255].repeat(64));
+    let pixel = |image: &fixtures::DecodedImage, x: usize, y: usize| {
+        image.data[(y * 8 + x) * 4..(y * 8 + x) * 4 + 4].to_vec()
+    };
+    assert_eq!(
+        pixel(&second, 0, 0),
+        [255, 0, 0, 255],
+        "first frame shows through"
+    );
+    assert_eq!(
+        pixel(&second, 3, 3),
+        [0, 0, 255, 255],
+        "second frame composited"
+    );
+
+    let joined = temp.path().join("joined.png");
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["apng", "join"])
+        .arg(frames_dir.join("anim_0001.png"))
+        .arg(frames_dir.join("anim_0002.png"))
+        .args(["--fps", "12", "-o"])
+        .arg(&joined)
+        .assert()
+        .success();
+
+    let mut reader = png::Decoder::new(std::fs::File::open(&joined)?).read_info()?;
+    assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+    let animation = reader.info().animation_control().copied().expect("acTL");
+    assert_eq!(animation.num_frames, 2);
+    let control = reader.info().frame_control().copied().expect("fcTL");
+    assert_eq!((control.delay_num, control.delay_den), (1, 12));
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buffer)?;
+    reader.next_frame(&mut buffer)?;
+
+    Ok(())
+}
+
+#[test]
+fn apng_join_maps_every_frame_against_the_same_palette() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let photo = fixtures::write_photo(&temp, "photo.png", 32);
+    let noise = fixtures::write_unoptimized_rgba(&temp, "noise.png", 32, 32);
+    let joined = temp.path().join("joined.png");
+
+    // Quality 60 dithers with a dither map, the remap that could refine the palette.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["apng", "join"])
+        .arg(&photo)
+        .arg(&noise)
+        .arg(&photo)
+        .args(["--quality", "60", "-o"])
+        .arg(&joined)
+        .assert()
+        .success();
+
+    let mut decoder = png::Decoder::new(std::fs::File::open(&joined)?);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut frames = Vec::new();
+    for _ in 0..3 {
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buffer)?;
+        frames.push(buffer);
+    }
+    assert_eq!(frames[0], frames[2], "equal frames map to equal pixels");
+
+    Ok(())
+}
+
+#[test]
+fn apng_frames_survive_both_modes_in_place() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_apng(&temp, "anim.apng");
+    let frames = |path: &std::path::Path| -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let mut decoder = png::Decoder::new(std::fs::File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let mut frames = Vec::new();
+        for _ in 0..reader.info().animation_control().expect("acTL").num_frames {
+            let mut buffer = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buffer)?;
+            buffer.truncate(info.buffer_size());
+            frames.push(buffer);
+        }
+        Ok(frames)
+    };
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.apng"),
+        ("compress", "_compressed.apng"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let output = fixtures::derived_output_path(&input, suffix);
+        let animation: Vec<[u8; 4]> = fixtures::chunk_names(&output)
+            .into_iter()
+            .filter(|name| matches!(name, b"acTL" | b"fcTL" | b"IDAT" | b"fdAT"))
+            .collect();
+        assert_eq!(
+            animation,
+            [*b"acTL", *b"fcTL", *b"IDAT", *b"fcTL", *b"fdAT"],
+            "{mode}"
+        );
+        assert_eq!(frames(&output)?, frames(&input)?, "{mode}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn apng_policy_passes_through_or_flattens_animations() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_apng(&temp, "anim.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--apng",
+            "passthrough",
+            "--set-text",
+            "Title=x",
+            "--no-progress",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&output)?, std::fs::read(&input)?);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--apng", "flatten", "--overwrite", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    let names = fixtures::chunk_names(&output);
+    assert!(
+        !names
+            .iter()
+            .any(|name| matches!(name, b"acTL" | b"fcTL" | b"fdAT")),
+        "{names:?}"
+    );
+    assert_eq!(
+        fixtures::decode_rgba(&output).data,
+        [255, 0, 0, 255].repeat(64)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn apng_extension_is_walked_and_kept_on_outputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_apng(&temp, "anim.apng");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("--no-progress")
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    assert!(temp.path().join("anim_optimized.apng").is_file());
+    assert!(!temp.path().join("anim_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn compare_reports_identical_images_as_lossless() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("compare")
+        .arg(&input)
+        .arg(&output)
+        .args(["--max-delta", "0", "--max-dssim", "0"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("max delta:  0"))
+        .stdout(predicates::str::contains("PSNR:       inf dB"));
+
+    Ok(())
+}
+
+#[test]
+fn stable_palette_keeps_palette_order_and_indices() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    // Unsorted, with a duplicate entry, so palette reduction would rewrite it.
+    let palette = [
+        [250, 250, 250],
+        [10, 10, 10],
+        [250, 250, 250],
+        [200, 30, 30],
+    ];
+    let input = fixtures::write_indexed(&temp, "lut.png", &palette);
+    let indices = |path: &std::path::Path| -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut reader = png::Decoder::new(std::fs::File::open(path)?).read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buffer)?;
+        Ok(buffer)
+    };
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    let reduced = fixtures::derived_output_path(&input, "_optimized.png");
+    assert_ne!(
+        fixtures::chunk_data(&reduced, b"PLTE"),
+        fixtures::chunk_data(&input, b"PLTE"),
+        "without the flag the palette is rewritten"
+    );
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--overwrite"])
+            .arg("--stable-palette")
+            .arg(&input)
+            .assert()
+            .success();
+
+        let output = fixtures::derived_output_path(&input, suffix);
+        assert_eq!(
+            fixtures::chunk_data(&output, b"PLTE"),
+            fixtures::chunk_data(&input, b"PLTE"),
+            "{mode} output"
+        );
+        assert_eq!(indices(&output)?, indices(&input)?, "{mode} output");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn analyze_recommends_a_mode_per_image() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let cases = [
+        (
+            fixtures::write_palette_source(&temp, "tiles.png"),
+            "--mode optimize (4 colors",
+        ),
+        (
+            fixtures::write_gamma_gradient(&temp, "gradient.png", 45_455),
+            "(severe)",
+        ),
+        (
+            fixtures::write_photo(&temp, "photo.png", 64),
+            "--mode compress --adaptive (256 colors, dithered)",
+        ),
+    ];
+
+    for (input, expected) in cases {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .arg("analyze")
+            .arg(&input)
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("unique colors:"))
+            .stdout(predicates::str::contains(expected));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stats_reports_chunks_and_formats_without_touching_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_interlaced(&temp, "interlaced.png", 16);
+    fixtures::write_tiles_with_chunks(&temp, "texts.png", &[(*b"tEXt", b"Comment\0hello")]);
+    std::fs::create_dir(temp.path().join("nested"))?;
+    std::fs::write(temp.path().join("nested/broken.png"), b"not a png")?;
+    let before = fixtures::snapshot_dir(temp.path());
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("stats")
+        .arg(temp.path())
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("2 PNGs"))
+        .stdout(predicates::str::contains("(1 unreadable)"))
+        .stdout(predicates::str::is_match(r"(?m)^tEXt +1 +1 +25 B")?)
+        .stdout(predicates::str::contains(
+            "interlaced (Adam7): 1 of 2 files",
+        ))
+        .stderr(predicates::str::contains("broken.png"));
+
+    assert_eq!(fixtures::snapshot_dir(temp.path()), before);
+
+    Ok(())
+}
+
+#[test]
+fn doctor_reports_environment_and_defaults() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("doctor")
+        .current_dir(temp.path())
+        .env("RAYON_NUM_THREADS", "3")
+        .assert()
+        .success()
+        .stdout(predicates::str::is_match(r"(?m)^arch: +\S+")?)
+        .stdout(predicates::str::contains(
+            "thread pool:   3 threads (RAYON_NUM_THREADS=3)",
+        ))
+        .stdout(predicates::str::is_match(
+            r"(?m)^temp dir: .*\(writable\)$",
+        )?)
+        .stdout(predicates::str::contains("stdout:        not a terminal"))
+        .stdout(predicates::str::contains("mode:          optimize"))
+        .stdout(predicates::str::contains("compress:      quality 90"));
+    assert!(fixtures::snapshot_dir(temp.path()).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn baseline_is_kept_unless_the_new_output_is_smaller() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "hero.png", 32);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    std::fs::create_dir(temp.path().join("shipped"))?;
+    let run = || {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .arg("--baseline")
+            .arg(temp.path().join("shipped"))
+            .arg(&input)
+            .assert()
+            .success();
+    };
+
+    // A baseline that is already smaller wins.
+    let tiny = fixtures::write_fixture(&temp, "shipped/hero_optimized.png");
+    run();
+    assert_eq!(std::fs::read(&output)?, std::fs::read(&tiny)?);
+
+    // A bloated baseline is replaced by the new output.
+    let bloated = fixtures::write_photo(&temp, "shipped/hero_optimized.png", 64);
+    run();
+    assert!(fixtures::file_size(&output) < fixtures::file_size(&bloated));
+
+    Ok(())
+}
+
+#[test]
+fn temp_dir_stages_outputs_outside_the_output_directory() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    let staging = TempDir::new()?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--temp-dir"])
+        .arg(staging.path())
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert!(output.exists());
+    assert_eq!(std::fs::read_dir(staging.path())?.count(), 0);
+    let leftovers = std::fs::read_dir(temp.path())?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--temp-dir"])
+        .arg(temp.path().join("missing"))
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("temp directory"));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn temp_dir_on_another_filesystem_copies_outputs_into_place() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new()?;
+    let Ok(staging) = TempDir::new_in("/dev/shm") else {
+        return Ok(());
+    };
+    if std::fs::metadata(staging.path())?.dev() == std::fs::metadata(temp.path())?.dev() {
+        return Ok(());
+    }
+    let input = fixtures::write_fixture(&temp, "sample.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    std::fs::write(&output, b"stale")?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--fsync"])
+        .arg("--temp-dir")
+        .arg(staging.path())
+        .arg(&input)
+        .assert()
+        .failure();
+    assert_eq!(std::fs::read(&output)?, b"stale");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "optimize",
+            "--no-progress",
+            "--fsync",
+            "--overwrite",
+        ])
+        .arg("--temp-dir")
+        .arg(staging.path())
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_ne!(std::fs::read(&output)?, b"stale");
+    assert_eq!(std::fs::read_dir(staging.path())?.count(), 0);
+    let leftovers = std::fs::read_dir(temp.path())?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    Ok(())
+}
+
+#[test]
+fn fsync_writes_outputs_in_both_modes() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "sample.png");
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--fsync"])
+            .arg(&input)
+            .assert()
+            .success();
+        assert!(fixtures::derived_output_path(&input, suffix).exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compress_keeps_adam7_interlacing() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let interlaced = fixtures::write_interlaced(&temp, "interlaced.png", 32);
+    let sequential = fixtures::write_unoptimized_rgba(&temp, "sequential.png", 32, 32);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress"])
+        .arg(&interlaced)
+        .arg(&sequential)
+        .assert()
+        .success();
+
+    assert!(fixtures::is_interlaced(&fixtures::derived_output_path(
+        &interlaced,
+        "_compressed.png"
+    )));
+    assert!(!fixtures::is_interlaced(&fixtures::derived_output_path(
+        &sequential,
+        "_compressed.png"
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn compress_decodes_every_8_bit_color_type() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    for (name, color_type) in [
+        ("rgba.png", png::ColorType::Rgba),
+        ("rgb.png", png::ColorType::Rgb),
+        ("gray_alpha.png", png::ColorType::GrayscaleAlpha),
+        ("gray.png", png::ColorType::Grayscale),
+    ] {
+        let input = fixtures::write_quadrants(&temp, name, color_type);
+        let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "compress", "--no-progress"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let original = fixtures::decode_rgba(&input);
+        let compressed = fixtures::decode_rgba(&output);
+        assert_eq!(
+            fixtures::max_abs_channel_difference(&original, &compressed),
+            0,
+            "four flat colors survive quantization exactly ({name})"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn deflater_backends_produce_identical_pixels() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 32);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    let original = fixtures::decode_rgba(&input);
+
+    for args in [
+        &["--deflater", "libdeflate", "--compression-level", "6"][..],
+        &["--deflater", "zlib"][..],
+        &["--deflater", "zopfli", "--compression-level", "2"][..],
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", "optimize", "--no-progress", "--overwrite"])
+            .args(args)
+            .arg(&input)
+            .assert()
+            .success();
+        assert_eq!(fixtures::decode_rgba(&output), original, "{args:?}");
+    }
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--no-progress",
+            "--deflater",
+            "zlib",
+            "--compression-level",
+            "12",
+        ])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "--compression-level 12 is out of range for zlib (0-9)",
+        ));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn post_cmd_keeps_smaller_result_and_tolerates_failures() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let tiny = fixtures::write_fixture(&temp, "tiny.png");
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--post-cmd"])
+        .arg(format!("cp '{}' {{out}}", tiny.display()))
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(fixtures::file_size(&output), fixtures::file_size(&tiny));
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--overwrite"])
+        .args([
+            "--emit-sidecar",
+            "--post-cmd",
+            "echo 'no optimizer here' >&2; exit 3",
+        ])
+        .arg(&input)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("no optimizer here").not());
+    assert!(fixtures::file_size(&output) > fixtures::file_size(&tiny));
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(output.with_extension("png.json"))?)?;
+    let notes = json["notes"].as_array().expect("notes are listed");
+    assert!(
+        notes.iter().any(|note| note
+            .as_str()
+            .is_some_and(|note| note.contains("exit status: 3: no optimizer here"))),
+        "{notes:?}"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn post_cmd_results_with_other_pixels_are_refused_in_optimize_mode() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let tiny = fixtures::write_fixture(&temp, "tiny.png");
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--emit-sidecar"])
+        .arg("--post-cmd")
+        .arg(format!("cp '{}' {{out}}", tiny.display()))
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fixtures::decode_rgba(&output).data,
+        fixtures::decode_rgba(&input).data
+    );
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(output.with_extension("png.json"))?)?;
+    let notes = json["notes"].as_array().expect("notes are listed");
+    assert!(
+        notes.contains(&"post-cmd failed (command changed the pixels), kept ours".into()),
+        "{notes:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn skip_quantize_keeps_compress_mode_lossless() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--skip-quantize"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let difference = fixtures::max_abs_channel_difference(
+        &fixtures::decode_rgba(&input),
+        &fixtures::decode_rgba(&output),
+    );
+    assert_eq!(difference, 0);
+
+    Ok(())
+}
+
+#[test]
+fn only_deflate_keeps_the_pixel_format() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_quadrants(&temp, "quadrants.png", png::ColorType::Rgba);
+
+    for (mode, suffix) in [
+        ("optimize", "_optimized.png"),
+        ("compress", "_compressed.png"),
+    ] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--mode", mode, "--no-progress", "--only-deflate"])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let output = fixtures::derived_output_path(&input, suffix);
+        assert_eq!(
+            fixtures::encoded_color_type(&output).0,
+            png::ColorType::Rgba
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn skip_stage_deflate_writes_the_encoded_palette() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "compress",
+            "--no-progress",
+            "--skip-stage",
+            "deflate",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fixtures::encoded_color_type(&output).0,
+        png::ColorType::Indexed
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lossy_bitcrush_stays_truecolor_within_a_rounding_step() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 64);
+    let output = fixtures::derived_output_path(&input, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--lossy", "bitcrush"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert_ne!(
+        fixtures::encoded_color_type(&output).0,
+        png::ColorType::Indexed
+    );
+    let source = fixtures::decode_rgba(&input);
+    let crushed = fixtures::decode_rgba(&output);
+    // Five bits per channel at the default quality: levels are 255 / 31 ≈ 8.2 apart.
+    assert!(fixtures::max_abs_channel_difference(&source, &crushed) <= 5);
+    assert!(fixtures::unique_color_count(&crushed) <= fixtures::unique_color_count(&source));
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "compress", "--no-progress", "--overwrite"])
+        .args(["--lossy", "none"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(
+        fixtures::max_abs_channel_difference(&source, &fixtures::decode_rgba(&output)),
+        0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn exhausted_time_budget_skips_remaining_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let first = fixtures::write_fixture(&temp, "first.png");
+    let second = fixtures::write_fixture(&temp, "second.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--time-budget", "0s"])
+        .arg(&first)
+        .arg(&second)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "time budget exhausted after 0 of 2 files",
+        ))
+        .stderr(predicates::str::contains("second.png"));
+    assert!(!fixtures::derived_output_path(&first, "_optimized.png").exists());
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--time-budget", "1h"])
+        .arg(&first)
+        .arg(&second)
+        .assert()
+        .success()
+        .stderr(predicates::str::is_empty());
+    assert!(fixtures::derived_output_path(&second, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn time_budget_report_lists_only_unstarted_files_even_when_others_fail()
+-> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let broken = temp.path().join("a_broken.png");
+    std::fs::write(&broken, b"not a png")?;
+    let slow = fixtures::write_fixture(&temp, "b_slow.png");
+    let unstarted = fixtures::write_fixture(&temp, "c_unstarted.png");
+
+    // The broken file fails at once; the next one outlasts the budget in its post-cmd.
+    let output = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--order", "name", "--time-budget", "1s"])
+        .args(["--post-cmd", "sleep 1.5"])
+        .arg(&broken)
+        .arg(&slow)
+        .arg(&unstarted)
+        .assert()
+        .failure()
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("time budget exhausted after 2 of 3 files; skipped:"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(&format!("  - {}", unstarted.display())),
+        "{stderr}"
+    );
+    assert!(
+        !stderr.contains(&format!("  - {}", broken.display())),
+        "{stderr}"
+    );
+    assert!(fixtures::derived_output_path(&slow, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_finishes_the_current_file_and_still_writes_run_reports() -> Result<(), Box<dyn Error>>
+{
+    let temp = TempDir::new()?;
+    let first = fixtures::write_fixture(&temp, "a_first.png");
+    let unstarted = fixtures::write_fixture(&temp, "b_unstarted.png");
+    let manifest = temp.path().join("manifest.json");
+
+    // The first file's post-cmd interrupts turbo-png, its parent, while the file is in flight.
+    let output = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .current_dir(temp.path())
+        .args(["--no-progress", "--order", "name", "--checksums", "sha256"])
+        .args(["--post-cmd", "kill -INT $PPID"])
+        .arg("--manifest-out")
+        .arg(&manifest)
+        .arg(&first)
+        .arg(&unstarted)
+        .assert()
+        .code(130)
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("interrupted after 1 of 2 files; the rest were not processed"),
+        "{stderr}"
+    );
+
+    assert!(fixtures::derived_output_path(&first, "_optimized.png").exists());
+    assert!(!fixtures::derived_output_path(&unstarted, "_optimized.png").exists());
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest)?)?;
+    assert_eq!(json.as_object().map(|entries| entries.len()), Some(1));
+    let sums = std::fs::read_to_string(temp.path().join("SHA256SUMS"))?;
+    assert!(sums.contains("a_first_optimized.png"), "{sums}");
+    assert!(!sums.contains("b_unstarted"), "{sums}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn paused_time_does_not_count_against_the_time_budget() -> Result<(), Box<dyn Error>> {
+    use std::process::{Command as Process, Stdio};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let temp = TempDir::new()?;
+    let inputs: Vec<_> = ["a.png", "b.png", "c.png"]
+        .into_iter()
+        .map(|name| fixtures::write_fixture(&temp, name))
+        .collect();
+
+    // Each file takes about half a second, so the run needs ~1.5s of its 3s budget,
+    // but spends longer than that stopped.
+    let child = Process::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--no-progress",
+            "--time-budget",
+            "3s",
+            "--post-cmd",
+            "sleep 0.5",
+        ])
+        .args(&inputs)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let signal = |name: &str| {
+        Process::new("kill")
+            .args([name, &child.id().to_string()])
+            .status()
+    };
+    sleep(Duration::from_millis(200));
+    assert!(signal("-TSTP")?.success());
+    sleep(Duration::from_secs(4));
+    assert!(signal("-CONT")?.success());
+
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("time budget exhausted"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    for input in &inputs {
+        assert!(fixtures::derived_output_path(input, "_optimized.png").exists());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn shards_partition_inputs_without_overlap() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let inputs: Vec<_> = ["a.png", "b.png", "c.png", "d.png", "e.png"]
+        .into_iter()
+        .map(|name| fixtures::write_fixture(&temp, name))
+        .collect();
+
+    let mut processed = Vec::new();
+    for shard in ["1/2", "2/2"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--shard", shard])
+            .args(&inputs)
+            .assert()
+            .success();
+
+        let count = inputs
+            .iter()
+            .filter(|input| fixtures::derived_output_path(input, "_optimized.png").exists())
+            .count();
+        processed.push(count);
+    }
+
+    // The first shard takes a, c, e; the second adds b and d.
+    assert_eq!(processed, [3, 5]);
+
+    Ok(())
+}
+
+#[test]
+fn output_dir_mirrors_input_tree() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    std::fs::create_dir_all(temp.path().join("assets/icons"))?;
+    fixtures::write_fixture(&temp, "assets/icons/a.png");
+    fixtures::write_fixture(&temp, "assets/b.png");
+    let out = temp.path().join("out");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--output-dir"])
+        .arg(&out)
+        .arg(temp.path().join("assets"))
+        .assert()
+        .success();
+
+    assert!(out.join("icons/a_optimized.png").exists());
+    assert!(out.join("b_optimized.png").exists());
+    assert!(!temp.path().join("assets/b_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn copy_unchanged_completes_the_output_tree() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    std::fs::create_dir_all(temp.path().join("site/icons"))?;
+    fixtures::write_fixture(&temp, "site/icons/a.png");
+    std::fs::write(temp.path().join("site/index.html"), "<img src=icons/a.png>")?;
+    std::fs::write(temp.path().join("site/icons/notes.txt"), "kept")?;
+    let site = temp.path().join("site");
+
+    let run = |out: &str, extra: &[&str]| {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--copy-unchanged"])
+            .args(extra)
+            .arg("--output-dir")
+            .arg(temp.path().join(out))
+            .arg(&site)
+            .assert()
+            .success();
+        temp.path().join(out)
+    };
+
+    let out = run("out", &[]);
+    assert!(out.join("icons/a_optimized.png").exists());
+    assert_eq!(
+        std::fs::read_to_string(out.join("index.html"))?,
+        "<img src=icons/a.png>"
+    );
+    assert_eq!(
+        std::fs::read_to_string(out.join("icons/notes.txt"))?,
+        "kept"
+    );
+
+    // A filter that skips every PNG still leaves a complete tree.
+    let skipped = run("skipped", &["--newer-than", "4102444800"]);
+    assert_eq!(
+        std::fs::read(skipped.join("icons/a_optimized.png"))?,
+        std::fs::read(site.join("icons/a.png"))?
+    );
+    assert!(skipped.join("index.html").exists());
+
+    Ok(())
+}
+
+#[test]
+fn flattened_output_dir_applies_collision_policy() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    std::fs::create_dir_all(temp.path().join("assets/x"))?;
+    std::fs::create_dir_all(temp.path().join("assets/y"))?;
+    fixtures::write_fixture(&temp, "assets/x/a.png");
+    fixtures::write_fixture(&temp, "assets/y/a.png");
+    fixtures::write_apng(&temp, "assets/y/a.apng");
+    let assets = temp.path().join("assets");
+
+    let run = |out: &str, policy: &str| {
+        let mut command = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"));
+        command
+            .args(["--no-progress", "--flatten", "--on-collision", policy])
+            .arg("--rename-map")
+            .arg(temp.path().join(format!("{out}.csv")))
+            .arg("--output-dir")
+            .arg(temp.path().join(out))
+            .arg(&assets);
+        command.assert()
+    };
+
+    run("error", "error")
+        .failure()
+        .stderr(predicates::str::contains("would both be written as a"));
+    assert!(!temp.path().join("error").exists());
+
+    run("rename", "rename").success();
+    assert!(temp.path().join("rename/a_optimized.png").exists());
+    assert!(temp.path().join("rename/a-2_optimized.png").exists());
+
+    run("skip", "skip").success();
+    assert!(temp.path().join("skip/a_optimized.png").exists());
+    assert!(!temp.path().join("skip/a-2_optimized.png").exists());
+    // An animation's output keeps its extension, so it takes no PNG's name.
+    assert!(temp.path().join("skip/a_optimized.apng").exists());
+    let skipped = format!(
+        "{},{},skipped",
+        assets.canonicalize()?.join("y/a.png").display(),
+        temp.path().join("skip/a_optimized.png").display()
+    );
+    assert!(std::fs::read_to_string(temp.path().join("skip.csv"))?.contains(&skipped));
+
+    Ok(())
+}
+
+#[test]
+fn force_writes_new_output_over_smaller_baseline() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_photo(&temp, "photo.png", 32);
+    let shipped = temp.path().join("shipped");
+    std::fs::create_dir(&shipped)?;
+    std::fs::write(shipped.join("photo_optimized.png"), b"tiny")?;
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--force", "--baseline"])
+        .arg(&shipped)
+        .arg(&input)
+        .assert()
+        .success();
+    assert_ne!(std::fs::read(&output)?, b"tiny");
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "remote"))]
+#[test]
+fn ssh_output_streams_results_through_the_ssh_command() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new()?;
+    std::fs::create_dir_all(temp.path().join("assets/icons"))?;
+    fixtures::write_fixture(&temp, "assets/icons/a.png");
+    fixtures::write_fixture(&temp, "assets/b.png");
+    let remote = temp.path().join("remote dir");
+    // Stands in for ssh: logs its options and host, then runs the remote script locally.
+    // The space in its path checks that --ssh-command is split like a shell would.
+    let ssh = temp.path().join("fake ssh");
+    std::fs::write(
+        &ssh,
+        "#!/bin/sh\n\
+         log=\"$(dirname \"$0\")/ssh.log\"\n\
+         while [ $# -gt 1 ]; do printf '%s ' \"$1\" >> \"$log\"; shift; done\n\
+         echo >> \"$log\"\n\
+         exec sh -c \"$1\"\n",
+    )?;
+    std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755))?;
+    let run = || {
+        let mut command = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"));
+        command
+            .args(["--no-progress", "--output"])
+            .arg(format!("ssh://deploy@cdn.example:2222{}", remote.display()))
+            .arg("--ssh-command")
+            .arg(format!("'{}' -o BatchMode=yes", ssh.display()))
+            .arg(temp.path().join("assets"));
+        command
+    };
+
+    run().assert().success();
+    assert!(remote.join("icons/a_optimized.png").exists());
+    assert!(remote.join("b_optimized.png").exists());
+    assert!(!temp.path().join("assets/b_optimized.png").exists());
+    assert!(!remote.join(".b_optimized.png.turbo-png.tmp").exists());
+    let log = std::fs::read_to_string(temp.path().join("ssh.log"))?;
+    assert_eq!(log.lines().count(), 2);
+    assert!(
+        log.lines()
+            .all(|line| line == "-o BatchMode=yes -p 2222 deploy@cdn.example ")
+    );
+
+    run()
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("already exists"));
+    run().arg("--overwrite").assert().success();
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "remote"))]
+#[test]
+fn sftp_output_with_an_ipv6_host_runs_the_ssh_command() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new()?;
+    let input = fixtures::write_fixture(&temp, "a.png");
+    let remote = temp.path().join("remote");
+    let ssh = temp.path().join("ssh");
+    std::fs::write(
+        &ssh,
+        "#!/bin/sh\n\
+         log=\"$(dirname \"$0\")/ssh.log\"\n\
+         while [ $# -gt 1 ]; do printf '%s ' \"$1\" >> \"$log\"; shift; done\n\
+         echo >> \"$log\"\n\
+         exec sh -c \"$1\"\n",
+    )?;
+    std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755))?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--output"])
+        .arg(format!("sftp://deploy@[::1]:2222{}", remote.display()))
+        .arg("--ssh-command")
+        .arg(&ssh)
+        .arg(&input)
+        .assert()
+        .success();
+    assert!(remote.join("a_optimized.png").exists());
+    assert_eq!(
+        std::fs::read_to_string(temp.path().join("ssh.log"))?,
+        "-p 2222 deploy@::1 \n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn base64_round_trips_data_urls_through_stdin_and_stdout() -> Result<(), Box<dyn Error>> {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "inline.png", 16, 16);
+    let original = std::fs::read(&input)?;
+    let data_url = format!("data:image/png;base64,{}\n", STANDARD.encode(&original));
+
+    let stdout = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("--base64")
+        .current_dir(temp.path())
+        .write_stdin(data_url)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(stdout)?;
+    let encoded = stdout
+        .trim_end()
+        .strip_prefix("data:image/png;base64,")
+        .expect("a PNG data URL on stdout");
+    let optimized = temp.path().join("inline_from_stdout.png");
+    std::fs::write(&optimized, STANDARD.decode(encoded)?)?;
+    assert!(fixtures::file_size(&optimized) < original.len() as u64);
+    assert_eq!(
+        fixtures::decode_rgba(&input),
+        fixtures::decode_rgba(&optimized)
+    );
+    // Nothing but the test's own files was written.
+    assert_eq!(std::fs::read_dir(temp.path())?.count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn base64_answers_bare_base64_with_bare_base64() -> Result<(), Box<dyn Error>> {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    let temp = TempDir::new()?;
+    let input = fixtures::write_palette_source(&temp, "bare.png");
+    // Line-wrapped, as `base64` writes it.
+    let encoded = STANDARD.encode(std::fs::read(&input)?);
+    let wrapped: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+        .collect();
+
+    let stdout = Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--base64", "--mode", "compress"])
+        .write_stdin(wrapped.join("\n"))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let output = STANDARD.decode(String::from_utf8(stdout)?.trim_end())?;
+    assert!(output.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+    Ok(())
+}
+
+#[cfg(feature = "bundle")]
+#[test]
+fn bundle_packs_outputs_by_relative_path_into_zip_and_tar() -> Result<(), Box<dyn Error>> {
+    use std::io::Read;
+
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "assets/a.png", 16, 16);
+    fixtures::write_palette_source(&temp, "assets/icons/b.png");
+    let run = |bundle: &str| {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--bundle", bundle, "assets"])
+            .current_dir(temp.path())
+            .assert()
+            .success();
+    };
+
+    run("pack.zip");
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(temp.path().join("pack.zip"))?)?;
+    let mut zipped = Vec::new();
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        zipped.push((entry.name()?.into_owned(), data));
+    }
+
+    run("pack.tar.gz");
+    let gz = flate2::read::GzDecoder::new(std::fs::File::open(temp.path().join("pack.tar.gz"))?);
+    let mut tarred = Vec::new();
+    for entry in tar::Archive::new(gz).entries()? {
+        let mut entry = entry?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        tarred.push((entry.path()?.display().to_string(), data));
+    }
+
+    let names: Vec<&str> = zipped.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["a_optimized.png", "icons/b_optimized.png"]);
+    assert_eq!(zipped, tarred);
+    assert!(zipped.iter().all(|(_, data)| data.starts_with(b"\x89PNG")));
+    // Nothing but the bundles was written next to the inputs.
+    let mut written: Vec<_> = std::fs::read_dir(temp.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+    written.sort();
+    assert_eq!(written, ["assets", "pack.tar.gz", "pack.zip"]);
+    assert!(!temp.path().join("assets/a_optimized.png").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn link_duplicates_hard_links_identical_outputs() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp = TempDir::new()?;
+    let first = fixtures::write_unoptimized_rgba(&temp, "icons/a.png", 16, 16);
+    let second = fixtures::write_unoptimized_rgba(&temp, "icons/b.png", 16, 16);
+    let third = fixtures::write_unoptimized_rgba(&temp, "icons/c.png", 8, 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--link-duplicates"])
+        .args([&first, &second, &third])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(format!(
+            "linked 1 duplicate output, collapsing {} B of duplication",
+            fixtures::file_size(&fixtures::derived_output_path(&first, "_optimized.png"))
+        )));
+
+    let inode = |input: &PathBuf| -> std::io::Result<u64> {
+        Ok(std::fs::metadata(fixtures::derived_output_path(input, "_optimized.png"))?.ino())
+    };
+    assert_eq!(inode(&first)?, inode(&second)?);
+    assert_ne!(inode(&first)?, inode(&third)?);
+    let leftovers = std::fs::read_dir(temp.path().join("icons"))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    Ok(())
+}
+
+#[test]
+fn stage_only_deflate_strips_metadata_when_the_image_data_is_kept() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input =
+        fixtures::write_tiles_with_chunks(&temp, "tagged.png", &[(*b"tEXt", b"Comment\0drop me")]);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    // Stored zlib blocks never beat the input's compressed image data.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--stage-only", "deflate"])
+        .args(["--deflater", "libdeflate", "--compression-level", "0"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert!(fixtures::chunk_data(&input, b"tEXt").is_some());
+    assert_eq!(fixtures::chunk_data(&output, b"tEXt"), None);
+    assert_eq!(
+        fixtures::chunk_data(&output, b"IDAT"),
+        fixtures::chunk_data(&input, b"IDAT")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stage_only_deflate_recompresses_the_existing_filtered_data() -> Result<(), Box<dyn Error>> {
+    use std::io::Read;
+
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "archive.png", 32, 32);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    for deflater in ["libdeflate", "zlib"] {
+        Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+            .args(["--no-progress", "--overwrite", "--stage-only", "deflate"])
+            .args(["--deflater", deflater])
+            .arg(&input)
+            .assert()
+            .success();
+
+        let inflate = |path: &PathBuf| -> std::io::Result<Vec<u8>> {
+            let idat = fixtures::chunk_data(path, b"IDAT").expect("an IDAT chunk");
+            let mut raw = Vec::new();
+            flate2::read::ZlibDecoder::new(idat.as_slice()).read_to_end(&mut raw)?;
+            Ok(raw)
+        };
+        assert!(fixtures::file_size(&output) < fixtures::file_size(&input));
+        // Same header and the same filter bytes and filtered rows, only deflated again.
+        assert_eq!(
+            fixtures::chunk_data(&output, b"IHDR"),
+            fixtures::chunk_data(&input, b"IHDR")
+        );
+        assert_eq!(inflate(&output)?, inflate(&input)?);
+    }
+
+    Ok(())
+}
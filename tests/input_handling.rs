@@ -1,8 +1,11 @@
 use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use assert_cmd::Command;
 use assert_fs::TempDir;
-use assert_fs::fixture::{PathChild, PathCreateDir};
+use assert_fs::fixture::{FileWriteStr, PathChild, PathCreateDir};
 use predicates::prelude::*;
 
 mod fixtures;
@@ -79,3 +82,303 @@ fn dry_run_no_write() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn changed_since_only_processes_modified_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(temp.path())
+            .status()
+            .expect("failed to run git");
+        assert!(status.success(), "git {args:?} failed");
+    };
+
+    git(&["init", "-q"]);
+    let untouched = fixtures::write_unoptimized_rgba(&temp, "untouched.png", 8, 8);
+    let modified = fixtures::write_unoptimized_rgba(&temp, "modified.png", 8, 8);
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "initial"]);
+    fixtures::write_unoptimized_rgba(&temp, "modified.png", 10, 10);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .current_dir(temp.path())
+        .args(["--changed-since", "HEAD", "--no-progress"])
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    assert!(fixtures::derived_output_path(&modified, "_optimized.png").exists());
+    assert!(!fixtures::derived_output_path(&untouched, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn newer_than_timestamp_skips_older_inputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let old = fixtures::write_unoptimized_rgba(&temp, "old.png", 8, 8);
+    let fresh = fixtures::write_unoptimized_rgba(&temp, "fresh.png", 8, 8);
+    set_modified(&old, UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    set_modified(&fresh, UNIX_EPOCH + Duration::from_secs(2_000_000_000));
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--newer-than", "1500000000.250", "--no-progress"])
+        .arg(&old)
+        .arg(&fresh)
+        .assert()
+        .success();
+
+    assert!(!fixtures::derived_output_path(&old, "_optimized.png").exists());
+    assert!(fixtures::derived_output_path(&fresh, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn only_if_newer_skips_up_to_date_outputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "incremental.png", 8, 8);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    set_modified(&input, UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    fs::write(&output, b"up to date")?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--only-if-newer", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&output)?, b"up to date");
+
+    set_modified(&input, SystemTime::now() + Duration::from_secs(60));
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--only-if-newer", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_ne!(
+        fs::read(&output)?,
+        b"up to date",
+        "stale output should be replaced"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn force_reprocesses_up_to_date_outputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "forced.png", 8, 8);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    set_modified(&input, UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    fs::write(&output, b"up to date")?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--only-if-newer", "--force", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+    assert_ne!(fs::read(&output)?, b"up to date");
+
+    Ok(())
+}
+
+fn set_modified(path: &Path, time: SystemTime) {
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(time))
+        .unwrap_or_else(|err| panic!("failed to set mtime on {}: {err}", path.display()));
+}
+
+#[test]
+fn symlinked_directories_are_not_processed_twice() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let real = temp.child("real");
+    real.create_dir_all()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "real/image.png", 8, 8);
+    std::os::unix::fs::symlink(real.path(), temp.child("alias").path())?;
+    std::os::unix::fs::symlink(temp.path(), real.child("loop").path())?;
+
+    // Processing `alias/image.png` as well would fail because its output already exists.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress"])
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    assert!(fixtures::derived_output_path(&input, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn recreate_symlinks_links_outputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "image.png", 8, 8);
+    let link = temp.child("alias.png");
+    std::os::unix::fs::symlink("image.png", link.path())?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--recreate-symlinks"])
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    let link_output = temp.child("alias_optimized.png");
+    assert_eq!(
+        fs::read_link(link_output.path())?,
+        Path::new("image_optimized.png")
+    );
+    assert_eq!(
+        fs::read(link_output.path())?,
+        fs::read(fixtures::derived_output_path(&input, "_optimized.png"))?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn follow_symlinks_off_skips_linked_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let outside = TempDir::new()?;
+    let target = fixtures::write_unoptimized_rgba(&outside, "target.png", 8, 8);
+    let images = temp.child("images");
+    images.create_dir_all()?;
+    let local = fixtures::write_unoptimized_rgba(&temp, "images/local.png", 8, 8);
+    std::os::unix::fs::symlink(&target, images.child("linked.png").path())?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--follow-symlinks", "off"])
+        .arg(images.path())
+        .assert()
+        .success();
+
+    assert!(fixtures::derived_output_path(&local, "_optimized.png").exists());
+    assert!(!images.child("linked_optimized.png").path().exists());
+
+    Ok(())
+}
+
+#[test]
+fn respect_gitignore_and_skip_hidden_prune_traversal() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fs::create_dir(temp.child(".git").path())?;
+    temp.child(".gitignore").write_str("build/\n")?;
+    let kept = fixtures::write_unoptimized_rgba(&temp, "assets/kept.png", 8, 8);
+    let ignored = fixtures::write_unoptimized_rgba(&temp, "build/ignored.png", 8, 8);
+    let hidden = fixtures::write_unoptimized_rgba(&temp, ".cache/hidden.png", 8, 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--respect-gitignore", "--skip-hidden"])
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    assert!(fixtures::derived_output_path(&kept, "_optimized.png").exists());
+    assert!(!fixtures::derived_output_path(&ignored, "_optimized.png").exists());
+    assert!(!fixtures::derived_output_path(&hidden, "_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn stats_walks_directories_like_input_resolution() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fs::create_dir(temp.child(".git").path())?;
+    temp.child(".gitignore").write_str("build/\n")?;
+    fixtures::write_unoptimized_rgba(&temp, "assets/kept.png", 8, 8);
+    fixtures::write_unoptimized_rgba(&temp, "build/ignored.png", 8, 8);
+    fixtures::write_unoptimized_rgba(&temp, ".cache/hidden.png", 8, 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["stats", "--respect-gitignore", "--skip-hidden"])
+        .arg(temp.path())
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("1 PNG,"));
+
+    Ok(())
+}
+
+#[test]
+fn extensions_option_includes_apng_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let png = fixtures::write_unoptimized_rgba(&temp, "still.png", 8, 8);
+    let apng = fixtures::write_unoptimized_rgba(&temp, "anim.APNG", 8, 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--extensions", "png,.apng"])
+        .arg(temp.path())
+        .assert()
+        .success();
+
+    assert!(fixtures::derived_output_path(&png, "_optimized.png").exists());
+    assert!(fixtures::derived_output_path(&apng, "_optimized.apng").exists());
+
+    Ok(())
+}
+
+#[test]
+fn interactive_prompts_for_each_existing_output() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for name in ["a", "b", "c", "d"] {
+        let input = fixtures::write_unoptimized_rgba(&temp, &format!("{name}.png"), 10, 10);
+        let output = fixtures::derived_output_path(&input, "_optimized.png");
+        fs::write(&output, b"stale")?;
+        inputs.push(input);
+        outputs.push(output);
+    }
+
+    // An unrecognised answer is asked again; end of input skips whatever is left.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--order", "name"])
+        .arg("--interactive")
+        .args(&inputs)
+        .write_stdin("maybe\ns\nr\no\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("a_optimized.png exists").count(2))
+        .stderr(predicate::str::contains("b-2_optimized.png instead"));
+
+    assert_eq!(fs::read(&outputs[0])?, b"stale", "skipped");
+    assert_eq!(fs::read(&outputs[1])?, b"stale", "renamed instead");
+    assert!(temp.child("b-2_optimized.png").path().exists());
+    assert_ne!(fs::read(&outputs[2])?, b"stale", "overwritten");
+    assert_eq!(fs::read(&outputs[3])?, b"stale", "unanswered");
+
+    Ok(())
+}
+
+#[test]
+fn interactive_overwrite_all_stops_asking() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for name in ["a", "b"] {
+        let input = fixtures::write_unoptimized_rgba(&temp, &format!("{name}.png"), 10, 10);
+        let output = fixtures::derived_output_path(&input, "_optimized.png");
+        fs::write(&output, b"stale")?;
+        inputs.push(input);
+        outputs.push(output);
+    }
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress", "--order", "name"])
+        .arg("--interactive")
+        .args(&inputs)
+        .write_stdin("a\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("exists").count(1));
+
+    for output in &outputs {
+        assert_ne!(fs::read(output)?, b"stale");
+    }
+
+    Ok(())
+}
@@ -40,3 +40,141 @@ fn quiet_progress_emits_no_spinner_output() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn extract_meta_dumps_text_and_exif_to_separate_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let sample = fixtures::write_fixture(&temp, "sample.png");
+    let photo = fixtures::write_with_exif(&temp, "photo.png", 6);
+    let out = temp.path().join("meta");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("extract-meta")
+        .args([&sample, &photo])
+        .arg("--out")
+        .arg(&out)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("sample.Comment.txt"));
+
+    assert_eq!(
+        std::fs::read_to_string(out.join("sample.Comment.txt"))?,
+        "licensed"
+    );
+    assert_eq!(
+        std::fs::read(out.join("photo.exif"))?,
+        fixtures::chunk_data(&photo, b"eXIf").expect("fixture has eXIf")
+    );
+
+    // Existing dumps are protected unless --overwrite is given.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("extract-meta")
+        .arg(&sample)
+        .arg("--out")
+        .arg(&out)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+
+    Ok(())
+}
+
+#[test]
+fn extract_meta_numbers_inputs_that_share_a_stem() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let still = fixtures::write_with_exif(&temp, "photo.png", 6);
+    let animated = fixtures::write_with_exif(&temp, "photo.apng", 3);
+    let out = temp.path().join("meta");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .arg("extract-meta")
+        .args([&still, &animated])
+        .arg("--out")
+        .arg(&out)
+        .assert()
+        .success();
+
+    assert_eq!(
+        std::fs::read(out.join("photo.exif"))?,
+        fixtures::chunk_data(&still, b"eXIf").expect("fixture has eXIf")
+    );
+    assert_eq!(
+        std::fs::read(out.join("photo-2.exif"))?,
+        fixtures::chunk_data(&animated, b"eXIf").expect("fixture has eXIf")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn warnings_are_reported_even_without_progress() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let sideways = fixtures::write_with_exif(&temp, "sideways.png", 6);
+    let private = fixtures::write_tiles_with_chunks(&temp, "private.png", &[(*b"prVt", b"x")]);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--no-progress"])
+        .arg(&sideways)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "⚠ {}: EXIF orientation 6 ignored",
+            sideways.display()
+        )));
+    // A kept eXIf still rotates the image in viewers, so nothing was lost.
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "optimize",
+            "--keep-exif",
+            "--overwrite",
+            "--no-progress",
+        ])
+        .arg(&sideways)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--keep-metadata", "--emit-sidecar"])
+        .arg("--no-progress")
+        .arg(&private)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "unknown ancillary chunk prVt preserved",
+        ));
+    let sidecar = fixtures::derived_output_path(&private, "_optimized.png");
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(sidecar.with_extension("png.json"))?)?;
+    assert_eq!(
+        sidecar["warnings"],
+        serde_json::json!(["unknown ancillary chunk prVt preserved"])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn warnings_as_errors_fails_files_that_raise_warnings() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let wide = fixtures::write_gray16_ramp(&temp, "wide.png", 64, 8, 0, 65535);
+    let output = fixtures::derived_output_path(&wide, "_compressed.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--mode",
+            "compress",
+            "--warnings-as-errors",
+            "--no-progress",
+        ])
+        .arg(&wide)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "warnings treated as errors: 16-bit samples reduced to 8 bits",
+        ));
+    assert!(!output.exists());
+
+    Ok(())
+}
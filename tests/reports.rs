@@ -0,0 +1,493 @@
+use std::error::Error;
+use std::fs;
+
+use assert_cmd::Command;
+use assert_fs::TempDir;
+use assert_fs::fixture::{FileWriteStr, PathChild};
+use predicates::prelude::*;
+use sha2::{Digest, Sha256};
+
+mod fixtures;
+
+#[test]
+fn emit_sidecar_describes_output() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "sidecar.png", 16, 16);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+    let sidecar = output.with_extension("png.json");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--emit-sidecar", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&sidecar)?)?;
+    assert_eq!(json["mode"], "optimize");
+    assert_eq!(json["original_size"], fixtures::file_size(&input));
+    assert_eq!(json["output_size"], fixtures::file_size(&output));
+    assert_eq!(json["tool_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(json["options"]["zopfli"], false);
+    // The fixture is saved with fast compression, so oxipng always finds something smaller.
+    let notes: Vec<&str> = json["notes"]
+        .as_array()
+        .expect("notes are listed")
+        .iter()
+        .filter_map(serde_json::Value::as_str)
+        .collect();
+    assert!(
+        notes.iter().any(|note| note.starts_with("filters=")),
+        "{notes:?}"
+    );
+    assert!(notes.contains(&"libdeflate"), "{notes:?}");
+    assert_eq!(
+        json["original_sha256"].as_str().map(str::len),
+        Some(64),
+        "expected hex-encoded SHA-256 of the original"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn emit_sidecar_notes_the_reductions_oxipng_made() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_tiles(
+        &temp,
+        "tiles.png",
+        &[[200, 40, 40, 255], [40, 200, 40, 255], [40, 40, 200, 255]],
+    );
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--mode", "optimize", "--emit-sidecar", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .success();
+
+    let json: serde_json::Value =
+        serde_json::from_slice(&fs::read(output.with_extension("png.json"))?)?;
+    let notes = json["notes"].as_array().expect("notes are listed");
+    // Every tile is opaque, so the alpha channel goes.
+    assert!(notes.contains(&"RGBA→RGB".into()), "{notes:?}");
+    assert!(notes.contains(&"filters=none".into()), "{notes:?}");
+
+    Ok(())
+}
+
+#[test]
+fn emit_sidecar_notes_webp_skipped_for_16_bit_images() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_gray16_ramp(&temp, "deep.png", 64, 4, 1, 65000);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--emit-smallest",
+            "png,webp",
+            "--emit-sidecar",
+            "--no-progress",
+        ])
+        .arg(&input)
+        .assert()
+        .success();
+
+    assert!(!fixtures::derived_output_path(&input, "_optimized.webp").exists());
+    let reader = png::Decoder::new(fs::File::open(&output)?).read_info()?;
+    assert_eq!(reader.info().bit_depth, png::BitDepth::Sixteen);
+    let json: serde_json::Value =
+        serde_json::from_slice(&fs::read(output.with_extension("png.json"))?)?;
+    let notes = json["notes"].as_array().expect("notes are listed");
+    assert!(
+        notes.contains(&"webp skipped, 16-bit samples".into()),
+        "{notes:?}"
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--emit-smallest", "webp", "--no-progress"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("16-bit"));
+
+    Ok(())
+}
+
+#[test]
+fn budgets_fail_when_output_exceeds_limit() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let small = fixtures::write_unoptimized_rgba(&temp, "icons/small.png", 4, 4);
+    let large = fixtures::write_unoptimized_rgba(&temp, "hero/large.png", 64, 64);
+    let budgets = temp.child("budgets.toml");
+    budgets.write_str("\"icons/*.png\" = 4096\n\"hero/*.png\" = 16\n")?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--budgets"])
+        .arg(budgets.path())
+        .arg(&small)
+        .arg(&large)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "1 file(s) exceed their size budget",
+        ))
+        .stderr(predicate::str::contains("large.png"))
+        .stderr(predicate::str::contains("small.png").not());
+
+    Ok(())
+}
+
+#[test]
+fn measure_only_records_exact_sizes_and_writes_nothing_else() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let first = fixtures::write_unoptimized_rgba(&temp, "first.png", 8, 8);
+    let second = fixtures::write_palette_source(&temp, "second.png");
+    // An existing output doesn't matter when nothing is written.
+    let stale = fixtures::derived_output_path(&second, "_optimized.png");
+    fs::write(&stale, b"stale")?;
+    let ledger = temp.child("ledger.json");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--measure-only"])
+        .arg(ledger.path())
+        .arg(&first)
+        .arg(&second)
+        .assert()
+        .success();
+
+    assert!(!fixtures::derived_output_path(&first, "_optimized.png").exists());
+    assert_eq!(fs::read(&stale)?, b"stale");
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(ledger.path())?)?;
+    let files = json["files"].as_object().expect("files are listed");
+    assert_eq!(files.len(), 2);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--overwrite"])
+        .arg(&first)
+        .arg(&second)
+        .assert()
+        .success();
+    let mut total = 0;
+    for input in [&first, &second] {
+        let sizes = files
+            .iter()
+            .find(|(key, _)| key.ends_with(&*input.file_name().unwrap().to_string_lossy()))
+            .map(|(_, sizes)| sizes)
+            .expect("ledger entry for input");
+        let output_size =
+            fixtures::file_size(&fixtures::derived_output_path(input, "_optimized.png"));
+        assert_eq!(sizes["original_size"], fixtures::file_size(input));
+        assert_eq!(
+            sizes["output_size"], output_size,
+            "measured sizes are exact"
+        );
+        total += output_size;
+    }
+    assert_eq!(json["total"]["output_size"], total);
+
+    Ok(())
+}
+
+#[test]
+fn measure_only_leaves_copy_unchanged_output_trees_unwritten() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fs::create_dir_all(temp.path().join("in"))?;
+    fixtures::write_unoptimized_rgba(&temp, "in/a.png", 8, 8);
+    fs::write(temp.path().join("in/readme.txt"), "kept")?;
+    let out = temp.path().join("out");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--mode", "optimize", "--measure-only"])
+        .arg(temp.path().join("ledger.json"))
+        .arg("--output-dir")
+        .arg(&out)
+        .arg("--copy-unchanged")
+        .arg(temp.path().join("in"))
+        .assert()
+        .success();
+
+    assert!(!out.join("readme.txt").exists());
+    assert!(!out.join("a_optimized.png").exists());
+
+    Ok(())
+}
+
+#[test]
+fn manifest_out_maps_inputs_to_hashed_outputs() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let first = fixtures::write_unoptimized_rgba(&temp, "first.png", 8, 8);
+    let second = fixtures::write_palette_source(&temp, "second.png");
+    let manifest = temp.child("assets.json");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--manifest-out"])
+        .arg(manifest.path())
+        .arg(&first)
+        .arg(&second)
+        .assert()
+        .success();
+
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(manifest.path())?)?;
+    let entries = json.as_object().expect("manifest is an object");
+    assert_eq!(entries.len(), 2);
+
+    for input in [&first, &second] {
+        let output = fixtures::derived_output_path(input, "_optimized.png");
+        let entry = entries
+            .iter()
+            .find(|(key, _)| key.ends_with(&*input.file_name().unwrap().to_string_lossy()))
+            .map(|(_, entry)| entry)
+            .expect("manifest entry for input");
+        assert!(
+            entry["output"]
+                .as_str()
+                .unwrap()
+                .ends_with("_optimized.png")
+        );
+        assert_eq!(entry["size"], fixtures::file_size(&output));
+        assert_eq!(entry["sha256"].as_str().map(str::len), Some(64));
+    }
+
+    Ok(())
+}
+
+fn sha256_hex(path: &std::path::Path) -> Result<String, Box<dyn Error>> {
+    Ok(Sha256::digest(fs::read(path)?)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[test]
+fn checksums_list_every_output_in_sha256sums() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "src/first.png", 8, 8);
+    fixtures::write_palette_source(&temp, "src/nested/second.png");
+    let out = temp.child("out");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--checksums", "sha256", "--output-dir"])
+        .arg(out.path())
+        .arg(temp.child("src").path())
+        .assert()
+        .success();
+
+    let sums = fs::read_to_string(out.child("SHA256SUMS").path())?;
+    let expected = format!(
+        "{}  first_optimized.png\n{}  nested/second_optimized.png\n",
+        sha256_hex(out.child("first_optimized.png").path())?,
+        sha256_hex(out.child("nested/second_optimized.png").path())?,
+    );
+    assert_eq!(sums, expected);
+
+    Ok(())
+}
+
+#[test]
+fn checksums_per_file_sit_next_to_each_output() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "single.png", 8, 8);
+    let output = fixtures::derived_output_path(&input, "_optimized.png");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--no-progress",
+            "--checksums",
+            "sha256",
+            "--checksums-per-file",
+        ])
+        .arg(&input)
+        .current_dir(temp.path())
+        .assert()
+        .success();
+
+    let checksum = fs::read_to_string(output.with_extension("png.sha256"))?;
+    assert_eq!(
+        checksum,
+        format!("{}  single_optimized.png\n", sha256_hex(&output)?)
+    );
+    assert!(!temp.child("SHA256SUMS").exists());
+
+    Ok(())
+}
+
+#[test]
+fn rename_map_lists_written_and_failed_files() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "good.png", 8, 8);
+    let broken = fixtures::write_fixture(&temp, "broken, really.png");
+    let bytes = fs::read(&broken)?;
+    fs::write(&broken, &bytes[..bytes.len() - 20])?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--no-progress",
+            "--order",
+            "name",
+            "--rename-map",
+            "map.csv",
+        ])
+        .args(["broken, really.png", "good.png"])
+        .current_dir(temp.path())
+        .assert()
+        .failure();
+
+    // Inputs are recorded as resolved, so with the temp directory as their parent.
+    let dir = temp.path().canonicalize()?;
+    let dir = dir.display();
+    assert_eq!(
+        fs::read_to_string(temp.child("map.csv").path())?,
+        format!(
+            "input,output,status\n\
+             \"{dir}/broken, really.png\",\"{dir}/broken, really_optimized.png\",failed\n\
+             {dir}/good.png,{dir}/good_optimized.png,written\n"
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rename_map_lists_skipped_files_when_nothing_is_processed() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "image.png", 8, 8);
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "image.png"])
+        .current_dir(temp.path())
+        .assert()
+        .success();
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args([
+            "--no-progress",
+            "--only-if-newer",
+            "--rename-map",
+            "map.csv",
+        ])
+        .arg("image.png")
+        .current_dir(temp.path())
+        .assert()
+        .success();
+
+    let dir = temp.path().canonicalize()?;
+    let dir = dir.display();
+    assert_eq!(
+        fs::read_to_string(temp.child("map.csv").path())?,
+        format!(
+            "input,output,status\n\
+             {dir}/image.png,{dir}/image_optimized.png,skipped\n"
+        )
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn log_syslog_reports_finished_and_failed_files() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "good.png", 8, 8);
+    let broken = fixtures::write_fixture(&temp, "broken.png");
+    let bytes = fs::read(&broken)?;
+    fs::write(&broken, &bytes[..bytes.len() - 20])?;
+    let socket_path = temp.child("log.sock");
+    let socket = UnixDatagram::bind(socket_path.path())?;
+    socket.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--log", "syslog", "--syslog-socket"])
+        .arg(socket_path.path())
+        .args(["good.png", "broken.png"])
+        .current_dir(temp.path())
+        .assert()
+        .failure();
+
+    let mut messages = Vec::new();
+    for _ in 0..2 {
+        let mut buf = vec![0; 64 * 1024];
+        let len = socket.recv(&mut buf)?;
+        messages.push(String::from_utf8(buf[..len].to_vec())?);
+    }
+    messages.sort();
+    let dir = temp.path().canonicalize()?;
+    let dir = dir.display();
+    // Facility `user`: error is <11>, info is <14>.
+    assert!(
+        messages[0].starts_with("<11>1 - - turbo-png "),
+        "{}",
+        messages[0]
+    );
+    assert!(messages[0].contains(&format!(
+        " failed [turbo-png@32473 input=\"{dir}/broken.png\""
+    )));
+    assert!(
+        messages[1].starts_with("<14>1 - - turbo-png "),
+        "{}",
+        messages[1]
+    );
+    assert!(messages[1].contains(&format!(
+        " finished [turbo-png@32473 input=\"{dir}/good.png\""
+    )));
+    assert!(messages[1].contains(&format!("output=\"{dir}/good_optimized.png\"")));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn log_syslog_reports_skipped_files_with_their_reason() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "fresh.png", 8, 8);
+    fixtures::write_unoptimized_rgba(&temp, "stale.png", 8, 8);
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "fresh.png"])
+        .current_dir(temp.path())
+        .assert()
+        .success();
+    let socket_path = temp.child("log.sock");
+    let socket = UnixDatagram::bind(socket_path.path())?;
+    socket.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--only-if-newer", "--log", "syslog"])
+        .arg("--syslog-socket")
+        .arg(socket_path.path())
+        .args(["fresh.png", "stale.png"])
+        .current_dir(temp.path())
+        .assert()
+        .success();
+
+    let mut messages = Vec::new();
+    for _ in 0..2 {
+        let mut buf = vec![0; 64 * 1024];
+        let len = socket.recv(&mut buf)?;
+        messages.push(String::from_utf8(buf[..len].to_vec())?);
+    }
+    let dir = temp.path().canonicalize()?;
+    let dir = dir.display();
+    let skipped = messages
+        .iter()
+        .find(|message| message.contains(" skipped "))
+        .expect("a skipped event");
+    assert!(
+        skipped.contains(&format!("input=\"{dir}/fresh.png\"")),
+        "{skipped}"
+    );
+    assert!(skipped.contains("reason=\"not newer\""), "{skipped}");
+    let finished = messages
+        .iter()
+        .find(|message| message.contains(" finished "))
+        .expect("a finished event");
+    assert!(
+        finished.contains(&format!("input=\"{dir}/stale.png\"")),
+        "{finished}"
+    );
+
+    Ok(())
+}
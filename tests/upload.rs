@@ -0,0 +1,167 @@
+#![cfg(feature = "upload")]
+
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use assert_cmd::Command;
+use assert_fs::TempDir;
+use assert_fs::fixture::PathChild;
+
+mod fixtures;
+
+/// One request the test server received.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Received {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Joins to everything the server received.
+type Server = thread::JoinHandle<Vec<Received>>;
+
+/// Serves `count` requests on a local port, answering each one with the next status from
+/// `statuses` (200 once they run out), and returns the port and what was received.
+fn serve(count: usize, statuses: Vec<u16>) -> Result<(u16, Server), Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let handle = thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        let mut received = Vec::new();
+        for stream in listener.incoming().take(count) {
+            let mut stream = stream.expect("accepting a connection");
+            let mut reader = BufReader::new(stream.try_clone().expect("cloning the stream"));
+            let mut request_line = String::new();
+            reader
+                .read_line(&mut request_line)
+                .expect("reading request");
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).expect("reading header");
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    length = value.trim().parse().expect("numeric content length");
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).expect("reading body");
+
+            let status = statuses.next().unwrap_or(200);
+            write!(
+                stream,
+                "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .expect("writing response");
+
+            let mut parts = request_line.split_whitespace();
+            received.push(Received {
+                method: parts.next().unwrap_or_default().to_owned(),
+                path: parts.next().unwrap_or_default().to_owned(),
+                body,
+            });
+        }
+        received
+    });
+    Ok((port, handle))
+}
+
+#[test]
+fn upload_url_puts_each_output_and_retries_server_errors() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    fixtures::write_unoptimized_rgba(&temp, "src/a b.png", 8, 8);
+    fixtures::write_palette_source(&temp, "src/nested/c.png");
+    let out = temp.child("out");
+    // The first request fails with a 503 and is retried.
+    let (port, server) = serve(3, vec![503])?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--upload-jobs", "1", "--output-dir"])
+        .arg(out.path())
+        .arg("--upload-url")
+        .arg(format!("http://127.0.0.1:{port}/put/{{relpath}}"))
+        .arg(temp.child("src").path())
+        .assert()
+        .success();
+
+    let mut received = server.join().expect("server thread");
+    received.sort();
+    received.dedup();
+    let expected = |path: &str, output: &str| -> Result<Received, Box<dyn Error>> {
+        Ok(Received {
+            method: "PUT".to_owned(),
+            path: path.to_owned(),
+            body: std::fs::read(out.child(output).path())?,
+        })
+    };
+    assert_eq!(
+        received,
+        [
+            expected("/put/a%20b_optimized.png", "a b_optimized.png")?,
+            expected("/put/nested/c_optimized.png", "nested/c_optimized.png")?,
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn upload_url_uploads_written_outputs_when_other_files_fail() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "good.png", 8, 8);
+    std::fs::write(temp.child("broken.png").path(), b"not a png")?;
+    let (port, server) = serve(1, vec![])?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--upload-url"])
+        .arg(format!("http://127.0.0.1:{port}/{{relpath}}"))
+        .arg(&input)
+        .arg(temp.child("broken.png").path())
+        .current_dir(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("broken.png"));
+
+    let received = server.join().expect("server thread");
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].path, "/good_optimized.png");
+
+    Ok(())
+}
+
+#[test]
+fn upload_url_fails_on_client_errors_without_retrying() -> Result<(), Box<dyn Error>> {
+    let temp = TempDir::new()?;
+    let input = fixtures::write_unoptimized_rgba(&temp, "a.png", 8, 8);
+    let (port, server) = serve(1, vec![403])?;
+
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--no-progress", "--upload-url"])
+        .arg(format!("http://127.0.0.1:{port}/{{relpath}}"))
+        .arg(&input)
+        .current_dir(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "one or more files failed during upload",
+        ))
+        .stderr(predicates::str::contains("403"));
+    assert_eq!(server.join().expect("server thread").len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn upload_url_needs_a_relpath_placeholder() {
+    Command::new(assert_cmd::cargo::cargo_bin!("turbo-png"))
+        .args(["--upload-url", "https://cdn.example/put/", "."])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("must contain {relpath}"));
+}